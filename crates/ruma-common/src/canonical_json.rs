@@ -2,8 +2,9 @@
 
 use std::{fmt, mem};
 
+use js_int::Int;
 use serde::Serialize;
-use serde_json::Value as JsonValue;
+use serde_json::{value::RawValue as RawJsonValue, Number as JsonNumber, Value as JsonValue};
 
 mod value;
 
@@ -115,6 +116,43 @@ pub fn to_canonical_value<T: Serialize>(
     serde_json::to_value(value).map_err(CanonicalJsonError::SerDe)?.try_into()
 }
 
+/// Serialize the given raw JSON value to a string in the [canonical JSON] form.
+///
+/// The keys of objects are sorted and insignificant whitespace is removed. Numbers with an exponent
+/// or a fractional part of zero are converted to integers.
+///
+/// # Errors
+///
+/// Returns an error if the value is not valid JSON, or if it contains a number that is not an
+/// integer or that is outside of the range of [`Int`].
+///
+/// [canonical JSON]: https://spec.matrix.org/latest/appendices/#canonical-json
+pub fn to_canonical_string(value: &RawJsonValue) -> Result<String, CanonicalJsonError> {
+    let mut value = serde_json::from_str(value.get()).map_err(CanonicalJsonError::SerDe)?;
+    integral_floats_to_integers(&mut value);
+
+    Ok(CanonicalJsonValue::try_from(value)?.to_string())
+}
+
+/// Replace the floats with a fractional part of zero in the given JSON value with integers, if
+/// they fit in an [`Int`].
+fn integral_floats_to_integers(value: &mut JsonValue) {
+    match value {
+        JsonValue::Number(num) if !num.is_i64() => {
+            if let Some(int) = num
+                .as_f64()
+                .filter(|float| float.fract() == 0.0)
+                .and_then(|float| Int::try_from(float as i64).ok())
+            {
+                *num = JsonNumber::from(i64::from(int));
+            }
+        }
+        JsonValue::Array(vec) => vec.iter_mut().for_each(integral_floats_to_integers),
+        JsonValue::Object(obj) => obj.values_mut().for_each(integral_floats_to_integers),
+        _ => {}
+    }
+}
+
 /// The value to put in `unsigned.redacted_because`.
 #[derive(Clone, Debug)]
 pub struct RedactedBecause(CanonicalJsonObject);
@@ -505,10 +543,12 @@ mod tests {
     use js_int::int;
     use serde_json::{
         from_str as from_json_str, json, to_string as to_json_string, to_value as to_json_value,
+        value::RawValue as RawJsonValue,
     };
 
     use super::{
-        redact_in_place, to_canonical_value, try_from_json_map, value::CanonicalJsonValue,
+        redact_in_place, to_canonical_string, to_canonical_value, try_from_json_map,
+        value::CanonicalJsonValue, CanonicalJsonError,
     };
     use crate::RoomVersionId;
 
@@ -558,6 +598,64 @@ mod tests {
         );
     }
 
+    fn canonical_string(json: &str) -> Result<String, CanonicalJsonError> {
+        to_canonical_string(&RawJsonValue::from_string(json.to_owned()).unwrap())
+    }
+
+    #[test]
+    fn canonical_string_spec_examples() {
+        assert_eq!(canonical_string("{}").unwrap(), "{}");
+        assert_eq!(
+            canonical_string(r#"{ "one": 1, "two": "Two" }"#).unwrap(),
+            r#"{"one":1,"two":"Two"}"#
+        );
+        assert_eq!(canonical_string(r#"{ "b": "2", "a": "1" }"#).unwrap(), r#"{"a":"1","b":"2"}"#);
+        assert_eq!(canonical_string(r#"{"b":"2","a":"1"}"#).unwrap(), r#"{"a":"1","b":"2"}"#);
+        assert_eq!(
+            canonical_string(
+                r#"{
+                    "auth": {
+                        "success": true,
+                        "mxid": "@john.doe:example.com",
+                        "profile": {
+                            "display_name": "John Doe",
+                            "three_pids": [
+                                {
+                                    "medium": "email",
+                                    "address": "john.doe@example.org"
+                                },
+                                {
+                                    "medium": "msisdn",
+                                    "address": "123456789"
+                                }
+                            ]
+                        }
+                    }
+                }"#
+            )
+            .unwrap(),
+            r#"{"auth":{"mxid":"@john.doe:example.com","profile":{"display_name":"John Doe","three_pids":[{"address":"john.doe@example.org","medium":"email"},{"address":"123456789","medium":"msisdn"}]},"success":true}}"#
+        );
+        assert_eq!(canonical_string(r#"{ "a": "日本語" }"#).unwrap(), r#"{"a":"日本語"}"#);
+        assert_eq!(canonical_string(r#"{ "本": 2, "日": 1 }"#).unwrap(), r#"{"日":1,"本":2}"#);
+        assert_eq!(canonical_string(r#"{ "a": "\u65E5" }"#).unwrap(), r#"{"a":"日"}"#);
+        assert_eq!(canonical_string(r#"{ "a": null }"#).unwrap(), r#"{"a":null}"#);
+        assert_eq!(
+            canonical_string(r#"{ "a": -0, "b": 1e10 }"#).unwrap(),
+            r#"{"a":0,"b":10000000000}"#
+        );
+    }
+
+    #[test]
+    fn canonical_string_invalid_numbers() {
+        assert_matches!(canonical_string(r#"{ "a": 1.5 }"#), Err(CanonicalJsonError::IntConvert));
+        assert_matches!(
+            canonical_string(r#"{ "a": 9007199254740992 }"#),
+            Err(CanonicalJsonError::IntConvert)
+        );
+        assert_matches!(canonical_string(r#"{ "a": 1e16 }"#), Err(CanonicalJsonError::IntConvert));
+    }
+
     #[test]
     fn serialize_map_to_canonical() {
         let mut expected = BTreeMap::new();
@@ -765,4 +863,85 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn redact_event_keys_per_version() {
+        let original_event = json!({
+            "auth_events": ["$auth"],
+            "content": {
+                "reason": "Spam",
+                "redacts": "$redacted",
+            },
+            "depth": 12,
+            "event_id": "$143273582443PhrSn",
+            "hashes": { "sha256": "hash" },
+            "membership": "join",
+            "origin": "example.org",
+            "origin_server_ts": 1_432_735,
+            "prev_events": ["$prev"],
+            "prev_state": [],
+            "redacts": "$redacted",
+            "room_id": "!jEsUZKDJdhlrceRyVU:example.org",
+            "sender": "@example:example.org",
+            "signatures": {},
+            "type": "m.room.redaction",
+            "unsigned": {
+                "age": 1234,
+            },
+        });
+
+        assert_matches!(
+            CanonicalJsonValue::try_from(original_event),
+            Ok(CanonicalJsonValue::Object(object))
+        );
+
+        // Before room version 11, `origin`, `membership` and `prev_state` are kept, but not the
+        // `redacts` key, neither at the top-level nor in the content.
+        let mut v1_object = object.clone();
+        redact_in_place(&mut v1_object, &RoomVersionId::V1, None).unwrap();
+
+        assert_eq!(
+            to_json_value(&v1_object).unwrap(),
+            json!({
+                "auth_events": ["$auth"],
+                "content": {},
+                "depth": 12,
+                "event_id": "$143273582443PhrSn",
+                "hashes": { "sha256": "hash" },
+                "membership": "join",
+                "origin": "example.org",
+                "origin_server_ts": 1_432_735,
+                "prev_events": ["$prev"],
+                "prev_state": [],
+                "room_id": "!jEsUZKDJdhlrceRyVU:example.org",
+                "sender": "@example:example.org",
+                "signatures": {},
+                "type": "m.room.redaction",
+            })
+        );
+
+        // Since room version 11, the `redacts` key of the content is kept, but not `origin`,
+        // `membership` and `prev_state`.
+        let mut v11_object = object;
+        redact_in_place(&mut v11_object, &RoomVersionId::V11, None).unwrap();
+
+        assert_eq!(
+            to_json_value(&v11_object).unwrap(),
+            json!({
+                "auth_events": ["$auth"],
+                "content": {
+                    "redacts": "$redacted",
+                },
+                "depth": 12,
+                "event_id": "$143273582443PhrSn",
+                "hashes": { "sha256": "hash" },
+                "origin_server_ts": 1_432_735,
+                "prev_events": ["$prev"],
+                "room_id": "!jEsUZKDJdhlrceRyVU:example.org",
+                "sender": "@example:example.org",
+                "signatures": {},
+                "type": "m.room.redaction",
+            })
+        );
+    }
 }
@@ -0,0 +1,64 @@
+//! Glob patterns, as used for server ACLs or push rules conditions.
+
+use wildmatch::WildMatch;
+
+/// A compiled glob pattern.
+///
+/// The only special characters are `*`, which matches zero or more characters, and `?`, which
+/// matches exactly one character. There is no way to escape them. The pattern must match the whole
+/// string.
+#[derive(Clone, Debug)]
+pub struct Glob(WildMatch);
+
+impl Glob {
+    /// Compile the given glob pattern.
+    pub fn compile(pattern: &str) -> Self {
+        Self(WildMatch::new(pattern))
+    }
+
+    /// Whether the given string matches this pattern.
+    pub fn matches(&self, s: &str) -> bool {
+        self.0.matches(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Glob;
+
+    #[test]
+    fn wildcard_subdomain() {
+        let glob = Glob::compile("*.example.com");
+        assert!(glob.matches("matrix.example.com"));
+        assert!(glob.matches("a.b.example.com"));
+        assert!(!glob.matches("example.com"));
+        assert!(!glob.matches("matrix.example.com.evil"));
+    }
+
+    #[test]
+    fn single_character() {
+        let glob = Glob::compile("host?.net");
+        assert!(glob.matches("host1.net"));
+        assert!(!glob.matches("host.net"));
+        assert!(!glob.matches("host12.net"));
+    }
+
+    #[test]
+    fn literal() {
+        let glob = Glob::compile("example.com");
+        assert!(glob.matches("example.com"));
+        assert!(!glob.matches("example.co"));
+        assert!(!glob.matches("www.example.com"));
+    }
+
+    #[test]
+    fn no_escaping() {
+        let glob = Glob::compile(r"\*.example.com");
+        assert!(glob.matches(r"\matrix.example.com"));
+        assert!(!glob.matches("*.example.com"));
+
+        let glob = Glob::compile("what?");
+        assert!(glob.matches("whats"));
+        assert!(glob.matches("what?"));
+    }
+}
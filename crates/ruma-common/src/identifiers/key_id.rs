@@ -209,7 +209,8 @@ mod tests {
     use assert_matches2::assert_matches;
     use ruma_identifiers_validation::Error;
 
-    use super::DeviceKeyId;
+    use super::{DeviceKeyId, ServerSigningKeyId};
+    use crate::SigningKeyAlgorithm;
 
     #[test]
     fn algorithm_and_key_name_are_correctly_extracted() {
@@ -237,4 +238,19 @@ mod tests {
         // Weirdly, this also reports MissingColon
         assert_matches!(error, Error::MissingColon);
     }
+
+    #[test]
+    fn server_signing_key_id() {
+        let key_id = ServerSigningKeyId::parse("ed25519:abc").expect("Should parse correctly");
+        assert_eq!(key_id.algorithm(), SigningKeyAlgorithm::Ed25519);
+        assert_eq!(key_id.key_name(), "abc");
+        assert_eq!(key_id.as_str(), "ed25519:abc");
+
+        let key_id =
+            ServerSigningKeyId::from_parts(SigningKeyAlgorithm::Ed25519, "abc".try_into().unwrap());
+        assert_eq!(key_id.to_string(), "ed25519:abc");
+
+        let error = ServerSigningKeyId::parse("abc").expect_err("Should fail to parse");
+        assert_matches!(error, Error::MissingColon);
+    }
 }
@@ -6,9 +6,8 @@ use regex::bytes::Regex;
 use ruma_macros::StringEnum;
 use serde::{Deserialize, Serialize};
 use serde_json::value::Value as JsonValue;
-use wildmatch::WildMatch;
 
-use crate::{power_levels::NotificationPowerLevels, OwnedRoomId, OwnedUserId, UserId};
+use crate::{glob::Glob, power_levels::NotificationPowerLevels, OwnedRoomId, OwnedUserId, UserId};
 #[cfg(feature = "unstable-msc3931")]
 use crate::{PrivOwnedStr, RoomVersionId};
 
@@ -352,7 +351,7 @@ impl StrExt for str {
         if match_words {
             value.matches_word(pattern)
         } else {
-            WildMatch::new(pattern).matches(value)
+            Glob::compile(pattern).matches(value)
         }
     }
 
@@ -0,0 +1,305 @@
+//! Decoding and encoding of [BlurHash] strings, like the one of [`ImageInfo`].
+//!
+//! [BlurHash]: https://blurha.sh
+//! [`ImageInfo`]: crate::room::ImageInfo
+
+use std::f32::consts::PI;
+
+/// The characters of the base 83 encoding used by BlurHash.
+const BASE83_CHARACTERS: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// An error encountered when decoding or encoding a BlurHash.
+#[derive(Copy, Clone, Debug, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum BlurHashError {
+    /// The BlurHash is shorter than the 6 characters of the smallest BlurHash.
+    #[error("BlurHash is too short")]
+    TooShort,
+
+    /// The length of the BlurHash doesn't match its number of components.
+    #[error("BlurHash should be {expected} characters long, but is {actual} characters long")]
+    InvalidLength {
+        /// The length expected from the number of components.
+        expected: usize,
+
+        /// The actual length of the BlurHash.
+        actual: usize,
+    },
+
+    /// The BlurHash contains a character that is not in the base 83 alphabet.
+    #[error("invalid character in BlurHash: {0:?}")]
+    InvalidCharacter(char),
+
+    /// The number of components is not between 1 and 9.
+    #[error("the number of components must be between 1 and 9")]
+    InvalidComponents,
+
+    /// The width or height is zero, or doesn't match the number of pixels.
+    #[error("invalid dimensions of the image")]
+    InvalidDimensions,
+}
+
+/// Decode the given BlurHash to an image of the given dimensions.
+///
+/// `punch` adjusts the contrast of the image, `1.0` keeps the contrast of the BlurHash.
+///
+/// Returns the pixels of the image, row by row, as RGBA bytes.
+pub fn decode_blurhash(
+    hash: &str,
+    width: u32,
+    height: u32,
+    punch: f32,
+) -> Result<Vec<u8>, BlurHashError> {
+    if width == 0 || height == 0 {
+        return Err(BlurHashError::InvalidDimensions);
+    }
+
+    // The hash is sliced by bytes below, which only matches the characters for ASCII.
+    if let Some(c) = hash.chars().find(|c| !c.is_ascii()) {
+        return Err(BlurHashError::InvalidCharacter(c));
+    }
+
+    if hash.len() < 6 {
+        return Err(BlurHashError::TooShort);
+    }
+
+    let size_flag = decode_base83(&hash[..1])?;
+    let num_x = (size_flag % 9 + 1) as usize;
+    let num_y = (size_flag / 9 + 1) as usize;
+
+    let expected = 4 + 2 * num_x * num_y;
+    if hash.len() != expected {
+        return Err(BlurHashError::InvalidLength { expected, actual: hash.len() });
+    }
+
+    let quantised_max_value = decode_base83(&hash[1..2])?;
+    let max_value = (quantised_max_value + 1) as f32 / 166.0 * punch;
+
+    let mut colors = Vec::with_capacity(num_x * num_y);
+    colors.push(decode_dc(decode_base83(&hash[2..6])?));
+    for i in 1..num_x * num_y {
+        let start = 4 + i * 2;
+        colors.push(decode_ac(decode_base83(&hash[start..start + 2])?, max_value));
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let mut pixel = [0.0; 3];
+
+            for j in 0..num_y {
+                for i in 0..num_x {
+                    let basis = (PI * x as f32 * i as f32 / width as f32).cos()
+                        * (PI * y as f32 * j as f32 / height as f32).cos();
+                    let color = colors[i + j * num_x];
+
+                    for (value, component) in pixel.iter_mut().zip(color) {
+                        *value += component * basis;
+                    }
+                }
+            }
+
+            pixels.extend(pixel.map(linear_to_srgb));
+            pixels.push(255);
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Encode the given image to a BlurHash with the given number of components.
+///
+/// `pixels` are the pixels of the image, row by row, as RGBA bytes. The alpha channel is ignored.
+/// The number of components on each axis must be between 1 and 9, more components keep more
+/// details of the image.
+pub fn encode_blurhash(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    components_x: u32,
+    components_y: u32,
+) -> Result<String, BlurHashError> {
+    if !(1..=9).contains(&components_x) || !(1..=9).contains(&components_y) {
+        return Err(BlurHashError::InvalidComponents);
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    if width == 0 || height == 0 || pixels.len() != width * height * 4 {
+        return Err(BlurHashError::InvalidDimensions);
+    }
+
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+
+            for y in 0..height {
+                for x in 0..width {
+                    let basis = normalisation
+                        * (PI * i as f32 * x as f32 / width as f32).cos()
+                        * (PI * j as f32 * y as f32 / height as f32).cos();
+                    let pixel = &pixels[(y * width + x) * 4..][..3];
+
+                    for (value, &component) in factor.iter_mut().zip(pixel) {
+                        *value += basis * srgb_to_linear(component);
+                    }
+                }
+            }
+
+            let scale = 1.0 / (width * height) as f32;
+            factors.push(factor.map(|value| value * scale));
+        }
+    }
+
+    let mut hash = String::with_capacity(4 + 2 * factors.len());
+    encode_base83((components_x - 1) + (components_y - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().expect("there is at least one component");
+
+    let max_value = if ac.is_empty() {
+        encode_base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual_max_value = ac.iter().flatten().fold(0.0_f32, |max, value| max.max(value.abs()));
+        let quantised_max_value = (actual_max_value * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        encode_base83(quantised_max_value, 1, &mut hash);
+        (quantised_max_value + 1) as f32 / 166.0
+    };
+
+    encode_base83(encode_dc(*dc), 4, &mut hash);
+    for &factor in ac {
+        encode_base83(encode_ac(factor, max_value), 2, &mut hash);
+    }
+
+    Ok(hash)
+}
+
+fn decode_base83(value: &str) -> Result<u32, BlurHashError> {
+    value.chars().try_fold(0, |acc, c| {
+        let digit = BASE83_CHARACTERS
+            .iter()
+            .position(|&b| char::from(b) == c)
+            .ok_or(BlurHashError::InvalidCharacter(c))?;
+        Ok(acc * 83 + digit as u32)
+    })
+}
+
+fn encode_base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83_u32.pow(length - i)) % 83;
+        hash.push(char::from(BASE83_CHARACTERS[digit as usize]));
+    }
+}
+
+fn decode_dc(value: u32) -> [f32; 3] {
+    [value >> 16, (value >> 8) & 255, value & 255].map(|component| srgb_to_linear(component as u8))
+}
+
+fn encode_dc(color: [f32; 3]) -> u32 {
+    let [r, g, b] = color.map(|component| u32::from(linear_to_srgb(component)));
+    (r << 16) + (g << 8) + b
+}
+
+fn decode_ac(value: u32, max_value: f32) -> [f32; 3] {
+    [value / (19 * 19), (value / 19) % 19, value % 19]
+        .map(|quantised| sign_pow((quantised as f32 - 9.0) / 9.0, 2.0) * max_value)
+}
+
+fn encode_ac(color: [f32; 3], max_value: f32) -> u32 {
+    let [r, g, b] = color.map(|component| {
+        (sign_pow(component / max_value, 0.5) * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+    });
+    r * 19 * 19 + g * 19 + b
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = f32::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        (value * 12.92 * 255.0 + 0.5) as u8
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u8
+    }
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base83, decode_blurhash, encode_blurhash, BlurHashError};
+
+    /// The BlurHash of the example image of the reference implementation, with 4x3 components.
+    const REFERENCE_HASH: &str = "LEHV6nWB2yk8pyo0adR*.7kCMdnj";
+
+    #[test]
+    fn solid_color() {
+        let white = vec![255; 4 * 4 * 4];
+        let hash = encode_blurhash(&white, 4, 4, 1, 1).unwrap();
+        assert_eq!(hash, "00TSUA");
+        assert_eq!(decode_blurhash(&hash, 4, 4, 1.0).unwrap(), white);
+
+        let red = [255, 0, 0, 255].repeat(16);
+        let hash = encode_blurhash(&red, 4, 4, 1, 1).unwrap();
+        assert_eq!(decode_blurhash(&hash, 8, 8, 1.0).unwrap(), red.repeat(4));
+    }
+
+    #[test]
+    fn reference_hash() {
+        let pixels = decode_blurhash(REFERENCE_HASH, 32, 32, 1.0).unwrap();
+        assert_eq!(pixels.len(), 32 * 32 * 4);
+        assert!(pixels.chunks(4).all(|pixel| pixel[3] == 255));
+
+        // Encoding the decoded image with the same number of components gives back almost the
+        // same average color.
+        let hash = encode_blurhash(&pixels, 32, 32, 4, 3).unwrap();
+        assert_eq!(hash.len(), REFERENCE_HASH.len());
+        assert_eq!(hash[..1], REFERENCE_HASH[..1]);
+
+        let average = decode_base83(&hash[2..6]).unwrap().to_be_bytes();
+        let reference_average = decode_base83(&REFERENCE_HASH[2..6]).unwrap().to_be_bytes();
+        assert!(average.iter().zip(reference_average).all(|(a, b)| a.abs_diff(b) <= 1));
+    }
+
+    #[test]
+    fn invalid_hash() {
+        assert_eq!(decode_blurhash("LEHV6", 32, 32, 1.0), Err(BlurHashError::TooShort));
+        assert_eq!(
+            decode_blurhash(&REFERENCE_HASH[..27], 32, 32, 1.0),
+            Err(BlurHashError::InvalidLength { expected: 28, actual: 27 })
+        );
+        assert_eq!(
+            decode_blurhash("00TSU\"", 32, 32, 1.0),
+            Err(BlurHashError::InvalidCharacter('"'))
+        );
+        assert_eq!(
+            decode_blurhash("é23456", 32, 32, 1.0),
+            Err(BlurHashError::InvalidCharacter('é'))
+        );
+        assert_eq!(
+            decode_blurhash("00é456", 32, 32, 1.0),
+            Err(BlurHashError::InvalidCharacter('é'))
+        );
+        assert_eq!(decode_blurhash("00TSUA", 0, 32, 1.0), Err(BlurHashError::InvalidDimensions));
+    }
+
+    #[test]
+    fn invalid_image() {
+        let pixels = vec![0; 4 * 4 * 4];
+        assert_eq!(encode_blurhash(&pixels, 4, 4, 0, 1), Err(BlurHashError::InvalidComponents));
+        assert_eq!(encode_blurhash(&pixels, 4, 4, 10, 1), Err(BlurHashError::InvalidComponents));
+        assert_eq!(encode_blurhash(&pixels, 4, 5, 1, 1), Err(BlurHashError::InvalidDimensions));
+    }
+}
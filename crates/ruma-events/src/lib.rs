@@ -143,6 +143,8 @@ pub mod audio;
 pub mod beacon;
 #[cfg(feature = "unstable-msc3489")]
 pub mod beacon_info;
+#[cfg(feature = "blurhash")]
+pub mod blurhash;
 pub mod call;
 pub mod direct;
 pub mod dummy;
@@ -2,6 +2,8 @@
 //!
 //! [MSC3488]: https://github.com/matrix-org/matrix-spec-proposals/pull/3488
 
+use std::{fmt, str::FromStr};
+
 use js_int::UInt;
 use ruma_macros::{EventContent, StringEnum};
 use serde::{Deserialize, Serialize};
@@ -112,6 +114,156 @@ impl LocationContent {
     pub fn new(uri: String) -> Self {
         Self { uri, description: None, zoom_level: None }
     }
+
+    /// Parse the `geo:` URI of this location.
+    pub fn geo_uri(&self) -> Result<GeoUri, GeoUriError> {
+        self.uri.parse()
+    }
+}
+
+/// An error encountered when trying to parse a `geo:` URI.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum GeoUriError {
+    /// The URI doesn't use the `geo` scheme.
+    #[error("URI doesn't use the `geo` scheme")]
+    InvalidScheme,
+
+    /// The URI doesn't have 2 or 3 valid coordinates.
+    #[error("invalid coordinates")]
+    InvalidCoordinates,
+
+    /// The latitude is not between -90 and 90, or the longitude is not between -180 and 180.
+    #[error("coordinates out of range")]
+    OutOfRange,
+
+    /// The URI uses another coordinate reference system than WGS-84.
+    #[error("unsupported coordinate reference system")]
+    UnsupportedCrs,
+
+    /// The uncertainty is not a valid positive number.
+    #[error("invalid uncertainty")]
+    InvalidUncertainty,
+}
+
+/// A parsed `geo:` URI.
+///
+/// See [RFC 5870](https://datatracker.ietf.org/doc/html/rfc5870) for more details. Only the WGS-84
+/// coordinate reference system is supported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeoUri {
+    uri: String,
+    latitude: f64,
+    longitude: f64,
+    altitude: Option<f64>,
+    uncertainty: Option<f64>,
+}
+
+impl GeoUri {
+    /// Parse the given `geo:` URI.
+    pub fn parse(uri: impl Into<String>) -> Result<Self, GeoUriError> {
+        let uri = uri.into();
+
+        let rest = uri
+            .get(..4)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("geo:"))
+            .map(|_| &uri[4..])
+            .ok_or(GeoUriError::InvalidScheme)?;
+
+        let mut parts = rest.split(';');
+        let coordinates = parts
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .map(parse_number)
+            .collect::<Option<Vec<_>>>();
+        let (latitude, longitude, altitude) = match coordinates.as_deref() {
+            Some(&[latitude, longitude]) => (latitude, longitude, None),
+            Some(&[latitude, longitude, altitude]) => (latitude, longitude, Some(altitude)),
+            _ => return Err(GeoUriError::InvalidCoordinates),
+        };
+
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(GeoUriError::OutOfRange);
+        }
+
+        let mut uncertainty = None;
+        for (i, param) in parts.enumerate() {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+
+            // The `crs` parameter must be the first one.
+            if i == 0 && name.eq_ignore_ascii_case("crs") {
+                if !value.eq_ignore_ascii_case("wgs84") {
+                    return Err(GeoUriError::UnsupportedCrs);
+                }
+            } else if name.eq_ignore_ascii_case("u") && uncertainty.is_none() {
+                let value = parse_number(value)
+                    .filter(|value| *value >= 0.0)
+                    .ok_or(GeoUriError::InvalidUncertainty)?;
+                uncertainty = Some(value);
+            }
+        }
+
+        Ok(Self { uri, latitude, longitude, altitude, uncertainty })
+    }
+
+    /// The latitude of the location, in decimal degrees.
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    /// The longitude of the location, in decimal degrees.
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+
+    /// The altitude of the location, in meters, if any.
+    pub fn altitude(&self) -> Option<f64> {
+        self.altitude
+    }
+
+    /// The uncertainty of the location, in meters, if any.
+    pub fn uncertainty(&self) -> Option<f64> {
+        self.uncertainty
+    }
+
+    /// The string representation of this URI.
+    pub fn as_str(&self) -> &str {
+        &self.uri
+    }
+}
+
+impl fmt::Display for GeoUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.uri)
+    }
+}
+
+impl FromStr for GeoUri {
+    type Err = GeoUriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl From<GeoUri> for LocationContent {
+    fn from(uri: GeoUri) -> Self {
+        Self::new(uri.uri)
+    }
+}
+
+/// Parse a number of a `geo:` URI, of the form `-?[0-9]+(\.[0-9]+)?`.
+fn parse_number(s: &str) -> Option<f64> {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, "0"));
+
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(integer) || !is_digits(fraction) {
+        return None;
+    }
+
+    s.parse().ok()
 }
 
 /// An error encountered when trying to convert to a `ZoomLevel`.
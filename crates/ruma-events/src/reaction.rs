@@ -41,7 +41,9 @@ mod tests {
     use serde_json::{from_value as from_json_value, json, to_value as to_json_value};
 
     use super::ReactionEventContent;
-    use crate::relation::Annotation;
+    use crate::{
+        relation::Annotation, AnyMessageLikeEvent, MessageLikeEvent, MessageLikeEventType,
+    };
 
     #[test]
     fn deserialize() {
@@ -61,6 +63,30 @@ mod tests {
         assert_eq!(relates_to.key, "🦛");
     }
 
+    #[test]
+    fn deserialize_event() {
+        let json = json!({
+            "content": {
+                "m.relates_to": {
+                    "rel_type": "m.annotation",
+                    "event_id": "$1598361704261elfgc:localhost",
+                    "key": "👍",
+                }
+            },
+            "event_id": "$143273582443PhrSn:example.org",
+            "origin_server_ts": 1_432_735_824_653_u64,
+            "room_id": "!jEsUZKDJdhlrceRyVU:example.org",
+            "sender": "@example:example.org",
+            "type": "m.reaction",
+        });
+
+        let event = from_json_value::<AnyMessageLikeEvent>(json).unwrap();
+        assert_eq!(event.event_type(), MessageLikeEventType::Reaction);
+        assert_matches!(event, AnyMessageLikeEvent::Reaction(MessageLikeEvent::Original(event)));
+        assert_eq!(event.content.relates_to.event_id, "$1598361704261elfgc:localhost");
+        assert_eq!(event.content.relates_to.key, "👍");
+    }
+
     #[test]
     fn serialize() {
         let content = ReactionEventContent::new(Annotation::new(
@@ -6,8 +6,8 @@ use std::collections::BTreeMap;
 
 use js_int::UInt;
 use ruma_common::{
-    serde::{base64::UrlSafe, Base64},
-    OwnedMxcUri,
+    serde::{base64::UrlSafe, Base64, JsonObject},
+    MxcUri, OwnedMxcUri,
 };
 use serde::{de, Deserialize, Serialize};
 
@@ -34,6 +34,9 @@ pub mod tombstone;
 pub mod topic;
 
 /// The source of a media file.
+///
+/// Only the `url` and `file` fields are kept when deserializing, so any other field sent alongside
+/// them is lost when the source is serialized again. Use [`RawMediaSource`] to keep them.
 #[derive(Clone, Debug, Serialize)]
 #[allow(clippy::exhaustive_enums)]
 pub enum MediaSource {
@@ -46,6 +49,29 @@ pub enum MediaSource {
     Encrypted(Box<EncryptedFile>),
 }
 
+impl MediaSource {
+    /// The MXC URI of the media file, whether it is encrypted or not.
+    pub fn url(&self) -> &MxcUri {
+        match self {
+            Self::Plain(url) => url,
+            Self::Encrypted(file) => &file.url,
+        }
+    }
+
+    /// Whether the media file is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Self::Encrypted(_))
+    }
+
+    /// The encryption info of the media file, if it is encrypted.
+    pub fn encrypted_file(&self) -> Option<&EncryptedFile> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Encrypted(file) => Some(file),
+        }
+    }
+}
+
 // Custom implementation of `Deserialize`, because serde doesn't guarantee what variant will be
 // deserialized for "externally tagged"¹ enums where multiple "tag" fields exist.
 //
@@ -70,6 +96,44 @@ impl<'de> Deserialize<'de> for MediaSource {
     }
 }
 
+/// A media source that keeps the fields it doesn't know about.
+///
+/// This should only be used to de-/serialize a standalone JSON object, since when it is flattened
+/// into another type it captures all the fields of that type.
+///
+/// If both the `url` and `file` fields are set, the `url` is still lost, like with
+/// [`MediaSource`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RawMediaSource {
+    /// The source of the media file.
+    #[serde(flatten)]
+    pub source: MediaSource,
+
+    /// The other fields of the media source.
+    #[serde(flatten)]
+    pub other: JsonObject,
+}
+
+impl RawMediaSource {
+    /// Creates a new `RawMediaSource` with the given source and no other fields.
+    pub fn new(source: MediaSource) -> Self {
+        Self { source, other: JsonObject::new() }
+    }
+}
+
+impl From<MediaSource> for RawMediaSource {
+    fn from(source: MediaSource) -> Self {
+        Self::new(source)
+    }
+}
+
+impl From<RawMediaSource> for MediaSource {
+    fn from(raw: RawMediaSource) -> Self {
+        raw.source
+    }
+}
+
 /// Metadata about an image.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
@@ -112,6 +176,17 @@ impl ImageInfo {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// The `(width, height)` that a thumbnail of this image should have to fit in the given
+    /// dimensions.
+    ///
+    /// The aspect ratio of the image is preserved, and the image is never upscaled: if it already
+    /// fits, its own dimensions are returned.
+    ///
+    /// Returns `None` if the width or height of the image is unknown or zero.
+    pub fn scaled_to_fit(&self, max_width: UInt, max_height: UInt) -> Option<(UInt, UInt)> {
+        scale_to_fit(self.width?, self.height?, max_width, max_height)
+    }
 }
 
 /// Metadata about a thumbnail.
@@ -140,6 +215,46 @@ impl ThumbnailInfo {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// The `(width, height)` that this thumbnail should have to fit in the given dimensions.
+    ///
+    /// See [`ImageInfo::scaled_to_fit()`] for details.
+    pub fn scaled_to_fit(&self, max_width: UInt, max_height: UInt) -> Option<(UInt, UInt)> {
+        scale_to_fit(self.width?, self.height?, max_width, max_height)
+    }
+}
+
+/// Scale the given dimensions down to fit in the given maximum dimensions, preserving the aspect
+/// ratio.
+fn scale_to_fit(
+    width: UInt,
+    height: UInt,
+    max_width: UInt,
+    max_height: UInt,
+) -> Option<(UInt, UInt)> {
+    if width == UInt::MIN || height == UInt::MIN {
+        return None;
+    }
+
+    if width <= max_width && height <= max_height {
+        return Some((width, height));
+    }
+
+    let (width, height) = (u128::from(u64::from(width)), u128::from(u64::from(height)));
+    let (max_width, max_height) =
+        (u128::from(u64::from(max_width)), u128::from(u64::from(max_height)));
+
+    // Compare the aspect ratios to know which side limits the size, and round the other side to
+    // the nearest integer, keeping at least 1 pixel.
+    let (scaled_width, scaled_height) = if width * max_height > height * max_width {
+        (max_width, ((height * max_width + width / 2) / width).max(1))
+    } else {
+        (((width * max_height + height / 2) / height).max(1), max_height)
+    };
+
+    // The scaled dimensions are smaller than the maximum dimensions, so they fit in a `UInt`.
+    let to_uint = |value: u128| UInt::try_from(u64::try_from(value).ok()?).ok();
+    Some((to_uint(scaled_width)?, to_uint(scaled_height)?))
 }
 
 /// A file sent to a room with end-to-end encryption enabled.
@@ -196,6 +311,41 @@ pub struct EncryptedFileInit {
     pub v: String,
 }
 
+impl EncryptedFile {
+    /// The version of the encrypted attachments protocol used for this file, parsed from `v`.
+    pub fn protocol_version(&self) -> AttachmentVersion {
+        match self.v.as_str() {
+            "v1" => AttachmentVersion::V1,
+            "v2" => AttachmentVersion::V2,
+            _ => AttachmentVersion::Unknown,
+        }
+    }
+
+    /// Whether this file doesn't use the current version of the encrypted attachments protocol.
+    ///
+    /// Such files can't be trusted to be decrypted correctly by other clients, and should be
+    /// re-encrypted or rejected.
+    pub fn requires_reencryption(&self) -> bool {
+        self.protocol_version() != AttachmentVersion::V2
+    }
+
+    /// Check that this file conforms to the constraints of the encrypted attachments protocol.
+    ///
+    /// This should be called before decrypting a file received from another client, to fail early
+    /// with a clear error.
+    pub fn validate(&self) -> Result<(), EncryptedFileError> {
+        if self.v != "v2" {
+            return Err(EncryptedFileError::UnsupportedVersion);
+        }
+
+        if !self.hashes.contains_key("sha256") {
+            return Err(EncryptedFileError::MissingSha256Hash);
+        }
+
+        self.key.validate()
+    }
+}
+
 impl From<EncryptedFileInit> for EncryptedFile {
     fn from(init: EncryptedFileInit) -> Self {
         let EncryptedFileInit { url, key, iv, hashes, v } = init;
@@ -203,6 +353,44 @@ impl From<EncryptedFileInit> for EncryptedFile {
     }
 }
 
+/// An error encountered when validating an [`EncryptedFile`] or a [`JsonWebKey`].
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum EncryptedFileError {
+    /// The version of the encrypted attachments protocol is not `v2`.
+    #[error("unsupported version of the encrypted attachments protocol")]
+    UnsupportedVersion,
+    /// The hashes don't contain a SHA-256 hash.
+    #[error("missing SHA-256 hash")]
+    MissingSha256Hash,
+    /// The key type is not `oct`.
+    #[error("key type is not `oct`")]
+    InvalidKeyType,
+    /// The key operations don't contain `encrypt` and `decrypt`.
+    #[error("key operations don't contain `encrypt` and `decrypt`")]
+    MissingKeyOperations,
+    /// The algorithm of the key is not `A256CTR`.
+    #[error("key algorithm is not `A256CTR`")]
+    InvalidAlgorithm,
+    /// The key is not extractable.
+    #[error("key is not extractable")]
+    NotExtractable,
+}
+
+/// The version of the encrypted attachments protocol of an [`EncryptedFile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum AttachmentVersion {
+    /// `v1`, used by very old clients, with a different MAC scheme.
+    V1,
+
+    /// `v2`, the current version.
+    V2,
+
+    /// An unknown version.
+    Unknown,
+}
+
 /// A [JSON Web Key](https://tools.ietf.org/html/rfc7517#appendix-A.3) object.
 ///
 /// To create an instance of this type, first create a `JsonWebKeyInit` and convert it via
@@ -274,16 +462,66 @@ impl From<JsonWebKeyInit> for JsonWebKey {
     }
 }
 
+impl JsonWebKey {
+    /// The decoded bytes of the key.
+    ///
+    /// With the `zeroize` feature, wrap the `JsonWebKey` in [`zeroize::Zeroizing`] to zero these
+    /// bytes when it is dropped.
+    pub fn key_bytes(&self) -> &[u8] {
+        self.k.as_bytes()
+    }
+
+    /// A copy of the decoded bytes of the key, that is zeroed when it is dropped.
+    #[cfg(feature = "zeroize")]
+    pub fn zeroizing_key_bytes(&self) -> zeroize::Zeroizing<Vec<u8>> {
+        zeroize::Zeroizing::new(self.k.as_bytes().to_vec())
+    }
+
+    /// Check that this key conforms to the constraints of the encrypted attachments protocol.
+    pub fn validate(&self) -> Result<(), EncryptedFileError> {
+        if self.kty != "oct" {
+            return Err(EncryptedFileError::InvalidKeyType);
+        }
+
+        if !["encrypt", "decrypt"].iter().all(|op| self.key_ops.iter().any(|key_op| key_op == op)) {
+            return Err(EncryptedFileError::MissingKeyOperations);
+        }
+
+        if self.alg != "A256CTR" {
+            return Err(EncryptedFileError::InvalidAlgorithm);
+        }
+
+        if !self.ext {
+            return Err(EncryptedFileError::NotExtractable);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for JsonWebKey {
+    /// Zero the bytes of the key and replace it with an empty key.
+    fn zeroize(&mut self) {
+        let mut bytes = std::mem::replace(&mut self.k, Base64::new(Vec::new())).into_inner();
+        bytes.zeroize();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
 
     use assert_matches2::assert_matches;
+    use js_int::uint;
     use ruma_common::{mxc_uri, serde::Base64};
     use serde::Deserialize;
-    use serde_json::{from_value as from_json_value, json};
+    use serde_json::{from_value as from_json_value, json, to_value as to_json_value};
 
-    use super::{EncryptedFile, JsonWebKey, MediaSource};
+    use super::{
+        AttachmentVersion, EncryptedFile, EncryptedFileError, ImageInfo, JsonWebKey, MediaSource,
+        RawMediaSource, ThumbnailInfo,
+    };
 
     #[derive(Deserialize)]
     struct MsgWithAttachment {
@@ -334,4 +572,199 @@ mod tests {
 
         assert_matches!(msg.source, MediaSource::Encrypted(_));
     }
+
+    #[test]
+    fn media_source_accessors() {
+        let plain = MediaSource::Plain(mxc_uri!("mxc://localhost/file").to_owned());
+        assert_eq!(plain.url(), "mxc://localhost/file");
+        assert!(!plain.is_encrypted());
+        assert!(plain.encrypted_file().is_none());
+
+        let encrypted = MediaSource::Encrypted(Box::new(encrypted_file()));
+        assert_eq!(encrypted.url(), "mxc://localhost/encryptedfile");
+        assert!(encrypted.is_encrypted());
+        assert_eq!(encrypted.encrypted_file().unwrap().v, "v2");
+    }
+
+    #[test]
+    fn raw_media_source_round_trip() {
+        let json = json!({
+            "url": "mxc://localhost/file",
+            "org.example.thumbnail_hint": { "w": 64 },
+        });
+
+        let raw = from_json_value::<RawMediaSource>(json.clone()).unwrap();
+        assert_matches!(&raw.source, MediaSource::Plain(url));
+        assert_eq!(url, "mxc://localhost/file");
+        assert_eq!(raw.other.len(), 1);
+        assert_eq!(to_json_value(&raw).unwrap(), json);
+
+        // The unknown field is lost with `MediaSource`.
+        let source = from_json_value::<MediaSource>(json).unwrap();
+        assert_eq!(to_json_value(&source).unwrap(), json!({ "url": "mxc://localhost/file" }));
+    }
+
+    #[test]
+    fn attachment_protocol_version() {
+        let mut file = encrypted_file();
+        assert_eq!(file.protocol_version(), AttachmentVersion::V2);
+        assert!(!file.requires_reencryption());
+
+        file.v = "v1".to_owned();
+        assert_eq!(file.protocol_version(), AttachmentVersion::V1);
+        assert!(file.requires_reencryption());
+
+        file.v = "not-a-version".to_owned();
+        assert_eq!(file.protocol_version(), AttachmentVersion::Unknown);
+        assert!(file.requires_reencryption());
+    }
+
+    #[test]
+    fn validate_encrypted_file() {
+        let mut file = encrypted_file();
+        file.hashes.insert("sha256".to_owned(), Base64::new(vec![0; 32]));
+        file.validate().unwrap();
+
+        let mut invalid = file.clone();
+        invalid.v = "v1".to_owned();
+        assert_eq!(invalid.validate(), Err(EncryptedFileError::UnsupportedVersion));
+
+        let mut invalid = file.clone();
+        invalid.hashes.clear();
+        assert_eq!(invalid.validate(), Err(EncryptedFileError::MissingSha256Hash));
+
+        let mut invalid = file.clone();
+        invalid.key.alg = "A128CTR".to_owned();
+        assert_eq!(invalid.validate(), Err(EncryptedFileError::InvalidAlgorithm));
+    }
+
+    #[test]
+    fn validate_json_web_key() {
+        dummy_jwt().validate().unwrap();
+
+        let mut key = dummy_jwt();
+        key.kty = "RSA".to_owned();
+        assert_eq!(key.validate(), Err(EncryptedFileError::InvalidKeyType));
+
+        let mut key = dummy_jwt();
+        key.key_ops = vec!["encrypt".to_owned()];
+        assert_eq!(key.validate(), Err(EncryptedFileError::MissingKeyOperations));
+
+        let mut key = dummy_jwt();
+        key.ext = false;
+        assert_eq!(key.validate(), Err(EncryptedFileError::NotExtractable));
+    }
+
+    #[test]
+    fn encrypted_file_base64_alphabets() {
+        let file_json = |k: &str, iv: &str| {
+            json!({
+                "url": "mxc://localhost/encryptedfile",
+                "key": {
+                    "kty": "oct",
+                    "key_ops": ["encrypt", "decrypt"],
+                    "alg": "A256CTR",
+                    "k": k,
+                    "ext": true,
+                },
+                "iv": iv,
+                "hashes": { "sha256": "aGFzaA" },
+                "v": "v2",
+            })
+        };
+
+        // The key uses the url-safe alphabet.
+        let file =
+            from_json_value::<EncryptedFile>(file_json("-_-_", "AAAAAAAAAAAAAAAAAAAAAA")).unwrap();
+        assert_eq!(file.key.k.as_bytes(), [0xfb, 0xff, 0xbf]);
+
+        // A key using the standard alphabet is rejected.
+        from_json_value::<EncryptedFile>(file_json("+/+/", "AAAAAAAAAAAAAAAAAAAAAA")).unwrap_err();
+
+        // The IV uses the standard alphabet.
+        let file = from_json_value::<EncryptedFile>(file_json("AAAA", "+/+/")).unwrap();
+        assert_eq!(file.iv.as_bytes(), [0xfb, 0xff, 0xbf]);
+        from_json_value::<EncryptedFile>(file_json("AAAA", "-_-_")).unwrap_err();
+    }
+
+    #[test]
+    fn json_web_key_bytes() {
+        let key = dummy_jwt();
+        assert_eq!(key.key_bytes(), [0; 64]);
+    }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn json_web_key_zeroize() {
+        use zeroize::{Zeroize, Zeroizing};
+
+        let mut key = dummy_jwt();
+        key.k = Base64::new(vec![1; 32]);
+        assert_eq!(*key.zeroizing_key_bytes(), [1; 32]);
+
+        let json = serde_json::to_value(&key).unwrap();
+        let copy = Zeroizing::new(from_json_value::<JsonWebKey>(json).unwrap());
+        assert_eq!(copy.key_bytes(), [1; 32]);
+        drop(copy);
+
+        // The fields can still be moved out of the key.
+        let JsonWebKey { alg, .. } = dummy_jwt();
+        assert_eq!(alg, "A256CTR");
+
+        key.zeroize();
+        assert!(key.key_bytes().is_empty());
+        assert_eq!(key.alg, "A256CTR");
+    }
+
+    #[test]
+    fn image_scaled_to_fit() {
+        let image = |width: u32, height: u32| {
+            let mut info = ImageInfo::new();
+            info.width = Some(width.into());
+            info.height = Some(height.into());
+            info
+        };
+
+        // Landscape.
+        assert_eq!(
+            image(1920, 1080).scaled_to_fit(uint!(800), uint!(600)),
+            Some((uint!(800), uint!(450)))
+        );
+        // Portrait.
+        assert_eq!(
+            image(1080, 1920).scaled_to_fit(uint!(800), uint!(600)),
+            Some((uint!(338), uint!(600)))
+        );
+        // Square.
+        assert_eq!(
+            image(1000, 1000).scaled_to_fit(uint!(800), uint!(600)),
+            Some((uint!(600), uint!(600)))
+        );
+        // Small images are not upscaled.
+        assert_eq!(
+            image(100, 50).scaled_to_fit(uint!(800), uint!(600)),
+            Some((uint!(100), uint!(50)))
+        );
+        // Very thin images keep at least 1 pixel.
+        assert_eq!(
+            image(10_000, 1).scaled_to_fit(uint!(800), uint!(600)),
+            Some((uint!(800), uint!(1)))
+        );
+
+        // Missing dimensions.
+        let mut info = ImageInfo::new();
+        info.width = Some(uint!(1920));
+        assert_eq!(info.scaled_to_fit(uint!(800), uint!(600)), None);
+        assert_eq!(image(0, 1080).scaled_to_fit(uint!(800), uint!(600)), None);
+    }
+
+    #[test]
+    fn thumbnail_scaled_to_fit() {
+        let mut info = ThumbnailInfo::new();
+        assert_eq!(info.scaled_to_fit(uint!(96), uint!(96)), None);
+
+        info.width = Some(uint!(800));
+        info.height = Some(uint!(600));
+        assert_eq!(info.scaled_to_fit(uint!(96), uint!(96)), Some((uint!(96), uint!(72))));
+    }
 }
@@ -80,6 +80,11 @@ impl RoomCreateEventContent {
             room_type: None,
         }
     }
+
+    /// The type of the room, or `None` if this is a normal room.
+    pub fn room_type(&self) -> Option<RoomType> {
+        self.room_type.clone()
+    }
 }
 
 impl RedactContent for RoomCreateEventContent {
@@ -227,4 +232,37 @@ mod tests {
         assert_matches!(content.predecessor, None);
         assert_eq!(content.room_type, Some(RoomType::Space));
     }
+
+    #[test]
+    fn room_type() {
+        let content = from_json_value::<RoomCreateEventContent>(json!({
+            "room_version": "11",
+            "type": "m.space"
+        }))
+        .unwrap();
+        assert_eq!(content.room_type(), Some(RoomType::Space));
+
+        let content =
+            from_json_value::<RoomCreateEventContent>(json!({ "room_version": "11" })).unwrap();
+        assert_eq!(content.room_type(), None);
+
+        let content = from_json_value::<RoomCreateEventContent>(json!({
+            "room_version": "11",
+            "type": "org.example.custom"
+        }))
+        .unwrap();
+        assert_eq!(content.room_type(), Some(RoomType::from("org.example.custom")));
+    }
+
+    #[test]
+    fn custom_type_round_trip() {
+        let json = json!({
+            "room_version": "11",
+            "type": "org.example.custom"
+        });
+
+        let content = from_json_value::<RoomCreateEventContent>(json.clone()).unwrap();
+        assert_eq!(content.room_type.as_ref().unwrap().as_str(), "org.example.custom");
+        assert_eq!(to_json_value(&content).unwrap(), json);
+    }
 }
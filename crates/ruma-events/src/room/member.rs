@@ -109,6 +109,12 @@ pub struct RoomMemberEventContent {
 }
 
 impl RoomMemberEventContent {
+    /// The largest length of `displayname` in bytes accepted by [`Self::validate()`].
+    pub const MAX_DISPLAYNAME_LENGTH: usize = 256;
+
+    /// The largest length of `avatar_url` in bytes accepted by [`Self::validate()`].
+    pub const MAX_AVATAR_URL_LENGTH: usize = 1024;
+
     /// Creates a new `RoomMemberEventContent` with the given membership state.
     pub fn new(membership: MembershipState) -> Self {
         Self {
@@ -155,6 +161,44 @@ impl RoomMemberEventContent {
     ) -> MembershipChange<'a> {
         membership_change(self.details(), prev_details, sender, state_key)
     }
+
+    /// Check that the profile of the user in this event is not abusive.
+    ///
+    /// Returns an error if `displayname` is longer than [`Self::MAX_DISPLAYNAME_LENGTH`] bytes, or
+    /// if `avatar_url` is longer than [`Self::MAX_AVATAR_URL_LENGTH`] bytes or is not a valid
+    /// `mxc://` URI.
+    pub fn validate(&self) -> Result<(), RoomMemberValidationError> {
+        if self.displayname.as_ref().is_some_and(|name| name.len() > Self::MAX_DISPLAYNAME_LENGTH) {
+            return Err(RoomMemberValidationError::DisplayNameTooLong);
+        }
+
+        if let Some(avatar_url) = &self.avatar_url {
+            if avatar_url.as_str().len() > Self::MAX_AVATAR_URL_LENGTH {
+                return Err(RoomMemberValidationError::AvatarUrlTooLong);
+            }
+
+            if !avatar_url.is_valid() {
+                return Err(RoomMemberValidationError::InvalidAvatarUrl);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An error encountered when validating a [`RoomMemberEventContent`].
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum RoomMemberValidationError {
+    /// The display name is longer than [`RoomMemberEventContent::MAX_DISPLAYNAME_LENGTH`].
+    #[error("display name too long")]
+    DisplayNameTooLong,
+    /// The avatar URL is longer than [`RoomMemberEventContent::MAX_AVATAR_URL_LENGTH`].
+    #[error("avatar URL too long")]
+    AvatarUrlTooLong,
+    /// The avatar URL is not a valid `mxc://` URI.
+    #[error("invalid avatar URL")]
+    InvalidAvatarUrl,
 }
 
 impl RedactContent for RoomMemberEventContent {
@@ -579,7 +623,7 @@ mod tests {
     };
     use serde_json::{from_value as from_json_value, json};
 
-    use super::{MembershipState, RoomMemberEventContent};
+    use super::{MembershipState, RoomMemberEventContent, RoomMemberValidationError};
     use crate::OriginalStateEvent;
 
     #[test]
@@ -821,4 +865,31 @@ mod tests {
             Some(user_id!("@notcarl:example.com"))
         );
     }
+
+    #[test]
+    fn validate() {
+        let mut content = RoomMemberEventContent::new(MembershipState::Join);
+        content.displayname = Some("Alice".to_owned());
+        content.avatar_url = Some(mxc_uri!("mxc://example.org/SEsfnsuifSDFSSEF").to_owned());
+        content.validate().unwrap();
+
+        content.displayname = Some("a".repeat(RoomMemberEventContent::MAX_DISPLAYNAME_LENGTH));
+        content.validate().unwrap();
+
+        content.displayname = Some("a".repeat(RoomMemberEventContent::MAX_DISPLAYNAME_LENGTH + 1));
+        assert_eq!(content.validate(), Err(RoomMemberValidationError::DisplayNameTooLong));
+
+        content.displayname = None;
+        content.avatar_url = Some("https://example.org/avatar.png".into());
+        assert_eq!(content.validate(), Err(RoomMemberValidationError::InvalidAvatarUrl));
+
+        content.avatar_url = Some(
+            format!(
+                "mxc://example.org/{}",
+                "a".repeat(RoomMemberEventContent::MAX_AVATAR_URL_LENGTH)
+            )
+            .into(),
+        );
+        assert_eq!(content.validate(), Err(RoomMemberValidationError::AvatarUrlTooLong));
+    }
 }
@@ -6,7 +6,7 @@ use std::borrow::Cow;
 
 use ruma_common::{
     serde::{JsonObject, Raw, StringEnum},
-    OwnedEventId, RoomId,
+    EventId, OwnedEventId, RoomId,
 };
 #[cfg(feature = "html")]
 use ruma_html::{sanitize_html, HtmlSanitizerMode, RemoveReplyFallback};
@@ -334,6 +334,41 @@ impl RoomMessageEventContent {
         self.msgtype.body()
     }
 
+    /// Whether this message is an edit of another message.
+    pub fn is_edit(&self) -> bool {
+        matches!(self.relates_to, Some(Relation::Replacement(_)))
+    }
+
+    /// The ID of the message that this message replaces, if it is an edit.
+    pub fn replaced_event_id(&self) -> Option<&EventId> {
+        match &self.relates_to {
+            Some(Relation::Replacement(replacement)) => Some(&replacement.event_id),
+            _ => None,
+        }
+    }
+
+    /// The ID of the root message of the thread that this message belongs to, if any.
+    pub fn thread_root(&self) -> Option<&EventId> {
+        match &self.relates_to {
+            Some(Relation::Thread(thread)) => Some(&thread.event_id),
+            _ => None,
+        }
+    }
+
+    /// The message that this message is a reply to, if any.
+    ///
+    /// The `m.in_reply_to` field of a thread relation is only considered a reply if it is not a
+    /// fallback for clients that don't support threads.
+    pub fn in_reply_to(&self) -> Option<&InReplyTo> {
+        match &self.relates_to {
+            Some(Relation::Reply { in_reply_to }) => Some(in_reply_to),
+            Some(Relation::Thread(thread)) if !thread.is_falling_back => {
+                thread.in_reply_to.as_ref()
+            }
+            _ => None,
+        }
+    }
+
     /// Apply the given new content from a [`Replacement`] to this message.
     pub fn apply_replacement(&mut self, new_content: RoomMessageEventContentWithoutRelation) {
         let RoomMessageEventContentWithoutRelation { msgtype, mentions } = new_content;
@@ -1,5 +1,8 @@
 //! Convenience methods and types to sanitize text messages.
 
+#[cfg(feature = "html")]
+pub use ruma_html::remove_html_reply_fallback;
+
 /// Remove the [rich reply fallback] of the given plain text string.
 ///
 /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
@@ -26,6 +29,8 @@ pub fn remove_plain_reply_fallback(mut s: &str) -> &str {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "html")]
+    use super::remove_html_reply_fallback;
     use super::remove_plain_reply_fallback;
 
     #[test]
@@ -52,5 +57,46 @@ mod tests {
             remove_plain_reply_fallback("> <@user:notareal.hs> Previous message\n\n> New quote"),
             "> New quote"
         );
+
+        // Example of the spec.
+        assert_eq!(
+            remove_plain_reply_fallback(
+                "> <@alice:example.org> This is the original body\n\nThis is where the reply goes"
+            ),
+            "This is where the reply goes"
+        );
+
+        assert_eq!(remove_plain_reply_fallback("> <@alice:example.org> Only a fallback"), "");
+    }
+
+    #[test]
+    #[cfg(feature = "html")]
+    fn remove_html_reply() {
+        assert_eq!(
+            remove_html_reply_fallback("<p>No reply here</p><p>Just a simple message</p>"),
+            "<p>No reply here</p><p>Just a simple message</p>"
+        );
+
+        // Example of the spec.
+        assert_eq!(
+            remove_html_reply_fallback(
+                "<mx-reply>\
+                    <blockquote>\
+                        <a href=\"https://matrix.to/#/!somewhere:example.org/$event:example.org\">In reply to</a> \
+                        <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>\
+                        <br />\
+                        Message<br />\
+                        on two lines\
+                    </blockquote>\
+                </mx-reply>\
+                This is where the related text goes"
+            ),
+            "This is where the related text goes"
+        );
+
+        assert_eq!(
+            remove_html_reply_fallback("<blockquote>Not a fallback</blockquote>Reply"),
+            "<blockquote>Not a fallback</blockquote>Reply"
+        );
     }
 }
@@ -23,6 +23,14 @@ impl RoomNameEventContent {
     pub fn new(name: String) -> Self {
         Self { name }
     }
+
+    /// The name of the room to display, if any.
+    ///
+    /// Returns `None` if the name is empty or only contains whitespace, which means that the room
+    /// has no name.
+    pub fn display_name(&self) -> Option<&str> {
+        (!self.name.trim().is_empty()).then_some(&self.name)
+    }
 }
 
 #[cfg(test)]
@@ -66,4 +74,14 @@ mod tests {
             "The room name"
         );
     }
+
+    #[test]
+    fn display_name() {
+        assert_eq!(
+            RoomNameEventContent::new("The room name".to_owned()).display_name(),
+            Some("The room name")
+        );
+        assert_eq!(RoomNameEventContent::new("".to_owned()).display_name(), None);
+        assert_eq!(RoomNameEventContent::new(" \n\t".to_owned()).display_name(), None);
+    }
 }
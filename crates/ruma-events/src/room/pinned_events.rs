@@ -2,7 +2,7 @@
 //!
 //! [`m.room.pinned_events`]: https://spec.matrix.org/latest/client-server-api/#mroompinned_events
 
-use ruma_common::OwnedEventId;
+use ruma_common::{EventId, OwnedEventId, OwnedRoomId, RoomId};
 use ruma_macros::EventContent;
 use serde::{Deserialize, Serialize};
 
@@ -24,11 +24,28 @@ impl RoomPinnedEventsEventContent {
     pub fn new(pinned: Vec<OwnedEventId>) -> Self {
         Self { pinned }
     }
+
+    /// Get the pinned events that don't exist or aren't in the given room.
+    ///
+    /// `fetch_room_id` is called for every pinned event, and must return the ID of the room of the
+    /// event, or `None` if the event doesn't exist.
+    ///
+    /// Clients should skip the returned events when displaying the pinned events.
+    pub fn validate_against<F>(&self, room_id: &RoomId, mut fetch_room_id: F) -> Vec<OwnedEventId>
+    where
+        F: FnMut(&EventId) -> Option<OwnedRoomId>,
+    {
+        self.pinned
+            .iter()
+            .filter(|event_id| fetch_room_id(event_id).as_deref() != Some(room_id))
+            .cloned()
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use ruma_common::owned_event_id;
+    use ruma_common::{owned_event_id, owned_room_id, room_id};
 
     use super::RoomPinnedEventsEventContent;
 
@@ -46,4 +63,24 @@ mod tests {
 
         assert_eq!(parsed_content.pinned, content.pinned);
     }
+
+    #[test]
+    fn validate_against() {
+        let content = RoomPinnedEventsEventContent::new(vec![
+            owned_event_id!("$a:example.com"),
+            owned_event_id!("$b:example.com"),
+            owned_event_id!("$c:example.com"),
+            owned_event_id!("$d:example.com"),
+        ]);
+
+        let invalid = content.validate_against(room_id!("!room:example.com"), |event_id| {
+            match event_id.as_str() {
+                "$a:example.com" | "$c:example.com" => Some(owned_room_id!("!room:example.com")),
+                "$d:example.com" => Some(owned_room_id!("!other:example.com")),
+                _ => None,
+            }
+        });
+
+        assert_eq!(invalid, [owned_event_id!("$b:example.com"), owned_event_id!("$d:example.com")]);
+    }
 }
@@ -126,6 +126,48 @@ impl RoomPowerLevelsEventContent {
             notifications: NotificationPowerLevels::default(),
         }
     }
+
+    /// Creates the initial `RoomPowerLevelsEventContent` that a server uses when creating a room
+    /// with the given version for the given creator.
+    ///
+    /// The creator gets a power level of 100, and actions like banning, kicking, redacting,
+    /// inviting and sending state events require a power level of 50. Changing the power levels,
+    /// the history visibility, the server ACLs, the encryption or upgrading the room requires a
+    /// power level of 100.
+    ///
+    /// All the room versions that are currently supported use the same initial power levels, the
+    /// room version is only taken into account so future room versions can change them.
+    pub fn default_for(_room_version: &RoomVersionId, creator: &UserId) -> Self {
+        let events = [
+            (TimelineEventType::RoomName, int!(50)),
+            (TimelineEventType::RoomAvatar, int!(50)),
+            (TimelineEventType::RoomCanonicalAlias, int!(50)),
+            (TimelineEventType::RoomPowerLevels, int!(100)),
+            (TimelineEventType::RoomHistoryVisibility, int!(100)),
+            (TimelineEventType::RoomServerAcl, int!(100)),
+            (TimelineEventType::RoomEncryption, int!(100)),
+            (TimelineEventType::RoomTombstone, int!(100)),
+        ];
+
+        Self {
+            events: events.into(),
+            invite: int!(50),
+            users: BTreeMap::from([(creator.to_owned(), int!(100))]),
+            ..Self::new()
+        }
+    }
+
+    /// Get the power level required to send an event of the given type.
+    ///
+    /// Returns the level set for `event_type` in `events` if there is one, otherwise
+    /// `state_default` if `is_state` is `true` or `events_default` if it is `false`.
+    pub fn required_level_for(&self, event_type: &TimelineEventType, is_state: bool) -> Int {
+        self.events.get(event_type).copied().unwrap_or(if is_state {
+            self.state_default
+        } else {
+            self.events_default
+        })
+    }
 }
 
 impl Default for RoomPowerLevelsEventContent {
@@ -700,10 +742,11 @@ mod tests {
     use assign::assign;
     use js_int::int;
     use maplit::btreemap;
-    use ruma_common::user_id;
+    use ruma_common::{user_id, RoomVersionId};
     use serde_json::{json, to_value as to_json_value};
 
     use super::{default_power_level, NotificationPowerLevels, RoomPowerLevelsEventContent};
+    use crate::TimelineEventType;
 
     #[test]
     fn serialization_with_optional_fields_as_none() {
@@ -770,4 +813,51 @@ mod tests {
 
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn required_level_for() {
+        let mut power_levels = RoomPowerLevelsEventContent::new();
+        power_levels.events_default = int!(10);
+        power_levels.events.insert(TimelineEventType::RoomMessage, int!(20));
+
+        assert_eq!(
+            power_levels.required_level_for(&TimelineEventType::RoomMessage, false),
+            int!(20)
+        );
+        assert_eq!(power_levels.required_level_for(&TimelineEventType::Reaction, false), int!(10));
+        assert_eq!(
+            power_levels.required_level_for(&TimelineEventType::RoomTopic, true),
+            default_power_level()
+        );
+    }
+
+    #[test]
+    fn default_for() {
+        let creator = user_id!("@creator:example.org");
+        let power_levels = RoomPowerLevelsEventContent::default_for(&RoomVersionId::V11, creator);
+
+        assert_eq!(power_levels.users.len(), 1);
+        assert_eq!(power_levels.users[creator], int!(100));
+        assert_eq!(power_levels.users_default, int!(0));
+        assert_eq!(power_levels.ban, int!(50));
+        assert_eq!(power_levels.kick, int!(50));
+        assert_eq!(power_levels.redact, int!(50));
+        assert_eq!(power_levels.invite, int!(50));
+        assert_eq!(power_levels.state_default, int!(50));
+        assert_eq!(power_levels.events_default, int!(0));
+        assert_eq!(power_levels.notifications.room, int!(50));
+        assert_eq!(
+            power_levels.required_level_for(&TimelineEventType::RoomPowerLevels, true),
+            int!(100)
+        );
+        assert_eq!(power_levels.required_level_for(&TimelineEventType::RoomTopic, true), int!(50));
+        assert_eq!(
+            power_levels.required_level_for(&TimelineEventType::RoomMessage, false),
+            int!(0)
+        );
+
+        // All the room versions use the same initial power levels.
+        let v1 = RoomPowerLevelsEventContent::default_for(&RoomVersionId::V1, creator);
+        assert_eq!(to_json_value(v1).unwrap(), to_json_value(power_levels).unwrap());
+    }
 }
@@ -2,10 +2,9 @@
 //!
 //! [`m.room.server_acl`]: https://spec.matrix.org/latest/client-server-api/#mroomserver_acl
 
-use ruma_common::ServerName;
+use ruma_common::{glob::Glob, ServerName};
 use ruma_macros::EventContent;
 use serde::{Deserialize, Serialize};
-use wildmatch::WildMatch;
 
 use crate::EmptyStateKey;
 
@@ -61,8 +60,8 @@ impl RoomServerAclEventContent {
 
         let host = server_name.host();
 
-        self.deny.iter().all(|d| !WildMatch::new(d).matches(host))
-            && self.allow.iter().any(|a| WildMatch::new(a).matches(host))
+        self.deny.iter().all(|d| !Glob::compile(d).matches(host))
+            && self.allow.iter().any(|a| Glob::compile(a).matches(host))
     }
 }
 
@@ -135,6 +134,14 @@ mod tests {
         assert!(acl_event.is_allowed(server_name!("conduit.rs")));
     }
 
+    #[test]
+    fn acl_empty_allow() {
+        // Nothing is allowed by default.
+        let acl_event = RoomServerAclEventContent::new(true, Vec::new(), Vec::new());
+        assert!(!acl_event.is_allowed(server_name!("matrix.org")));
+        assert!(!acl_event.is_allowed(server_name!("1.1.1.1")));
+    }
+
     #[test]
     fn acl_explicit_allow() {
         let acl_event = RoomServerAclEventContent {
@@ -5,6 +5,8 @@
 use ruma_macros::EventContent;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "unstable-msc3765")]
+use crate::message::TextContentBlock;
 use crate::EmptyStateKey;
 
 /// The content of an `m.room.topic` event.
@@ -14,13 +16,126 @@ use crate::EmptyStateKey;
 #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
 #[ruma_event(type = "m.room.topic", kind = State, state_key_type = EmptyStateKey)]
 pub struct RoomTopicEventContent {
-    /// The topic text.
+    /// The topic as plain text.
+    ///
+    /// This must be consistent with the `text/plain` representation in `topic_block`.
     pub topic: String,
+
+    /// Textual representation of the topic in different mimetypes.
+    #[cfg(feature = "unstable-msc3765")]
+    #[serde(rename = "m.topic", default, skip_serializing_if = "TopicContentBlock::is_empty")]
+    pub topic_block: TopicContentBlock,
 }
 
 impl RoomTopicEventContent {
-    /// Creates a new `RoomTopicEventContent` with the given topic.
+    /// Creates a new `RoomTopicEventContent` with the given plain text topic.
     pub fn new(topic: String) -> Self {
-        Self { topic }
+        Self {
+            #[cfg(feature = "unstable-msc3765")]
+            topic_block: TopicContentBlock::plain(topic.clone()),
+            topic,
+        }
+    }
+
+    /// Convenience constructor to create a new HTML topic with a plain text fallback.
+    #[cfg(feature = "unstable-msc3765")]
+    pub fn html(plain: impl Into<String>, html: impl Into<String>) -> Self {
+        let plain = plain.into();
+        Self { topic_block: TopicContentBlock::html(plain.clone(), html), topic: plain }
+    }
+
+    /// Get the plain text representation of the topic.
+    ///
+    /// Falls back to the legacy `topic` field if `topic_block` doesn't have a plain text
+    /// representation.
+    #[cfg(feature = "unstable-msc3765")]
+    pub fn plain_topic(&self) -> &str {
+        self.topic_block.text.find_plain().unwrap_or(&self.topic)
+    }
+
+    /// Get the HTML representation of the topic, if any.
+    #[cfg(feature = "unstable-msc3765")]
+    pub fn html_topic(&self) -> Option<&str> {
+        self.topic_block.text.find_html()
+    }
+}
+
+/// A block for topic content.
+///
+/// To construct a `TopicContentBlock` with a custom [`TextContentBlock`], convert it with
+/// `TopicContentBlock::from()` / `.into()`.
+#[cfg(feature = "unstable-msc3765")]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct TopicContentBlock {
+    /// The text representations of the topic.
+    #[serde(rename = "m.text")]
+    pub text: TextContentBlock,
+}
+
+#[cfg(feature = "unstable-msc3765")]
+impl TopicContentBlock {
+    /// A convenience constructor to create a plain text topic.
+    pub fn plain(body: impl Into<String>) -> Self {
+        Self { text: TextContentBlock::plain(body) }
+    }
+
+    /// A convenience constructor to create an HTML topic with a plain text fallback.
+    pub fn html(body: impl Into<String>, html_body: impl Into<String>) -> Self {
+        Self { text: TextContentBlock::html(body, html_body) }
+    }
+
+    /// Whether this content block is empty.
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+#[cfg(feature = "unstable-msc3765")]
+impl From<TextContentBlock> for TopicContentBlock {
+    fn from(text: TextContentBlock) -> Self {
+        Self { text }
+    }
+}
+
+#[cfg(all(test, feature = "unstable-msc3765"))]
+mod tests {
+    use serde_json::{from_value as from_json_value, json, to_value as to_json_value};
+
+    use super::RoomTopicEventContent;
+
+    #[test]
+    fn html_topic_round_trip() {
+        let content = RoomTopicEventContent::html("Hello world", "<b>Hello</b> world");
+        assert_eq!(content.topic, "Hello world");
+
+        let json = json!({
+            "topic": "Hello world",
+            "m.topic": {
+                "m.text": [
+                    { "mimetype": "text/html", "body": "<b>Hello</b> world" },
+                    { "body": "Hello world" },
+                ],
+            },
+        });
+        assert_eq!(to_json_value(&content).unwrap(), json);
+
+        let content = from_json_value::<RoomTopicEventContent>(json).unwrap();
+        assert_eq!(content.topic, "Hello world");
+        assert_eq!(content.plain_topic(), "Hello world");
+        assert_eq!(content.html_topic(), Some("<b>Hello</b> world"));
+    }
+
+    #[test]
+    fn legacy_topic_round_trip() {
+        let json = json!({ "topic": "Hello world" });
+
+        let content = from_json_value::<RoomTopicEventContent>(json.clone()).unwrap();
+        assert_eq!(content.topic, "Hello world");
+        assert!(content.topic_block.is_empty());
+        assert_eq!(content.plain_topic(), "Hello world");
+        assert_eq!(content.html_topic(), None);
+
+        assert_eq!(to_json_value(&content).unwrap(), json);
     }
 }
@@ -7,7 +7,10 @@ use ruma_common::{
     event_id, owned_event_id, room_id, serde::CanBeEmpty, user_id, MilliSecondsSinceUnixEpoch,
 };
 use ruma_events::{
-    location::{AssetType, LocationContent, LocationEventContent, ZoomLevel, ZoomLevelError},
+    location::{
+        AssetType, GeoUri, GeoUriError, LocationContent, LocationEventContent, ZoomLevel,
+        ZoomLevelError,
+    },
     message::TextContentBlock,
     relation::InReplyTo,
     room::message::{LocationMessageEventContent, MessageType, Relation, RoomMessageEventContent},
@@ -134,6 +137,43 @@ fn zoomlevel_deserialization_too_high() {
     assert_eq!(err.to_string(), ZoomLevelError::TooHigh.to_string());
 }
 
+#[test]
+fn geo_uri_parsing() {
+    let uri = GeoUri::parse("geo:51.5008,0.1247;u=35").unwrap();
+    assert_eq!(uri.latitude(), 51.5008);
+    assert_eq!(uri.longitude(), 0.1247);
+    assert_eq!(uri.altitude(), None);
+    assert_eq!(uri.uncertainty(), Some(35.0));
+    assert_eq!(uri.as_str(), "geo:51.5008,0.1247;u=35");
+
+    let uri = "GEO:-48.198634,-16.371648,3.4;crs=wgs84;u=40.0;foo=bar".parse::<GeoUri>().unwrap();
+    assert_eq!(uri.latitude(), -48.198_634);
+    assert_eq!(uri.longitude(), -16.371_648);
+    assert_eq!(uri.altitude(), Some(3.4));
+    assert_eq!(uri.uncertainty(), Some(40.0));
+
+    let content = LocationContent::new("geo:90,-180".to_owned());
+    let uri = content.geo_uri().unwrap();
+    assert_eq!(uri.latitude(), 90.0);
+    assert_eq!(uri.longitude(), -180.0);
+    assert_eq!(uri.uncertainty(), None);
+}
+
+#[test]
+fn geo_uri_parsing_invalid() {
+    assert_eq!(GeoUri::parse("51.5008,0.1247"), Err(GeoUriError::InvalidScheme));
+    assert_eq!(GeoUri::parse("https:51.5008,0.1247"), Err(GeoUriError::InvalidScheme));
+    assert_eq!(GeoUri::parse("geo:51.5008"), Err(GeoUriError::InvalidCoordinates));
+    assert_eq!(GeoUri::parse("geo:51.5008,0.1247,1,2"), Err(GeoUriError::InvalidCoordinates));
+    assert_eq!(GeoUri::parse("geo:51.5008,abc"), Err(GeoUriError::InvalidCoordinates));
+    assert_eq!(GeoUri::parse("geo:+51.5008,1e2"), Err(GeoUriError::InvalidCoordinates));
+    assert_eq!(GeoUri::parse("geo:91,0.1247"), Err(GeoUriError::OutOfRange));
+    assert_eq!(GeoUri::parse("geo:51.5008,-180.5"), Err(GeoUriError::OutOfRange));
+    assert_eq!(GeoUri::parse("geo:51.5008,0.1247;crs=mars"), Err(GeoUriError::UnsupportedCrs));
+    assert_eq!(GeoUri::parse("geo:51.5008,0.1247;u=-1"), Err(GeoUriError::InvalidUncertainty));
+    assert_eq!(GeoUri::parse("geo:51.5008,0.1247;u="), Err(GeoUriError::InvalidUncertainty));
+}
+
 #[test]
 fn message_event_deserialization() {
     let json_data = json!({
@@ -469,6 +469,32 @@ fn unstable_start_event_content_deserialization_missing_poll_start() {
     from_json_value::<UnstablePollStartEventContent>(json_data).unwrap_err();
 }
 
+#[test]
+fn unstable_start_content_msc_example_roundtrip() {
+    // The example of the MSC, with the unstable prefixes.
+    let json_data = json!({
+        "org.matrix.msc1767.text": "What should we order for the party?\n1. Pizza 🍕\n2. Poutine 🍟\n3. Italian 🍝\n4. Wings 🔥",
+        "org.matrix.msc3381.poll.start": {
+            "kind": "org.matrix.msc3381.poll.disclosed",
+            "max_selections": 1,
+            "question": { "org.matrix.msc1767.text": "What should we order for the party?" },
+            "answers": [
+                { "id": "pizza", "org.matrix.msc1767.text": "Pizza 🍕" },
+                { "id": "poutine", "org.matrix.msc1767.text": "Poutine 🍟" },
+                { "id": "italian", "org.matrix.msc1767.text": "Italian 🍝" },
+                { "id": "wings", "org.matrix.msc1767.text": "Wings 🔥" },
+            ],
+        },
+    });
+
+    let content = from_json_value::<UnstablePollStartEventContent>(json_data.clone()).unwrap();
+    assert_matches!(&content, UnstablePollStartEventContent::New(new_content));
+    assert_eq!(new_content.poll_start.kind, PollKind::Disclosed);
+    assert_eq!(new_content.poll_start.answers.len(), 4);
+
+    assert_eq!(to_json_value(&content).unwrap(), json_data);
+}
+
 #[test]
 fn new_unstable_start_event_deserialization() {
     let json_data = json!({
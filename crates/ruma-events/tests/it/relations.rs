@@ -32,6 +32,25 @@ fn reply_deserialize() {
     assert_eq!(event_id, "$1598361704261elfgc:localhost");
 }
 
+#[test]
+fn reply_accessors() {
+    let json = json!({
+        "msgtype": "m.text",
+        "body": "<text msg>",
+        "m.relates_to": {
+            "m.in_reply_to": {
+                "event_id": "$1598361704261elfgc:localhost",
+            },
+        },
+    });
+
+    let content = from_json_value::<RoomMessageEventContent>(json).unwrap();
+    assert!(!content.is_edit());
+    assert_eq!(content.replaced_event_id(), None);
+    assert_eq!(content.thread_root(), None);
+    assert_eq!(content.in_reply_to().unwrap().event_id, "$1598361704261elfgc:localhost");
+}
+
 #[test]
 fn reply_serialize() {
     let content = assign!(RoomMessageEventContent::text_plain("This is a reply"), {
@@ -127,6 +146,28 @@ fn replacement_deserialize() {
     assert_eq!(text.body, "Hello! My name is bar");
 }
 
+#[test]
+fn replacement_accessors() {
+    let json = json!({
+        "msgtype": "m.text",
+        "body": "<text msg>",
+        "m.new_content": {
+            "body": "Hello! My name is bar",
+            "msgtype": "m.text",
+        },
+        "m.relates_to": {
+            "rel_type": "m.replace",
+            "event_id": "$1598361704261elfgc",
+        },
+    });
+
+    let content = from_json_value::<RoomMessageEventContent>(json).unwrap();
+    assert!(content.is_edit());
+    assert_eq!(content.replaced_event_id().unwrap(), "$1598361704261elfgc");
+    assert_eq!(content.thread_root(), None);
+    assert!(content.in_reply_to().is_none());
+}
+
 #[test]
 fn replacement_serialization_roundtrip() {
     let body = "<text msg>";
@@ -288,6 +329,48 @@ fn thread_unstable_deserialize() {
     assert!(!thread.is_falling_back);
 }
 
+#[test]
+fn thread_accessors() {
+    // A thread fallback is not a reply.
+    let json = json!({
+        "msgtype": "m.text",
+        "body": "<text msg>",
+        "m.relates_to": {
+            "rel_type": "m.thread",
+            "event_id": "$1598361704261elfgc",
+            "m.in_reply_to": {
+                "event_id": "$latesteventid",
+            },
+            "is_falling_back": true,
+        },
+    });
+
+    let content = from_json_value::<RoomMessageEventContent>(json).unwrap();
+    assert!(!content.is_edit());
+    assert_eq!(content.thread_root().unwrap(), "$1598361704261elfgc");
+    assert!(content.in_reply_to().is_none());
+    assert_matches!(content.relates_to, Some(Relation::Thread(thread)));
+    assert!(thread.is_falling_back);
+    assert_eq!(thread.in_reply_to.unwrap().event_id, "$latesteventid");
+
+    // A reply in a thread.
+    let json = json!({
+        "msgtype": "m.text",
+        "body": "<text msg>",
+        "m.relates_to": {
+            "rel_type": "m.thread",
+            "event_id": "$1598361704261elfgc",
+            "m.in_reply_to": {
+                "event_id": "$repliedtoeventid",
+            },
+        },
+    });
+
+    let content = from_json_value::<RoomMessageEventContent>(json).unwrap();
+    assert_eq!(content.thread_root().unwrap(), "$1598361704261elfgc");
+    assert_eq!(content.in_reply_to().unwrap().event_id, "$repliedtoeventid");
+}
+
 #[test]
 fn thread_serialization_roundtrip() {
     let body = "<text msg>";
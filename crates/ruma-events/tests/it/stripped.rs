@@ -9,10 +9,17 @@ use serde_json::{from_value as from_json_value, json, to_value as to_json_value}
 
 #[test]
 fn serialize_stripped_state_event_any_content() {
-    assert_eq!(
-        to_json_value(RoomTopicEventContent::new("Testing room".into())).unwrap(),
-        json!({ "topic": "Testing room" })
-    );
+    #[cfg(not(feature = "unstable-msc3765"))]
+    let expected = json!({ "topic": "Testing room" });
+    #[cfg(feature = "unstable-msc3765")]
+    let expected = json!({
+        "topic": "Testing room",
+        "m.topic": {
+            "m.text": [{ "body": "Testing room" }],
+        },
+    });
+
+    assert_eq!(to_json_value(RoomTopicEventContent::new("Testing room".into())).unwrap(), expected);
 }
 
 #[test]
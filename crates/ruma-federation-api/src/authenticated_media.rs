@@ -2,10 +2,15 @@
 //!
 //! [MSC3916]: https://github.com/matrix-org/matrix-spec-proposals/pull/3916
 
-use std::borrow::Cow;
+use std::{borrow::Cow, time::Duration};
 
-use ruma_common::http_headers::ContentDisposition;
+use js_int::UInt;
+use ruma_common::{
+    api::error::{MatrixError, MatrixErrorBody},
+    http_headers::ContentDisposition,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 pub mod get_content;
 pub mod get_content_thumbnail;
@@ -62,6 +67,91 @@ impl Content {
     }
 }
 
+/// An error of the content repository that clients are expected to handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum MediaError {
+    /// `M_NOT_YET_UPLOADED`
+    ///
+    /// The content was created asynchronously and is not uploaded yet. The request can be retried
+    /// later.
+    NotYetUploaded,
+
+    /// `M_TOO_LARGE`
+    ///
+    /// The content is too large for the server to serve.
+    TooLarge,
+
+    /// `M_LIMIT_EXCEEDED`
+    ///
+    /// Too many requests were sent in a short period of time.
+    LimitExceeded {
+        /// How long to wait before retrying the request, if the server specified it.
+        retry_after: Option<Duration>,
+    },
+}
+
+impl MediaError {
+    /// Get the `MediaError` of the given `MatrixError`, if it has the error code of one of the
+    /// errors of the content repository.
+    pub fn from_matrix_error(error: &MatrixError) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            errcode: String,
+            retry_after_ms: Option<UInt>,
+        }
+
+        let MatrixErrorBody::Json(json) = &error.body else {
+            return None;
+        };
+        let body = ErrorBody::deserialize(json).ok()?;
+
+        match body.errcode.as_str() {
+            "M_NOT_YET_UPLOADED" => Some(Self::NotYetUploaded),
+            "M_TOO_LARGE" => Some(Self::TooLarge),
+            "M_LIMIT_EXCEEDED" => Some(Self::LimitExceeded {
+                retry_after: body.retry_after_ms.map(|ms| Duration::from_millis(ms.into())),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<MediaError> for MatrixError {
+    fn from(error: MediaError) -> Self {
+        let (status_code, body) = match error {
+            MediaError::NotYetUploaded => (
+                http::StatusCode::GATEWAY_TIMEOUT,
+                json!({
+                    "errcode": "M_NOT_YET_UPLOADED",
+                    "error": "Content has not yet been uploaded",
+                }),
+            ),
+            MediaError::TooLarge => (
+                http::StatusCode::BAD_GATEWAY,
+                json!({
+                    "errcode": "M_TOO_LARGE",
+                    "error": "Content is too large to serve",
+                }),
+            ),
+            MediaError::LimitExceeded { retry_after } => {
+                let mut body = json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": "Too many requests",
+                });
+                if let Some(retry_after) = retry_after {
+                    let retry_after_ms =
+                        UInt::try_from(retry_after.as_millis()).unwrap_or(UInt::MAX);
+                    body["retry_after_ms"] = u64::from(retry_after_ms).into();
+                }
+                (http::StatusCode::TOO_MANY_REQUESTS, body)
+            }
+        };
+
+        Self { status_code, body: MatrixErrorBody::Json(body) }
+    }
+}
+
 /// Serialize the given metadata and content into a `http::Response` `multipart/mixed` body.
 ///
 /// Returns a tuple containing the boundary used
@@ -140,7 +230,7 @@ fn try_from_multipart_mixed_response<T: AsRef<[u8]>>(
     http_response: http::Response<T>,
 ) -> Result<
     (ContentMetadata, FileOrLocation),
-    ruma_common::api::error::FromHttpResponseError<ruma_common::api::error::MatrixError>,
+    ruma_common::api::error::FromHttpResponseError<MatrixError>,
 > {
     use ruma_common::api::error::{HeaderDeserializationError, MultipartMixedDeserializationError};
 
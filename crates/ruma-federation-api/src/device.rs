@@ -1,3 +1,4 @@
 //! Endpoints to retrieve information about user devices
 
 pub mod get_devices;
+pub mod tracker;
@@ -0,0 +1,154 @@
+//! Helpers to track remote users' device lists incrementally.
+//!
+//! The `stream_id` returned by [`get_devices`] is matched against the `stream_id` and `prev_id`
+//! fields of [`m.device_list_update`] EDUs to decide whether an update can be applied on top of
+//! the locally cached device list, or whether the device list must be fetched again.
+//!
+//! [`get_devices`]: super::get_devices
+//! [`m.device_list_update`]: crate::transactions::edu::DeviceListUpdateContent
+
+use std::collections::BTreeMap;
+
+use js_int::UInt;
+use ruma_common::{OwnedUserId, UserId};
+
+use super::get_devices;
+use crate::transactions::edu::DeviceListUpdateContent;
+
+/// The outcome of checking an `m.device_list_update` EDU against a [`DeviceListTracker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DeviceListUpdateOrdering {
+    /// The update directly follows the last known update and can be applied to the cached device
+    /// list.
+    InOrder,
+
+    /// Some updates were missed, or the user's device list is not tracked yet.
+    ///
+    /// The device list must be fetched again with a [`get_devices`](super::get_devices) request.
+    Gap,
+
+    /// The update is not newer than the last known update and must be ignored.
+    Stale,
+}
+
+/// Stores the last seen device list `stream_id` of remote users.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceListTracker {
+    stream_ids: BTreeMap<OwnedUserId, UInt>,
+}
+
+impl DeviceListTracker {
+    /// Creates an empty `DeviceListTracker`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The last seen `stream_id` for the given user, if their device list is tracked.
+    pub fn stream_id(&self, user_id: &UserId) -> Option<UInt> {
+        self.stream_ids.get(user_id).copied()
+    }
+
+    /// Record the `stream_id` of a full device list fetched with a `get_devices` request.
+    ///
+    /// This replaces any previously known `stream_id` for the user.
+    pub fn apply_response(&mut self, response: &get_devices::v1::Response) {
+        self.stream_ids.insert(response.user_id.clone(), response.stream_id);
+    }
+
+    /// Stop tracking the device list of the given user.
+    pub fn forget(&mut self, user_id: &UserId) {
+        self.stream_ids.remove(user_id);
+    }
+
+    /// Check the given `m.device_list_update` EDU against the last seen `stream_id` of its user.
+    ///
+    /// If the update is [`InOrder`](DeviceListUpdateOrdering::InOrder), its `stream_id` is
+    /// recorded as the last seen one. Otherwise the tracker is left unchanged.
+    pub fn check_update(&mut self, update: &DeviceListUpdateContent) -> DeviceListUpdateOrdering {
+        let Some(last_stream_id) = self.stream_ids.get_mut(&update.user_id) else {
+            return DeviceListUpdateOrdering::Gap;
+        };
+
+        if update.stream_id <= *last_stream_id {
+            return DeviceListUpdateOrdering::Stale;
+        }
+
+        // Every update that was not referenced yet should be listed in `prev_id`, so if all of
+        // them are at most the last seen `stream_id`, we didn't miss anything.
+        if update.prev_id.iter().all(|prev_id| prev_id <= last_stream_id) {
+            *last_stream_id = update.stream_id;
+            DeviceListUpdateOrdering::InOrder
+        } else {
+            DeviceListUpdateOrdering::Gap
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use js_int::uint;
+    use ruma_common::{owned_device_id, owned_user_id, user_id};
+
+    use super::{DeviceListTracker, DeviceListUpdateOrdering};
+    use crate::{device::get_devices, transactions::edu::DeviceListUpdateContent};
+
+    fn tracker() -> DeviceListTracker {
+        let mut tracker = DeviceListTracker::new();
+        tracker.apply_response(&get_devices::v1::Response::new(
+            owned_user_id!("@alice:example.org"),
+            uint!(5),
+        ));
+        tracker
+    }
+
+    fn update(stream_id: u32, prev_id: &[u32]) -> DeviceListUpdateContent {
+        let mut update = DeviceListUpdateContent::new(
+            owned_user_id!("@alice:example.org"),
+            owned_device_id!("DEVICE"),
+            stream_id.into(),
+        );
+        update.prev_id = prev_id.iter().map(|&id| id.into()).collect();
+        update
+    }
+
+    #[test]
+    fn in_order_update() {
+        let mut tracker = tracker();
+
+        assert_eq!(tracker.check_update(&update(6, &[5])), DeviceListUpdateOrdering::InOrder);
+        assert_eq!(tracker.stream_id(user_id!("@alice:example.org")), Some(uint!(6)));
+    }
+
+    #[test]
+    fn in_order_update_with_several_prev_ids() {
+        let mut tracker = tracker();
+
+        assert_eq!(tracker.check_update(&update(7, &[4, 5])), DeviceListUpdateOrdering::InOrder);
+        assert_eq!(tracker.stream_id(user_id!("@alice:example.org")), Some(uint!(7)));
+
+        assert_eq!(tracker.check_update(&update(9, &[7, 8])), DeviceListUpdateOrdering::Gap);
+        assert_eq!(tracker.stream_id(user_id!("@alice:example.org")), Some(uint!(7)));
+    }
+
+    #[test]
+    fn gap_update() {
+        let mut tracker = tracker();
+
+        assert_eq!(tracker.check_update(&update(8, &[7])), DeviceListUpdateOrdering::Gap);
+        assert_eq!(tracker.stream_id(user_id!("@alice:example.org")), Some(uint!(5)));
+
+        let mut unknown = update(1, &[]);
+        unknown.user_id = owned_user_id!("@bob:example.org");
+        assert_eq!(tracker.check_update(&unknown), DeviceListUpdateOrdering::Gap);
+    }
+
+    #[test]
+    fn stale_update() {
+        let mut tracker = tracker();
+
+        assert_eq!(tracker.check_update(&update(7, &[5])), DeviceListUpdateOrdering::InOrder);
+        assert_eq!(tracker.check_update(&update(6, &[5])), DeviceListUpdateOrdering::Stale);
+        assert_eq!(tracker.stream_id(user_id!("@alice:example.org")), Some(uint!(7)));
+    }
+}
@@ -73,4 +73,71 @@ pub mod v1 {
             Self { room_version: None, event }
         }
     }
+
+    /// Pick the newest room version that is both in `offered` and `supported`.
+    ///
+    /// Room versions that are integers are considered newer than other room versions, and are
+    /// compared by their numeric value.
+    ///
+    /// Returns `None` if there is no room version in common.
+    pub fn negotiate_room_version(
+        offered: &[RoomVersionId],
+        supported: &[RoomVersionId],
+    ) -> Option<RoomVersionId> {
+        offered
+            .iter()
+            .filter(|version| supported.contains(version))
+            .max_by_key(|version| version.as_str().parse::<u64>().ok())
+            .cloned()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use ruma_common::RoomVersionId;
+
+        use super::negotiate_room_version;
+
+        #[test]
+        fn overlapping_versions() {
+            assert_eq!(
+                negotiate_room_version(
+                    &[RoomVersionId::V6, RoomVersionId::V9, RoomVersionId::V11],
+                    &[RoomVersionId::V5, RoomVersionId::V6, RoomVersionId::V9],
+                ),
+                Some(RoomVersionId::V9)
+            );
+        }
+
+        #[test]
+        fn disjoint_versions() {
+            assert_eq!(
+                negotiate_room_version(
+                    &[RoomVersionId::V1, RoomVersionId::V2],
+                    &[RoomVersionId::V10, RoomVersionId::V11],
+                ),
+                None
+            );
+        }
+
+        #[test]
+        fn prefer_newer_version() {
+            // `10` is newer than `9`, even though it is smaller as a string.
+            assert_eq!(
+                negotiate_room_version(
+                    &[RoomVersionId::V10, RoomVersionId::V9],
+                    &[RoomVersionId::V9, RoomVersionId::V10],
+                ),
+                Some(RoomVersionId::V10)
+            );
+
+            let custom = RoomVersionId::try_from("org.example.custom").unwrap();
+            assert_eq!(
+                negotiate_room_version(
+                    &[custom.clone(), RoomVersionId::V1],
+                    &[RoomVersionId::V1, custom],
+                ),
+                Some(RoomVersionId::V1)
+            );
+        }
+    }
 }
@@ -14,7 +14,7 @@ pub mod v1 {
         api::{request, response, Metadata},
         metadata,
     };
-    use serde_json::Value as JsonValue;
+    use serde_json::value::RawValue as RawJsonValue;
 
     const METADATA: Metadata = metadata! {
         method: GET,
@@ -41,8 +41,10 @@ pub mod v1 {
     #[response]
     pub struct Response {
         /// The body of the response.
+        ///
+        /// Its format depends on the query type.
         #[ruma_api(body)]
-        pub body: JsonValue,
+        pub body: Box<RawJsonValue>,
     }
 
     impl Request {
@@ -54,7 +56,7 @@ pub mod v1 {
 
     impl Response {
         /// Creates a new response with the given body.
-        pub fn new(body: JsonValue) -> Self {
+        pub fn new(body: Box<RawJsonValue>) -> Self {
             Self { body }
         }
     }
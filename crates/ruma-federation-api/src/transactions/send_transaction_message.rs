@@ -7,15 +7,19 @@ pub mod v1 {
     //!
     //! [spec]: https://spec.matrix.org/latest/server-server-api/#put_matrixfederationv1sendtxnid
 
-    use std::collections::BTreeMap;
+    use std::{
+        cmp::Reverse,
+        collections::{BTreeMap, BinaryHeap},
+    };
 
     use ruma_common::{
         api::{request, response, Metadata},
         metadata,
         serde::Raw,
-        MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedServerName, OwnedTransactionId,
+        EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedServerName, OwnedTransactionId,
     };
-    use serde_json::value::RawValue as RawJsonValue;
+    use serde::{de::IgnoredAny, Deserialize};
+    use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
 
     use crate::transactions::edu::Edu;
 
@@ -85,6 +89,15 @@ pub mod v1 {
         ) -> Self {
             Self { transaction_id, origin, origin_server_ts, pdus: vec![], edus: vec![] }
         }
+
+        /// Whether this transaction only contains EDUs.
+        ///
+        /// Transactions with ephemeral messages, like typing notifications or presence updates,
+        /// but without any PDU are very common. Servers can handle them without going through
+        /// the processing of PDUs, and respond with an empty map of PDUs.
+        pub fn is_edu_only(&self) -> bool {
+            self.pdus.is_empty() && !self.edus.is_empty()
+        }
     }
 
     impl Response {
@@ -93,4 +106,183 @@ pub mod v1 {
             Self { pdus }
         }
     }
+
+    /// Compute the order in which the given PDUs should be processed.
+    ///
+    /// PDUs are sent as a flat list, but a PDU should only be processed after its `prev_events`.
+    /// This returns the indices of `pdus` sorted so that every PDU comes after the PDUs of the list
+    /// that are in its `prev_events`. PDUs that don't depend on each other keep their order in the
+    /// list.
+    ///
+    /// `event_id` is used to get the ID of a PDU, which depends on its room version. If it returns
+    /// `None`, the PDU can't be a parent of another PDU of the list.
+    ///
+    /// PDUs that are part of a cycle, which can only happen with invalid PDUs, are returned last,
+    /// in their order in the list.
+    pub fn topologically_order_pdus<F>(pdus: &[Box<RawJsonValue>], event_id: F) -> Vec<usize>
+    where
+        F: Fn(&RawJsonValue) -> Option<OwnedEventId>,
+    {
+        /// An entry of `prev_events`, which also contains the hashes of the event in room
+        /// versions 1 and 2.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum PrevEvent {
+            Id(OwnedEventId),
+            IdWithHashes(OwnedEventId, IgnoredAny),
+        }
+
+        impl PrevEvent {
+            fn event_id(&self) -> &EventId {
+                match self {
+                    Self::Id(id) | Self::IdWithHashes(id, _) => id,
+                }
+            }
+        }
+
+        #[derive(Deserialize)]
+        struct PduPrevEvents {
+            #[serde(default)]
+            prev_events: Vec<PrevEvent>,
+        }
+
+        let indices: BTreeMap<OwnedEventId, usize> =
+            pdus.iter().enumerate().filter_map(|(idx, pdu)| Some((event_id(pdu)?, idx))).collect();
+
+        // The number of parents in the list of each PDU, and the children in the list of each PDU.
+        let mut parent_counts = vec![0_usize; pdus.len()];
+        let mut children = vec![Vec::new(); pdus.len()];
+
+        for (idx, pdu) in pdus.iter().enumerate() {
+            let prev_events = from_json_str::<PduPrevEvents>(pdu.get())
+                .map(|p| p.prev_events)
+                .unwrap_or_default();
+
+            for prev_event in prev_events {
+                if let Some(&parent_idx) = indices.get(prev_event.event_id()) {
+                    if parent_idx != idx && !children[parent_idx].contains(&idx) {
+                        children[parent_idx].push(idx);
+                        parent_counts[idx] += 1;
+                    }
+                }
+            }
+        }
+
+        let mut ready: BinaryHeap<_> = parent_counts
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &count)| (count == 0).then_some(Reverse(idx)))
+            .collect();
+        let mut order = Vec::with_capacity(pdus.len());
+
+        while let Some(Reverse(idx)) = ready.pop() {
+            order.push(idx);
+
+            for &child_idx in &children[idx] {
+                parent_counts[child_idx] -= 1;
+                if parent_counts[child_idx] == 0 {
+                    ready.push(Reverse(child_idx));
+                }
+            }
+        }
+
+        if order.len() < pdus.len() {
+            order.extend((0..pdus.len()).filter(|idx| parent_counts[*idx] > 0));
+        }
+
+        order
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use ruma_common::{
+            owned_room_id, owned_server_name, owned_user_id, serde::Raw,
+            MilliSecondsSinceUnixEpoch, OwnedEventId,
+        };
+        use serde::Deserialize;
+        use serde_json::{
+            from_str as from_json_str, json,
+            value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
+            Value as JsonValue,
+        };
+
+        use super::{topologically_order_pdus, Request};
+        use crate::transactions::edu::{Edu, TypingContent};
+
+        fn pdus(pdus: &[JsonValue]) -> Vec<Box<RawJsonValue>> {
+            pdus.iter().map(|pdu| to_raw_json_value(pdu).unwrap()).collect()
+        }
+
+        fn event_id(pdu: &RawJsonValue) -> Option<OwnedEventId> {
+            #[derive(Deserialize)]
+            struct PduEventId {
+                event_id: OwnedEventId,
+            }
+
+            from_json_str::<PduEventId>(pdu.get()).ok().map(|pdu| pdu.event_id)
+        }
+
+        #[test]
+        fn out_of_order_pdus() {
+            let pdus = pdus(&[
+                json!({
+                    "event_id": "$d:example.org",
+                    "prev_events": ["$b:example.org", "$c:example.org"],
+                }),
+                json!({ "event_id": "$c:example.org", "prev_events": ["$a:example.org"] }),
+                json!({ "event_id": "$b:example.org", "prev_events": ["$a:example.org"] }),
+                json!({ "event_id": "$a:example.org", "prev_events": ["$unknown:example.org"] }),
+                json!({ "event_id": "$e:example.org", "prev_events": [] }),
+            ]);
+
+            assert_eq!(topologically_order_pdus(&pdus, event_id), [3, 1, 2, 0, 4]);
+        }
+
+        #[test]
+        fn pdus_with_hashes_in_prev_events() {
+            let pdus = pdus(&[
+                json!({
+                    "event_id": "$b:example.org",
+                    "prev_events": [["$a:example.org", { "sha256": "aGFzaA" }]],
+                }),
+                json!({ "event_id": "$a:example.org", "prev_events": [] }),
+            ]);
+
+            assert_eq!(topologically_order_pdus(&pdus, event_id), [1, 0]);
+        }
+
+        #[test]
+        fn cyclic_pdus() {
+            let pdus = pdus(&[
+                json!({ "event_id": "$a:example.org", "prev_events": ["$b:example.org"] }),
+                json!({ "event_id": "$b:example.org", "prev_events": ["$a:example.org"] }),
+                json!({ "event_id": "$c:example.org", "prev_events": [] }),
+            ]);
+
+            assert_eq!(topologically_order_pdus(&pdus, event_id), [2, 0, 1]);
+        }
+
+        #[test]
+        fn edu_only_transaction() {
+            let mut request = Request::new(
+                "txn".into(),
+                owned_server_name!("example.org"),
+                MilliSecondsSinceUnixEpoch::now(),
+            );
+            assert!(!request.is_edu_only());
+
+            request.edus.push(
+                Raw::new(&Edu::Typing(TypingContent::new(
+                    owned_room_id!("!room:example.org"),
+                    owned_user_id!("@alice:example.org"),
+                    true,
+                )))
+                .unwrap(),
+            );
+            assert!(request.is_edu_only());
+
+            request.pdus = pdus(&[json!({ "event_id": "$a:example.org", "prev_events": [] })]);
+            assert!(!request.is_edu_only());
+        }
+    }
 }
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use ruma_common::api::{
+    error::{MatrixError, MatrixErrorBody},
+    EndpointError,
+};
+use ruma_federation_api::authenticated_media::MediaError;
+use serde_json::{json, to_vec as to_json_vec, Value as JsonValue};
+
+fn matrix_error(status: u16, body: JsonValue) -> MatrixError {
+    let response =
+        http::Response::builder().status(status).body(to_json_vec(&body).unwrap()).unwrap();
+    MatrixError::from_http_response(response)
+}
+
+#[test]
+fn deserialize_not_yet_uploaded() {
+    let error = matrix_error(
+        504,
+        json!({
+            "errcode": "M_NOT_YET_UPLOADED",
+            "error": "Content has not yet been uploaded",
+        }),
+    );
+    assert_eq!(MediaError::from_matrix_error(&error), Some(MediaError::NotYetUploaded));
+}
+
+#[test]
+fn deserialize_too_large() {
+    let error = matrix_error(
+        502,
+        json!({
+            "errcode": "M_TOO_LARGE",
+            "error": "Content is too large to serve",
+        }),
+    );
+    assert_eq!(MediaError::from_matrix_error(&error), Some(MediaError::TooLarge));
+}
+
+#[test]
+fn deserialize_limit_exceeded() {
+    let error = matrix_error(
+        429,
+        json!({
+            "errcode": "M_LIMIT_EXCEEDED",
+            "error": "Too many requests",
+            "retry_after_ms": 2000,
+        }),
+    );
+    assert_eq!(
+        MediaError::from_matrix_error(&error),
+        Some(MediaError::LimitExceeded { retry_after: Some(Duration::from_secs(2)) })
+    );
+
+    let error = matrix_error(429, json!({ "errcode": "M_LIMIT_EXCEEDED" }));
+    assert_eq!(
+        MediaError::from_matrix_error(&error),
+        Some(MediaError::LimitExceeded { retry_after: None })
+    );
+}
+
+#[test]
+fn deserialize_other_errors() {
+    let error = matrix_error(404, json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }));
+    assert_eq!(MediaError::from_matrix_error(&error), None);
+
+    let error = MatrixError {
+        status_code: http::StatusCode::BAD_GATEWAY,
+        body: MatrixErrorBody::from_bytes(b"<html>Bad gateway</html>"),
+    };
+    assert_eq!(MediaError::from_matrix_error(&error), None);
+}
+
+#[test]
+fn media_error_into_matrix_error() {
+    let error = MatrixError::from(MediaError::LimitExceeded {
+        retry_after: Some(Duration::from_millis(1500)),
+    });
+    assert_eq!(error.status_code, http::StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        MediaError::from_matrix_error(&error),
+        Some(MediaError::LimitExceeded { retry_after: Some(Duration::from_millis(1500)) })
+    );
+
+    let error = MatrixError::from(MediaError::NotYetUploaded);
+    assert_eq!(error.status_code, http::StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(MediaError::from_matrix_error(&error), Some(MediaError::NotYetUploaded));
+}
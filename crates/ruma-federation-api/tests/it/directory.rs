@@ -0,0 +1,88 @@
+mod get_public_rooms_filtered {
+    #[cfg(feature = "client")]
+    #[test]
+    fn serialize_request_search() {
+        use js_int::uint;
+        use ruma_common::{
+            api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+            directory::Filter,
+        };
+        use ruma_federation_api::directory::get_public_rooms_filtered::v1::Request;
+        use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+        let mut filter = Filter::new();
+        filter.generic_search_term = Some("rust".to_owned());
+        let mut req = Request::new();
+        req.limit = Some(uint!(10));
+        req.filter = filter;
+
+        let req = req
+            .try_into_http_request::<Vec<u8>>(
+                "https://example.org",
+                SendAccessToken::IfRequired("auth_tok"),
+                &[MatrixVersion::V1_1],
+            )
+            .unwrap();
+
+        assert_eq!(
+            from_json_slice::<JsonValue>(req.body()).unwrap(),
+            json!({
+                "limit": 10,
+                "filter": { "generic_search_term": "rust" },
+            })
+        );
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn serialize_request_third_party_network() {
+        use ruma_common::{
+            api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+            directory::{Filter, RoomNetwork, RoomTypeFilter},
+        };
+        use ruma_federation_api::directory::get_public_rooms_filtered::v1::Request;
+        use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+        let mut filter = Filter::new();
+        filter.room_types = vec![RoomTypeFilter::Space];
+        let mut req = Request::new();
+        req.filter = filter;
+        req.room_network = RoomNetwork::ThirdParty("irc".to_owned());
+
+        let req = req
+            .try_into_http_request::<Vec<u8>>(
+                "https://example.org",
+                SendAccessToken::IfRequired("auth_tok"),
+                &[MatrixVersion::V1_1],
+            )
+            .unwrap();
+
+        assert_eq!(
+            from_json_slice::<JsonValue>(req.body()).unwrap(),
+            json!({
+                "filter": { "room_types": ["m.space"] },
+                "third_party_instance_id": "irc",
+            })
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn deserialize_request_all_networks_and_third_party_network() {
+        use ruma_common::api::IncomingRequest;
+        use ruma_federation_api::directory::get_public_rooms_filtered::v1::Request;
+        use serde_json::{json, to_vec as to_json_vec};
+
+        let req = http::Request::post("https://example.org/_matrix/federation/v1/publicRooms")
+            .body(
+                to_json_vec(&json!({
+                    "include_all_networks": true,
+                    "third_party_instance_id": "irc",
+                }))
+                .unwrap(),
+            )
+            .unwrap();
+
+        Request::try_from_http_request(req, &[] as &[String]).unwrap_err();
+    }
+}
@@ -1 +1,4 @@
+mod authenticated_media;
+mod directory;
 mod membership;
+mod query;
@@ -0,0 +1,87 @@
+mod get_custom_information {
+    #[cfg(feature = "client")]
+    #[test]
+    fn serialize_request() {
+        use std::collections::BTreeMap;
+
+        use ruma_common::api::{MatrixVersion, OutgoingRequest, SendAccessToken};
+        use ruma_federation_api::query::get_custom_information::v1::Request;
+
+        let params = BTreeMap::from([
+            ("room_alias".to_owned(), "#room:example.org".to_owned()),
+            ("limit".to_owned(), "10".to_owned()),
+        ]);
+        let req = Request::new("org.example.custom".to_owned(), params)
+            .try_into_http_request::<Vec<u8>>(
+                "https://example.org",
+                SendAccessToken::IfRequired("auth_tok"),
+                &[MatrixVersion::V1_1],
+            )
+            .unwrap();
+
+        assert_eq!(
+            req.uri(),
+            "https://example.org/_matrix/federation/v1/query/org.example.custom?limit=10&room_alias=%23room%3Aexample.org"
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn serialize_response() {
+        use ruma_common::api::OutgoingResponse;
+        use ruma_federation_api::query::get_custom_information::v1::Response;
+        use serde_json::{
+            from_slice as from_json_slice, json, value::to_raw_value as to_raw_json_value,
+            Value as JsonValue,
+        };
+
+        let body = json!({ "custom": { "answer": 42 } });
+        let res = Response::new(to_raw_json_value(&body).unwrap())
+            .try_into_http_response::<Vec<u8>>()
+            .unwrap();
+
+        assert_eq!(from_json_slice::<JsonValue>(res.body()).unwrap(), body);
+    }
+}
+
+mod get_profile_information {
+    #[cfg(all(feature = "server", feature = "unstable-msc2448"))]
+    #[test]
+    fn serialize_response_with_blurhash() {
+        use ruma_common::{api::OutgoingResponse, owned_mxc_uri};
+        use ruma_federation_api::query::get_profile_information::v1::Response;
+        use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+        let mut response = Response::new();
+        response.avatar_url = Some(owned_mxc_uri!("mxc://example.org/abcdef"));
+        response.blurhash = Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_owned());
+
+        let res = response.try_into_http_response::<Vec<u8>>().unwrap();
+
+        assert_eq!(
+            from_json_slice::<JsonValue>(res.body()).unwrap(),
+            json!({
+                "avatar_url": "mxc://example.org/abcdef",
+                "xyz.amorgan.blurhash": "LEHV6nWB2yk8pyo0adR*.7kCMdnj",
+            })
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn serialize_response_without_blurhash() {
+        use ruma_common::{api::OutgoingResponse, owned_mxc_uri};
+        use ruma_federation_api::query::get_profile_information::v1::Response;
+        use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+        let mut response = Response::new();
+        response.avatar_url = Some(owned_mxc_uri!("mxc://example.org/abcdef"));
+
+        let res = response.try_into_http_response::<Vec<u8>>().unwrap();
+
+        assert_eq!(
+            from_json_slice::<JsonValue>(res.body()).unwrap(),
+            json!({ "avatar_url": "mxc://example.org/abcdef" })
+        );
+    }
+}
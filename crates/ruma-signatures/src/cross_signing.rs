@@ -0,0 +1,354 @@
+//! Verification of the cross-signing keys of a user.
+
+use std::collections::BTreeMap;
+
+use ruma_common::{
+    encryption::{CrossSigningKey, DeviceKeys, KeyUsage},
+    serde::{base64::Standard, Base64, Raw},
+    CanonicalJsonObject, OwnedDeviceId, OwnedUserId, SigningKeyAlgorithm, UserId,
+};
+use serde_json::from_str as from_json_str;
+use thiserror::Error;
+
+use crate::{
+    canonical_json,
+    verification::{Ed25519Verifier, Verifier},
+    Error, ParseError,
+};
+
+/// The cross-signing keys of a user.
+///
+/// Over federation, only the master and self-signing keys of a user are shared, in the
+/// `master_keys` and `self_signing_keys` fields of the response of a `get_keys` request.
+#[derive(Clone, Debug)]
+#[allow(clippy::exhaustive_structs)]
+pub struct CrossSigningKeys {
+    /// The master key of the user.
+    pub master: Raw<CrossSigningKey>,
+
+    /// The self-signing key of the user, used to sign their devices.
+    pub self_signing: Option<Raw<CrossSigningKey>>,
+
+    /// The user-signing key of the user, used to sign the master keys of other users.
+    pub user_signing: Option<Raw<CrossSigningKey>>,
+}
+
+impl CrossSigningKeys {
+    /// Creates a new `CrossSigningKeys` with the given master key.
+    pub fn new(master: Raw<CrossSigningKey>) -> Self {
+        Self { master, self_signing: None, user_signing: None }
+    }
+}
+
+/// An error when verifying the cross-signing keys of a user.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CrossSigningError {
+    /// A key could not be deserialized.
+    #[error("failed to deserialize key: {0}")]
+    Deserialization(#[from] serde_json::Error),
+
+    /// A key or a device belongs to another user than the master key.
+    #[error("key belongs to another user: {0}")]
+    UserIdMismatch(OwnedUserId),
+
+    /// The keys of a device are listed under another device ID than their own.
+    #[error("keys of device {found} are listed under device {expected}")]
+    DeviceIdMismatch {
+        /// The device ID under which the keys are listed.
+        expected: OwnedDeviceId,
+
+        /// The device ID in the keys.
+        found: OwnedDeviceId,
+    },
+
+    /// A cross-signing key doesn't have the usage it is used for.
+    #[error("key doesn't have the `{0}` usage")]
+    MissingUsage(KeyUsage),
+
+    /// A cross-signing key doesn't have exactly one Ed25519 public key.
+    #[error("the `{0}` key must have exactly one Ed25519 public key")]
+    InvalidPublicKey(KeyUsage),
+
+    /// A cross-signing key is not signed by the master key.
+    #[error("the `{0}` key is not signed by the master key")]
+    KeyNotSigned(KeyUsage),
+
+    /// The signature of a cross-signing key by the master key is invalid.
+    #[error("invalid signature of the `{0}` key: {1}")]
+    InvalidKeySignature(KeyUsage, #[source] Error),
+
+    /// A device is not signed by the self-signing key.
+    #[error("device {0} is not signed by the self-signing key")]
+    DeviceNotSigned(OwnedDeviceId),
+
+    /// The signature of a device by the self-signing key is invalid.
+    #[error("invalid signature of device {0}: {1}")]
+    InvalidDeviceSignature(OwnedDeviceId, #[source] Error),
+}
+
+/// Verifies that the given cross-signing keys and device keys chain up to the master key.
+///
+/// The self-signing and user-signing keys, if any, must be signed by the master key, and all the
+/// `device_keys` must be signed by the self-signing key. Other signatures, like the signatures of
+/// the master key by the devices of the user, are ignored.
+///
+/// # Parameters
+///
+/// * keys: The cross-signing keys of the user.
+/// * device_keys: The keys of the devices of the user, like the ones for this user in the
+///   `device_keys` field of the response of a `get_keys` request.
+///
+/// # Errors
+///
+/// Returns an error if a key is malformed, if a key belongs to another user, if the keys of a
+/// device are listed under another device ID, or if a signature in the chain is missing or
+/// invalid.
+pub fn verify_cross_signing(
+    keys: &CrossSigningKeys,
+    device_keys: &BTreeMap<OwnedDeviceId, Raw<DeviceKeys>>,
+) -> Result<(), CrossSigningError> {
+    let master = keys.master.deserialize()?;
+    let master_key = public_key(&master, KeyUsage::Master)?;
+    let user_id = &master.user_id;
+
+    for (key, usage) in
+        [(&keys.self_signing, KeyUsage::SelfSigning), (&keys.user_signing, KeyUsage::UserSigning)]
+    {
+        let Some(key) = key else {
+            continue;
+        };
+
+        let key_content = key.deserialize()?;
+        if key_content.user_id != *user_id {
+            return Err(CrossSigningError::UserIdMismatch(key_content.user_id));
+        }
+        public_key(&key_content, usage.clone())?;
+
+        match verify_signature(key.json().get(), user_id, master_key)? {
+            None => return Err(CrossSigningError::KeyNotSigned(usage)),
+            Some(Err(error)) => return Err(CrossSigningError::InvalidKeySignature(usage, error)),
+            Some(Ok(())) => {}
+        }
+    }
+
+    let self_signing = keys.self_signing.as_ref().map(Raw::deserialize).transpose()?;
+    let self_signing_key =
+        self_signing.as_ref().map(|key| public_key(key, KeyUsage::SelfSigning)).transpose()?;
+
+    for (device_id, device) in device_keys {
+        let device_content = device.deserialize()?;
+        if device_content.user_id != *user_id {
+            return Err(CrossSigningError::UserIdMismatch(device_content.user_id));
+        }
+        if device_content.device_id != *device_id {
+            return Err(CrossSigningError::DeviceIdMismatch {
+                expected: device_id.clone(),
+                found: device_content.device_id,
+            });
+        }
+
+        let Some(self_signing_key) = self_signing_key else {
+            return Err(CrossSigningError::DeviceNotSigned(device_id.clone()));
+        };
+
+        match verify_signature(device.json().get(), user_id, self_signing_key)? {
+            None => return Err(CrossSigningError::DeviceNotSigned(device_id.clone())),
+            Some(Err(error)) => {
+                return Err(CrossSigningError::InvalidDeviceSignature(device_id.clone(), error))
+            }
+            Some(Ok(())) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The Ed25519 public key of the given cross-signing key, as a `(key ID, public key)` pair.
+///
+/// Checks that the key has the given usage.
+fn public_key(key: &CrossSigningKey, usage: KeyUsage) -> Result<(&str, &str), CrossSigningError> {
+    if !key.usage.contains(&usage) {
+        return Err(CrossSigningError::MissingUsage(usage));
+    }
+
+    let mut keys = key.keys.iter();
+    match (keys.next(), keys.next()) {
+        (Some((key_id, public_key)), None)
+            if key_id.algorithm() == SigningKeyAlgorithm::Ed25519 =>
+        {
+            Ok((key_id.as_str(), public_key))
+        }
+        _ => Err(CrossSigningError::InvalidPublicKey(usage)),
+    }
+}
+
+/// Verifies the signature of the given JSON object by the given key of `user_id`.
+///
+/// Returns `None` if the object is not signed by this key.
+fn verify_signature(
+    json: &str,
+    user_id: &UserId,
+    (key_id, public_key): (&str, &str),
+) -> Result<Option<Result<(), Error>>, CrossSigningError> {
+    let object: CanonicalJsonObject = from_json_str(json)?;
+
+    let Some(signature) = object
+        .get("signatures")
+        .and_then(|signatures| signatures.as_object()?.get(user_id.as_str())?.as_object())
+        .and_then(|signature_set| signature_set.get(key_id)?.as_str())
+    else {
+        return Ok(None);
+    };
+
+    let result = (|| {
+        let public_key = Base64::<Standard>::parse(public_key)
+            .map_err(|e| ParseError::base64("public key", public_key, e))?;
+        let signature = Base64::<Standard>::parse(signature)
+            .map_err(|e| ParseError::base64("signature", signature, e))?;
+        let object = canonical_json(object.clone())?;
+
+        Ed25519Verifier.verify_json(public_key.as_bytes(), signature.as_bytes(), object.as_bytes())
+    })();
+
+    Ok(Some(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ruma_common::{
+        device_id,
+        encryption::{DeviceKeys, KeyUsage},
+        serde::{base64::Standard, Base64, Raw},
+        CanonicalJsonObject, CanonicalJsonValue, OwnedDeviceId,
+    };
+    use serde_json::{from_value as from_json_value, json};
+
+    use super::{verify_cross_signing, CrossSigningError, CrossSigningKeys};
+    use crate::{sign_json, Ed25519KeyPair};
+
+    const USER_ID: &str = "@alice:localhost";
+
+    /// Generate a key pair whose version is its public key, like cross-signing keys.
+    fn key_pair() -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        let public_key =
+            Ed25519KeyPair::from_der(&document, "tmp".to_owned()).unwrap().public_key();
+        let version = Base64::<Standard, _>::new(public_key.to_vec()).encode();
+        Ed25519KeyPair::from_der(&document, version).unwrap()
+    }
+
+    fn sign<T>(mut object: CanonicalJsonObject, key_pair: &Ed25519KeyPair) -> Raw<T> {
+        sign_json(USER_ID, key_pair, &mut object).unwrap();
+        Raw::new(&object).unwrap().cast()
+    }
+
+    fn cross_signing_key(usage: &str, key_pair: &Ed25519KeyPair) -> CanonicalJsonObject {
+        let public_key = Base64::<Standard, _>::new(key_pair.public_key().to_vec()).encode();
+        from_json_value(json!({
+            "user_id": USER_ID,
+            "usage": [usage],
+            "keys": { format!("ed25519:{public_key}"): public_key },
+        }))
+        .unwrap()
+    }
+
+    fn device_keys(device_id: &str) -> CanonicalJsonObject {
+        from_json_value(json!({
+            "user_id": USER_ID,
+            "device_id": device_id,
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+            "keys": { format!("ed25519:{device_id}"): "key" },
+            "signatures": {},
+        }))
+        .unwrap()
+    }
+
+    struct Chain {
+        keys: CrossSigningKeys,
+        device_keys: BTreeMap<OwnedDeviceId, Raw<DeviceKeys>>,
+        master_pair: Ed25519KeyPair,
+        self_signing_pair: Ed25519KeyPair,
+    }
+
+    fn valid_chain() -> Chain {
+        let master_pair = key_pair();
+        let self_signing_pair = key_pair();
+        let user_signing_pair = key_pair();
+
+        let mut keys = CrossSigningKeys::new(
+            Raw::new(&cross_signing_key("master", &master_pair)).unwrap().cast(),
+        );
+        keys.self_signing =
+            Some(sign(cross_signing_key("self_signing", &self_signing_pair), &master_pair));
+        keys.user_signing =
+            Some(sign(cross_signing_key("user_signing", &user_signing_pair), &master_pair));
+
+        let device_keys =
+            BTreeMap::from([("DEVICE".into(), sign(device_keys("DEVICE"), &self_signing_pair))]);
+
+        Chain { keys, device_keys, master_pair, self_signing_pair }
+    }
+
+    #[test]
+    fn valid_cross_signing_chain() {
+        let chain = valid_chain();
+        verify_cross_signing(&chain.keys, &chain.device_keys).unwrap();
+
+        // The master key alone is valid.
+        verify_cross_signing(&CrossSigningKeys::new(chain.keys.master), &BTreeMap::new()).unwrap();
+    }
+
+    #[test]
+    fn broken_self_signing_signature() {
+        let mut chain = valid_chain();
+        let self_signing = cross_signing_key("self_signing", &chain.self_signing_pair);
+
+        // The self-signing key is signed by itself instead of the master key.
+        chain.keys.self_signing = Some(sign(self_signing.clone(), &chain.self_signing_pair));
+        let err = verify_cross_signing(&chain.keys, &chain.device_keys).unwrap_err();
+        assert!(matches!(err, CrossSigningError::KeyNotSigned(KeyUsage::SelfSigning)), "{err:?}");
+
+        // The self-signing key was modified after it was signed by the master key.
+        let mut signed =
+            sign::<CanonicalJsonObject>(self_signing, &chain.master_pair).deserialize().unwrap();
+        signed.insert("extra".to_owned(), CanonicalJsonValue::Bool(true));
+        chain.keys.self_signing = Some(Raw::new(&signed).unwrap().cast());
+        let err = verify_cross_signing(&chain.keys, &chain.device_keys).unwrap_err();
+        assert!(
+            matches!(err, CrossSigningError::InvalidKeySignature(KeyUsage::SelfSigning, _)),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn device_not_signed_by_self_signing_key() {
+        let mut chain = valid_chain();
+        chain.device_keys.insert("OTHER".into(), sign(device_keys("OTHER"), &chain.master_pair));
+
+        let err = verify_cross_signing(&chain.keys, &chain.device_keys).unwrap_err();
+        assert!(
+            matches!(&err, CrossSigningError::DeviceNotSigned(device_id) if device_id == "OTHER"),
+            "{err:?}"
+        );
+    }
+    #[test]
+    fn device_keys_under_other_device_id() {
+        let mut chain = valid_chain();
+        // The keys of DEVICE are correctly signed, but listed under another device ID.
+        let device = chain.device_keys.remove(device_id!("DEVICE")).unwrap();
+        chain.device_keys.insert("OTHER".into(), device);
+
+        let err = verify_cross_signing(&chain.keys, &chain.device_keys).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                CrossSigningError::DeviceIdMismatch { expected, found }
+                    if expected == "OTHER" && found == "DEVICE"
+            ),
+            "{err:?}"
+        );
+    }
+}
@@ -9,11 +9,14 @@ use std::{
 use base64::{alphabet, Engine};
 use ruma_common::{
     canonical_json::{redact, JsonType},
-    serde::{base64::Standard, Base64},
-    CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName,
-    OwnedServerSigningKeyId, RoomVersionId, UserId,
+    serde::{base64::Standard, Base64, Raw},
+    CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedEventId,
+    OwnedServerName, OwnedServerSigningKeyId, RoomVersionId, ServerName, UserId,
+};
+use serde_json::{
+    from_str as from_json_str, to_string as to_json_string,
+    value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
 };
-use serde_json::to_string as to_json_string;
 use sha2::{digest::Digest, Sha256};
 
 use crate::{
@@ -468,6 +471,109 @@ where
     Ok(())
 }
 
+/// Extension trait to re-sign raw events, like the `Raw<Pdu>`s exchanged during the invite and
+/// join flows.
+pub trait RawEventExt {
+    /// Converts the event to a [`CanonicalJsonObject`] without the signatures of the given server.
+    ///
+    /// The signatures of other servers are kept intact.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    ///
+    /// * The event is not a JSON object.
+    /// * The event contains a field called `signatures` that is not a JSON object.
+    fn without_signatures_of(&self, server_name: &ServerName)
+        -> Result<CanonicalJsonObject, Error>;
+
+    /// Hashes and signs the event with the given key pair, replacing any prior signature of the
+    /// given server.
+    ///
+    /// The signatures of other servers are kept intact. See [`hash_and_sign_event`] for details
+    /// about the signing process.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the event could not be converted to a [`CanonicalJsonObject`] or if
+    /// [`hash_and_sign_event`] fails.
+    fn sign_with<K>(
+        &self,
+        server_name: &ServerName,
+        key_pair: &K,
+        version: &RoomVersionId,
+    ) -> Result<Box<RawJsonValue>, Error>
+    where
+        K: KeyPair;
+}
+
+impl<T> RawEventExt for Raw<T> {
+    fn without_signatures_of(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<CanonicalJsonObject, Error> {
+        let mut object: CanonicalJsonObject = self.deserialize_as().map_err(JsonError::Serde)?;
+
+        match object.get_mut("signatures") {
+            Some(CanonicalJsonValue::Object(signatures)) => {
+                signatures.remove(server_name.as_str());
+            }
+            Some(_) => return Err(JsonError::not_of_type("signatures", JsonType::Object)),
+            None => {}
+        }
+
+        Ok(object)
+    }
+
+    fn sign_with<K>(
+        &self,
+        server_name: &ServerName,
+        key_pair: &K,
+        version: &RoomVersionId,
+    ) -> Result<Box<RawJsonValue>, Error>
+    where
+        K: KeyPair,
+    {
+        let mut object = self.without_signatures_of(server_name)?;
+        hash_and_sign_event(server_name.as_str(), key_pair, &mut object, version)?;
+
+        Ok(to_raw_json_value(&object).map_err(JsonError::Serde)?)
+    }
+}
+
+/// Completes an event template returned by a `make_*` federation endpoint into a signed PDU.
+///
+/// The `make_join`, `make_knock` and `make_leave` endpoints return an unsigned event template that
+/// the requesting server must complete and sign before sending it back with the corresponding
+/// `send_*` endpoint. This sets the `origin_server_ts` of the template, and hashes and signs it
+/// with [`hash_and_sign_event`].
+///
+/// # Errors
+///
+/// Returns an error if the template is not a JSON object that can be converted to a
+/// [`CanonicalJsonObject`], or if [`hash_and_sign_event`] fails.
+pub fn complete_template<K>(
+    template: &RawJsonValue,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    server_name: &ServerName,
+    key_pair: &K,
+    version: &RoomVersionId,
+) -> Result<Box<RawJsonValue>, Error>
+where
+    K: KeyPair,
+{
+    let mut object: CanonicalJsonObject =
+        from_json_str(template.get()).map_err(JsonError::Serde)?;
+    object.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(origin_server_ts.get().into()),
+    );
+
+    hash_and_sign_event(server_name.as_str(), key_pair, &mut object, version)?;
+
+    Ok(to_raw_json_value(&object).map_err(JsonError::Serde)?)
+}
+
 /// Verifies that the signed event contains all the required valid signatures.
 ///
 /// Some room versions may require signatures from multiple homeservers, so this function takes a
@@ -744,13 +850,16 @@ mod tests {
 
     use assert_matches2::assert_matches;
     use ruma_common::{
-        serde::Base64, CanonicalJsonValue, RoomVersionId, ServerSigningKeyId, SigningKeyAlgorithm,
+        serde::{Base64, Raw},
+        server_name, CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch,
+        RoomVersionId, ServerSigningKeyId, SigningKeyAlgorithm,
     };
-    use serde_json::json;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
 
     use super::canonical_json;
     use crate::{
-        sign_json, verify_event, Ed25519KeyPair, Error, PublicKeyMap, PublicKeySet,
+        complete_template, content_hash, hash_and_sign_event, reference_hash, sign_json,
+        verify_event, Ed25519KeyPair, Error, PublicKeyMap, PublicKeySet, RawEventExt,
         VerificationError, Verified,
     };
 
@@ -786,6 +895,80 @@ mod tests {
         assert_eq!(canonical_json(object).unwrap(), canonical);
     }
 
+    #[test]
+    fn reference_hash_per_room_version() {
+        // The signed event of the "Signing Events" example of the spec.
+        let event = json!({
+            "auth_events": [],
+            "content": {},
+            "depth": 3,
+            "hashes": {
+                "sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+            },
+            "origin": "domain",
+            "origin_server_ts": 1_000_000,
+            "prev_events": [],
+            "room_id": "!x:domain",
+            "sender": "@a:domain",
+            "signatures": {
+                "domain": {
+                    "ed25519:1": "KxwGjPSDEtvnFgU00fwFz+l6d2pJM6XBIaMEn81SXPTRl16AqLAYqfIReFGZlHi5KLjAWbOoMszkwsQma+lYAg"
+                }
+            },
+            "type": "X",
+            "unsigned": {
+                "age_ts": 1_000_000
+            }
+        });
+        let mut object = match CanonicalJsonValue::try_from(event).unwrap() {
+            CanonicalJsonValue::Object(obj) => obj,
+            _ => unreachable!(),
+        };
+
+        // The content hash is the one given by the spec.
+        assert_eq!(
+            content_hash(&object).unwrap().encode(),
+            "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+        );
+
+        // The expected reference hashes were computed with Python's `hashlib` and `base64`, over
+        // the canonical JSON of the event redacted by hand:
+        //
+        // {"auth_events":[],"content":{},"depth":3,"hashes":{"sha256":"
+        // 5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"},"origin":"domain","origin_server_ts":
+        // 1000000,"prev_events":[],"room_id":"!x:domain","sender":"@a:domain","type":"X"}
+        //
+        // and the same JSON without `origin`, which is redacted since room version 11.
+        assert_eq!(
+            reference_hash(&object, &RoomVersionId::V3).unwrap(),
+            "8yif6p8EqgoSten2BLje9ntKm720NyFLWQv9tn8memc"
+        );
+        assert_eq!(
+            reference_hash(&object, &RoomVersionId::V4).unwrap(),
+            "8yif6p8EqgoSten2BLje9ntKm720NyFLWQv9tn8memc"
+        );
+        // The URL-safe base64 alphabet is used since room version 4, the standard one would give
+        // `70O/oKlXzFbkfu0KE88USi98DjSWrOELrPj+8tisl8I`.
+        assert_eq!(
+            reference_hash(&object, &RoomVersionId::V11).unwrap(),
+            "70O_oKlXzFbkfu0KE88USi98DjSWrOELrPj-8tisl8I"
+        );
+
+        // The redacted content and the unsigned data don't change the hash.
+        object.insert(
+            "content".to_owned(),
+            CanonicalJsonValue::Object(BTreeMap::from([(
+                "body".to_owned(),
+                CanonicalJsonValue::String("Hello world".to_owned()),
+            )])),
+        );
+        object.remove("unsigned");
+        assert_eq!(
+            reference_hash(&object, &RoomVersionId::V11).unwrap(),
+            "70O_oKlXzFbkfu0KE88USi98DjSWrOELrPj-8tisl8I"
+        );
+    }
+
     #[test]
     fn verify_event_does_not_check_signatures_for_third_party_invites() {
         let signed_event = serde_json::from_str(
@@ -1168,6 +1351,103 @@ mod tests {
         );
     }
 
+    #[test]
+    fn strip_and_resign_raw_event() {
+        let key_pair_sender = generate_key_pair("1");
+        let key_pair_resident = generate_key_pair("2");
+        let mut event: CanonicalJsonObject = serde_json::from_str(
+            r#"{
+                "event_id": "$event_id:domain-sender",
+                "auth_events": [],
+                "content": {},
+                "depth": 3,
+                "origin": "domain",
+                "origin_server_ts": 1000000,
+                "prev_events": [],
+                "room_id": "!x:domain",
+                "sender": "@name:domain-sender",
+                "type": "X"
+            }"#,
+        )
+        .unwrap();
+        hash_and_sign_event("domain-sender", &key_pair_sender, &mut event, &RoomVersionId::V6)
+            .unwrap();
+        hash_and_sign_event("domain-resident", &key_pair_resident, &mut event, &RoomVersionId::V6)
+            .unwrap();
+        let raw = Raw::<CanonicalJsonObject>::from_json(to_raw_json_value(&event).unwrap());
+
+        // Strip the signature of the resident server only.
+        let stripped = raw.without_signatures_of(server_name!("domain-resident")).unwrap();
+        let Some(CanonicalJsonValue::Object(signatures)) = stripped.get("signatures") else {
+            panic!("signatures should be kept");
+        };
+        assert!(signatures.contains_key("domain-sender"));
+        assert!(!signatures.contains_key("domain-resident"));
+
+        // Sign it again with a new key of the resident server.
+        let new_key_pair_resident = generate_key_pair("3");
+        let resigned = raw
+            .sign_with(server_name!("domain-resident"), &new_key_pair_resident, &RoomVersionId::V6)
+            .unwrap();
+        let resigned: CanonicalJsonObject = serde_json::from_str(resigned.get()).unwrap();
+
+        let mut public_key_map = BTreeMap::new();
+        add_key_to_map(&mut public_key_map, "domain-sender", &key_pair_sender);
+        add_key_to_map(&mut public_key_map, "domain-resident", &new_key_pair_resident);
+        let verification = verify_event(&public_key_map, &resigned, &RoomVersionId::V6).unwrap();
+        assert_eq!(verification, Verified::All);
+
+        // The old signature of the resident server is gone.
+        let Some(CanonicalJsonValue::Object(signatures)) = resigned.get("signatures") else {
+            panic!("signatures should be present");
+        };
+        let Some(CanonicalJsonValue::Object(resident_signatures)) =
+            signatures.get("domain-resident")
+        else {
+            panic!("resident signatures should be present");
+        };
+        assert_eq!(resident_signatures.len(), 1);
+        assert!(resident_signatures.contains_key("ed25519:3"));
+    }
+
+    #[test]
+    fn complete_make_leave_template() {
+        let key_pair = generate_key_pair("1");
+        let template = to_raw_json_value(&json!({
+            "type": "m.room.member",
+            "room_id": "!room:domain-resident",
+            "sender": "@alice:domain-sender",
+            "state_key": "@alice:domain-sender",
+            "origin": "domain-sender",
+            "content": { "membership": "leave" },
+            "auth_events": ["$create:domain-resident", "$member:domain-resident"],
+            "prev_events": ["$prev:domain-resident"],
+            "depth": 12,
+        }))
+        .unwrap();
+
+        let pdu = complete_template(
+            &template,
+            MilliSecondsSinceUnixEpoch(1_000_000_u32.into()),
+            server_name!("domain-sender"),
+            &key_pair,
+            &RoomVersionId::V6,
+        )
+        .unwrap();
+        let pdu: CanonicalJsonObject = serde_json::from_str(pdu.get()).unwrap();
+
+        assert_eq!(
+            pdu.get("origin_server_ts"),
+            Some(&CanonicalJsonValue::Integer(1_000_000.into()))
+        );
+        assert!(pdu.contains_key("hashes"));
+
+        let mut public_key_map = BTreeMap::new();
+        add_key_to_map(&mut public_key_map, "domain-sender", &key_pair);
+        let verification = verify_event(&public_key_map, &pdu, &RoomVersionId::V6).unwrap();
+        assert_eq!(verification, Verified::All);
+    }
+
     fn generate_key_pair(name: &str) -> Ed25519KeyPair {
         let key_content = Ed25519KeyPair::generate().unwrap();
         Ed25519KeyPair::from_der(&key_content, name.to_owned())
@@ -47,16 +47,19 @@
 use ruma_common::serde::{AsRefStr, DisplayAsRefStr};
 
 pub use self::{
+    cross_signing::{verify_cross_signing, CrossSigningError, CrossSigningKeys},
     error::{Error, JsonError, ParseError, VerificationError},
     functions::{
-        canonical_json, content_hash, hash_and_sign_event, reference_hash, required_keys,
-        servers_to_check_signatures, sign_json, verify_event, verify_json,
+        canonical_json, complete_template, content_hash, hash_and_sign_event, reference_hash,
+        required_keys, servers_to_check_signatures, sign_json, verify_event, verify_json,
+        RawEventExt,
     },
     keys::{Ed25519KeyPair, KeyPair, PublicKeyMap, PublicKeySet},
     signatures::Signature,
     verification::Verified,
 };
 
+mod cross_signing;
 mod error;
 mod functions;
 mod keys;
@@ -112,7 +115,7 @@ mod tests {
     use pkcs8::{der::Decode, PrivateKeyInfo};
     use ruma_common::{
         serde::{base64::Standard, Base64},
-        RoomVersionId,
+        CanonicalJsonValue, RoomVersionId,
     };
     use serde_json::{from_str as from_json_str, to_string as to_json_string};
 
@@ -299,6 +302,45 @@ mod tests {
         verify_json(&public_key_map, reverse_value).unwrap();
     }
 
+    #[test]
+    fn sign_and_verify_json_roundtrip() {
+        let key_pair = Ed25519KeyPair::from_der(&pkcs8(), "1".into()).unwrap();
+
+        let mut object = from_json_str(
+            r#"{
+                "type": "m.room.member",
+                "state_key": "@bob:other",
+                "content": { "membership": "invite" },
+                "signatures": {
+                    "other": { "ed25519:key": "signature" }
+                }
+            }"#,
+        )
+        .unwrap();
+        sign_json("domain", &key_pair, &mut object).unwrap();
+
+        // The existing signatures of other entities are kept.
+        let signatures = object.get("signatures").unwrap().as_object().unwrap();
+        assert_eq!(signatures.len(), 2);
+        assert!(signatures.get("other").is_some());
+
+        let mut public_key_map = BTreeMap::new();
+        public_key_map
+            .insert("domain".into(), BTreeMap::from([("ed25519:1".into(), public_key_string())]));
+
+        // The public keys of all the signing entities are required.
+        verify_json(&public_key_map, object.clone()).unwrap_err();
+
+        let signatures = object.get_mut("signatures").unwrap();
+        let CanonicalJsonValue::Object(signatures) = signatures else { unreachable!() };
+        signatures.remove("other");
+        verify_json(&public_key_map, object.clone()).unwrap();
+
+        // The signature is not valid anymore once the object changes.
+        object.insert("state_key".into(), "@carol:other".to_owned().into());
+        verify_json(&public_key_map, object).unwrap_err();
+    }
+
     #[test]
     fn fail_verify_json() {
         let value = from_json_str(r#"{"not":"empty","signatures":{"domain":"lXjsnvhVlz8t3etR+6AEJ0IT70WujeHC1CFjDDsVx0xSig1Bx7lvoi1x3j/2/GPNjQM4a2gD34UqsXFluaQEBA"}}"#).unwrap();
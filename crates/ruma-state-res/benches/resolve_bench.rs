@@ -0,0 +1,280 @@
+// Benchmarks of state resolution on synthetic rooms.
+//
+// Run them with `cargo bench --features criterion,test-utils --bench resolve_bench`. The forked
+// rooms are parameterized as `{state sets}x{auth chain depth}`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures_util::future::ready;
+use js_int::{int, uint};
+use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, RoomVersionId};
+use ruma_events::{StateEventType, TimelineEventType};
+use ruma_state_res::{
+    self as state_res,
+    test_utils::{
+        alice, event_id, member_content_join, room_id, to_pdu_event, PduEvent, TestStore,
+        INITIAL_EVENTS,
+    },
+    Event, EventTypeExt, StateMap,
+};
+use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+/// The number of conflicted events in the synthetic rooms.
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+/// The number of state sets and the depth of the auth chains in the synthetic forked rooms.
+const FORKS: [(usize, usize); 4] = [(2, 10), (2, 100), (10, 10), (10, 100)];
+
+/// A room where one fork has `size` joined users that the other fork doesn't know about.
+struct SyntheticRoom {
+    store: TestStore<PduEvent>,
+    state_sets: [StateMap<OwnedEventId>; 2],
+    auth_chain_sets: Vec<HashSet<OwnedEventId>>,
+}
+
+impl SyntheticRoom {
+    fn new(size: usize) -> Self {
+        let mut events = INITIAL_EVENTS();
+
+        let mut initial_state = StateMap::new();
+        for id in ["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"] {
+            let event = &events[&event_id(id)];
+            initial_state.insert(
+                event.event_type().with_state_key(event.state_key().unwrap()),
+                event.event_id().clone(),
+            );
+        }
+
+        let mut fork_state = initial_state.clone();
+        for i in 0..size {
+            let user_id: OwnedUserId = format!("@user{i}:foo").try_into().unwrap();
+            let event = to_pdu_event(
+                &format!("JOIN{i}"),
+                &user_id,
+                TimelineEventType::RoomMember,
+                Some(user_id.as_str()),
+                member_content_join(),
+                &["CREATE", "IJR", "IPOWER"],
+                &["IMC"],
+            );
+            fork_state.insert(
+                StateEventType::RoomMember.with_state_key(user_id.as_str()),
+                event.event_id().clone(),
+            );
+            events.insert(event.event_id().clone(), event);
+        }
+
+        let store = TestStore(events);
+        let state_sets = [initial_state, fork_state];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|state| {
+                store.auth_event_ids(room_id(), state.values().cloned().collect()).unwrap()
+            })
+            .collect();
+
+        Self { store, state_sets, auth_chain_sets }
+    }
+
+    async fn resolve(&self) -> StateMap<OwnedEventId> {
+        let fetch = |id: OwnedEventId| ready(self.store.0.get(&id).map(Arc::clone));
+        let exists = |id: OwnedEventId| ready(self.store.0.contains_key(&id));
+
+        state_res::StateResolution::new(RoomVersionId::V6, &fetch, &exists)
+            .resolve(&self.state_sets, &self.auth_chain_sets)
+            .await
+            .unwrap()
+    }
+}
+
+/// A room with `state_sets` forks, where each fork changes the power levels `depth` times before
+/// changing the topic.
+///
+/// The power levels events of a fork are authorized by the previous ones, so the auth chains of
+/// the conflicted events are `depth` events deep.
+struct ForkedRoom {
+    store: TestStore<PduEvent>,
+    state_sets: Vec<StateMap<OwnedEventId>>,
+    auth_chain_sets: Vec<HashSet<OwnedEventId>>,
+    /// The last power levels event of each fork.
+    power_levels: Vec<OwnedEventId>,
+    /// The topic event of each fork.
+    topics: Vec<OwnedEventId>,
+}
+
+impl ForkedRoom {
+    fn new(state_sets: usize, depth: usize) -> Self {
+        let mut events = INITIAL_EVENTS();
+
+        let mut initial_state = StateMap::new();
+        for id in ["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"] {
+            let event = &events[&event_id(id)];
+            initial_state.insert(
+                event.event_type().with_state_key(event.state_key().unwrap()),
+                event.event_id().clone(),
+            );
+        }
+
+        let mut forks = Vec::with_capacity(state_sets);
+        let mut power_levels = Vec::with_capacity(state_sets);
+        let mut topics = Vec::with_capacity(state_sets);
+        for fork in 0..state_sets {
+            let mut prev = "IPOWER".to_owned();
+            for level in 0..depth {
+                let id = format!("PL{fork}_{level}");
+                let event = to_pdu_event(
+                    &id,
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(
+                        &json!({ "users": { alice(): 100 }, "state_default": level }),
+                    )
+                    .unwrap(),
+                    &["CREATE", "IMA", &prev],
+                    &[&prev],
+                );
+                events.insert(event.event_id().clone(), event);
+                prev = id;
+            }
+
+            let topic = to_pdu_event(
+                &format!("T{fork}"),
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": format!("fork {fork}") })).unwrap(),
+                &["CREATE", "IMA", &prev],
+                &[&prev],
+            );
+
+            let mut state = initial_state.clone();
+            state.insert(StateEventType::RoomPowerLevels.with_state_key(""), event_id(&prev));
+            state.insert(StateEventType::RoomTopic.with_state_key(""), topic.event_id().clone());
+            forks.push(state);
+            power_levels.push(event_id(&prev));
+            topics.push(topic.event_id().clone());
+            events.insert(topic.event_id().clone(), topic);
+        }
+
+        let store = TestStore(events);
+        let auth_chain_sets = forks
+            .iter()
+            .map(|state| {
+                store.auth_event_ids(room_id(), state.values().cloned().collect()).unwrap()
+            })
+            .collect();
+
+        Self { store, state_sets: forks, auth_chain_sets, power_levels, topics }
+    }
+
+    async fn resolve(&self) -> StateMap<OwnedEventId> {
+        let fetch = |id: OwnedEventId| ready(self.store.0.get(&id).map(Arc::clone));
+        let exists = |id: OwnedEventId| ready(self.store.0.contains_key(&id));
+
+        state_res::StateResolution::new(RoomVersionId::V6, &fetch, &exists)
+            .resolve(&self.state_sets, &self.auth_chain_sets)
+            .await
+            .unwrap()
+    }
+
+    /// Sort the topics of all the forks against the mainline of the first fork.
+    async fn mainline_sort(&self) -> Vec<OwnedEventId> {
+        let fetch = |id: OwnedEventId| ready(self.store.0.get(&id).map(Arc::clone));
+
+        state_res::mainline_sort(&self.topics, Some(self.power_levels[0].clone()), &fetch)
+            .await
+            .unwrap()
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread().build().unwrap()
+}
+
+fn resolve(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("resolve");
+    group.sample_size(10);
+
+    for size in SIZES {
+        let room = SyntheticRoom::new(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &room, |b, room| {
+            b.iter(|| rt.block_on(room.resolve()));
+        });
+    }
+
+    group.finish();
+}
+
+fn lexicographical_topological_sort(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("lexicographical_topological_sort");
+
+    for size in SIZES {
+        // Every event points to the previous one and to the first one.
+        let ids = (0..size).map(|i| event_id(&format!("E{i}"))).collect::<Vec<_>>();
+        let graph = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let edges = if i == 0 {
+                    HashSet::new()
+                } else {
+                    HashSet::from([ids[0].clone(), ids[i - 1].clone()])
+                };
+                (id.clone(), edges)
+            })
+            .collect::<HashMap<_, _>>();
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &graph, |b, graph| {
+            b.iter(|| {
+                rt.block_on(state_res::lexicographical_topological_sort(graph, &|_| async {
+                    Ok((int!(0), MilliSecondsSinceUnixEpoch(uint!(0))))
+                }))
+                .unwrap()
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn resolve_forks(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("resolve_forks");
+    group.sample_size(10);
+
+    for (state_sets, depth) in FORKS {
+        let room = ForkedRoom::new(state_sets, depth);
+        let id = BenchmarkId::from_parameter(format!("{state_sets}x{depth}"));
+        group.bench_with_input(id, &room, |b, room| {
+            b.iter(|| rt.block_on(room.resolve()));
+        });
+    }
+
+    group.finish();
+}
+
+fn mainline_sort(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("mainline_sort");
+
+    for (state_sets, depth) in FORKS {
+        let room = ForkedRoom::new(state_sets, depth);
+        let id = BenchmarkId::from_parameter(format!("{state_sets}x{depth}"));
+        group.bench_with_input(id, &room, |b, room| {
+            b.iter(|| rt.block_on(room.mainline_sort()));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, resolve, resolve_forks, lexicographical_topological_sort, mainline_sort);
+
+criterion_main!(benches);
@@ -33,7 +33,7 @@ use ruma_events::{
     },
     StateEventType, TimelineEventType,
 };
-use ruma_state_res::{self as state_res, Error, Event, Result, StateMap};
+use ruma_state_res::{self as state_res, Error, Event, Result, StateMap, StateResolution};
 use serde_json::{
     json,
     value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
@@ -77,14 +77,9 @@ fn resolution_shallow_auth_chain(c: &mut Criterion) {
                 })
                 .collect();
 
-            let _ = match state_res::resolve(
-                &RoomVersionId::V6,
-                state_sets.into_iter(),
-                &auth_chain_sets,
-                &fetch,
-                &exists,
-            )
-            .await
+            let _ = match StateResolution::new(RoomVersionId::V6, &fetch, &exists)
+                .resolve(state_sets.into_iter(), &auth_chain_sets)
+                .await
             {
                 Ok(state) => state,
                 Err(e) => panic!("{e}"),
@@ -142,14 +137,9 @@ fn resolve_deeper_event_set(c: &mut Criterion) {
 
             let fetch = |id: OwnedEventId| ready(inner.get(&id).map(Arc::clone));
             let exists = |id: OwnedEventId| ready(inner.get(&id).is_some());
-            let _ = match state_res::resolve(
-                &RoomVersionId::V6,
-                state_sets.into_iter(),
-                &auth_chain_sets,
-                &fetch,
-                &exists,
-            )
-            .await
+            let _ = match StateResolution::new(RoomVersionId::V6, &fetch, &exists)
+                .resolve(state_sets.into_iter(), &auth_chain_sets)
+                .await
             {
                 Ok(state) => state,
                 Err(_) => panic!("resolution failed during benchmarking"),
@@ -544,6 +534,7 @@ impl EventTypeExt for &TimelineEventType {
 }
 
 mod event {
+    use js_int::UInt;
     use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, UserId};
     use ruma_events::{pdu::Pdu, TimelineEventType};
     use ruma_state_res::Event;
@@ -602,6 +593,15 @@ mod event {
             }
         }
 
+        fn depth(&self) -> UInt {
+            match &self.rest {
+                Pdu::RoomV1Pdu(ev) => ev.depth,
+                Pdu::RoomV3Pdu(ev) => ev.depth,
+                #[cfg(not(feature = "unstable-exhaustive-types"))]
+                _ => unreachable!("new PDU version"),
+            }
+        }
+
         fn state_key(&self) -> Option<&str> {
             match &self.rest {
                 Pdu::RoomV1Pdu(ev) => ev.state_key.as_deref(),
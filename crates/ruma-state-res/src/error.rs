@@ -24,6 +24,27 @@ pub enum Error {
     #[error("Invalid PDU: {0}")]
     InvalidPdu(String),
 
+    /// The given option of the `ResolveOptions` is not supported by the state resolution
+    /// algorithm of the room version.
+    #[error("Option `{0}` is not supported by the state resolution algorithm of the room version")]
+    UnsupportedOption(&'static str),
+
+    /// The full conflicted set is larger than the limit set in the `ResolveOptions`.
+    #[error("Too many conflicted events")]
+    TooManyConflictedEvents,
+
+    /// State resolution was cancelled.
+    #[error("State resolution was cancelled")]
+    Cancelled,
+
+    /// The graph of the control events is larger than the limit set in the `ResolveOptions`.
+    #[error("Too many events in the graph of the control events")]
+    GraphTooLarge,
+
+    /// An event could not be fetched because of an error of the store.
+    #[error("Failed to fetch an event: {0}")]
+    Fetch(Box<dyn std::error::Error + Send>),
+
     /// A custom error.
     #[error("{0}")]
     Custom(Box<dyn std::error::Error + Send>),
@@ -34,3 +55,139 @@ impl Error {
         Self::Custom(Box::new(e))
     }
 }
+
+/// A PDU is larger than the maximum size allowed by its room version.
+#[derive(Error, Debug)]
+#[error("PDU is {size} bytes long, but the maximum size is {max_size} bytes")]
+#[non_exhaustive]
+pub struct PduTooLarge {
+    /// The size of the PDU, in bytes.
+    pub size: usize,
+
+    /// The maximum size of a PDU in the room version, in bytes.
+    pub max_size: usize,
+}
+
+/// An error when validating a PDU with [`validate_pdu`](crate::validate_pdu).
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum PduValidationError {
+    /// The room version is not supported.
+    #[error("unsupported room version: {0}")]
+    UnsupportedRoomVersion(String),
+
+    /// The PDU is larger than the maximum size allowed by the room version.
+    #[error(transparent)]
+    TooLarge(#[from] PduTooLarge),
+
+    /// The PDU is not a JSON object that can be represented as canonical JSON.
+    #[error("PDU is not valid canonical JSON: {0}")]
+    NotCanonicalJson(#[source] JsonError),
+
+    /// A field required by the room version is missing from the PDU, or has the wrong type.
+    #[error("PDU is missing the `{0}` field or it has the wrong type")]
+    MissingField(&'static str),
+
+    /// The signatures of the PDU could not be verified.
+    #[error("PDU signatures are invalid: {0}")]
+    InvalidSignatures(#[source] ruma_signatures::Error),
+
+    /// The signatures of the PDU are valid, but its content hash doesn't match.
+    ///
+    /// According to the spec, such a PDU must not be dropped but redacted before being processed.
+    #[error("PDU content hash doesn't match")]
+    ContentHashMismatch,
+}
+
+/// The authorization rule that an event failed, returned by
+/// [`auth_check_detailed`](crate::event_auth::auth_check_detailed).
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthError {
+    /// The given field of the event, or the whole `event`, is larger than the limits of PDUs.
+    #[error("the `{0}` is larger than the limits of PDUs")]
+    TooLarge(&'static str),
+
+    /// The `m.room.create` event has previous events.
+    #[error("m.room.create event has previous events")]
+    CreateEventHasPrevEvents,
+
+    /// The room ID of the `m.room.create` event has no server name.
+    #[error("room ID has no server name")]
+    RoomIdWithoutServerName,
+
+    /// The server name of the room ID doesn't match the server of the sender of the
+    /// `m.room.create` event.
+    #[error("server name of room ID doesn't match server name of sender")]
+    CreateEventServerNameMismatch,
+
+    /// The room version of the `m.room.create` event is not recognized.
+    #[error("unknown room version in m.room.create event")]
+    UnknownRoomVersion,
+
+    /// The `m.room.create` event has no `creator` field, in room versions that require it.
+    #[error("no creator field in m.room.create event")]
+    MissingCreator,
+
+    /// There is no `m.room.create` event in the state.
+    #[error("no m.room.create event in the state")]
+    MissingCreateEvent,
+
+    /// The `m.room.create` event is not in the auth events of the event.
+    #[error("m.room.create event is not in the auth events")]
+    CreateEventNotInAuthEvents,
+
+    /// The room is not federated and the sender is on another server than the creator of the
+    /// room.
+    #[error("room is not federated and sender is on another server than the creator")]
+    RoomNotFederated,
+
+    /// The state key of the `m.room.aliases` event doesn't match the server of the sender.
+    #[error("state key of m.room.aliases event doesn't match server name of sender")]
+    AliasesStateKeyMismatch,
+
+    /// The `m.room.member` event has no state key.
+    #[error("m.room.member event has no state key")]
+    MemberEventWithoutStateKey,
+
+    /// The `m.room.member` event has no valid `membership` field.
+    #[error("m.room.member event has no valid membership")]
+    InvalidMembership,
+
+    /// The `m.room.member` event is not an allowed membership change.
+    #[error("membership change is not allowed")]
+    MembershipChangeNotAllowed,
+
+    /// The `m.room.member` event is a join that the join rules of the room don't allow.
+    #[error("join rules don't allow the join")]
+    JoinNotAllowed,
+
+    /// The sender of the event is not in the room.
+    #[error("sender is not in the room")]
+    SenderNotInRoom,
+
+    /// The membership of the sender of the event is not `join`.
+    #[error("sender is not joined to the room")]
+    SenderNotJoined,
+
+    /// The sender of the `m.room.third_party_invite` event doesn't have the invite power level.
+    #[error("sender cannot send invites")]
+    CannotSendInvites,
+
+    /// The sender doesn't have the power level required to send the event, or the state key
+    /// of the event is another user ID.
+    #[error("sender cannot send the event")]
+    CannotSendEvent,
+
+    /// The `m.room.power_levels` event is not an allowed change of the power levels.
+    #[error("power levels change is not allowed")]
+    PowerLevelsChangeNotAllowed,
+
+    /// The content of the `m.room.power_levels` event is invalid.
+    #[error("invalid m.room.power_levels event content")]
+    InvalidPowerLevels,
+
+    /// The sender of the `m.room.redaction` event is not allowed to redact the event.
+    #[error("redaction is not allowed")]
+    RedactionNotAllowed,
+}
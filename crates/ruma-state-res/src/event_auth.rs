@@ -1,10 +1,14 @@
-use std::{borrow::Borrow, collections::BTreeSet};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+    iter,
+};
 
 use futures_util::Future;
-use js_int::{int, Int};
+use js_int::{int, Int, UInt};
 use ruma_common::{
-    serde::{Base64, Raw},
-    OwnedUserId, RoomVersionId, UserId,
+    canonical_json::to_canonical_string, serde::Raw, CanonicalJsonObject, CanonicalJsonValue,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedUserId, RoomId, RoomVersionId, UserId,
 };
 use ruma_events::room::{
     create::RoomCreateEventContent,
@@ -15,9 +19,12 @@ use ruma_events::room::{
 };
 use serde::{
     de::{Error as _, IgnoredAny},
-    Deserialize,
+    Deserialize, Serialize,
+};
+use serde_json::{
+    from_str as from_json_str,
+    value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
 };
-use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
 use tracing::{debug, error, instrument, trace, warn};
 
 use crate::{
@@ -26,7 +33,7 @@ use crate::{
         deserialize_power_levels_content_invite, deserialize_power_levels_content_redact,
     },
     room_version::RoomVersion,
-    Error, Event, Result, StateEventType, TimelineEventType,
+    AuthError, Error, Event, Result, StateEventType, TimelineEventType,
 };
 
 // FIXME: field extracting could be bundled for `content`
@@ -122,13 +129,136 @@ pub fn auth_types_for_event(
 ///
 /// The `fetch_state` closure should gather state from a state snapshot. We need to know if the
 /// event passes auth against some state not a recursive collection of auth_events fields.
-#[instrument(level = "debug", skip_all, fields(event_id = incoming_event.event_id().borrow().as_str()))]
 pub async fn auth_check<F, Fut, Fetched, Incoming>(
     room_version: &RoomVersion,
     incoming_event: &Incoming,
     current_third_party_invite: Option<&Incoming>,
     fetch_state: F,
 ) -> Result<bool>
+where
+    F: Fn(&'static StateEventType, &str) -> Fut,
+    Fut: Future<Output = Option<Fetched>> + Send,
+    Fetched: Event + Send,
+    Incoming: Event + Send,
+{
+    auth_check_detailed(room_version, incoming_event, current_third_party_invite, fetch_state)
+        .await
+        .map(|decision| decision.is_allowed())
+}
+
+/// The decision of the authorization rules for an event.
+///
+/// This is returned by [`auth_check_detailed`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum AuthDecision {
+    /// The event passes the authorization rules.
+    Allowed,
+
+    /// The event is rejected because of the rule that failed.
+    Denied(AuthError),
+}
+
+impl AuthDecision {
+    /// Whether the event passes the authorization rules.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Self::Allowed)
+    }
+}
+
+/// The maximum size of a PDU, in bytes.
+pub const MAX_PDU_SIZE: usize = 65_536;
+
+/// The maximum length of the `event_id`, `room_id`, `sender`, `type` and `state_key` fields of a
+/// PDU, in bytes.
+pub const MAX_FIELD_LENGTH: usize = 255;
+
+/// Check that the given event is within the size limits of PDUs.
+///
+/// The `event_id`, `room_id`, `sender`, `type` and `state_key` fields must not be longer than
+/// [`MAX_FIELD_LENGTH`], and the [canonical JSON] of the event must not be larger than
+/// [`MAX_PDU_SIZE`]. The hashes, signatures and unsigned data of the PDU are not available from
+/// [`Event`], so the size of the full PDU should also be checked with
+/// [`validate_pdu_size`](crate::validate_pdu_size) when it is received.
+///
+/// This is called by [`auth_check`] before the authorization rules.
+///
+/// [canonical JSON]: https://spec.matrix.org/latest/appendices/#canonical-json
+pub fn check_size_limits(event: &impl Event) -> std::result::Result<(), AuthError> {
+    let fields = [
+        ("event_id", event.event_id().borrow().as_str().len()),
+        ("room_id", event.room_id().as_str().len()),
+        ("sender", event.sender().as_str().len()),
+        ("type", event.event_type().to_string().len()),
+        ("state_key", event.state_key().map_or(0, str::len)),
+    ];
+
+    for (field, len) in fields {
+        if len > MAX_FIELD_LENGTH {
+            return Err(AuthError::TooLarge(field));
+        }
+    }
+
+    if canonical_event_size(event) > MAX_PDU_SIZE {
+        return Err(AuthError::TooLarge("event"));
+    }
+
+    Ok(())
+}
+
+/// The length of the canonical JSON of the fields of the PDU that are available from the event.
+///
+/// Falls back to the length of the JSON that is not canonical if the event can't be converted to
+/// canonical JSON.
+fn canonical_event_size(event: &impl Event) -> usize {
+    #[derive(Serialize)]
+    struct PduFields<'a> {
+        auth_events: Vec<&'a EventId>,
+        content: &'a RawJsonValue,
+        depth: UInt,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        prev_events: Vec<&'a EventId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        redacts: Option<&'a EventId>,
+        room_id: &'a RoomId,
+        sender: &'a UserId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state_key: Option<&'a str>,
+        #[serde(rename = "type")]
+        event_type: &'a TimelineEventType,
+    }
+
+    let fields = PduFields {
+        auth_events: event.auth_events().map(Borrow::borrow).collect(),
+        content: event.content(),
+        depth: event.depth(),
+        origin_server_ts: event.origin_server_ts(),
+        prev_events: event.prev_events().map(Borrow::borrow).collect(),
+        redacts: event.redacts().map(Borrow::borrow),
+        room_id: event.room_id(),
+        sender: event.sender(),
+        state_key: event.state_key(),
+        event_type: event.event_type(),
+    };
+
+    match to_raw_json_value(&fields) {
+        Ok(json) => {
+            to_canonical_string(&json).map_or(json.get().len(), |canonical| canonical.len())
+        }
+        // The fields always serialize successfully, but count the content at least.
+        Err(_) => event.content().get().len(),
+    }
+}
+
+/// Authenticate the incoming `event`, like [`auth_check`], but return the authorization rule that
+/// failed if it is rejected.
+#[instrument(level = "debug", skip_all, fields(event_id = incoming_event.event_id().borrow().as_str()))]
+pub async fn auth_check_detailed<F, Fut, Fetched, Incoming>(
+    room_version: &RoomVersion,
+    incoming_event: &Incoming,
+    current_third_party_invite: Option<&Incoming>,
+    fetch_state: F,
+) -> Result<AuthDecision>
 where
     F: Fn(&'static StateEventType, &str) -> Fut,
     Fut: Future<Output = Option<Fetched>> + Send,
@@ -145,9 +275,10 @@ where
 
     // [synapse] do_sig_check check the event has valid signatures for member events
 
-    // TODO do_size_check is false when called by `iterative_auth_check`
-    // do_size_check is also mostly accomplished by ruma with the exception of checking event_type,
-    // state_key, and json are below a certain size (255 and 65_536 respectively)
+    if let Err(error) = check_size_limits(incoming_event) {
+        warn!(%error, "event is too large");
+        return Ok(AuthDecision::Denied(error));
+    }
 
     let sender = incoming_event.sender();
 
@@ -166,37 +297,37 @@ where
         // If it has any previous events, reject
         if incoming_event.prev_events().next().is_some() {
             warn!("the room creation event had previous events");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::CreateEventHasPrevEvents));
         }
 
         // If the domain of the room_id does not match the domain of the sender, reject
         let Some(room_id_server_name) = incoming_event.room_id().server_name() else {
             warn!("room ID has no servername");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::RoomIdWithoutServerName));
         };
 
         if room_id_server_name != sender.server_name() {
             warn!("servername of room ID does not match servername of sender");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::CreateEventServerNameMismatch));
         }
 
         // If content.room_version is present and is not a recognized version, reject
         let content: RoomCreateContentFields = from_json_str(incoming_event.content().get())?;
         if content.room_version.map(|v| v.deserialize().is_err()).unwrap_or(false) {
             warn!("invalid room version found in m.room.create event");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::UnknownRoomVersion));
         }
 
         if !room_version.use_room_create_sender {
             // If content has no creator field, reject
             if content.creator.is_none() {
                 warn!("no creator field found in m.room.create content");
-                return Ok(false);
+                return Ok(AuthDecision::Denied(AuthError::MissingCreator));
             }
         }
 
         debug!("m.room.create event was allowed");
-        return Ok(true);
+        return Ok(AuthDecision::Allowed);
     }
 
     /*
@@ -227,7 +358,7 @@ where
     let room_create_event = match fetch_state(&StateEventType::RoomCreate, "").await {
         None => {
             warn!("no m.room.create event in auth chain");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::MissingCreateEvent));
         }
         Some(e) => e,
     };
@@ -236,7 +367,7 @@ where
     if !incoming_event.auth_events().any(|id| id.borrow() == room_create_event.event_id().borrow())
     {
         warn!("no m.room.create event in auth events");
-        return Ok(false);
+        return Ok(AuthDecision::Denied(AuthError::CreateEventNotInAuthEvents));
     }
 
     // If the create event content has the field m.federate set to false and the sender domain of
@@ -252,7 +383,7 @@ where
         && room_create_event.sender().server_name() != incoming_event.sender().server_name()
     {
         warn!("room is not federated and event's sender domain does not match create event's sender domain");
-        return Ok(false);
+        return Ok(AuthDecision::Denied(AuthError::RoomNotFederated));
     }
 
     // Only in some room versions 6 and below
@@ -264,11 +395,11 @@ where
             // If sender's domain doesn't matches state_key, reject
             if incoming_event.state_key() != Some(sender.server_name().as_str()) {
                 warn!("state_key does not match sender");
-                return Ok(false);
+                return Ok(AuthDecision::Denied(AuthError::AliasesStateKeyMismatch));
             }
 
             debug!("m.room.aliases event was allowed");
-            return Ok(true);
+            return Ok(AuthDecision::Allowed);
         }
     }
 
@@ -281,7 +412,7 @@ where
         let state_key = match incoming_event.state_key() {
             None => {
                 warn!("no statekey in member event");
-                return Ok(false);
+                return Ok(AuthDecision::Denied(AuthError::MemberEventWithoutStateKey));
             }
             Some(s) => s,
         };
@@ -289,7 +420,7 @@ where
         let content: RoomMemberContentFields = from_json_str(incoming_event.content().get())?;
         if content.membership.as_ref().and_then(|m| m.deserialize().ok()).is_none() {
             warn!("no valid membership field found for m.room.member event content");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::InvalidMembership));
         }
 
         let target_user =
@@ -309,25 +440,25 @@ where
             .map(|mem| mem.membership)
             .unwrap_or(MembershipState::Leave);
 
-        if !valid_membership_change(
+        let decision = valid_membership_change(
             room_version,
             target_user,
             fetch_state(&StateEventType::RoomMember, target_user.as_str()).await.as_ref(),
             sender,
             sender_member_event.as_ref(),
-            &incoming_event,
+            incoming_event,
             current_third_party_invite,
             power_levels_event.as_ref(),
             fetch_state(&StateEventType::RoomJoinRules, "").await.as_ref(),
             user_for_join_auth.as_deref(),
             &user_for_join_auth_membership,
             room_create_event,
-        )? {
-            return Ok(false);
+        )?;
+        if decision.is_allowed() {
+            debug!("m.room.member event was allowed");
         }
 
-        debug!("m.room.member event was allowed");
-        return Ok(true);
+        return Ok(decision);
     }
 
     // If the sender's current membership state is not join, reject
@@ -335,7 +466,7 @@ where
         Some(mem) => mem,
         None => {
             warn!("sender not found in room");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::SenderNotInRoom));
         }
     };
 
@@ -348,7 +479,7 @@ where
 
     if !matches!(membership_state, MembershipState::Join) {
         warn!("sender's membership is not join");
-        return Ok(false);
+        return Ok(AuthDecision::Denied(AuthError::SenderNotJoined));
     }
 
     // If type is m.room.third_party_invite
@@ -361,13 +492,8 @@ where
         }
     } else {
         // If no power level event found the creator gets 100 everyone else gets 0
-        let is_creator = if room_version.use_room_create_sender {
-            room_create_event.sender() == sender
-        } else {
-            #[allow(deprecated)]
-            from_json_str::<RoomCreateEventContent>(room_create_event.content().get())
-                .is_ok_and(|create| create.creator.unwrap() == *sender)
-        };
+        let is_creator =
+            room_creator(room_version, &room_create_event).is_ok_and(|creator| creator == sender);
 
         if is_creator {
             int!(100)
@@ -389,18 +515,23 @@ where
 
         if sender_power_level < invite_level {
             warn!("sender's cannot send invites in this room");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::CannotSendInvites));
         }
 
         debug!("m.room.third_party_invite event was allowed");
-        return Ok(true);
+        return Ok(AuthDecision::Allowed);
     }
 
     // If the event type's required power level is greater than the sender's power level, reject
     // If the event has a state_key that starts with an @ and does not match the sender, reject.
-    if !can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level) {
+    if !can_send_event(
+        room_version,
+        incoming_event,
+        power_levels_event.as_ref(),
+        sender_power_level,
+    ) {
         warn!("user cannot send event");
-        return Ok(false);
+        return Ok(AuthDecision::Denied(AuthError::CannotSendEvent));
     }
 
     // If type is m.room.power_levels
@@ -409,17 +540,17 @@ where
 
         if let Some(required_pwr_lvl) = check_power_levels(
             room_version,
-            &incoming_event,
+            incoming_event,
             power_levels_event.as_ref(),
             sender_power_level,
         ) {
             if !required_pwr_lvl {
                 warn!("m.room.power_levels was not allowed");
-                return Ok(false);
+                return Ok(AuthDecision::Denied(AuthError::PowerLevelsChangeNotAllowed));
             }
         } else {
             warn!("m.room.power_levels was not allowed");
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::InvalidPowerLevels));
         }
         debug!("m.room.power_levels event allowed");
     }
@@ -442,18 +573,43 @@ where
         };
 
         if !check_redaction(room_version, incoming_event, sender_power_level, redact_level)? {
-            return Ok(false);
+            return Ok(AuthDecision::Denied(AuthError::RedactionNotAllowed));
         }
     }
 
     debug!("allowing event passed all checks");
-    Ok(true)
+    Ok(AuthDecision::Allowed)
+}
+
+/// Get the creator of a room from its `m.room.create` event.
+///
+/// Depending on the room version, the creator is either the `creator` field of the content (room
+/// versions 1 through 10) or the `sender` of the event (room versions 11 and later).
+///
+/// # Errors
+///
+/// Returns an error if the room version uses the `creator` field of the content and the content
+/// doesn't have a valid `creator` field.
+pub fn room_creator(room_version: &RoomVersion, create_event: &impl Event) -> Result<OwnedUserId> {
+    if room_version.use_room_create_sender {
+        return Ok(create_event.sender().to_owned());
+    }
+
+    #[allow(deprecated)]
+    let creator = from_json_str::<RoomCreateEventContent>(create_event.content().get())?
+        .creator
+        .ok_or_else(|| serde_json::Error::missing_field("creator"))?;
+
+    Ok(creator)
 }
 
 // TODO deserializing the member, power, join_rules event contents is done in conduit
 // just before this is called. Could they be passed in?
 /// Does the user who sent this member event have required power levels to do so.
 ///
+/// Returns [`AuthError::JoinNotAllowed`] if the join rules don't allow a join, and
+/// [`AuthError::MembershipChangeNotAllowed`] for any other rejected membership change.
+///
 /// * `user` - Information about the membership event and user making the request.
 /// * `auth_events` - The set of auth events that relate to a membership event.
 ///
@@ -473,7 +629,7 @@ fn valid_membership_change(
     user_for_join_auth: Option<&UserId>,
     user_for_join_auth_membership: &MembershipState,
     create_room: impl Event,
-) -> Result<bool> {
+) -> Result<AuthDecision> {
     #[derive(Deserialize)]
     struct GetThirdPartyInvite {
         third_party_invite: Option<Raw<ThirdPartyInvite>>,
@@ -544,7 +700,7 @@ fn valid_membership_change(
         false
     };
 
-    Ok(match target_membership {
+    let allow = match target_membership {
         MembershipState::Join => {
             // 1. If the only previous event is an m.room.create and the state_key is the creator,
             // allow
@@ -557,22 +713,10 @@ fn valid_membership_change(
             let no_more_prev_events = prev_events.next().is_none();
 
             if prev_event_is_create_event && no_more_prev_events {
-                let is_creator = if room_version.use_room_create_sender {
-                    let creator = create_room.sender();
-
-                    creator == sender && creator == target_user
-                } else {
-                    #[allow(deprecated)]
-                    let creator =
-                        from_json_str::<RoomCreateEventContent>(create_room.content().get())?
-                            .creator
-                            .ok_or_else(|| serde_json::Error::missing_field("creator"))?;
+                let creator = room_creator(room_version, &create_room)?;
 
-                    creator == sender && creator == target_user
-                };
-
-                if is_creator {
-                    return Ok(true);
+                if creator == sender && creator == target_user {
+                    return Ok(AuthDecision::Allowed);
                 }
             }
 
@@ -596,28 +740,34 @@ fn valid_membership_change(
                 || room_version.knock_restricted_join_rule
                     && matches!(join_rules, JoinRule::KnockRestricted(_))
             {
-                // If the join_rule is restricted or knock_restricted
-                if matches!(
+                // If the join_rule is restricted or knock_restricted:
+                // - If membership state is join or invite, allow.
+                // - If the join_authorised_via_users_server key in content is not a user with
+                //   sufficient permission to invite other users, reject.
+                // - Otherwise, allow.
+                if !matches!(
                     target_user_current_membership,
                     MembershipState::Invite | MembershipState::Join
-                ) {
-                    // If membership state is join or invite, allow.
-                    true
-                } else {
-                    // If the join_authorised_via_users_server key in content is not a user with
-                    // sufficient permission to invite other users, reject.
-                    // Otherwise, allow.
-                    user_for_join_auth_is_valid
+                ) && !user_for_join_auth_is_valid
+                {
+                    warn!(?join_rules, "No valid authorising user for the restricted join");
+                    return Ok(AuthDecision::Denied(AuthError::JoinNotAllowed));
                 }
-            } else {
+                true
+            } else if join_rules == JoinRule::Public {
                 // If the join_rule is public, allow.
+                true
+            } else {
                 // Otherwise, reject.
-                join_rules == JoinRule::Public
+                warn!(?join_rules, "Join rule doesn't allow the user to join");
+                return Ok(AuthDecision::Denied(AuthError::JoinNotAllowed));
             }
         }
         MembershipState::Invite => {
             // If content has third_party_invite key
-            if let Some(tp_id) = third_party_invite.and_then(|i| i.deserialize().ok()) {
+            if let Some((tp_id, raw_tp_id)) =
+                third_party_invite.and_then(|raw| Some((raw.deserialize().ok()?, raw)))
+            {
                 if target_user_current_membership == MembershipState::Ban {
                     warn!(?target_user_membership_event_id, "Can't invite banned user");
                     false
@@ -625,6 +775,7 @@ fn valid_membership_change(
                     let allow = verify_third_party_invite(
                         Some(target_user),
                         sender,
+                        &raw_tp_id,
                         &tp_id,
                         current_third_party_invite,
                     );
@@ -748,14 +899,26 @@ fn valid_membership_change(
             warn!("Unknown membership transition");
             false
         }
+    };
+
+    Ok(if allow {
+        AuthDecision::Allowed
+    } else {
+        AuthDecision::Denied(AuthError::MembershipChangeNotAllowed)
     })
 }
 
 /// Is the user allowed to send a specific event based on the rooms power levels.
 ///
 /// Does the event have the correct userId as its state_key if it's not the "" state_key.
-fn can_send_event(event: impl Event, ple: Option<impl Event>, user_level: Int) -> bool {
-    let event_type_power_level = get_send_level(event.event_type(), event.state_key(), ple);
+fn can_send_event(
+    room_version: &RoomVersion,
+    event: impl Event,
+    ple: Option<impl Event>,
+    user_level: Int,
+) -> bool {
+    let event_type_power_level =
+        get_send_level(room_version, event.event_type(), event.state_key(), ple);
 
     debug!(
         required_level = i64::from(event_type_power_level),
@@ -956,23 +1119,18 @@ fn check_redaction(
 
 /// Helper function to fetch the power level needed to send an event of type
 /// `e_type` based on the rooms "m.room.power_level" event.
+///
+/// See `PowerLevelsContentFields` for the defaults that apply.
 fn get_send_level(
+    room_version: &RoomVersion,
     e_type: &TimelineEventType,
     state_key: Option<&str>,
     power_lvl: Option<impl Event>,
 ) -> Int {
     power_lvl
         .and_then(|ple| {
-            from_json_str::<RoomPowerLevelsEventContent>(ple.content().get())
-                .map(|content| {
-                    content.events.get(e_type).copied().unwrap_or_else(|| {
-                        if state_key.is_some() {
-                            content.state_default
-                        } else {
-                            content.events_default
-                        }
-                    })
-                })
+            deserialize_power_levels_content_fields(ple.content().get(), room_version)
+                .map(|content| content.send_level(e_type, state_key))
                 .ok()
         })
         .unwrap_or_else(|| if state_key.is_some() { int!(50) } else { int!(0) })
@@ -981,9 +1139,15 @@ fn get_send_level(
 fn verify_third_party_invite(
     target_user: Option<&UserId>,
     sender: &UserId,
+    raw_tp_id: &Raw<ThirdPartyInvite>,
     tp_id: &ThirdPartyInvite,
     current_third_party_invite: Option<impl Event>,
 ) -> bool {
+    #[derive(Deserialize)]
+    struct GetSigned {
+        signed: CanonicalJsonObject,
+    }
+
     // 1. Check for user being banned happens before this is called
     // checking for mxid and token keys is done by ruma when deserializing
 
@@ -1015,45 +1179,83 @@ fn verify_third_party_invite(
             Err(_) => return false,
         };
 
-    let decoded_invite_token = match Base64::parse(&tp_id.signed.token) {
-        Ok(tok) => tok,
-        // FIXME: Log a warning?
-        Err(_) => return false,
+    // Keep the fields of `signed` that are not known by ruma, since they are signed too.
+    let Ok(GetSigned { mut signed }) = raw_tp_id.deserialize_as() else {
+        return false;
+    };
+    let Some(CanonicalJsonValue::Object(signatures)) = signed.remove("signatures") else {
+        return false;
     };
 
-    // A list of public keys in the public_keys field
-    for key in tpid_ev.public_keys.unwrap_or_default() {
-        if key.public_key == decoded_invite_token {
-            return true;
+    // The public key in the public_key field, and the list of public keys in the public_keys field
+    let public_keys = iter::once(tpid_ev.public_key)
+        .chain(tpid_ev.public_keys.unwrap_or_default().into_iter().map(|key| key.public_key))
+        .collect::<Vec<_>>();
+
+    for (server, server_signatures) in signatures {
+        let CanonicalJsonValue::Object(server_signatures) = server_signatures else {
+            continue;
+        };
+
+        for (key_id, signature) in server_signatures {
+            // Only check this signature, the other ones might have been made with other keys.
+            let mut signed = signed.clone();
+            signed.insert(
+                "signatures".to_owned(),
+                CanonicalJsonValue::Object(BTreeMap::from([(
+                    server.clone(),
+                    CanonicalJsonValue::Object(BTreeMap::from([(key_id.clone(), signature)])),
+                )])),
+            );
+
+            for public_key in &public_keys {
+                let public_key_map = BTreeMap::from([(
+                    server.clone(),
+                    BTreeMap::from([(key_id.clone(), public_key.clone())]),
+                )]);
+
+                if ruma_signatures::verify_json(&public_key_map, signed.clone()).is_ok() {
+                    return true;
+                }
+            }
         }
     }
 
-    // A single public key in the public_key field
-    tpid_ev.public_key == decoded_invite_token
+    false
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
+    use futures_util::future::ready;
+    use ruma_common::{serde::Base64, CanonicalJsonObject};
     use ruma_events::{
         room::{
             join_rules::{
                 AllowRule, JoinRule, Restricted, RoomJoinRulesEventContent, RoomMembership,
             },
             member::{MembershipState, RoomMemberEventContent},
+            third_party_invite::RoomThirdPartyInviteEventContent,
         },
         StateEventType, TimelineEventType,
     };
-    use serde_json::value::to_raw_value as to_raw_json_value;
+    use ruma_signatures::Ed25519KeyPair;
+    use serde_json::{
+        from_value as from_json_value, json,
+        value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
+    };
 
     use crate::{
-        event_auth::valid_membership_change,
+        event_auth::{
+            auth_types_for_event, check_size_limits, room_creator, valid_membership_change,
+            MAX_FIELD_LENGTH, MAX_PDU_SIZE,
+        },
         test_utils::{
-            alice, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
-            to_pdu_event, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
+            alice, bob, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
+            to_init_pdu_event, to_pdu_event, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
         },
-        Event, EventTypeExt, RoomVersion, StateMap,
+        AuthDecision, AuthError, Event, EventTypeExt, RoomVersion, StateMap,
     };
 
     #[test]
@@ -1095,7 +1297,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
     }
 
     #[test]
@@ -1137,7 +1340,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
     }
 
     #[test]
@@ -1179,7 +1383,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
     }
 
     #[test]
@@ -1221,7 +1426,88 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
+    }
+
+    #[test]
+    fn test_join_rules() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let can_join = |join_rule: JoinRule, invited: bool| {
+            let mut events = INITIAL_EVENTS();
+            *events.get_mut(&event_id("IJR")).unwrap() = to_pdu_event(
+                "IJR",
+                alice(),
+                TimelineEventType::RoomJoinRules,
+                Some(""),
+                to_raw_json_value(&RoomJoinRulesEventContent::new(join_rule)).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IPOWER"],
+            );
+            if invited {
+                events.insert(
+                    event_id("IVE"),
+                    to_pdu_event(
+                        "IVE",
+                        alice(),
+                        TimelineEventType::RoomMember,
+                        Some(ella().as_str()),
+                        to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Invite))
+                            .unwrap(),
+                        &["CREATE", "IJR", "IPOWER", "IMA"],
+                        &["IMC"],
+                    ),
+                );
+            }
+
+            let auth_events = events
+                .values()
+                .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+                .collect::<StateMap<_>>();
+            let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+
+            let requester = to_pdu_event(
+                "HELLO",
+                ella(),
+                TimelineEventType::RoomMember,
+                Some(ella().as_str()),
+                member_content_join(),
+                &["CREATE", "IJR", "IPOWER"],
+                &["IMC"],
+            );
+
+            valid_membership_change(
+                &RoomVersion::V6,
+                ella(),
+                fetch_state(StateEventType::RoomMember, ella().to_string()),
+                ella(),
+                fetch_state(StateEventType::RoomMember, ella().to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap()
+        };
+
+        // A join into a public room is allowed.
+        assert_eq!(can_join(JoinRule::Public, false), AuthDecision::Allowed);
+        // A join into a private or invite-only room without an invite is rejected.
+        assert_eq!(
+            can_join(JoinRule::Private, false),
+            AuthDecision::Denied(AuthError::JoinNotAllowed)
+        );
+        assert_eq!(
+            can_join(JoinRule::Invite, false),
+            AuthDecision::Denied(AuthError::JoinNotAllowed)
+        );
+        // A join into an invite-only room with a prior invite is allowed.
+        assert_eq!(can_join(JoinRule::Invite, true), AuthDecision::Allowed);
     }
 
     #[test]
@@ -1280,7 +1566,8 @@ mod tests {
             &MembershipState::Join,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
 
         assert!(!valid_membership_change(
             &RoomVersion::V9,
@@ -1296,7 +1583,231 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
+    }
+
+    #[test]
+    fn restricted_join_authorising_user() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut events = INITIAL_EVENTS();
+        *events.get_mut(&event_id("IPOWER")).unwrap() = to_pdu_event(
+            "IPOWER",
+            alice(),
+            TimelineEventType::RoomPowerLevels,
+            Some(""),
+            to_raw_json_value(&json!({ "users": { alice(): 100 }, "invite": 50 })).unwrap(),
+            &["CREATE", "IMA"],
+            &["IMA"],
+        );
+        *events.get_mut(&event_id("IJR")).unwrap() = to_pdu_event(
+            "IJR",
+            alice(),
+            TimelineEventType::RoomJoinRules,
+            Some(""),
+            to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Restricted(
+                Restricted::new(vec![AllowRule::RoomMembership(RoomMembership::new(
+                    room_id().to_owned(),
+                ))]),
+            )))
+            .unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IPOWER"],
+        );
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+
+        let mut member = RoomMemberEventContent::new(MembershipState::Join);
+        member.join_authorized_via_users_server = Some(bob().to_owned());
+        let requester = to_pdu_event(
+            "HELLO",
+            ella(),
+            TimelineEventType::RoomMember,
+            Some(ella().as_str()),
+            to_raw_json_value(&member).unwrap(),
+            &["CREATE", "IJR", "IPOWER", "IMB"],
+            &["IMC"],
+        );
+
+        // Bob is joined, but his power level is lower than the invite level.
+        assert!(!valid_membership_change(
+            &RoomVersion::V9,
+            ella(),
+            None::<PduEvent>,
+            ella(),
+            None::<PduEvent>,
+            &requester,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            Some(bob()),
+            &MembershipState::Join,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+
+        // Ella was invited, so she can join even if the authorising user is not allowed to invite.
+        let invite = to_pdu_event(
+            "IME",
+            alice(),
+            TimelineEventType::RoomMember,
+            Some(ella().as_str()),
+            to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Invite)).unwrap(),
+            &["CREATE", "IJR", "IPOWER", "IMA"],
+            &["IMC"],
+        );
+        assert!(valid_membership_change(
+            &RoomVersion::V9,
+            ella(),
+            Some(invite),
+            ella(),
+            None::<PduEvent>,
+            &requester,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            Some(bob()),
+            &MembershipState::Join,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+    }
+
+    #[test]
+    fn auth_types_restricted_join() {
+        let mut member = RoomMemberEventContent::new(MembershipState::Join);
+        member.join_authorized_via_users_server = Some(alice().to_owned());
+
+        let auth_types = auth_types_for_event(
+            &TimelineEventType::RoomMember,
+            ella(),
+            Some(ella().as_str()),
+            &to_raw_json_value(&member).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            auth_types,
+            [
+                (StateEventType::RoomPowerLevels, "".to_owned()),
+                (StateEventType::RoomMember, ella().to_string()),
+                (StateEventType::RoomCreate, "".to_owned()),
+                (StateEventType::RoomJoinRules, "".to_owned()),
+                (StateEventType::RoomMember, alice().to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_knock_restricted_join_rule() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut events = INITIAL_EVENTS();
+        *events.get_mut(&event_id("IJR")).unwrap() = to_pdu_event(
+            "IJR",
+            alice(),
+            TimelineEventType::RoomJoinRules,
+            Some(""),
+            to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::KnockRestricted(
+                Restricted::new(vec![AllowRule::RoomMembership(RoomMembership::new(
+                    room_id().to_owned(),
+                ))]),
+            )))
+            .unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IPOWER"],
+        );
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+        let target_user = ella();
+        let sender = ella();
+
+        // Join through a user of an allowed room.
+        let mut member = RoomMemberEventContent::new(MembershipState::Join);
+        member.join_authorized_via_users_server = Some(alice().to_owned());
+        let joiner = to_pdu_event(
+            "HELLO",
+            ella(),
+            TimelineEventType::RoomMember,
+            Some(ella().as_str()),
+            to_raw_json_value(&member).unwrap(),
+            &["CREATE", "IJR", "IPOWER", "new"],
+            &["new"],
+        );
+
+        assert!(valid_membership_change(
+            &RoomVersion::V10,
+            target_user,
+            fetch_state(StateEventType::RoomMember, target_user.to_string()),
+            sender,
+            fetch_state(StateEventType::RoomMember, sender.to_string()),
+            &joiner,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            Some(alice()),
+            &MembershipState::Join,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+
+        // The join rule is not supported before room version 10.
+        assert!(!valid_membership_change(
+            &RoomVersion::V9,
+            target_user,
+            fetch_state(StateEventType::RoomMember, target_user.to_string()),
+            sender,
+            fetch_state(StateEventType::RoomMember, sender.to_string()),
+            &joiner,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            Some(alice()),
+            &MembershipState::Join,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+
+        // Knock.
+        let knocker = to_pdu_event(
+            "HELLO",
+            ella(),
+            TimelineEventType::RoomMember,
+            Some(ella().as_str()),
+            to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Knock)).unwrap(),
+            &[],
+            &["IMC"],
+        );
+
+        assert!(valid_membership_change(
+            &RoomVersion::V10,
+            target_user,
+            fetch_state(StateEventType::RoomMember, target_user.to_string()),
+            sender,
+            fetch_state(StateEventType::RoomMember, sender.to_string()),
+            &knocker,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            None,
+            &MembershipState::Leave,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
     }
 
     #[test]
@@ -1347,6 +1858,426 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_allowed());
+        // Knocking is not supported before room version 7.
+        assert!(!valid_membership_change(
+            &RoomVersion::V6,
+            target_user,
+            fetch_state(StateEventType::RoomMember, target_user.to_string()),
+            sender,
+            fetch_state(StateEventType::RoomMember, sender.to_string()),
+            &requester,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            None,
+            &MembershipState::Leave,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+    }
+
+    #[test]
+    fn test_knock_not_knockable() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let events = INITIAL_EVENTS();
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+
+        let requester = to_pdu_event(
+            "HELLO",
+            ella(),
+            TimelineEventType::RoomMember,
+            Some(ella().as_str()),
+            to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Knock)).unwrap(),
+            &[],
+            &["IMC"],
+        );
+
+        let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+
+        // The join rule of the room is public.
+        assert!(!valid_membership_change(
+            &RoomVersion::V7,
+            ella(),
+            None::<PduEvent>,
+            ella(),
+            None::<PduEvent>,
+            &requester,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            None,
+            &MembershipState::Leave,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+    }
+
+    #[test]
+    fn test_knock_invite_join() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut events = INITIAL_EVENTS();
+        *events.get_mut(&event_id("IJR")).unwrap() = to_pdu_event(
+            "IJR",
+            alice(),
+            TimelineEventType::RoomJoinRules,
+            Some(""),
+            to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Knock)).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IPOWER"],
+        );
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+
+        let member_event = |id: &str, sender, membership| {
+            to_pdu_event(
+                id,
+                sender,
+                TimelineEventType::RoomMember,
+                Some(ella().as_str()),
+                to_raw_json_value(&RoomMemberEventContent::new(membership)).unwrap(),
+                &["CREATE", "IJR", "IPOWER"],
+                &["IMC"],
+            )
+        };
+        let knock = member_event("KNOCK", ella(), MembershipState::Knock);
+        let invite = member_event("INVITE", alice(), MembershipState::Invite);
+        let join = member_event("JOIN", ella(), MembershipState::Join);
+
+        // Ella can't join before being invited.
+        assert!(!valid_membership_change(
+            &RoomVersion::V7,
+            ella(),
+            Some(&knock),
+            ella(),
+            Some(&knock),
+            &join,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            None,
+            &MembershipState::Leave,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+
+        // Alice invites Ella after she knocked.
+        assert!(valid_membership_change(
+            &RoomVersion::V7,
+            ella(),
+            Some(&knock),
+            alice(),
+            fetch_state(StateEventType::RoomMember, alice().to_string()),
+            &invite,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            None,
+            &MembershipState::Leave,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+
+        // Ella joins after being invited.
+        assert!(valid_membership_change(
+            &RoomVersion::V7,
+            ella(),
+            Some(&invite),
+            ella(),
+            Some(&invite),
+            &join,
+            None::<PduEvent>,
+            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+            None,
+            &MembershipState::Leave,
+            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+        )
+        .unwrap()
+        .is_allowed());
+    }
+
+    #[test]
+    fn room_creator_from_content() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "creator": bob(), "room_version": "10" })).unwrap(),
+        );
+
+        assert_eq!(room_creator(&RoomVersion::V10, &create).unwrap(), bob());
+
+        let no_creator = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "room_version": "10" })).unwrap(),
+        );
+        room_creator(&RoomVersion::V10, &no_creator).unwrap_err();
+    }
+
+    #[test]
+    fn room_creator_from_sender() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "room_version": "11" })).unwrap(),
+        );
+
+        assert_eq!(room_creator(&RoomVersion::V11, &create).unwrap(), alice());
+    }
+
+    #[tokio::test]
+    async fn create_event_without_creator() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "room_version": "11" })).unwrap(),
+        );
+        let fetch_state = |_: &'static StateEventType, _: &str| ready(None::<PduEvent>);
+
+        // Since room version 11, the creator is the sender of the create event.
+        assert!(crate::auth_check(&RoomVersion::V11, &create, None, fetch_state).await.unwrap());
+
+        // Before room version 11, the `creator` field is required.
+        assert!(!crate::auth_check(&RoomVersion::V10, &create, None, fetch_state).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn size_limits() {
+        let auth_events = INITIAL_EVENTS()
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.clone()))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty: &'static StateEventType, key: &str| {
+            ready(auth_events.get(&ty.with_state_key(key)).cloned())
+        };
+        let event = |event_type: &str, topic: String| {
+            to_pdu_event(
+                "T",
+                alice(),
+                event_type.into(),
+                Some(""),
+                to_raw_json_value(&json!({ "topic": topic })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            )
+        };
+
+        let small = event("m.room.topic", "a".repeat(100));
+        check_size_limits(&small).unwrap();
+        assert!(crate::auth_check(&RoomVersion::V6, &small, None, fetch_state).await.unwrap());
+
+        let too_large = event("m.room.topic", "a".repeat(MAX_PDU_SIZE));
+        assert_eq!(check_size_limits(&too_large), Err(AuthError::TooLarge("event")));
+        assert_eq!(
+            crate::auth_check_detailed(&RoomVersion::V6, &too_large, None, fetch_state)
+                .await
+                .unwrap(),
+            AuthDecision::Denied(AuthError::TooLarge("event"))
+        );
+
+        // The content is below the limit, but not the rest of the event.
+        let topic = "a".repeat(MAX_PDU_SIZE - 100);
+        let content_len = to_raw_json_value(&json!({ "topic": topic })).unwrap().get().len();
+        assert!(content_len < MAX_PDU_SIZE);
+        let too_large = event("m.room.topic", topic);
+        assert_eq!(check_size_limits(&too_large), Err(AuthError::TooLarge("event")));
+
+        // Insignificant whitespace in the content doesn't count.
+        let padded = to_pdu_event(
+            "T",
+            alice(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            RawJsonValue::from_string(format!(
+                "{{{}\"topic\":\"topic\"}}",
+                " ".repeat(MAX_PDU_SIZE)
+            ))
+            .unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        );
+        check_size_limits(&padded).unwrap();
+
+        let long_type = event(&"a".repeat(MAX_FIELD_LENGTH + 1), "topic".to_owned());
+        assert_eq!(check_size_limits(&long_type), Err(AuthError::TooLarge("type")));
+        assert!(!crate::auth_check(&RoomVersion::V6, &long_type, None, fetch_state).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn auth_check_detailed_denied() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "room_version": "10" })).unwrap(),
+        );
+        let fetch_nothing = |_: &'static StateEventType, _: &str| ready(None::<PduEvent>);
+        assert_eq!(
+            crate::auth_check_detailed(&RoomVersion::V10, &create, None, fetch_nothing)
+                .await
+                .unwrap(),
+            AuthDecision::Denied(AuthError::MissingCreator)
+        );
+
+        let topic = |sender| {
+            to_pdu_event(
+                "TOPIC",
+                sender,
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "Hello" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            )
+        };
+        assert_eq!(
+            crate::auth_check_detailed(&RoomVersion::V6, &topic(alice()), None, fetch_nothing)
+                .await
+                .unwrap(),
+            AuthDecision::Denied(AuthError::MissingCreateEvent)
+        );
+
+        let auth_events = INITIAL_EVENTS()
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.clone()))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty: &'static StateEventType, key: &str| {
+            ready(auth_events.get(&ty.with_state_key(key)).cloned())
+        };
+
+        let decision =
+            crate::auth_check_detailed(&RoomVersion::V6, &topic(alice()), None, fetch_state)
+                .await
+                .unwrap();
+        assert!(decision.is_allowed());
+
+        assert_eq!(
+            crate::auth_check_detailed(&RoomVersion::V6, &topic(ella()), None, fetch_state)
+                .await
+                .unwrap(),
+            AuthDecision::Denied(AuthError::SenderNotInRoom)
+        );
+        assert_eq!(
+            crate::auth_check_detailed(&RoomVersion::V6, &topic(bob()), None, fetch_state)
+                .await
+                .unwrap(),
+            AuthDecision::Denied(AuthError::CannotSendEvent)
+        );
+    }
+
+    #[test]
+    fn test_third_party_invite() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let key_pair = |version: &str| {
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), version.to_owned())
+                .unwrap()
+        };
+        let identity_server_key = key_pair("0");
+        let other_key = key_pair("1");
+
+        let events = INITIAL_EVENTS();
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+
+        let third_party_invite = to_pdu_event(
+            "TPI",
+            alice(),
+            TimelineEventType::RoomThirdPartyInvite,
+            Some("TOKEN"),
+            to_raw_json_value(&RoomThirdPartyInviteEventContent::new(
+                "e...@example.org".to_owned(),
+                "https://identity.example.org/_matrix/identity/v2/pubkey/isvalid".to_owned(),
+                Base64::new(identity_server_key.public_key().to_vec()),
+            ))
+            .unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IPOWER"],
+        );
+
+        let invite = |token: &str, key_pair: &Ed25519KeyPair| {
+            let mut signed: CanonicalJsonObject =
+                from_json_value(json!({ "mxid": ella(), "token": token })).unwrap();
+            ruma_signatures::sign_json("identity.example.org", key_pair, &mut signed).unwrap();
+
+            to_pdu_event(
+                "HELLO",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(ella().as_str()),
+                to_raw_json_value(&json!({
+                    "membership": "invite",
+                    "third_party_invite": {
+                        "display_name": "e...@example.org",
+                        "signed": signed,
+                    },
+                }))
+                .unwrap(),
+                &["CREATE", "IMA", "IPOWER", "TPI"],
+                &["TPI"],
+            )
+        };
+        let valid_invite = |invite: Arc<PduEvent>, third_party_invite: Option<Arc<PduEvent>>| {
+            valid_membership_change(
+                &RoomVersion::V6,
+                ella(),
+                fetch_state(StateEventType::RoomMember, ella().to_string()),
+                alice(),
+                fetch_state(StateEventType::RoomMember, alice().to_string()),
+                &invite,
+                third_party_invite,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap()
+            .is_allowed()
+        };
+
+        // The token matches the invite, and the signature is made with its public key.
+        assert!(valid_invite(
+            invite("TOKEN", &identity_server_key),
+            Some(third_party_invite.clone())
+        ));
+
+        // There is no invite for the token.
+        assert!(!valid_invite(invite("TOKEN", &identity_server_key), None));
+        assert!(!valid_invite(
+            invite("OTHER_TOKEN", &identity_server_key),
+            Some(third_party_invite.clone())
+        ));
+
+        // The signature was not made with the public key of the invite.
+        assert!(!valid_invite(invite("TOKEN", &other_key), Some(third_party_invite)));
     }
 }
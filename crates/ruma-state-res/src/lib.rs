@@ -1,42 +1,259 @@
 use std::{
     borrow::Borrow,
-    cmp::Reverse,
-    collections::{BinaryHeap, HashMap, HashSet},
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
     hash::Hash,
+    mem,
+    pin::pin,
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Mutex,
+    },
 };
 
 use futures_util::{future, stream, Future, StreamExt};
 use itertools::Itertools;
 use js_int::{int, Int};
-use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomVersionId};
+use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
 use ruma_events::{
-    room::member::{MembershipState, RoomMemberEventContent},
+    room::{
+        encryption::RoomEncryptionEventContent,
+        history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+        member::{MembershipState, RoomMemberEventContent},
+        server_acl::RoomServerAclEventContent,
+    },
     StateEventType, TimelineEventType,
 };
+use serde::Deserialize;
 use serde_json::from_str as from_json_str;
+use sha2::{Digest, Sha256};
 use tracing::{debug, instrument, trace, warn};
 
 mod error;
 pub mod event_auth;
 mod power_levels;
+mod resolution;
 pub mod room_version;
 mod state_event;
-#[cfg(test)]
-mod test_utils;
-
-pub use error::{Error, Result};
-pub use event_auth::{auth_check, auth_types_for_event};
-use power_levels::PowerLevelsContentFields;
-pub use room_version::RoomVersion;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod v1;
+mod validation;
+
+pub use error::{AuthError, Error, PduTooLarge, PduValidationError, Result};
+pub use event_auth::{
+    auth_check, auth_check_detailed, auth_types_for_event, room_creator, AuthDecision,
+};
+use power_levels::{deserialize_power_levels_content_fields, PowerLevelsContentFields};
+pub use resolution::{
+    resolve_compute_chains, resolve_conflicted, resolve_sync, resolve_with_report, BatchFetch,
+    PartialReason, RejectionReason, ResolveOptions, ResolveOutcome, ResolveReport, ResolvedState,
+    StateResObserver, StateResolution,
+};
+pub use room_version::{
+    room_version_from_create, validate_pdu_size, RoomVersion, RoomVersionRules,
+};
 pub use state_event::Event;
+pub use v1::resolve_v1;
+pub use validation::{validate_pdu, ValidatedPdu};
 
 /// A mapping of event type and state_key to some value `T`, usually an `EventId`.
 pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 
+/// How many events are processed by the loops of the resolution between two calls to the
+/// `should_continue` predicate.
+const CANCELLATION_CHECK_INTERVAL: usize = 64;
+
+/// Compute a SHA-256 hash of the given state map.
+///
+/// The entries are hashed in `(event type, state key)` order, so two maps containing the same
+/// entries always produce the same hash, regardless of their insertion order. This allows to
+/// cheaply check whether two servers agree on the state of a room.
+pub fn state_map_hash<Id>(state: &StateMap<Id>) -> [u8; 32]
+where
+    Id: Borrow<EventId>,
+{
+    let mut hasher = Sha256::new();
+
+    for ((event_type, state_key), id) in state.iter().sorted_unstable_by_key(|(key, _)| *key) {
+        // Prefix every field with its length so that the boundaries between fields are not
+        // ambiguous.
+        for field in [event_type.to_string().as_str(), state_key, id.borrow().as_str()] {
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field);
+        }
+    }
+
+    hasher.finalize().into()
+}
+
+/// Whether the history of the room with the given state can be read by users that are not joined.
+///
+/// This is the case if the `m.room.history_visibility` event of the state has a `world_readable`
+/// history visibility. If there is no such event, or if it can't be fetched or deserialized, the
+/// history visibility defaults to `shared` and this returns `false`.
+pub async fn is_world_readable<E, F, Fut>(state: &StateMap<E::Id>, fetch_event: &F) -> bool
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    let Some(history_visibility_event_id) =
+        state.get(&StateEventType::RoomHistoryVisibility.with_state_key(""))
+    else {
+        return false;
+    };
+
+    match fetch_event(history_visibility_event_id.clone()).await {
+        Some(event) => from_json_str::<RoomHistoryVisibilityEventContent>(event.content().get())
+            .is_ok_and(|content| content.history_visibility == HistoryVisibility::WorldReadable),
+        None => false,
+    }
+}
+
+/// Split the given PDUs of a room into the ones that are allowed by the `m.room.server_acl` event
+/// of the room's state, and the ones that are not.
+///
+/// A PDU is rejected if the server of its sender is denied by the ACL. This should be applied to
+/// the PDUs of an incoming transaction before they are used for state resolution. If the state has
+/// no `m.room.server_acl` event, or if it can't be fetched or deserialized, all the PDUs are
+/// accepted.
+///
+/// Returns an `(accepted, rejected)` tuple, where the PDUs keep their order.
+pub async fn filter_pdus_by_acl<E, F, Fut>(
+    pdus: impl IntoIterator<Item = E>,
+    state: &StateMap<E::Id>,
+    fetch_event: &F,
+) -> (Vec<E>, Vec<E>)
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    let Some(acl) = server_acl(state, fetch_event).await else {
+        return (pdus.into_iter().collect(), Vec::new());
+    };
+
+    pdus.into_iter().partition(|pdu| {
+        let allowed = acl.is_allowed(pdu.sender_server());
+        if !allowed {
+            warn!(event_id = %pdu.event_id(), "sender server of PDU is denied by the server ACL");
+        }
+        allowed
+    })
+}
+
+/// The content of the `m.room.server_acl` event of the given state, if it can be fetched and
+/// deserialized.
+async fn server_acl<E, F, Fut>(
+    state: &StateMap<E::Id>,
+    fetch_event: &F,
+) -> Option<RoomServerAclEventContent>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    let acl_event_id = state.get(&StateEventType::RoomServerAcl.with_state_key(""))?;
+    let acl_event = fetch_event(acl_event_id.clone()).await?;
+    from_json_str(acl_event.content().get()).ok()
+}
+
+/// Whether the given state loses the encryption of the room, compared to the state before it.
+///
+/// Once encryption is enabled in a room, it can't be disabled. This returns `true` if `before`
+/// has a valid `m.room.encryption` event, but `after` doesn't have one, or its event can't be
+/// fetched or deserialized.
+///
+/// State resolution is never supposed to remove encryption from a room, so servers should raise an
+/// alarm if this ever happens, as it is either a bug or an attack on the room.
+pub async fn detect_encryption_downgrade<E, F, Fut>(
+    before: &StateMap<E::Id>,
+    after: &StateMap<E::Id>,
+    fetch_event: &F,
+) -> bool
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    is_encrypted(before, fetch_event).await && !is_encrypted(after, fetch_event).await
+}
+
+/// Whether the given state has a valid `m.room.encryption` event.
+async fn is_encrypted<E, F, Fut>(state: &StateMap<E::Id>, fetch_event: &F) -> bool
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    let Some(encryption_event_id) = state.get(&StateEventType::RoomEncryption.with_state_key(""))
+    else {
+        return false;
+    };
+
+    fetch_event(encryption_event_id.clone()).await.is_some_and(|event| {
+        from_json_str::<RoomEncryptionEventContent>(event.content().get()).is_ok()
+    })
+}
+
+/// Fetch the events of the given state and the union of their auth chains.
+///
+/// This returns the `pdus` and `auth_chain` of a response to a federation `/state` request, for
+/// example with the state returned by [`StateResolution::resolve()`]. The auth chain contains the
+/// full recursive set of the `auth_events` of the state events. Both lists are sorted by event ID.
+///
+/// Returns an error if one of the events can't be fetched, since the response would be incomplete.
+pub async fn build_state_response<E, F, Fut>(
+    state: &StateMap<E::Id>,
+    fetch_event: &F,
+) -> Result<(Vec<E>, Vec<E>)>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+{
+    let mut state_ids = state.values().cloned().collect::<Vec<_>>();
+    state_ids.sort_unstable_by(|a, b| a.borrow().cmp(b.borrow()));
+    state_ids.dedup();
+
+    let mut pdus = Vec::with_capacity(state_ids.len());
+    let mut auth_chain_ids = HashSet::new();
+    let mut stack = Vec::new();
+
+    for event_id in state_ids {
+        let event = fetch_event(event_id.clone())
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Failed to find state event {event_id}")))?;
+        stack.extend(event.auth_events().cloned());
+        pdus.push(event);
+    }
+
+    let mut auth_chain = Vec::new();
+    while let Some(event_id) = stack.pop() {
+        if !auth_chain_ids.insert(event_id.clone()) {
+            continue;
+        }
+
+        let event = fetch_event(event_id.clone())
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Failed to find auth event {event_id}")))?;
+        stack.extend(event.auth_events().cloned());
+        auth_chain.push(event);
+    }
+    auth_chain.sort_unstable_by(|a, b| a.event_id().borrow().cmp(b.event_id().borrow()));
+
+    Ok((pdus, auth_chain))
+}
+
 /// Resolve sets of state events as they come in.
 ///
-/// Internally `StateResolution` builds a graph and an auth chain to allow for state conflict
-/// resolution.
+/// This is the same as [`StateResolution::resolve()`] with the default [`ResolveOptions`]. Use
+/// [`StateResolution`] instead, which can be configured without adding arguments.
+///
+/// Internally this builds a graph and an auth chain to allow for state conflict resolution.
 ///
 /// ## Arguments
 ///
@@ -54,6 +271,7 @@ pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 /// The caller of `resolve` must ensure that all the events are from the same room. Although this
 /// function takes a `RoomId` it does not check that each event is part of the same room.
 //#[instrument(level = "debug", skip(state_sets, auth_chain_sets, event_fetch))]
+#[deprecated = "Use `StateResolution` instead."]
 pub async fn resolve<'a, E, SetIter, Fetch, FetchFut, Exists, ExistsFut>(
     room_version: &RoomVersionId,
     state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
@@ -71,62 +289,146 @@ where
     E::Id: Borrow<EventId> + Send + Sync,
     for<'b> &'b E: Send,
 {
-    debug!("State resolution starting");
-
-    // Split non-conflicting and conflicting state
-    let (clean, conflicting) = separate(state_sets.into_iter());
-
-    debug!(count = clean.len(), "non-conflicting events");
-    trace!(map = ?clean, "non-conflicting events");
-
-    if conflicting.is_empty() {
-        debug!("no conflicting state found");
-        return Ok(clean);
-    }
+    StateResolution::new(room_version.clone(), event_fetch, event_exists)
+        .resolve(state_sets, auth_chain_sets)
+        .await
+}
 
-    debug!(count = conflicting.len(), "conflicting events");
-    trace!(map = ?conflicting, "conflicting events");
+/// Resolve the `conflicting` state, given the auth chain difference of the state sets.
+///
+/// The `clean` state takes precedence over the resolved conflicted state.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_with_auth_chain_diff<
+    E,
+    Fetch,
+    FetchFut,
+    Batch,
+    Exists,
+    ExistsFut,
+    Continue,
+    Observer,
+>(
+    room_version: &RoomVersionId,
+    clean: StateMap<E::Id>,
+    conflicting: StateMap<Vec<E::Id>>,
+    auth_chain_diff: impl Iterator<Item = E::Id>,
+    event_fetch: &Fetch,
+    batch_fetch: &Batch,
+    event_exists: &Exists,
+    options: &ResolveOptions,
+    should_continue: &Continue,
+    observer: &Observer,
+    rejected: &mut Vec<(E::Id, RejectionReason)>,
+) -> Result<ResolveOutcome<E::Id>>
+where
+    Observer: StateResObserver + Sync,
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Batch: BatchFetch<E> + Sync,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+    Continue: Fn() -> bool + Sync,
+{
+    let room_version = RoomVersion::new(room_version)?;
 
-    let auth_chain_diff =
-        get_auth_chain_diff(&auth_chain_sets).chain(conflicting.into_values().flatten());
+    let auth_chain_diff = auth_chain_diff.chain(conflicting.into_values().flatten());
 
     // `all_conflicted` contains unique items
     // synapse says `full_set = {eid for eid in full_conflicted_set if eid in event_map}`
-    let all_conflicted: HashSet<E::Id> = stream::iter(auth_chain_diff)
+    let mut full_conflicted = pin!(stream::iter(auth_chain_diff)
         // Don't honor events we cannot "verify"
-        .filter(|id| event_exists(id.clone()))
-        .collect()
-        .await;
+        .filter(|id| event_exists(id.clone())));
+
+    // Check the limit while collecting the events, to avoid allocating a huge set.
+    let mut all_conflicted = HashSet::new();
+    while let Some(id) = full_conflicted.next().await {
+        all_conflicted.insert(id);
+
+        if options.max_conflicted_events.is_some_and(|max| all_conflicted.len() > max) {
+            warn!(count = all_conflicted.len(), "too many conflicted events");
+            return Ok(ResolveOutcome::Partial {
+                resolved_so_far: clean,
+                reason: PartialReason::TooManyConflictedEvents,
+            });
+        }
+    }
+
+    if !should_continue() {
+        debug!("state resolution cancelled before resolving power events");
+        return Ok(ResolveOutcome::Partial {
+            resolved_so_far: clean,
+            reason: PartialReason::Cancelled,
+        });
+    }
 
     debug!(count = all_conflicted.len(), "full conflicted set");
     trace!(set = ?all_conflicted, "full conflicted set");
+    observer.on_conflicted(all_conflicted.len());
 
     // We used to check that all events are events from the correct room
     // this is now a check the caller of `resolve` must make.
 
-    // Get only the control events with a state_key: "" or ban/kick event (sender != state_key)
-    let control_events = stream::iter(all_conflicted.iter())
-        .filter(|&id| is_power_event_id(id, &event_fetch))
-        .map(Clone::clone)
-        .collect::<Vec<_>>()
-        .await;
-
-    // Sort the control events based on power_level/clock/event_id and outgoing/incoming edges
-    let sorted_control_levels =
-        reverse_topological_power_sort(control_events, &all_conflicted, &event_fetch).await?;
+    let sorted_control_levels = match sort_control_events_batched(
+        &all_conflicted,
+        Some(&room_version),
+        event_fetch,
+        batch_fetch,
+        should_continue,
+        observer,
+        options,
+    )
+    .await
+    {
+        Err(Error::Cancelled) => {
+            debug!("state resolution cancelled while sorting power events");
+            return Ok(ResolveOutcome::Partial {
+                resolved_so_far: clean,
+                reason: PartialReason::Cancelled,
+            });
+        }
+        result => result?,
+    };
 
     debug!(count = sorted_control_levels.len(), "power events");
     trace!(list = ?sorted_control_levels, "sorted power events");
+    observer.on_power_events(sorted_control_levels.len());
 
-    let room_version = RoomVersion::new(room_version)?;
     // Sequentially auth check each control event.
-    let resolved_control =
-        iterative_auth_check(&room_version, &sorted_control_levels, clean.clone(), &event_fetch)
-            .await?;
+    let resolved_control = match iterative_auth_check(
+        &room_version,
+        &sorted_control_levels,
+        clean.clone(),
+        &event_fetch,
+        options,
+        should_continue,
+        observer,
+        rejected,
+    )
+    .await
+    {
+        Err(Error::Cancelled) => {
+            debug!("state resolution cancelled while resolving power events");
+            return Ok(ResolveOutcome::Partial {
+                resolved_so_far: clean,
+                reason: PartialReason::Cancelled,
+            });
+        }
+        result => result?,
+    };
 
     debug!(count = resolved_control.len(), "resolved power events");
     trace!(map = ?resolved_control, "resolved power events");
 
+    if !should_continue() {
+        debug!("state resolution cancelled after resolving power events");
+        let mut resolved_so_far = resolved_control;
+        resolved_so_far.extend(clean);
+        return Ok(ResolveOutcome::Partial { resolved_so_far, reason: PartialReason::Cancelled });
+    }
+
     // At this point the control_events have been resolved we now have to
     // sort the remaining events using the mainline of the resolved power level.
     let deduped_power_ev = sorted_control_levels.into_iter().collect::<HashSet<_>>();
@@ -152,31 +454,86 @@ where
 
     trace!(list = ?sorted_left_events, "events left, sorted");
 
-    let mut resolved_state = iterative_auth_check(
+    let mut resolved_state = match iterative_auth_check(
         &room_version,
         &sorted_left_events,
-        resolved_control, // The control events are added to the final resolved state
+        resolved_control.clone(), // The control events are added to the final resolved state
         &event_fetch,
+        options,
+        should_continue,
+        observer,
+        rejected,
     )
-    .await?;
+    .await
+    {
+        Err(Error::Cancelled) => {
+            debug!("state resolution cancelled while resolving the other events");
+            let mut resolved_so_far = resolved_control;
+            resolved_so_far.extend(clean);
+            return Ok(ResolveOutcome::Partial {
+                resolved_so_far,
+                reason: PartialReason::Cancelled,
+            });
+        }
+        result => result?,
+    };
 
     // Add unconflicted state to the resolved state
     // We priorities the unconflicting state
     resolved_state.extend(clean);
 
+    if options.report_rejected {
+        debug!(list = ?rejected, "rejected events");
+    }
+
     debug!("state resolution finished");
 
-    Ok(resolved_state)
+    Ok(ResolveOutcome::Complete(resolved_state))
 }
 
 /// Split the events that have no conflicts from those that are conflicting.
 ///
-/// The return tuple looks like `(unconflicted, conflicted)`.
+/// The return tuple looks like `(unconflicted, conflicted)`, where `conflicted` contains the event
+/// IDs of each conflicting key in the state sets that have it.
 ///
 /// State is determined to be conflicting if for the given key (StateEventType, StateKey) there is
 /// not exactly one event ID. This includes missing events, if one state_set includes an event that
 /// none of the other have this is a conflicting event.
-fn separate<'a, Id>(
+///
+/// Such a key is resolved like any other conflicting key, even if all the state sets that have it
+/// agree on the same event: the event can still be rejected because of the rest of the conflicted
+/// state, like a join that is not allowed by a conflicting join rule.
+///
+/// This is cheap compared to the full state resolution, so it can be used to check whether state
+/// sets diverge at all before resolving them.
+///
+/// # Example
+///
+/// ```
+/// use ruma_common::owned_event_id;
+/// use ruma_events::StateEventType;
+/// use ruma_state_res::{separate, StateMap};
+///
+/// let create_key = (StateEventType::RoomCreate, "".to_owned());
+/// let topic_key = (StateEventType::RoomTopic, "".to_owned());
+///
+/// let fork_a: StateMap<_> = [(create_key.clone(), owned_event_id!("$create"))].into();
+/// let fork_b = fork_a.clone();
+///
+/// // The state sets are identical, there is nothing to resolve.
+/// let (unconflicted, conflicted) = separate([&fork_a, &fork_b].into_iter());
+/// assert!(conflicted.is_empty());
+/// assert_eq!(unconflicted, fork_a);
+///
+/// // A key that is missing from some state sets is conflicting.
+/// let mut fork_c = fork_a.clone();
+/// fork_c.insert(topic_key.clone(), owned_event_id!("$topic"));
+///
+/// let (unconflicted, conflicted) = separate([&fork_a, &fork_c].into_iter());
+/// assert_eq!(unconflicted, fork_a);
+/// assert_eq!(conflicted[&topic_key], [owned_event_id!("$topic")]);
+/// ```
+pub fn separate<'a, Id>(
     state_sets_iter: impl Iterator<Item = &'a StateMap<Id>> + Clone,
 ) -> (StateMap<Id>, StateMap<Vec<Id>>)
 where
@@ -185,7 +542,12 @@ where
     let mut unconflicted_state = StateMap::new();
     let mut conflicted_state = StateMap::new();
 
-    for key in state_sets_iter.clone().flat_map(|map| map.keys()).unique() {
+    // Sort the keys so the maps are built in the same order regardless of the iteration order of
+    // the state sets.
+    let mut keys = state_sets_iter.clone().flat_map(|map| map.keys()).unique().collect::<Vec<_>>();
+    keys.sort_unstable();
+
+    for key in keys {
         let mut event_ids =
             state_sets_iter.clone().map(|state_set| state_set.get(key)).collect::<Vec<_>>();
 
@@ -204,1081 +566,4231 @@ where
     (unconflicted_state, conflicted_state)
 }
 
-/// Returns a Vec of deduped EventIds that appear in some chains but not others.
-fn get_auth_chain_diff<Id>(auth_chain_sets: &Vec<HashSet<Id>>) -> impl Iterator<Item = Id>
+/// Split the events that have no conflicts from those that are conflicting, and count the number
+/// of state sets that contain each unconflicted key.
+///
+/// The return tuple looks like `(unconflicted, conflicted, present_in)`, where the first two
+/// elements are the same as the ones returned by [`separate`], and `present_in` contains, for each
+/// key of `unconflicted`, the number of state sets that have it.
+pub fn separate_with_counts<'a, Id>(
+    state_sets_iter: impl Iterator<Item = &'a StateMap<Id>> + Clone,
+) -> (StateMap<Id>, StateMap<Vec<Id>>, StateMap<usize>)
+where
+    Id: Clone + Eq + 'a,
+{
+    let (unconflicted_state, conflicted_state) = separate(state_sets_iter.clone());
+
+    let present_in = unconflicted_state
+        .keys()
+        .map(|key| {
+            let count = state_sets_iter.clone().filter(|map| map.contains_key(key)).count();
+            (key.clone(), count)
+        })
+        .collect();
+
+    (unconflicted_state, conflicted_state, present_in)
+}
+
+/// Get the difference of the given auth chains.
+///
+/// The returned iterator yields each ID that appears in some but not all of the `auth_chain_sets`
+/// exactly once, in no particular order. It is empty if there are less than two auth chains.
+///
+/// This is the auth chain difference used by [`StateResolution::resolve()`], which can be useful to
+/// prefetch the events that are part of the full conflicted set. Use [`AuthChainDiff`] to update
+/// the difference incrementally.
+pub fn get_auth_chain_diff<Id>(auth_chain_sets: &Vec<HashSet<Id>>) -> impl Iterator<Item = Id>
 where
     Id: Clone + Eq + Hash,
 {
-    let num_sets = auth_chain_sets.len();
-    let mut id_counts: HashMap<Id, usize> = HashMap::new();
-    for id in auth_chain_sets.into_iter().flatten() {
-        *id_counts.entry(id.clone()).or_default() += 1;
+    let mut auth_chain_diff = AuthChainDiff::new();
+    for auth_chain in auth_chain_sets {
+        auth_chain_diff.add_fork(auth_chain);
     }
 
-    id_counts.into_iter().filter_map(move |(id, count)| (count < num_sets).then_some(id))
+    auth_chain_diff.into_diff()
 }
 
-/// Events are sorted from "earliest" to "latest".
+/// The difference of the auth chains of a set of forks, that can be updated incrementally.
 ///
-/// They are compared using the negative power level (reverse topological ordering), the origin
-/// server timestamp and in case of a tie the `EventId`s are compared lexicographically.
-///
-/// The power level is negative because a higher power level is equated to an earlier (further back
-/// in time) origin server timestamp.
-#[instrument(level = "debug", skip_all)]
-async fn reverse_topological_power_sort<E, F, Fut>(
-    events_to_sort: Vec<E::Id>,
-    auth_diff: &HashSet<E::Id>,
-    fetch_event: &F,
-) -> Result<Vec<E::Id>>
+/// This keeps track of the number of forks whose auth chain contains each event, so forks can be
+/// added and removed without computing the difference of all the auth chains again.
+#[derive(Clone, Debug)]
+pub struct AuthChainDiff<Id> {
+    /// The number of forks whose auth chain contains each event.
+    id_counts: HashMap<Id, usize>,
+
+    /// The number of forks.
+    num_forks: usize,
+}
+
+impl<Id> AuthChainDiff<Id>
 where
-    F: Fn(E::Id) -> Fut + Sync,
-    Fut: Future<Output = Option<E>> + Send,
-    E: Event + Send,
-    E::Id: Borrow<EventId> + Send + Sync,
+    Id: Clone + Eq + Hash,
 {
-    debug!("reverse topological sort of power events");
+    /// Creates a new `AuthChainDiff` without any fork.
+    pub fn new() -> Self {
+        Self { id_counts: HashMap::new(), num_forks: 0 }
+    }
 
-    let mut graph = HashMap::new();
-    for event_id in events_to_sort {
-        add_event_and_auth_chain_to_graph(&mut graph, event_id, auth_diff, fetch_event).await;
+    /// Add a fork with the given auth chain.
+    pub fn add_fork(&mut self, auth_chain: &HashSet<Id>) {
+        for id in auth_chain {
+            *self.id_counts.entry(id.clone()).or_default() += 1;
+        }
 
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
+        self.num_forks += 1;
     }
 
-    // This is used in the `key_fn` passed to the lexico_topo_sort fn
-    let mut event_to_pl = HashMap::new();
-    for event_id in graph.keys() {
-        let pl = get_power_level_for_sender(event_id, fetch_event).await?;
-        debug!(
-            event_id = event_id.borrow().as_str(),
-            power_level = i64::from(pl),
-            "found the power level of an event's sender",
-        );
+    /// Remove a fork with the given auth chain.
+    ///
+    /// The auth chain must be the same as the one that was used to add the fork with
+    /// [`AuthChainDiff::add_fork()`], otherwise the difference will be wrong.
+    pub fn remove_fork(&mut self, auth_chain: &HashSet<Id>) {
+        for id in auth_chain {
+            if let Some(count) = self.id_counts.get_mut(id) {
+                *count -= 1;
+
+                if *count == 0 {
+                    self.id_counts.remove(id);
+                }
+            }
+        }
 
-        event_to_pl.insert(event_id.clone(), pl);
+        self.num_forks = self.num_forks.saturating_sub(1);
+    }
 
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
+    /// The number of forks.
+    pub fn num_forks(&self) -> usize {
+        self.num_forks
     }
 
-    let event_to_pl = &event_to_pl;
-    let fetcher = |event_id: E::Id| async move {
-        let pl = *event_to_pl.get(event_id.borrow()).ok_or_else(|| Error::NotFound("".into()))?;
-        let ev = fetch_event(event_id).await.ok_or_else(|| Error::NotFound("".into()))?;
-        Ok((pl, ev.origin_server_ts()))
-    };
+    /// The events that appear in the auth chains of some forks but not others.
+    pub fn iter(&self) -> impl Iterator<Item = &Id> {
+        self.id_counts.iter().filter_map(|(id, count)| (*count < self.num_forks).then_some(id))
+    }
 
-    lexicographical_topological_sort(&graph, &fetcher).await
+    /// Convert this into the events that appear in the auth chains of some forks but not others.
+    pub fn into_diff(self) -> impl Iterator<Item = Id> {
+        let num_forks = self.num_forks;
+        self.id_counts.into_iter().filter_map(move |(id, count)| (count < num_forks).then_some(id))
+    }
 }
 
-/// Sorts the event graph based on number of outgoing/incoming edges.
-///
-/// `key_fn` is used as to obtain the power level and age of an event for breaking ties (together
-/// with the event ID).
-#[instrument(level = "debug", skip_all)]
-pub async fn lexicographical_topological_sort<Id, F, Fut>(
-    graph: &HashMap<Id, HashSet<Id>>,
-    key_fn: &F,
-) -> Result<Vec<Id>>
+impl<Id> Default for AuthChainDiff<Id>
 where
-    F: Fn(Id) -> Fut,
-    Fut: Future<Output = Result<(Int, MilliSecondsSinceUnixEpoch)>> + Send,
-    Id: Borrow<EventId> + Clone + Eq + Hash + Ord + Send,
+    Id: Clone + Eq + Hash,
 {
-    #[derive(Eq, Ord, PartialEq, PartialOrd)]
-    struct TieBreaker<'a, Id> {
-        inv_power_level: Int,
-        age: MilliSecondsSinceUnixEpoch,
-        event_id: &'a Id,
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    debug!("starting lexicographical topological sort");
-    // NOTE: an event that has no incoming edges happened most recently,
-    // and an event that has no outgoing edges happened least recently.
+/// A cache for the data about events that is computed while sorting the power events.
+///
+/// It stores the power level of the sender of each event, as found in the `auth_events` of the
+/// event, and whether each event is a power event. It can be shared between the resolutions of
+/// the state of a room with [`ResolveOptions::power_level_cache`], to avoid computing this data
+/// again for the events that are part of several resolutions, like during a busy join.
+///
+/// This data only depends on the event itself, so the cache is keyed by event ID and never needs
+/// to be invalidated when a new `m.room.power_levels` event is seen. However, a cache should not
+/// be shared between rooms, because an event ID is not guaranteed to refer to the same event in
+/// different rooms.
+///
+/// The cache holds at most [`capacity()`](Self::capacity) events. When it is full, the events
+/// that were added first are evicted.
+#[derive(Debug)]
+pub struct PowerLevelCache {
+    /// The maximum number of cached events.
+    capacity: usize,
+
+    /// The cached events.
+    entries: Mutex<PowerLevelCacheEntries>,
+}
 
-    // NOTE: this is basically Kahn's algorithm except we look at nodes with no
-    // outgoing edges, c.f.
-    // https://en.wikipedia.org/wiki/Topological_sorting#Kahn's_algorithm
+impl PowerLevelCache {
+    /// The default maximum number of cached events.
+    pub const DEFAULT_CAPACITY: usize = 100_000;
 
-    // outdegree_map is an event referring to the events before it, the
-    // more outdegree's the more recent the event.
-    let mut outdegree_map = graph.clone();
+    /// Creates a new empty `PowerLevelCache` holding at most [`Self::DEFAULT_CAPACITY`] events.
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
 
-    // The number of events that depend on the given event (the EventId key)
-    // How many events reference this event in the DAG as a parent
-    let mut reverse_graph: HashMap<_, HashSet<_>> = HashMap::new();
+    /// Creates a new empty `PowerLevelCache` holding at most `capacity` events.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { capacity, entries: Default::default() }
+    }
 
-    // Vec of nodes that have zero out degree, least recent events.
-    let mut zero_outdegree = Vec::new();
+    /// The maximum number of cached events.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
 
-    for (node, edges) in graph {
-        if edges.is_empty() {
-            let (power_level, age) = key_fn(node.clone()).await?;
-            // The `Reverse` is because rusts `BinaryHeap` sorts largest -> smallest we need
-            // smallest -> largest
-            zero_outdegree.push(Reverse(TieBreaker {
-                inv_power_level: -power_level,
-                age,
-                event_id: node,
-            }));
-        }
+    /// The number of cached events.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
 
-        reverse_graph.entry(node).or_default();
-        for edge in edges {
-            reverse_graph.entry(edge).or_default().insert(node);
-        }
+    /// Whether there is no cached event.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    let mut heap = BinaryHeap::from(zero_outdegree);
+    /// Remove all the cached events.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.clear();
+        entries.order.clear();
+    }
 
-    // We remove the oldest node (most incoming edges) and check against all other
-    let mut sorted = vec![];
-    // Destructure the `Reverse` and take the smallest `node` each time
-    while let Some(Reverse(item)) = heap.pop() {
-        let node = item.event_id;
+    /// Only keep the cached events for which `f` returns `true`.
+    pub fn retain(&self, mut f: impl FnMut(&EventId) -> bool) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.retain(|event_id, _| f(event_id));
 
-        for &parent in reverse_graph.get(node).expect("EventId in heap is also in reverse_graph") {
-            // The number of outgoing edges this node has
-            let out = outdegree_map
-                .get_mut(parent.borrow())
-                .expect("outdegree_map knows of all referenced EventIds");
+        let PowerLevelCacheEntries { map, order } = &mut *entries;
+        order.retain(|event_id| map.contains_key(event_id));
+    }
 
-            // Only push on the heap once older events have been cleared
-            out.remove(node.borrow());
-            if out.is_empty() {
-                let (power_level, age) = key_fn(parent.clone()).await?;
-                heap.push(Reverse(TieBreaker {
-                    inv_power_level: -power_level,
-                    age,
-                    event_id: parent,
-                }));
-            }
+    fn power_level(&self, event_id: &EventId) -> Option<Int> {
+        self.entries.lock().unwrap().map.get(event_id).and_then(|entry| entry.power_level)
+    }
+
+    fn insert_power_level(&self, event_id: &EventId, power_level: Int) {
+        self.update(event_id, |entry| entry.power_level = Some(power_level));
+    }
+
+    fn is_power_event(&self, event_id: &EventId) -> Option<bool> {
+        self.entries.lock().unwrap().map.get(event_id).and_then(|entry| entry.is_power_event)
+    }
+
+    fn insert_is_power_event(&self, event_id: &EventId, is_power: bool) {
+        self.update(event_id, |entry| entry.is_power_event = Some(is_power));
+    }
+
+    fn update(&self, event_id: &EventId, f: impl FnOnce(&mut CachedEventData)) {
+        if self.capacity == 0 {
+            return;
         }
 
-        // synapse yields we push then return the vec
-        sorted.push(node.clone());
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.map.get_mut(event_id) {
+            f(entry);
+            return;
+        }
+
+        while entries.map.len() >= self.capacity {
+            let Some(oldest) = entries.order.pop_front() else { break };
+            entries.map.remove(&oldest);
+        }
+
+        let mut entry = CachedEventData::default();
+        f(&mut entry);
+        entries.map.insert(event_id.to_owned(), entry);
+        entries.order.push_back(event_id.to_owned());
     }
+}
 
-    Ok(sorted)
+impl Default for PowerLevelCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-/// Find the power level for the sender of `event_id` or return a default value of zero.
+/// The events of a [`PowerLevelCache`].
+#[derive(Debug, Default)]
+struct PowerLevelCacheEntries {
+    /// The data of each event.
+    map: HashMap<OwnedEventId, CachedEventData>,
+
+    /// The IDs of the events, in the order they were added.
+    order: VecDeque<OwnedEventId>,
+}
+
+/// The data of an event in a [`PowerLevelCache`].
+#[derive(Clone, Copy, Debug, Default)]
+struct CachedEventData {
+    /// The power level of the sender of the event.
+    power_level: Option<Int>,
+
+    /// Whether the event is a power event.
+    is_power_event: Option<bool>,
+}
+
+/// Returns the given events and their full recursive set of `auth_events`.
 ///
-/// Do NOT use this any where but topological sort, we find the power level for the eventId
-/// at the eventId's generation (we walk backwards to `EventId`s most recent previous power level
-/// event).
-async fn get_power_level_for_sender<E, F, Fut>(
-    event_id: &E::Id,
+/// Events that can't be fetched are included, but their `auth_events` are not.
+async fn auth_chain<E, F, Fut>(
+    event_ids: impl IntoIterator<Item = E::Id>,
     fetch_event: &F,
-) -> serde_json::Result<Int>
+) -> HashSet<E::Id>
 where
     F: Fn(E::Id) -> Fut,
     Fut: Future<Output = Option<E>> + Send,
     E: Event + Send,
-    E::Id: Borrow<EventId> + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
 {
-    debug!("fetch event ({event_id}) senders power level");
-
-    let event = fetch_event(event_id.clone()).await;
-    let mut pl = None;
-
-    for aid in event.as_ref().map(|pdu| pdu.auth_events()).into_iter().flatten() {
-        if let Some(aev) = fetch_event(aid.clone()).await {
-            if is_type_and_key(&aev, &TimelineEventType::RoomPowerLevels, "") {
-                pl = Some(aev);
-                break;
-            }
-        }
-    }
-
-    let content: PowerLevelsContentFields = match pl {
-        None => return Ok(int!(0)),
-        Some(ev) => from_json_str(ev.content().get())?,
-    };
-
-    if let Some(ev) = event {
-        if let Some(&user_level) = content.users.get(ev.sender()) {
-            debug!("found {} at power_level {user_level}", ev.sender());
-            return Ok(user_level);
-        }
-    }
-
-    Ok(content.users_default)
+    let event_ids = event_ids.into_iter().collect::<HashSet<_>>();
+    let mut chain = walk_auth_events(event_ids.iter().cloned(), fetch_event, None).await;
+    chain.extend(event_ids);
+    chain
 }
 
-/// Check the that each event is authenticated based on the events before it.
+/// Get the full auth chain of an event.
 ///
-/// ## Returns
+/// This walks the `auth_events` of the event transitively, and returns the IDs of all the events
+/// that were found, without the event itself. The union of the auth chains of the events of a
+/// state set is the auth chain set expected by the resolution functions, like
+/// [`StateResolution::resolve()`].
 ///
-/// The `unconflicted_state` combined with the newly auth'ed events. So any event that fails the
-/// `event_auth::auth_check` will be excluded from the returned state map.
+/// Each event is fetched at most once, so cycles in a malformed auth graph don't cause an infinite
+/// loop. Events that can't be fetched are included, but their `auth_events` are not.
 ///
-/// For each `events_to_check` event we gather the events needed to auth it from the the
-/// `fetch_event` closure and verify each event using the `event_auth::auth_check` function.
-async fn iterative_auth_check<E, F, Fut>(
-    room_version: &RoomVersion,
-    events_to_check: &[E::Id],
-    unconflicted_state: StateMap<E::Id>,
+/// ## Arguments
+///
+/// * `event_id` - The ID of the event.
+///
+/// * `fetch_event` - The closure to fetch events.
+///
+/// * `max_depth` - The maximum number of generations of auth events to walk. With `Some(1)`, only
+///   the `auth_events` of the event are returned. With `None`, the whole auth chain is returned.
+pub async fn auth_chain_for<E, F, Fut>(
+    event_id: E::Id,
     fetch_event: &F,
-) -> Result<StateMap<E::Id>>
+    max_depth: Option<usize>,
+) -> HashSet<E::Id>
 where
     F: Fn(E::Id) -> Fut,
     Fut: Future<Output = Option<E>> + Send,
     E: Event + Send,
     E::Id: Borrow<EventId> + Clone + Send,
-    for<'a> &'a E: Send,
 {
-    debug!("starting iterative auth check");
-
-    trace!(list = ?events_to_check, "events to check");
-
-    let mut resolved_state = unconflicted_state;
+    let mut chain = walk_auth_events([event_id.clone()], fetch_event, max_depth).await;
+    chain.remove::<E::Id>(&event_id);
+    chain
+}
 
-    for event_id in events_to_check {
-        let event = fetch_event(event_id.clone())
-            .await
-            .ok_or_else(|| Error::NotFound(format!("Failed to find {event_id}")))?;
-        let state_key = event
-            .state_key()
-            .ok_or_else(|| Error::InvalidPdu("State event had no state key".to_owned()))?;
+/// Walk the `auth_events` of the given events transitively, one generation at a time.
+///
+/// Returns the IDs of the auth events that were found, up to `max_depth` generations from the
+/// given events. The given events are not fetched again if they are found in the auth chain of
+/// other given events.
+async fn walk_auth_events<E, F, Fut>(
+    event_ids: impl IntoIterator<Item = E::Id>,
+    fetch_event: &F,
+    max_depth: Option<usize>,
+) -> HashSet<E::Id>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+{
+    let mut generation = event_ids.into_iter().collect::<Vec<_>>();
+    let mut visited = generation.iter().cloned().collect::<HashSet<_>>();
+    let mut chain = HashSet::new();
+    let mut depth = 0;
+
+    while !generation.is_empty() && max_depth.map_or(true, |max_depth| depth < max_depth) {
+        depth += 1;
+
+        let mut next_generation = Vec::new();
+        for event_id in generation {
+            let Some(event) = fetch_event(event_id).await else {
+                continue;
+            };
 
-        let mut auth_events = StateMap::new();
-        for aid in event.auth_events() {
-            if let Some(ev) = fetch_event(aid.clone()).await {
-                // TODO synapse check "rejected_reason" which is most likely
-                // related to soft-failing
-                auth_events.insert(
-                    ev.event_type().with_state_key(ev.state_key().ok_or_else(|| {
-                        Error::InvalidPdu("State event had no state key".to_owned())
-                    })?),
-                    ev,
-                );
-            } else {
-                warn!(event_id = aid.borrow().as_str(), "missing auth event");
-            }
-        }
+            for auth_event_id in event.auth_events() {
+                chain.insert(auth_event_id.clone());
 
-        for key in auth_types_for_event(
-            event.event_type(),
-            event.sender(),
-            Some(state_key),
-            event.content(),
-        )? {
-            if let Some(ev_id) = resolved_state.get(&key) {
-                if let Some(event) = fetch_event(ev_id.clone()).await {
-                    // TODO synapse checks `rejected_reason` is None here
-                    auth_events.insert(key.to_owned(), event);
+                if visited.insert(auth_event_id.clone()) {
+                    next_generation.push(auth_event_id.clone());
                 }
             }
         }
 
-        debug!("event to check {:?}", event.event_id());
+        generation = next_generation;
+    }
 
-        // The key for this is (eventType + a state_key of the signed token not sender) so
-        // search for it
-        let current_third_party = auth_events.iter().find_map(|(_, pdu)| {
-            (*pdu.event_type() == TimelineEventType::RoomThirdPartyInvite).then_some(pdu)
-        });
+    chain
+}
 
-        let fetch_state = |ty: &StateEventType, key: &str| {
-            future::ready(auth_events.get(&ty.with_state_key(key)))
-        };
+/// Select the control events of the full conflicted set and sort them in the order in which they
+/// are authorized during state resolution.
+///
+/// Control events are the `m.room.create`, `m.room.power_levels` and `m.room.join_rules` events
+/// with an empty state key, and the `m.room.member` events that kick or ban a user. They are sorted
+/// with the reverse topological power ordering, using the auth events that are part of
+/// `full_conflicted`.
+///
+/// This is the first step of [`StateResolution::resolve()`], which can be useful to debug the
+/// resolution of a room.
+pub async fn sort_control_events<E, F, Fut>(
+    full_conflicted: &HashSet<E::Id>,
+    fetch_event: &F,
+) -> Result<Vec<E::Id>>
+where
+    F: Fn(E::Id) -> Fut + Sync,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+{
+    sort_control_events_batched(
+        full_conflicted,
+        None,
+        fetch_event,
+        fetch_event,
+        &|| true,
+        &(),
+        &ResolveOptions::default(),
+    )
+    .await
+}
 
-        if auth_check(room_version, &event, current_third_party, fetch_state).await? {
-            // add event to resolved state map
-            resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
-        } else {
-            // synapse passes here on AuthError. We do not add this event to resolved_state.
-            warn!("event {event_id} failed the authentication check");
-        }
+/// Sort the control events of the given full conflicted set, using `batch_fetch` to fetch the auth
+/// events of the control events.
+///
+/// Returns [`Error::Cancelled`] if `should_continue` returns `false` during the sort.
+///
+/// The power levels are deserialized according to `room_version`. If it is `None`, power levels
+/// encoded as strings are accepted, like before room version 10.
+#[allow(clippy::too_many_arguments)]
+async fn sort_control_events_batched<E, F, Fut, B, Continue, Observer>(
+    full_conflicted: &HashSet<E::Id>,
+    room_version: Option<&RoomVersion>,
+    fetch_event: &F,
+    batch_fetch: &B,
+    should_continue: &Continue,
+    observer: &Observer,
+    options: &ResolveOptions,
+) -> Result<Vec<E::Id>>
+where
+    Observer: StateResObserver + Sync,
+    F: Fn(E::Id) -> Fut + Sync,
+    Fut: Future<Output = Option<E>> + Send,
+    B: BatchFetch<E> + Sync,
+    Continue: Fn() -> bool + Sync,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+{
+    let power_level_cache = options.power_level_cache.as_deref();
 
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
-    }
-    Ok(resolved_state)
+    // Get only the control events with a state_key: "" or ban/kick event (sender != state_key)
+    let control_events = stream::iter(full_conflicted.iter())
+        .filter(|&id| is_power_event_id(id, fetch_event, power_level_cache))
+        .map(Clone::clone)
+        .collect::<Vec<_>>()
+        .await;
+
+    // Sort the control events based on power_level/clock/event_id and outgoing/incoming edges
+    reverse_topological_power_sort_with(
+        control_events,
+        full_conflicted,
+        room_version,
+        fetch_event,
+        batch_fetch,
+        should_continue,
+        observer,
+        options,
+    )
+    .await
 }
 
-/// Returns the sorted `to_sort` list of `EventId`s based on a mainline sort using the depth of
-/// `resolved_power_level`, the server timestamp, and the eventId.
+/// Sort the given control events with the reverse topological power ordering of the state
+/// resolution algorithm.
 ///
-/// The depth of the given event is calculated based on the depth of it's closest "parent"
-/// power_level event. If there have been two power events the after the most recent are depth 0,
-/// the events before (with the first power level as a parent) will be marked as depth 1. depth 1 is
-/// "older" than depth 0.
-async fn mainline_sort<E, F, Fut>(
-    to_sort: &[E::Id],
-    resolved_power_level: Option<E::Id>,
+/// Events are sorted from "earliest" to "latest". The graph that is sorted contains
+/// `events_to_sort` and their auth events that are in `auth_diff`, usually the full conflicted set,
+/// and an event always comes after its auth events. When several events can come next, the ties
+/// are broken by comparing, in that order:
+///
+/// 1. The power level of the sender of the event, from the `m.room.power_levels` event in its auth
+///    events. A higher power level comes first.
+/// 2. The `origin_server_ts` of the event. An older event comes first.
+/// 3. The ID of the event, lexicographically.
+///
+/// This is used by [`StateResolution::resolve()`] to sort the conflicted control events, before
+/// checking them against the authorization rules. The returned list contains the auth events of
+/// `events_to_sort` that are in `auth_diff`.
+///
+/// # Example
+///
+/// ```
+/// # use std::collections::HashSet;
+/// #
+/// # use futures_util::{future::ready, FutureExt};
+/// # use js_int::{uint, UInt};
+/// # use ruma_common::{
+/// #     owned_event_id, owned_room_id, user_id, MilliSecondsSinceUnixEpoch, OwnedEventId,
+/// #     OwnedRoomId, OwnedUserId, RoomId, UserId,
+/// # };
+/// # use ruma_events::TimelineEventType;
+/// # use ruma_state_res::{reverse_topological_power_sort, Event};
+/// # use serde_json::{json, value::{to_raw_value, RawValue}};
+/// #
+/// # struct Pdu {
+/// #     event_id: OwnedEventId,
+/// #     room_id: OwnedRoomId,
+/// #     sender: OwnedUserId,
+/// #     origin_server_ts: MilliSecondsSinceUnixEpoch,
+/// #     kind: TimelineEventType,
+/// #     content: Box<RawValue>,
+/// #     auth_events: Vec<OwnedEventId>,
+/// # }
+/// #
+/// # impl Event for Pdu {
+/// #     type Id = OwnedEventId;
+/// #
+/// #     fn event_id(&self) -> &OwnedEventId { &self.event_id }
+/// #     fn room_id(&self) -> &RoomId { &self.room_id }
+/// #     fn sender(&self) -> &UserId { &self.sender }
+/// #     fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch { self.origin_server_ts }
+/// #     fn depth(&self) -> UInt { uint!(0) }
+/// #     fn event_type(&self) -> &TimelineEventType { &self.kind }
+/// #     fn content(&self) -> &RawValue { &self.content }
+/// #     fn state_key(&self) -> Option<&str> { Some("") }
+/// #     fn prev_events(&self) -> impl DoubleEndedIterator<Item = &OwnedEventId> + Send + '_ {
+/// #         std::iter::empty()
+/// #     }
+/// #     fn auth_events(&self) -> impl DoubleEndedIterator<Item = &OwnedEventId> + Send + '_ {
+/// #         self.auth_events.iter()
+/// #     }
+/// #     fn redacts(&self) -> Option<&OwnedEventId> { None }
+/// # }
+/// #
+/// # fn pdu(
+/// #     event_id: OwnedEventId,
+/// #     sender: &UserId,
+/// #     ts: u32,
+/// #     kind: TimelineEventType,
+/// #     content: serde_json::Value,
+/// #     auth_events: &[OwnedEventId],
+/// # ) -> Pdu {
+/// #     Pdu {
+/// #         event_id,
+/// #         room_id: owned_room_id!("!room:example.org"),
+/// #         sender: sender.to_owned(),
+/// #         origin_server_ts: MilliSecondsSinceUnixEpoch(ts.into()),
+/// #         kind,
+/// #         content: to_raw_value(&content).unwrap(),
+/// #         auth_events: auth_events.to_vec(),
+/// #     }
+/// # }
+/// #
+/// let alice = user_id!("@alice:example.org");
+/// let bob = user_id!("@bob:example.org");
+///
+/// let create = owned_event_id!("$create");
+/// let power_levels = owned_event_id!("$power_levels");
+/// let join_rules_alice = owned_event_id!("$join_rules_alice");
+/// let join_rules_bob = owned_event_id!("$join_rules_bob");
+///
+/// let events = [
+///     pdu(create.clone(), alice, 0, TimelineEventType::RoomCreate, json!({}), &[]),
+///     pdu(
+///         power_levels.clone(),
+///         alice,
+///         1,
+///         TimelineEventType::RoomPowerLevels,
+///         json!({ "users": { alice: 100, bob: 50 } }),
+///         &[create.clone()],
+///     ),
+///     // Bob changed the join rules before Alice.
+///     pdu(
+///         join_rules_alice.clone(),
+///         alice,
+///         10,
+///         TimelineEventType::RoomJoinRules,
+///         json!({ "join_rule": "invite" }),
+///         &[create.clone(), power_levels.clone()],
+///     ),
+///     pdu(
+///         join_rules_bob.clone(),
+///         bob,
+///         5,
+///         TimelineEventType::RoomJoinRules,
+///         json!({ "join_rule": "public" }),
+///         &[create.clone(), power_levels.clone()],
+///     ),
+/// ];
+/// let fetch_event = |id: OwnedEventId| ready(events.iter().find(|ev| ev.event_id == id));
+///
+/// let sorted = reverse_topological_power_sort(
+///     vec![join_rules_bob.clone(), join_rules_alice.clone()],
+///     &HashSet::new(),
+///     &fetch_event,
+/// )
+/// .now_or_never()
+/// .unwrap()
+/// .unwrap();
+///
+/// // Alice has a higher power level than Bob, so her event comes first.
+/// assert_eq!(sorted, [join_rules_alice, join_rules_bob]);
+/// ```
+pub async fn reverse_topological_power_sort<E, F, Fut>(
+    events_to_sort: Vec<E::Id>,
+    auth_diff: &HashSet<E::Id>,
     fetch_event: &F,
 ) -> Result<Vec<E::Id>>
 where
-    F: Fn(E::Id) -> Fut,
+    F: Fn(E::Id) -> Fut + Sync,
     Fut: Future<Output = Option<E>> + Send,
     E: Event + Send,
-    E::Id: Borrow<EventId> + Clone + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
 {
-    debug!("mainline sort of events");
+    reverse_topological_power_sort_with(
+        events_to_sort,
+        auth_diff,
+        None,
+        fetch_event,
+        fetch_event,
+        &|| true,
+        &(),
+        &ResolveOptions::default(),
+    )
+    .await
+}
 
-    // There are no EventId's to sort, bail.
-    if to_sort.is_empty() {
-        return Ok(vec![]);
-    }
+/// Sort the given control events like [`reverse_topological_power_sort`], using `batch_fetch` to
+/// fetch the auth events and calling `should_continue` regularly.
+///
+/// The power levels are deserialized according to `room_version`, if any.
+#[instrument(level = "debug", skip_all)]
+#[allow(clippy::too_many_arguments)]
+async fn reverse_topological_power_sort_with<E, F, Fut, B, Continue, Observer>(
+    events_to_sort: Vec<E::Id>,
+    auth_diff: &HashSet<E::Id>,
+    room_version: Option<&RoomVersion>,
+    fetch_event: &F,
+    batch_fetch: &B,
+    should_continue: &Continue,
+    observer: &Observer,
+    options: &ResolveOptions,
+) -> Result<Vec<E::Id>>
+where
+    Observer: StateResObserver + Sync,
+    F: Fn(E::Id) -> Fut + Sync,
+    Fut: Future<Output = Option<E>> + Send,
+    B: BatchFetch<E> + Sync,
+    Continue: Fn() -> bool + Sync,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+{
+    debug!("reverse topological sort of power events");
 
-    let mut mainline = vec![];
-    let mut pl = resolved_power_level;
-    while let Some(p) = pl {
-        mainline.push(p.clone());
+    let mut graph = HashMap::new();
+    for event_id in events_to_sort {
+        add_event_and_auth_chain_to_graph(
+            &mut graph,
+            event_id,
+            auth_diff,
+            batch_fetch,
+            options.max_graph_nodes,
+        )
+        .await?;
 
-        let event = fetch_event(p.clone())
-            .await
-            .ok_or_else(|| Error::NotFound(format!("Failed to find {p}")))?;
-        pl = None;
-        for aid in event.auth_events() {
-            let ev = fetch_event(aid.clone())
-                .await
-                .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
-            if is_type_and_key(&ev, &TimelineEventType::RoomPowerLevels, "") {
-                pl = Some(aid.to_owned());
-                break;
-            }
-        }
         // TODO: if these functions are ever made async here
         // is a good place to yield every once in a while so other
         // tasks can make progress
     }
 
-    let mainline_map = mainline
-        .iter()
-        .rev()
-        .enumerate()
-        .map(|(idx, eid)| ((*eid).clone(), idx))
-        .collect::<HashMap<_, _>>();
+    observer.on_graph_nodes(graph.len());
 
-    let mut order_map = HashMap::new();
-    for ev_id in to_sort.iter() {
-        if let Some(event) = fetch_event(ev_id.clone()).await {
-            if let Ok(depth) = get_mainline_depth(Some(event), &mainline_map, fetch_event).await {
-                order_map.insert(
-                    ev_id,
-                    (
-                        depth,
-                        fetch_event(ev_id.clone()).await.map(|ev| ev.origin_server_ts()),
-                        ev_id,
-                    ),
-                );
-            }
-        }
+    // This is used in the `key_fn` passed to the lexico_topo_sort fn
+    let mut event_to_pl = HashMap::new();
+    for event_id in graph.keys() {
+        let pl = get_power_level_for_sender(
+            event_id,
+            room_version,
+            fetch_event,
+            options.power_level_cache.as_deref(),
+        )
+        .await?;
+        debug!(
+            event_id = event_id.borrow().as_str(),
+            power_level = i64::from(pl),
+            "found the power level of an event's sender",
+        );
+
+        event_to_pl.insert(event_id.clone(), pl);
 
         // TODO: if these functions are ever made async here
         // is a good place to yield every once in a while so other
         // tasks can make progress
     }
 
-    // Sort the event_ids by their depth, timestamp and EventId
-    // unwrap is OK order map and sort_event_ids are from to_sort (the same Vec)
-    let mut sort_event_ids = order_map.keys().map(|&k| k.clone()).collect::<Vec<_>>();
-    sort_event_ids.sort_by_key(|sort_id| order_map.get(sort_id).unwrap());
+    let event_to_pl = &event_to_pl;
+    let fetcher = |event_id: E::Id| async move {
+        let pl = *event_to_pl.get(event_id.borrow()).ok_or_else(|| Error::NotFound("".into()))?;
+        let ev = fetch_event(event_id).await.ok_or_else(|| Error::NotFound("".into()))?;
+        Ok((pl, ev.origin_server_ts()))
+    };
 
-    Ok(sort_event_ids)
+    lexicographical_topological_sort_cancellable(&graph, &fetcher, should_continue).await
 }
 
-/// Get the mainline depth from the `mainline_map` or finds a power_level event that has an
-/// associated mainline depth.
-async fn get_mainline_depth<E, F, Fut>(
-    mut event: Option<E>,
-    mainline_map: &HashMap<E::Id, usize>,
-    fetch_event: &F,
-) -> Result<usize>
+/// Sorts the event graph based on number of outgoing/incoming edges.
+///
+/// `key_fn` is used as to obtain the power level and age of an event for breaking ties (together
+/// with the event ID).
+///
+/// This is the same as [`lexicographical_topological_sort_by`] with the comparator of the state
+/// resolution algorithm.
+#[instrument(level = "debug", skip_all)]
+pub async fn lexicographical_topological_sort<Id, F, Fut>(
+    graph: &HashMap<Id, HashSet<Id>>,
+    key_fn: &F,
+) -> Result<Vec<Id>>
 where
-    F: Fn(E::Id) -> Fut,
-    Fut: Future<Output = Option<E>> + Send,
-    E: Event + Send,
-    E::Id: Borrow<EventId> + Send,
+    F: Fn(Id) -> Fut,
+    Fut: Future<Output = Result<(Int, MilliSecondsSinceUnixEpoch)>> + Send,
+    Id: Borrow<EventId> + Clone + Eq + Hash + Ord + Send,
 {
-    while let Some(sort_ev) = event {
-        debug!(event_id = sort_ev.event_id().borrow().as_str(), "mainline");
-        let id = sort_ev.event_id();
-        if let Some(depth) = mainline_map.get(id.borrow()) {
-            return Ok(*depth);
-        }
+    lexicographical_topological_sort_cancellable(graph, key_fn, &|| true).await
+}
 
-        event = None;
-        for aid in sort_ev.auth_events() {
-            let aev = fetch_event(aid.clone())
-                .await
-                .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
-            if is_type_and_key(&aev, &TimelineEventType::RoomPowerLevels, "") {
-                event = Some(aev);
-                break;
-            }
-        }
+/// Sorts the event graph like [`lexicographical_topological_sort`], calling `should_continue`
+/// regularly and returning [`Error::Cancelled`] if it returns `false`.
+async fn lexicographical_topological_sort_cancellable<Id, F, Fut, Continue>(
+    graph: &HashMap<Id, HashSet<Id>>,
+    key_fn: &F,
+    should_continue: &Continue,
+) -> Result<Vec<Id>>
+where
+    F: Fn(Id) -> Fut,
+    Fut: Future<Output = Result<(Int, MilliSecondsSinceUnixEpoch)>> + Send,
+    Id: Borrow<EventId> + Clone + Eq + Hash + Ord + Send,
+    Continue: Fn() -> bool,
+{
+    let mut keys = HashMap::with_capacity(graph.len());
+    for node in graph.keys() {
+        let (power_level, age) = key_fn(node.clone()).await?;
+        keys.insert(node, (-power_level, age));
     }
-    // Did not find a power level event so we default to zero
-    Ok(0)
+
+    lexicographical_topological_sort_by_cancellable(
+        graph,
+        |a, b| {
+            let (inv_power_level_a, age_a) = keys[a];
+            let (inv_power_level_b, age_b) = keys[b];
+            (inv_power_level_a, age_a, a).cmp(&(inv_power_level_b, age_b, b))
+        },
+        should_continue,
+    )
 }
 
-async fn add_event_and_auth_chain_to_graph<E, F, Fut>(
-    graph: &mut HashMap<E::Id, HashSet<E::Id>>,
-    event_id: E::Id,
-    auth_diff: &HashSet<E::Id>,
-    fetch_event: &F,
-) where
-    F: Fn(E::Id) -> Fut,
-    Fut: Future<Output = Option<E>> + Send,
-    E: Event + Send,
-    E::Id: Borrow<EventId> + Clone + Send,
+/// Sorts the event graph based on number of outgoing/incoming edges, with the given comparator to
+/// break ties.
+///
+/// When several events can be added to the sorted list, the smallest one according to `cmp` is
+/// added first. `cmp` must be a total order, so it should compare the IDs of the events last to
+/// have a deterministic output.
+///
+/// Unlike [`lexicographical_topological_sort`], this doesn't require the IDs to implement `Ord`,
+/// so it can be used with ID types that are only ordered by `cmp`, like their reference hash.
+pub fn lexicographical_topological_sort_by<Id, C>(
+    graph: &HashMap<Id, HashSet<Id>>,
+    cmp: C,
+) -> Vec<Id>
+where
+    C: Fn(&Id, &Id) -> Ordering,
+    Id: Clone + Eq + Hash,
 {
-    let mut state = vec![event_id];
-    while let Some(eid) = state.pop() {
-        graph.entry(eid.clone()).or_default();
-        // Prefer the store to event as the store filters dedups the events
-        for aid in
-            fetch_event(eid.clone()).await.as_ref().map(|ev| ev.auth_events()).into_iter().flatten()
-        {
-            if auth_diff.contains(aid.borrow()) {
-                if !graph.contains_key(aid.borrow()) {
-                    state.push(aid.to_owned());
-                }
+    lexicographical_topological_sort_by_cancellable(graph, cmp, &|| true)
+        .expect("the sort is never cancelled")
+}
+
+/// Sorts the event graph like [`lexicographical_topological_sort_by`], calling `should_continue`
+/// every [`CANCELLATION_CHECK_INTERVAL`] sorted events and returning [`Error::Cancelled`] if it
+/// returns `false`.
+fn lexicographical_topological_sort_by_cancellable<Id, C, Continue>(
+    graph: &HashMap<Id, HashSet<Id>>,
+    cmp: C,
+    should_continue: &Continue,
+) -> Result<Vec<Id>>
+where
+    C: Fn(&Id, &Id) -> Ordering,
+    Id: Clone + Eq + Hash,
+    Continue: Fn() -> bool,
+{
+    struct TieBreaker<'a, Id, C> {
+        event_id: &'a Id,
+        cmp: &'a C,
+    }
+
+    impl<Id, C: Fn(&Id, &Id) -> Ordering> PartialEq for TieBreaker<'_, Id, C> {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    impl<Id, C: Fn(&Id, &Id) -> Ordering> Eq for TieBreaker<'_, Id, C> {}
+
+    impl<Id, C: Fn(&Id, &Id) -> Ordering> PartialOrd for TieBreaker<'_, Id, C> {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl<Id, C: Fn(&Id, &Id) -> Ordering> Ord for TieBreaker<'_, Id, C> {
+        fn cmp(&self, other: &Self) -> Ordering {
+            (self.cmp)(self.event_id, other.event_id)
+        }
+    }
+
+    debug!("starting lexicographical topological sort");
+    // NOTE: an event that has no incoming edges happened most recently,
+    // and an event that has no outgoing edges happened least recently.
+
+    // NOTE: this is basically Kahn's algorithm except we look at nodes with no
+    // outgoing edges, c.f.
+    // https://en.wikipedia.org/wiki/Topological_sorting#Kahn's_algorithm
+
+    // outdegree_map is an event referring to the events before it, the
+    // more outdegree's the more recent the event.
+    let mut outdegree_map = graph.clone();
+
+    // The number of events that depend on the given event (the EventId key)
+    // How many events reference this event in the DAG as a parent
+    let mut reverse_graph: HashMap<_, HashSet<_>> = HashMap::new();
+
+    // Vec of nodes that have zero out degree, least recent events.
+    let mut zero_outdegree = Vec::new();
+
+    for (node, edges) in graph {
+        if edges.is_empty() {
+            // The `Reverse` is because rusts `BinaryHeap` sorts largest -> smallest we need
+            // smallest -> largest
+            zero_outdegree.push(Reverse(TieBreaker { event_id: node, cmp: &cmp }));
+        }
+
+        reverse_graph.entry(node).or_default();
+        for edge in edges {
+            reverse_graph.entry(edge).or_default().insert(node);
+        }
+    }
+
+    let mut heap = BinaryHeap::from(zero_outdegree);
+
+    // We remove the oldest node (most incoming edges) and check against all other
+    let mut sorted = vec![];
+    // Destructure the `Reverse` and take the smallest `node` each time
+    while let Some(Reverse(item)) = heap.pop() {
+        if sorted.len() % CANCELLATION_CHECK_INTERVAL == 0 && !should_continue() {
+            debug!(count = sorted.len(), "lexicographical topological sort cancelled");
+            return Err(Error::Cancelled);
+        }
+
+        let node = item.event_id;
+
+        for &parent in reverse_graph.get(node).expect("EventId in heap is also in reverse_graph") {
+            // The number of outgoing edges this node has
+            let out = outdegree_map
+                .get_mut(parent)
+                .expect("outdegree_map knows of all referenced EventIds");
 
-                // We just inserted this at the start of the while loop
-                graph.get_mut(eid.borrow()).unwrap().insert(aid.to_owned());
+            // Only push on the heap once older events have been cleared
+            out.remove(node);
+            if out.is_empty() {
+                heap.push(Reverse(TieBreaker { event_id: parent, cmp: &cmp }));
             }
         }
+
+        // synapse yields we push then return the vec
+        sorted.push(node.clone());
     }
+
+    Ok(sorted)
 }
 
-async fn is_power_event_id<E, F, Fut>(event_id: &E::Id, fetch: &F) -> bool
+/// Find the power level for the sender of `event_id` or return a default value of zero.
+///
+/// Do NOT use this any where but topological sort, we find the power level for the eventId
+/// at the eventId's generation (we walk backwards to `EventId`s most recent previous power level
+/// event).
+///
+/// The power levels are deserialized according to `room_version`. If it is `None`, power levels
+/// encoded as strings are accepted, like before room version 10.
+async fn get_power_level_for_sender<E, F, Fut>(
+    event_id: &E::Id,
+    room_version: Option<&RoomVersion>,
+    fetch_event: &F,
+    power_level_cache: Option<&PowerLevelCache>,
+) -> serde_json::Result<Int>
 where
     F: Fn(E::Id) -> Fut,
     Fut: Future<Output = Option<E>> + Send,
     E: Event + Send,
     E::Id: Borrow<EventId> + Send,
 {
-    match fetch(event_id.clone()).await.as_ref() {
-        Some(state) => is_power_event(state),
-        _ => false,
+    if let Some(pl) = power_level_cache.and_then(|cache| cache.power_level(event_id.borrow())) {
+        return Ok(pl);
     }
-}
 
-fn is_type_and_key(ev: impl Event, ev_type: &TimelineEventType, state_key: &str) -> bool {
-    ev.event_type() == ev_type && ev.state_key() == Some(state_key)
-}
+    debug!("fetch event ({event_id}) senders power level");
 
-fn is_power_event(event: impl Event) -> bool {
-    match event.event_type() {
-        TimelineEventType::RoomPowerLevels
-        | TimelineEventType::RoomJoinRules
-        | TimelineEventType::RoomCreate => event.state_key() == Some(""),
-        TimelineEventType::RoomMember => {
-            if let Ok(content) = from_json_str::<RoomMemberEventContent>(event.content().get()) {
-                if [MembershipState::Leave, MembershipState::Ban].contains(&content.membership) {
-                    return Some(event.sender().as_str()) != event.state_key();
-                }
-            }
+    let event = fetch_event(event_id.clone()).await;
+    let mut pl = None;
 
-            false
+    for aid in event.as_ref().map(|pdu| pdu.auth_events()).into_iter().flatten() {
+        if let Some(aev) = fetch_event(aid.clone()).await {
+            if is_type_and_key(&aev, &TimelineEventType::RoomPowerLevels, "") {
+                pl = Some(aev);
+                break;
+            }
         }
-        _ => false,
     }
-}
 
-/// Convenience trait for adding event type plus state key to state maps.
-pub trait EventTypeExt {
-    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String);
-}
+    let content: PowerLevelsContentFields = match (pl, room_version) {
+        (None, _) => return Ok(int!(0)),
+        (Some(ev), Some(room_version)) => {
+            deserialize_power_levels_content_fields(ev.content().get(), room_version)?
+        }
+        (Some(ev), None) => from_json_str(ev.content().get())?,
+    };
 
-impl EventTypeExt for StateEventType {
-    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String) {
-        (self, state_key.into())
-    }
-}
+    let Some(ev) = event else {
+        return Ok(content.users_default);
+    };
 
-impl EventTypeExt for TimelineEventType {
-    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String) {
-        (self.to_string().into(), state_key.into())
+    let pl = match content.users.get(ev.sender()) {
+        Some(&user_level) => {
+            debug!("found {} at power_level {user_level}", ev.sender());
+            user_level
+        }
+        None => content.users_default,
+    };
+
+    if let Some(cache) = power_level_cache {
+        cache.insert_power_level(event_id.borrow(), pl);
     }
+
+    Ok(pl)
 }
 
-impl<T> EventTypeExt for &T
+/// Check the that each event is authenticated based on the events before it.
+///
+/// ## Returns
+///
+/// The `unconflicted_state` combined with the newly auth'ed events. So any event that fails the
+/// `event_auth::auth_check` will be excluded from the returned state map, and added to `rejected`
+/// with the reason of the rejection if [`ResolveOptions::report_rejected`] is set.
+///
+/// For each `events_to_check` event we gather the events needed to auth it from the the
+/// `fetch_event` closure and verify each event using the `event_auth::auth_check` function.
+///
+/// `should_continue` is called every [`CANCELLATION_CHECK_INTERVAL`] checked events, and
+/// [`Error::Cancelled`] is returned if it returns `false`.
+#[allow(clippy::too_many_arguments)]
+async fn iterative_auth_check<E, F, Fut, Continue, Observer>(
+    room_version: &RoomVersion,
+    events_to_check: &[E::Id],
+    unconflicted_state: StateMap<E::Id>,
+    fetch_event: &F,
+    options: &ResolveOptions,
+    should_continue: &Continue,
+    observer: &Observer,
+    rejected: &mut Vec<(E::Id, RejectionReason)>,
+) -> Result<StateMap<E::Id>>
 where
-    T: EventTypeExt + Clone,
+    Observer: StateResObserver,
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    Continue: Fn() -> bool,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+    for<'a> &'a E: Send,
 {
-    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String) {
-        self.to_owned().with_state_key(state_key)
-    }
-}
+    debug!("starting iterative auth check");
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        collections::{HashMap, HashSet},
-        sync::Arc,
-    };
+    trace!(list = ?events_to_check, "events to check");
 
-    use js_int::{int, uint};
-    use maplit::{hashmap, hashset};
-    use rand::seq::SliceRandom;
-    use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
-    use ruma_events::{
-        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
-        StateEventType, TimelineEventType,
-    };
-    use serde_json::{json, value::to_raw_value as to_raw_json_value};
-    use tracing::debug;
+    let mut resolved_state = unconflicted_state;
+
+    for (i, event_id) in events_to_check.iter().enumerate() {
+        if i % CANCELLATION_CHECK_INTERVAL == 0 && !should_continue() {
+            debug!(count = i, "iterative auth check cancelled");
+            return Err(Error::Cancelled);
+        }
+
+        let event = fetch_event(event_id.clone())
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Failed to find {event_id}")))?;
+        if !event.is_state_event() {
+            return Err(Error::InvalidPdu(format!("{event_id} is not a state event")));
+        }
+        let state_key = event.state_key().expect("state events have a state key");
+
+        if options.trusted.contains(event_id.borrow()) {
+            debug!("event {event_id} is trusted, skipping the authentication check");
+            resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
+            continue;
+        }
+
+        if options.strict_sender_membership
+            && !is_sender_joined(&event, &resolved_state, fetch_event).await
+        {
+            warn!("sender of event {event_id} is not joined in the resolved state");
+            observer.on_auth_check(event_id.borrow(), false);
+            if options.report_rejected {
+                rejected.push((event_id.clone(), RejectionReason::SenderNotJoined));
+            }
+            continue;
+        }
+
+        if options.enforce_server_acl
+            && server_acl(&resolved_state, fetch_event)
+                .await
+                .is_some_and(|acl| !acl.is_allowed(event.sender_server()))
+        {
+            warn!("server of the sender of event {event_id} is denied by the server ACL");
+            observer.on_auth_check(event_id.borrow(), false);
+            if options.report_rejected {
+                rejected.push((event_id.clone(), RejectionReason::ServerDenied));
+            }
+            continue;
+        }
+
+        let mut auth_events = StateMap::new();
+        let mut seen_auth_event_ids = HashSet::new();
+        let mut has_conflicting_auth_events = false;
+        let mut missing_auth_event = None;
+        for aid in event.auth_events() {
+            // Malformed events might list the same auth event several times.
+            if !seen_auth_event_ids.insert(aid) {
+                continue;
+            }
+
+            if let Some(ev) = fetch_event(aid.clone()).await {
+                // TODO synapse check "rejected_reason" which is most likely
+                // related to soft-failing
+                let key =
+                    ev.event_type().with_state_key(ev.state_key().ok_or_else(|| {
+                        Error::InvalidPdu("State event had no state key".to_owned())
+                    })?);
+
+                // Reject events with several auth events for the same type and state key.
+                if auth_events.insert(key, ev).is_some() {
+                    has_conflicting_auth_events = true;
+                    break;
+                }
+            } else {
+                warn!(event_id = aid.borrow().as_str(), "missing auth event");
+                missing_auth_event.get_or_insert_with(|| aid.borrow().to_owned());
+            }
+        }
+
+        if has_conflicting_auth_events {
+            warn!("event {event_id} has several auth events with the same type and state key");
+            observer.on_auth_check(event_id.borrow(), false);
+            if options.report_rejected {
+                rejected.push((event_id.clone(), RejectionReason::DuplicateAuthEvents));
+            }
+            continue;
+        }
+
+        for key in event.required_auth_types()? {
+            if let Some(ev_id) = resolved_state.get(&key) {
+                if let Some(event) = fetch_event(ev_id.clone()).await {
+                    // TODO synapse checks `rejected_reason` is None here
+                    auth_events.insert(key.to_owned(), event);
+                }
+            }
+        }
+
+        debug!("event to check {:?}", event.event_id());
+
+        // The key for this is (eventType + a state_key of the signed token not sender) so
+        // search for it
+        let current_third_party = third_party_invite_token(&event).and_then(|token| {
+            auth_events.get(&StateEventType::RoomThirdPartyInvite.with_state_key(token))
+        });
+
+        // Whether the check needed state that is not in the auth events, which might be the
+        // missing auth event.
+        let needed_absent_state = AtomicBool::new(false);
+        let fetch_state = |ty: &StateEventType, key: &str| {
+            let state = auth_events.get(&ty.with_state_key(key));
+            if state.is_none() {
+                needed_absent_state.store(true, AtomicOrdering::Relaxed);
+            }
+            future::ready(state)
+        };
+
+        let decision =
+            auth_check_detailed(room_version, &event, current_third_party, fetch_state).await?;
+        observer.on_auth_check(event_id.borrow(), decision.is_allowed());
+
+        match decision {
+            AuthDecision::Allowed => {
+                // add event to resolved state map
+                resolved_state
+                    .insert(event.event_type().with_state_key(state_key), event_id.clone());
+            }
+            AuthDecision::Denied(error) => {
+                // synapse passes here on AuthError. We do not add this event to resolved_state.
+                warn!(%error, "event {event_id} failed the authentication check");
+                if options.report_rejected {
+                    // The event might have been accepted with the missing auth event, but only if
+                    // the check needed state that was not found.
+                    let reason = missing_auth_event
+                        .filter(|_| needed_absent_state.load(AtomicOrdering::Relaxed))
+                        .map_or_else(|| error.into(), RejectionReason::MissingAuthEvent);
+                    rejected.push((event_id.clone(), reason));
+                }
+            }
+        }
+
+        // TODO: if these functions are ever made async here
+        // is a good place to yield every once in a while so other
+        // tasks can make progress
+    }
+    Ok(resolved_state)
+}
+
+/// Check each event of the unconflicted state that is not `trusted` against its own `auth_events`,
+/// and add the events that fail to `failures`, sorted by ID.
+async fn auth_check_unconflicted<E, F, Fut>(
+    room_version: &RoomVersion,
+    unconflicted_state: &StateMap<E::Id>,
+    fetch_event: &F,
+    trusted: &HashSet<OwnedEventId>,
+    failures: &mut Vec<E::Id>,
+) -> Result<()>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+    for<'a> &'a E: Send,
+{
+    let mut event_ids: Vec<_> = unconflicted_state
+        .values()
+        .filter(|id| !trusted.contains((*id).borrow()))
+        .cloned()
+        .collect();
+    event_ids.sort_unstable_by(|a, b| a.borrow().cmp(b.borrow()));
+
+    let options = ResolveOptions { report_rejected: true, ..Default::default() };
+    let mut rejected = Vec::new();
+    for event_id in event_ids {
+        // Check the events one by one against an empty state, so only their own `auth_events`
+        // are used.
+        iterative_auth_check(
+            room_version,
+            slice::from_ref(&event_id),
+            StateMap::new(),
+            fetch_event,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await?;
+    }
+    failures.extend(rejected.into_iter().map(|(event_id, _)| event_id));
+
+    if !failures.is_empty() {
+        warn!(list = ?failures, "unconflicted events failed the authentication check");
+    }
+
+    Ok(())
+}
+
+/// The token of the third-party invite used by the given `m.room.member` event, if any.
+fn third_party_invite_token(event: &impl Event) -> Option<String> {
+    #[derive(Deserialize)]
+    struct GetThirdPartyInvite {
+        third_party_invite: Option<GetSigned>,
+    }
+
+    #[derive(Deserialize)]
+    struct GetSigned {
+        signed: GetToken,
+    }
+
+    #[derive(Deserialize)]
+    struct GetToken {
+        token: String,
+    }
+
+    if *event.event_type() != TimelineEventType::RoomMember {
+        return None;
+    }
+
+    from_json_str::<GetThirdPartyInvite>(event.content().get())
+        .ok()?
+        .third_party_invite
+        .map(|invite| invite.signed.token)
+}
+
+/// Whether the sender of the given event is joined according to the given state.
+///
+/// This is always true for `m.room.create` events and for `m.room.member` events where the sender
+/// changes their own membership.
+async fn is_sender_joined<E, F, Fut>(event: &E, state: &StateMap<E::Id>, fetch_event: &F) -> bool
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    let sender = event.sender();
+
+    match event.event_type() {
+        TimelineEventType::RoomCreate => return true,
+        TimelineEventType::RoomMember if event.state_key() == Some(sender.as_str()) => return true,
+        _ => {}
+    }
+
+    let Some(member_event_id) = state.get(&StateEventType::RoomMember.with_state_key(sender))
+    else {
+        return false;
+    };
+
+    match fetch_event(member_event_id.clone()).await {
+        Some(member_event) => from_json_str::<RoomMemberEventContent>(member_event.content().get())
+            .is_ok_and(|content| content.membership == MembershipState::Join),
+        None => false,
+    }
+}
+
+/// Returns the sorted `to_sort` list of `EventId`s based on a mainline sort using the depth of
+/// `resolved_power_level`, the server timestamp, and the eventId.
+///
+/// The mainline is made of `resolved_power_level` and the chain of `m.room.power_levels` events
+/// found by following the auth events of each power levels event. The mainline position of an event
+/// is the position of the closest power levels event of the mainline in its auth chain, starting
+/// from the oldest one. Events are sorted by comparing, in that order:
+///
+/// 1. The mainline position of the event. An event that is authorized by an older power levels
+///    event comes first.
+/// 2. The `origin_server_ts` of the event. An older event comes first.
+/// 3. The ID of the event, lexicographically.
+///
+/// This is used by [`StateResolution::resolve()`] to sort the conflicted events that are not
+/// control events, before checking them against the authorization rules. Events that can't be
+/// fetched, or whose auth events can't be fetched, are left out of the returned list.
+pub async fn mainline_sort<E, F, Fut>(
+    to_sort: &[E::Id],
+    resolved_power_level: Option<E::Id>,
+    fetch_event: &F,
+) -> Result<Vec<E::Id>>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+{
+    debug!("mainline sort of events");
+
+    // There are no EventId's to sort, bail.
+    if to_sort.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut mainline = vec![];
+    let mut pl = resolved_power_level;
+    while let Some(p) = pl {
+        mainline.push(p.clone());
+
+        let event = fetch_event(p.clone())
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Failed to find {p}")))?;
+        pl = None;
+        for aid in event.auth_events() {
+            let ev = fetch_event(aid.clone())
+                .await
+                .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
+            if is_type_and_key(&ev, &TimelineEventType::RoomPowerLevels, "") {
+                pl = Some(aid.to_owned());
+                break;
+            }
+        }
+        // TODO: if these functions are ever made async here
+        // is a good place to yield every once in a while so other
+        // tasks can make progress
+    }
+
+    let mainline_map = mainline
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, eid)| ((*eid).clone(), idx))
+        .collect::<HashMap<_, _>>();
+
+    let mut order_map = HashMap::new();
+    for ev_id in to_sort.iter() {
+        if let Some(event) = fetch_event(ev_id.clone()).await {
+            if let Ok(depth) = get_mainline_depth(Some(event), &mainline_map, fetch_event).await {
+                order_map.insert(
+                    ev_id,
+                    (
+                        depth,
+                        fetch_event(ev_id.clone()).await.map(|ev| ev.origin_server_ts()),
+                        ev_id,
+                    ),
+                );
+            }
+        }
+
+        // TODO: if these functions are ever made async here
+        // is a good place to yield every once in a while so other
+        // tasks can make progress
+    }
+
+    // Sort the event_ids by their depth, timestamp and EventId
+    // unwrap is OK order map and sort_event_ids are from to_sort (the same Vec)
+    let mut sort_event_ids = order_map.keys().map(|&k| k.clone()).collect::<Vec<_>>();
+    sort_event_ids.sort_by_key(|sort_id| order_map.get(sort_id).unwrap());
+
+    Ok(sort_event_ids)
+}
+
+/// Get the mainline depth from the `mainline_map` or finds a power_level event that has an
+/// associated mainline depth.
+async fn get_mainline_depth<E, F, Fut>(
+    mut event: Option<E>,
+    mainline_map: &HashMap<E::Id, usize>,
+    fetch_event: &F,
+) -> Result<usize>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send,
+{
+    while let Some(sort_ev) = event {
+        debug!(event_id = sort_ev.event_id().borrow().as_str(), "mainline");
+        let id = sort_ev.event_id();
+        if let Some(depth) = mainline_map.get(id.borrow()) {
+            return Ok(*depth);
+        }
+
+        event = None;
+        for aid in sort_ev.auth_events() {
+            let aev = fetch_event(aid.clone())
+                .await
+                .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
+            if is_type_and_key(&aev, &TimelineEventType::RoomPowerLevels, "") {
+                event = Some(aev);
+                break;
+            }
+        }
+    }
+    // Did not find a power level event so we default to zero
+    Ok(0)
+}
+
+async fn add_event_and_auth_chain_to_graph<E, B>(
+    graph: &mut HashMap<E::Id, HashSet<E::Id>>,
+    event_id: E::Id,
+    auth_diff: &HashSet<E::Id>,
+    batch_fetch: &B,
+    max_graph_nodes: usize,
+) -> Result<()>
+where
+    B: BatchFetch<E>,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+{
+    if !graph.contains_key(event_id.borrow()) {
+        check_graph_size(graph, max_graph_nodes)?;
+        graph.insert(event_id.clone(), HashSet::new());
+    }
+
+    // Fetch the events one generation at a time, so all the auth events of an event are fetched
+    // in a single batch.
+    let mut to_fetch = vec![event_id];
+    while !to_fetch.is_empty() {
+        let requested = mem::take(&mut to_fetch);
+        let mut events = batch_fetch.fetch_many(requested.clone()).await;
+
+        for eid in requested {
+            let Some(event) = events.remove(eid.borrow()) else {
+                continue;
+            };
+
+            for aid in event.auth_events() {
+                if auth_diff.contains(aid.borrow()) {
+                    if !graph.contains_key(aid.borrow()) {
+                        check_graph_size(graph, max_graph_nodes)?;
+                        graph.insert(aid.to_owned(), HashSet::new());
+                        to_fetch.push(aid.to_owned());
+                    }
+
+                    // All the requested events were inserted before being fetched
+                    graph.get_mut(eid.borrow()).unwrap().insert(aid.to_owned());
+                }
+            }
+        }
+
+        if !events.is_empty() {
+            warn!(
+                count = events.len(),
+                "ignoring events that were fetched without being requested"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a node can be added to the given graph without exceeding `max_graph_nodes`.
+fn check_graph_size<Id>(graph: &HashMap<Id, HashSet<Id>>, max_graph_nodes: usize) -> Result<()> {
+    if graph.len() >= max_graph_nodes {
+        warn!(max_graph_nodes, "too many events in the graph of the control events");
+        return Err(Error::GraphTooLarge);
+    }
+
+    Ok(())
+}
+
+async fn is_power_event_id<E, F, Fut>(
+    event_id: &E::Id,
+    fetch: &F,
+    power_level_cache: Option<&PowerLevelCache>,
+) -> bool
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send,
+{
+    if let Some(is_power) =
+        power_level_cache.and_then(|cache| cache.is_power_event(event_id.borrow()))
+    {
+        return is_power;
+    }
+
+    match fetch(event_id.clone()).await.as_ref() {
+        Some(state) => {
+            let is_power = is_power_event(state);
+            if let Some(cache) = power_level_cache {
+                cache.insert_is_power_event(event_id.borrow(), is_power);
+            }
+            is_power
+        }
+        _ => false,
+    }
+}
+
+fn is_type_and_key(ev: impl Event, ev_type: &TimelineEventType, state_key: &str) -> bool {
+    ev.event_type() == ev_type && ev.state_key() == Some(state_key)
+}
+
+fn is_power_event(event: impl Event) -> bool {
+    match event.event_type() {
+        TimelineEventType::RoomPowerLevels
+        | TimelineEventType::RoomJoinRules
+        | TimelineEventType::RoomCreate => event.state_key() == Some(""),
+        TimelineEventType::RoomMember => {
+            if let Ok(content) = from_json_str::<RoomMemberEventContent>(event.content().get()) {
+                if [MembershipState::Leave, MembershipState::Ban].contains(&content.membership) {
+                    return Some(event.sender().as_str()) != event.state_key();
+                }
+            }
+
+            false
+        }
+        _ => false,
+    }
+}
+
+/// Convenience trait for adding event type plus state key to state maps.
+pub trait EventTypeExt {
+    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String);
+}
+
+impl EventTypeExt for StateEventType {
+    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String) {
+        (self, state_key.into())
+    }
+}
+
+impl EventTypeExt for TimelineEventType {
+    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String) {
+        (self.to_string().into(), state_key.into())
+    }
+}
+
+impl<T> EventTypeExt for &T
+where
+    T: EventTypeExt + Clone,
+{
+    fn with_state_key(self, state_key: impl Into<String>) -> (StateEventType, String) {
+        self.to_owned().with_state_key(state_key)
+    }
+}
+
+/// Convenience trait for combining state maps.
+pub trait StateMapExt<T> {
+    /// Insert all the entries of `other` into this map.
+    ///
+    /// When a key is in both maps, `resolve` is called with the key, the value of this map and the
+    /// value of `other`, and the value it returns is used.
+    fn merge_with<F>(&mut self, other: StateMap<T>, resolve: F)
+    where
+        F: FnMut(&(StateEventType, String), T, T) -> T;
+}
+
+impl<T> StateMapExt<T> for StateMap<T> {
+    fn merge_with<F>(&mut self, other: StateMap<T>, mut resolve: F)
+    where
+        F: FnMut(&(StateEventType, String), T, T) -> T,
+    {
+        for (key, value) in other {
+            let value = match self.remove(&key) {
+                Some(current) => resolve(&key, current, value),
+                None => value,
+            };
+            self.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+// The deprecated `resolve` is still tested, as long as it is part of the API.
+#[allow(deprecated)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use js_int::{int, uint};
+    use maplit::{hashmap, hashset};
+    use rand::seq::SliceRandom;
+    use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
+    use ruma_events::{
+        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
+        StateEventType, TimelineEventType,
+    };
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+    use tracing::debug;
+
+    use crate::{
+        is_power_event,
+        room_version::RoomVersion,
+        separate, state_map_hash,
+        test_utils::{
+            alice, bob, charlie, do_check, ella, event_id, member_content_ban, member_content_join,
+            room_id, to_init_pdu_event, to_pdu_event, zara, PduEvent, TestStore, INITIAL_EVENTS,
+        },
+        AuthChainDiff, BatchFetch, Error, Event, EventTypeExt, PartialReason, PowerLevelCache,
+        RejectionReason, ResolveOptions, ResolveOutcome, StateMap, StateMapExt, StateResObserver,
+        StateResolution,
+    };
+
+    async fn test_event_sort() {
+        use futures_util::future::ready;
+
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let events = INITIAL_EVENTS();
+
+        let event_map = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.clone()))
+            .collect::<StateMap<_>>();
+
+        let auth_chain: HashSet<OwnedEventId> = HashSet::new();
+
+        let power_events = event_map
+            .values()
+            .filter(|&pdu| is_power_event(&**pdu))
+            .map(|pdu| pdu.event_id.clone())
+            .collect::<Vec<_>>();
+
+        let fetcher = |id| ready(events.get(&id).cloned());
+        let sorted_power_events =
+            crate::reverse_topological_power_sort(power_events, &auth_chain, &fetcher)
+                .await
+                .unwrap();
+
+        let resolved_power = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &sorted_power_events,
+            HashMap::new(), // unconflicted events
+            &fetcher,
+            &ResolveOptions::default(),
+            &|| true,
+            &(),
+            &mut Vec::new(),
+        )
+        .await
+        .expect("iterative auth check failed on resolved events");
+
+        // don't remove any events so we know it sorts them all correctly
+        let mut events_to_sort = events.keys().cloned().collect::<Vec<_>>();
+
+        events_to_sort.shuffle(&mut rand::thread_rng());
+
+        let power_level =
+            resolved_power.get(&(StateEventType::RoomPowerLevels, "".to_owned())).cloned();
+
+        let sorted_event_ids =
+            crate::mainline_sort(&events_to_sort, power_level, &fetcher).await.unwrap();
+
+        assert_eq!(
+            vec![
+                "$CREATE:foo",
+                "$IMA:foo",
+                "$IPOWER:foo",
+                "$IJR:foo",
+                "$IMB:foo",
+                "$IMC:foo",
+                "$START:foo",
+                "$END:foo"
+            ],
+            sorted_event_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sort() {
+        for _ in 0..20 {
+            // since we shuffle the eventIds before we sort them introducing randomness
+            // seems like we should test this a few times
+            test_event_sort().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn ban_vs_power_level() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "MA",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(alice().to_string().as_str()),
+                member_content_join(),
+            ),
+            to_init_pdu_event(
+                "MB",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(bob().to_string().as_str()),
+                member_content_ban(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "MB", "MA", "PA", "START"], vec!["END", "PA", "PB"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let expected_state_ids =
+            vec!["PA", "MA", "MB"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check(events, edges, expected_state_ids).await;
+    }
+
+    #[tokio::test]
+    async fn sort_control_events() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+
+        let full_conflicted = ["PA", "PB", "MB", "IME"].into_iter().map(event_id).collect();
+        let fetcher = |id| ready(events.get(&id).cloned());
+
+        let sorted = crate::sort_control_events(&full_conflicted, &fetcher).await.unwrap();
+
+        // The join of Ella is not a control event, and the ban is sorted after its auth events.
+        assert_eq!(sorted, ["PA", "PB", "MB"].map(event_id));
+    }
+
+    #[tokio::test]
+    async fn topic_basic() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA1",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T2",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA2",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T3",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+        ];
+
+        let edges =
+            vec![vec!["END", "PA2", "T2", "PA1", "T1", "START"], vec!["END", "T3", "PB", "PA1"]]
+                .into_iter()
+                .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+
+        let expected_state_ids = vec!["PA2", "T2"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check(events, edges, expected_state_ids).await;
+    }
+
+    #[tokio::test]
+    async fn topic_reset() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T2",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "MB",
+                alice(),
+                TimelineEventType::RoomMember,
+                Some(bob().to_string().as_str()),
+                member_content_ban(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "MB", "T2", "PA", "T1", "START"], vec!["END", "T1"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let expected_state_ids =
+            vec!["T1", "MB", "PA"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check(events, edges, expected_state_ids).await;
+    }
+
+    // The member event of ella is the only event for its key, but it must still be authorized
+    // against the join rules of the other fork rather than be kept directly.
+    #[tokio::test]
+    async fn join_rule_evasion() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "JR",
+                alice(),
+                TimelineEventType::RoomJoinRules,
+                Some(""),
+                to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Private)).unwrap(),
+            ),
+            to_init_pdu_event(
+                "ME",
+                ella(),
+                TimelineEventType::RoomMember,
+                Some(ella().to_string().as_str()),
+                member_content_join(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "JR", "START"], vec!["END", "ME", "START"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let expected_state_ids = vec![event_id("JR")];
+
+        do_check(events, edges, expected_state_ids).await;
+    }
+
+    #[tokio::test]
+    async fn offtopic_power_level() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50, charlie(): 50 } }))
+                    .unwrap(),
+            ),
+            to_init_pdu_event(
+                "PC",
+                charlie(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50, charlie(): 0 } }))
+                    .unwrap(),
+            ),
+        ];
+
+        let edges = vec![vec!["END", "PC", "PB", "PA", "START"], vec!["END", "PA"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let expected_state_ids = vec!["PC"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check(events, edges, expected_state_ids).await;
+    }
+
+    #[tokio::test]
+    async fn topic_setting() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let events = &[
+            to_init_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA1",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T2",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PA2",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "PB",
+                bob(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T3",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "MZ1",
+                zara(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+            to_init_pdu_event(
+                "T4",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+            ),
+        ];
+
+        let edges = vec![
+            vec!["END", "T4", "MZ1", "PA2", "T2", "PA1", "T1", "START"],
+            vec!["END", "MZ1", "T3", "PB", "PA1"],
+        ]
+        .into_iter()
+        .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+        let expected_state_ids = vec!["T4", "PA2"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check(events, edges, expected_state_ids).await;
+    }
+
+    #[tokio::test]
+    async fn test_event_map_none() {
+        use futures_util::future::ready;
+
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+
+        // build up the DAG
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let ev_map = store.0.clone();
+        let fetcher = |id| ready(ev_map.get(&id).cloned());
+
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&*id).is_some());
+
+        let state_sets = [state_at_bob, state_at_charlie];
+        let auth_chain = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let resolved =
+            match crate::resolve(&RoomVersionId::V2, &state_sets, &auth_chain, &fetcher, &exists)
+                .await
+            {
+                Ok(state) => state,
+                Err(e) => panic!("{e}"),
+            };
+
+        assert_eq!(expected, resolved);
+    }
+
+    #[tokio::test]
+    async fn test_lexicographical_sort() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let graph = hashmap! {
+            event_id("l") => hashset![event_id("o")],
+            event_id("m") => hashset![event_id("n"), event_id("o")],
+            event_id("n") => hashset![event_id("o")],
+            event_id("o") => hashset![], // "o" has zero outgoing edges but 4 incoming edges
+            event_id("p") => hashset![event_id("o")],
+        };
+
+        let res = crate::lexicographical_topological_sort(&graph, &|_id| async {
+            Ok((int!(0), MilliSecondsSinceUnixEpoch(uint!(0))))
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            vec!["o", "l", "n", "m", "p"],
+            res.iter()
+                .map(ToString::to_string)
+                .map(|s| s.replace('$', "").replace(":foo", ""))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn lexicographical_sort_by() {
+        let graph = hashmap! {
+            event_id("l") => hashset![event_id("o")],
+            event_id("m") => hashset![event_id("n"), event_id("o")],
+            event_id("n") => hashset![event_id("o")],
+            event_id("o") => hashset![],
+            event_id("p") => hashset![event_id("o")],
+        };
+        let ages = hashmap! {
+            event_id("l") => 1_u32,
+            event_id("m") => 4,
+            event_id("n") => 2,
+            event_id("o") => 0,
+            event_id("p") => 3,
+        };
+        let to_strings = |ids: Vec<OwnedEventId>| {
+            ids.iter()
+                .map(ToString::to_string)
+                .map(|s| s.replace('$', "").replace(":foo", ""))
+                .collect::<Vec<_>>()
+        };
+
+        let res = crate::lexicographical_topological_sort(&graph, &|id: OwnedEventId| {
+            let age = ages[&id];
+            async move { Ok((int!(0), MilliSecondsSinceUnixEpoch(age.into()))) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(to_strings(res), ["o", "l", "n", "p", "m"]);
+
+        // Newer events first.
+        let res = crate::lexicographical_topological_sort_by(&graph, |a, b| {
+            ages[b].cmp(&ages[a]).then_with(|| a.cmp(b))
+        });
+        assert_eq!(to_strings(res), ["o", "p", "n", "m", "l"]);
+    }
+
+    #[test]
+    fn lexicographical_sort_by_without_ord() {
+        // An event ID type that can only be ordered through its reference hash.
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        struct HashedId {
+            id: &'static str,
+            reference_hash: [u8; 2],
+        }
+
+        let id = |id, reference_hash| HashedId { id, reference_hash };
+        let (o, l, m, n) = (id("o", [0, 9]), id("l", [4, 1]), id("m", [1, 0]), id("n", [2, 2]));
+        let graph = hashmap! {
+            l.clone() => hashset![o.clone()],
+            m.clone() => hashset![n.clone(), o.clone()],
+            n.clone() => hashset![o.clone()],
+            o.clone() => hashset![],
+        };
+
+        let res = crate::lexicographical_topological_sort_by(&graph, |a, b| {
+            a.reference_hash.cmp(&b.reference_hash)
+        });
+        assert_eq!(res, [o, n, m, l]);
+    }
+
+    #[test]
+    fn lexicographical_sort_cancelled() {
+        use std::cell::Cell;
+
+        use crate::CANCELLATION_CHECK_INTERVAL;
+
+        // A chain of events, each one pointing to the previous one.
+        let count = 2 * CANCELLATION_CHECK_INTERVAL + 1;
+        let graph = (0..count)
+            .map(|i| {
+                let edges =
+                    if i == 0 { hashset![] } else { hashset![event_id(&format!("E{}", i - 1))] };
+                (event_id(&format!("E{i}")), edges)
+            })
+            .collect::<HashMap<_, _>>();
+
+        // The predicate is only called every `CANCELLATION_CHECK_INTERVAL` events.
+        let calls = Cell::new(0);
+        let res = crate::lexicographical_topological_sort_by_cancellable(
+            &graph,
+            |a, b| a.cmp(b),
+            &|| {
+                calls.set(calls.get() + 1);
+                true
+            },
+        )
+        .unwrap();
+        assert_eq!(res.len(), count);
+        assert_eq!(calls.get(), 3);
+
+        // The sort stops as soon as the predicate returns `false`.
+        calls.set(0);
+        let res = crate::lexicographical_topological_sort_by_cancellable(
+            &graph,
+            |a, b| a.cmp(b),
+            &|| {
+                calls.set(calls.get() + 1);
+                calls.get() < 2
+            },
+        );
+        assert!(matches!(res, Err(Error::Cancelled)), "{res:?}");
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn iterative_auth_check_cancelled() {
+        use futures_util::future::ready;
+
+        let events = INITIAL_EVENTS();
+        let fetcher = |id| ready(events.get(&id).cloned());
+
+        let res = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("CREATE"), event_id("IMA")],
+            StateMap::new(),
+            &fetcher,
+            &ResolveOptions::default(),
+            &|| false,
+            &(),
+            &mut Vec::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::Cancelled)), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn ban_with_auth_chains() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let ban = BAN_STATE_SET();
+
+        let edges = vec![vec!["END", "MB", "PA", "START"], vec!["END", "IME", "MB"]]
+            .into_iter()
+            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let expected_state_ids = vec!["PA", "MB"].into_iter().map(event_id).collect::<Vec<_>>();
+
+        do_check(&ban.values().cloned().collect::<Vec<_>>(), edges, expected_state_ids).await;
+    }
+
+    #[tokio::test]
+    async fn ban_with_auth_chains2() {
+        use futures_util::future::ready;
+
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let init = INITIAL_EVENTS();
+        let ban = BAN_STATE_SET();
+
+        let mut inner = init.clone();
+        inner.extend(ban);
+        let store = TestStore(inner.clone());
+
+        let state_set_a = [
+            inner.get(&event_id("CREATE")).unwrap(),
+            inner.get(&event_id("IJR")).unwrap(),
+            inner.get(&event_id("IMA")).unwrap(),
+            inner.get(&event_id("IMB")).unwrap(),
+            inner.get(&event_id("IMC")).unwrap(),
+            inner.get(&event_id("MB")).unwrap(),
+            inner.get(&event_id("PA")).unwrap(),
+        ]
+        .iter()
+        .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone()))
+        .collect::<StateMap<_>>();
+
+        let state_set_b = [
+            inner.get(&event_id("CREATE")).unwrap(),
+            inner.get(&event_id("IJR")).unwrap(),
+            inner.get(&event_id("IMA")).unwrap(),
+            inner.get(&event_id("IMB")).unwrap(),
+            inner.get(&event_id("IMC")).unwrap(),
+            inner.get(&event_id("IME")).unwrap(),
+            inner.get(&event_id("PA")).unwrap(),
+        ]
+        .iter()
+        .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone()))
+        .collect::<StateMap<_>>();
+
+        let ev_map = &store.0;
+        let state_sets = [state_set_a, state_set_b];
+        let auth_chain = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+        let resolved =
+            match crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain, &fetcher, &exists)
+                .await
+            {
+                Ok(state) => state,
+                Err(e) => panic!("{e}"),
+            };
+
+        debug!(
+            resolved = ?resolved
+                .iter()
+                .map(|((ty, key), id)| format!("(({ty}{key:?}), {id})"))
+                .collect::<Vec<_>>(),
+                "resolved state",
+        );
+
+        let expected =
+            ["$CREATE:foo", "$IJR:foo", "$PA:foo", "$IMA:foo", "$IMB:foo", "$IMC:foo", "$MB:foo"];
+
+        for id in expected.iter().map(|i| event_id(i)) {
+            // make sure our resolved events are equal to the expected list
+            assert!(resolved.values().any(|eid| eid == &id) || init.contains_key(&id), "{id}");
+        }
+        assert_eq!(expected.len(), resolved.len());
+    }
+
+    #[tokio::test]
+    async fn resolve_variants_match_resolve() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        let resolved =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        let resolved_compute_chains = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .resolve_compute_chains(&state_sets)
+            .await
+            .unwrap();
+
+        assert_eq!(resolved_compute_chains, resolved);
+
+        let resolved_sync = StateResolution::new(
+            RoomVersionId::V6,
+            &|id: <PduEvent as Event>::Id| ev_map.get(&id).cloned(),
+            &|id: <PduEvent as Event>::Id| ev_map.contains_key(&id),
+        )
+        .resolve_sync(&state_sets, &auth_chain_sets)
+        .unwrap();
+        assert_eq!(resolved_sync, resolved);
+
+        let resolved_sync = crate::resolve_sync(
+            &RoomVersionId::V6,
+            &state_sets,
+            &auth_chain_sets,
+            &|id: <PduEvent as Event>::Id| ev_map.get(&id).cloned(),
+            &|id: <PduEvent as Event>::Id| ev_map.contains_key(&id),
+        )
+        .unwrap();
+        assert_eq!(resolved_sync, resolved);
+    }
+
+    #[tokio::test]
+    async fn resolve_fallible() {
+        use std::io;
+
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        let resolved =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        let resolved_fallible = StateResolution::new(
+            RoomVersionId::V6,
+            &|id: <PduEvent as Event>::Id| ready(Ok::<_, io::Error>(ev_map.get(&id).cloned())),
+            &exists,
+        )
+        .resolve_fallible(&state_sets, &auth_chain_sets)
+        .await
+        .unwrap();
+        assert_eq!(resolved_fallible, resolved);
+
+        // The store fails to fetch a conflicted event.
+        let err = StateResolution::new(
+            RoomVersionId::V6,
+            &|id: <PduEvent as Event>::Id| {
+                ready(if id == event_id("MB") {
+                    Err(io::Error::other("connection lost"))
+                } else {
+                    Ok(ev_map.get(&id).cloned())
+                })
+            },
+            &exists,
+        )
+        .resolve_fallible(&state_sets, &auth_chain_sets)
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(&err, Error::Fetch(error) if error.to_string() == "connection lost"),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn with_batch_fetch() {
+        use std::{
+            future::Future,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        use futures_util::future::ready;
+
+        struct CountingBatchFetch<'a> {
+            events: &'a HashMap<OwnedEventId, Arc<PduEvent>>,
+            calls: AtomicUsize,
+        }
+
+        impl BatchFetch<Arc<PduEvent>> for CountingBatchFetch<'_> {
+            fn fetch_many(
+                &self,
+                ids: Vec<OwnedEventId>,
+            ) -> impl Future<Output = HashMap<OwnedEventId, Arc<PduEvent>>> + Send {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                ready(
+                    ids.into_iter()
+                        .filter_map(|id| Some((id.clone(), self.events.get(&id)?.clone())))
+                        .collect(),
+                )
+            }
+        }
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetches = AtomicUsize::new(0);
+        let fetcher = |id: <PduEvent as Event>::Id| {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            ready(ev_map.get(&id).cloned())
+        };
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+        let batch_fetch = CountingBatchFetch { events: ev_map, calls: AtomicUsize::new(0) };
+
+        let resolved =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        let resolved_batched = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_batch_fetch(CountingBatchFetch { events: ev_map, calls: AtomicUsize::new(0) })
+            .resolve(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved_batched, resolved);
+
+        // All the auth events of `PA` are fetched in a single batch.
+        let auth_diff: HashSet<_> = ["PA", "CREATE", "IMA", "IPOWER"].map(event_id).into();
+        fetches.store(0, Ordering::SeqCst);
+        batch_fetch.calls.store(0, Ordering::SeqCst);
+
+        let mut graph = HashMap::new();
+        crate::add_event_and_auth_chain_to_graph(
+            &mut graph,
+            event_id("PA"),
+            &auth_diff,
+            &fetcher,
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        let mut batched_graph = HashMap::new();
+        crate::add_event_and_auth_chain_to_graph(
+            &mut batched_graph,
+            event_id("PA"),
+            &auth_diff,
+            &batch_fetch,
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(batched_graph, graph);
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+        assert_eq!(batch_fetch.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn batch_fetch_unrequested_events() {
+        use std::future::Future;
+
+        use futures_util::future::ready;
+
+        // Returns all the events of the store, regardless of the requested IDs.
+        struct GreedyBatchFetch<'a>(&'a HashMap<OwnedEventId, Arc<PduEvent>>);
+
+        impl BatchFetch<Arc<PduEvent>> for GreedyBatchFetch<'_> {
+            fn fetch_many(
+                &self,
+                _ids: Vec<OwnedEventId>,
+            ) -> impl Future<Output = HashMap<OwnedEventId, Arc<PduEvent>>> + Send {
+                ready(self.0.clone())
+            }
+        }
+
+        let store = TestStore(INITIAL_EVENTS());
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let auth_diff: HashSet<_> = ["IMB", "CREATE", "IJR", "IPOWER"].map(event_id).into();
+
+        let mut graph = HashMap::new();
+        crate::add_event_and_auth_chain_to_graph(
+            &mut graph,
+            event_id("IMB"),
+            &auth_diff,
+            &fetcher,
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        let mut greedy_graph = HashMap::new();
+        crate::add_event_and_auth_chain_to_graph(
+            &mut greedy_graph,
+            event_id("IMB"),
+            &auth_diff,
+            &GreedyBatchFetch(ev_map),
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(greedy_graph, graph);
+    }
+
+    #[tokio::test]
+    async fn graph_too_large() {
+        use futures_util::future::ready;
+
+        // A chain of events where each event is authorized by the previous one.
+        let mut events = HashMap::new();
+        for i in 0_u8..10 {
+            let id = format!("T{i}");
+            let prev_id = i.checked_sub(1).map(|prev| format!("T{prev}"));
+            let event = to_pdu_event(
+                &id,
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": id })).unwrap(),
+                prev_id.as_deref().as_slice(),
+                &[],
+            );
+            events.insert(event.event_id.clone(), event);
+        }
+
+        let fetcher = |id: OwnedEventId| ready(events.get(&id).cloned());
+        let auth_diff = events.keys().cloned().collect::<HashSet<_>>();
+
+        let mut graph = HashMap::new();
+        let err = crate::add_event_and_auth_chain_to_graph(
+            &mut graph,
+            event_id("T9"),
+            &auth_diff,
+            &fetcher,
+            5,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::GraphTooLarge), "{err:?}");
+        assert_eq!(graph.len(), 5);
+
+        let mut graph = HashMap::new();
+        crate::add_event_and_auth_chain_to_graph(
+            &mut graph,
+            event_id("T9"),
+            &auth_diff,
+            &fetcher,
+            10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(graph.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn resolve_extra_creator() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+
+        assert_eq!(resolved.create_event_id, Some(event_id("CREATE")));
+        assert_eq!(resolved.creator.as_deref(), Some(alice()));
+    }
+
+    #[tokio::test]
+    async fn report_rejected() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("TA"),
+            to_pdu_event(
+                "TA",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "alice" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        // Bob doesn't have the power level required to change the topic.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TA"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TB"]),
+        ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+        let topic_key = StateEventType::RoomTopic.with_state_key("");
+
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved.state.get(&topic_key), Some(&event_id("TA")));
+        assert!(resolved.rejected.is_empty());
+
+        let mut options = ResolveOptions::new();
+        options.report_rejected = true;
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved.state.get(&topic_key), Some(&event_id("TA")));
+        assert_eq!(resolved.rejected, [event_id("TB")]);
+        assert!(!resolved.state.values().any(|id| *id == event_id("TB")));
+    }
+
+    #[tokio::test]
+    async fn entry_points_honor_options() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("TA"),
+            to_pdu_event(
+                "TA",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "alice" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        // Bob doesn't have the power level required to change the topic.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let mut state_sets = [
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TA"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TB"]),
+        ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let fallible_fetcher =
+            |id: <PduEvent as Event>::Id| ready(Ok::<_, std::io::Error>(ev_map.get(&id).cloned()));
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        // The rejected events are reported by the entry points that return them.
+        let mut options = ResolveOptions::new();
+        options.report_rejected = true;
+
+        let report = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve_report(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(report.rejected, [(event_id("TB"), RejectionReason::InsufficientPowerLevel)]);
+
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved.rejected, [event_id("TB")]);
+
+        // The unconflicted state is checked by all the entry points, so the unknown name is not
+        // found.
+        for state_set in &mut state_sets {
+            state_set.insert(StateEventType::RoomName.with_state_key(""), event_id("UNKNOWN"));
+        }
+        let (clean, conflicting) = separate(state_sets.iter());
+        let mut options = ResolveOptions::new();
+        options.auth_check_unconflicted = true;
+
+        let resolution = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone());
+        let results = [
+            resolution.resolve(&state_sets, &auth_chain_sets).await.map(|_| ()),
+            resolution.resolve_report(&state_sets, &auth_chain_sets).await.map(|_| ()),
+            resolution.resolve_partial(&state_sets, &auth_chain_sets).await.map(|_| ()),
+            resolution.resolve_extra(&state_sets, &auth_chain_sets).await.map(|_| ()),
+            resolution.resolve_conflicted(clean, conflicting, &auth_chain_sets).await.map(|_| ()),
+            resolution.resolve_compute_chains(&state_sets).await.map(|_| ()),
+            crate::resolve_with_report(
+                &RoomVersionId::V6,
+                &state_sets,
+                &auth_chain_sets,
+                &fetcher,
+                &exists,
+                &options,
+            )
+            .await
+            .map(|_| ()),
+            {
+                let (clean, conflicting) = separate(state_sets.iter());
+                crate::resolve_conflicted(
+                    &RoomVersionId::V6,
+                    clean,
+                    conflicting,
+                    &auth_chain_sets,
+                    &fetcher,
+                    &exists,
+                    &options,
+                )
+                .await
+                .map(|_| ())
+            },
+            crate::resolve_compute_chains(
+                &RoomVersionId::V6,
+                &state_sets,
+                &fetcher,
+                &exists,
+                &options,
+            )
+            .await
+            .map(|_| ()),
+            StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+                .with_options(options.clone())
+                .with_batch_fetch(&fetcher)
+                .resolve(&state_sets, &auth_chain_sets)
+                .await
+                .map(|_| ()),
+            StateResolution::new(RoomVersionId::V6, &fallible_fetcher, &exists)
+                .with_options(options)
+                .resolve_fallible(&state_sets, &auth_chain_sets)
+                .await
+                .map(|_| ()),
+        ];
+
+        for (i, result) in results.into_iter().enumerate() {
+            assert!(matches!(result, Err(Error::NotFound(_))), "entry point {i}: {result:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_report() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("TA"),
+            to_pdu_event(
+                "TA",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "alice" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        // Bob doesn't have the power level required to change the topic.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TA"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TB"]),
+        ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        // The rejected events are reported without setting `report_rejected`.
+        let report = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .resolve_report(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
 
-    use crate::{
-        is_power_event,
-        room_version::RoomVersion,
-        test_utils::{
-            alice, bob, charlie, do_check, ella, event_id, member_content_ban, member_content_join,
-            room_id, to_init_pdu_event, to_pdu_event, zara, PduEvent, TestStore, INITIAL_EVENTS,
-        },
-        Event, EventTypeExt, StateMap,
-    };
+        let state =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        assert_eq!(report.state, state);
+        assert_eq!(
+            report.state.get(&StateEventType::RoomTopic.with_state_key("")),
+            Some(&event_id("TA"))
+        );
+        assert_eq!(report.rejected, [(event_id("TB"), RejectionReason::InsufficientPowerLevel)]);
+        assert_eq!(report.conflicted, HashSet::from([event_id("TA"), event_id("TB")]));
 
-    async fn test_event_sort() {
-        use futures_util::future::ready;
+        // The same state is resolved from the separated state.
+        let (clean, conflicting) = separate(state_sets.iter());
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .resolve_conflicted(clean, conflicting, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved, state);
+    }
 
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
-        let events = INITIAL_EVENTS();
+    #[tokio::test]
+    async fn with_observer() {
+        use std::sync::Mutex;
 
-        let event_map = events
-            .values()
-            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.clone()))
-            .collect::<StateMap<_>>();
+        use futures_util::future::ready;
+        use ruma_common::EventId;
 
-        let auth_chain: HashSet<OwnedEventId> = HashSet::new();
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
 
-        let power_events = event_map
-            .values()
-            .filter(|&pdu| is_power_event(&**pdu))
-            .map(|pdu| pdu.event_id.clone())
-            .collect::<Vec<_>>();
+        impl StateResObserver for Recorder {
+            fn on_conflicted(&self, count: usize) {
+                self.0.lock().unwrap().push(format!("conflicted {count}"));
+            }
 
-        let fetcher = |id| ready(events.get(&id).cloned());
-        let sorted_power_events =
-            crate::reverse_topological_power_sort(power_events, &auth_chain, &fetcher)
-                .await
-                .unwrap();
+            fn on_graph_nodes(&self, count: usize) {
+                self.0.lock().unwrap().push(format!("graph nodes {count}"));
+            }
 
-        let resolved_power = crate::iterative_auth_check(
-            &RoomVersion::V6,
-            &sorted_power_events,
-            HashMap::new(), // unconflicted events
-            &fetcher,
-        )
-        .await
-        .expect("iterative auth check failed on resolved events");
+            fn on_power_events(&self, count: usize) {
+                self.0.lock().unwrap().push(format!("power events {count}"));
+            }
 
-        // don't remove any events so we know it sorts them all correctly
-        let mut events_to_sort = events.keys().cloned().collect::<Vec<_>>();
+            fn on_auth_check(&self, event_id: &EventId, passed: bool) {
+                self.0.lock().unwrap().push(format!("auth check {event_id} {passed}"));
+            }
+        }
 
-        events_to_sort.shuffle(&mut rand::thread_rng());
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("PA"),
+            to_pdu_event(
+                "PA",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        // Bob doesn't have the power level required to change the topic.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "PA", "IJR", "IMB", "IMC"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TB"]),
+        ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
 
-        let power_level =
-            resolved_power.get(&(StateEventType::RoomPowerLevels, "".to_owned())).cloned();
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
 
-        let sorted_event_ids =
-            crate::mainline_sort(&events_to_sort, power_level, &fetcher).await.unwrap();
+        let recorder = Recorder::default();
+        let state = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_observer(&recorder)
+            .resolve(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
 
         assert_eq!(
-            vec![
-                "$CREATE:foo",
-                "$IMA:foo",
-                "$IPOWER:foo",
-                "$IJR:foo",
-                "$IMB:foo",
-                "$IMC:foo",
-                "$START:foo",
-                "$END:foo"
-            ],
-            sorted_event_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>()
+            state,
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            recorder.0.into_inner().unwrap(),
+            [
+                "conflicted 3".to_owned(),
+                "graph nodes 2".to_owned(),
+                "power events 2".to_owned(),
+                format!("auth check {} true", event_id("IPOWER")),
+                format!("auth check {} true", event_id("PA")),
+                format!("auth check {} true", event_id("TB")),
+            ]
         );
     }
 
     #[tokio::test]
-    async fn test_sort() {
-        for _ in 0..20 {
-            // since we shuffle the eventIds before we sort them introducing randomness
-            // seems like we should test this a few times
-            test_event_sort().await;
-        }
+    async fn power_level_for_sender_string_values() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("SPOWER"),
+            to_pdu_event(
+                "SPOWER",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): "100" } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+        );
+        events.insert(
+            event_id("T"),
+            to_pdu_event(
+                "T",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "string" })).unwrap(),
+                &["CREATE", "IMA", "SPOWER"],
+                &["SPOWER"],
+            ),
+        );
+        let fetcher = |id| ready(events.get(&id).cloned());
+
+        // Power levels can be strings before room version 10.
+        let pl = crate::get_power_level_for_sender(
+            &event_id("T"),
+            Some(&RoomVersion::V9),
+            &fetcher,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(pl, int!(100));
+
+        crate::get_power_level_for_sender(&event_id("T"), Some(&RoomVersion::V10), &fetcher, None)
+            .await
+            .unwrap_err();
     }
 
     #[tokio::test]
-    async fn ban_vs_power_level() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+    async fn resolve_with_power_level_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 
-        let events = &[
-            to_init_pdu_event(
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("PA"),
+            to_pdu_event(
                 "PA",
                 alice(),
                 TimelineEventType::RoomPowerLevels,
                 Some(""),
                 to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
             ),
-            to_init_pdu_event(
-                "MA",
-                alice(),
-                TimelineEventType::RoomMember,
-                Some(alice().to_string().as_str()),
-                member_content_join(),
-            ),
-            to_init_pdu_event(
-                "MB",
-                alice(),
-                TimelineEventType::RoomMember,
-                Some(bob().to_string().as_str()),
-                member_content_ban(),
-            ),
-            to_init_pdu_event(
+        );
+        events.insert(
+            event_id("PB"),
+            to_pdu_event(
                 "PB",
-                bob(),
+                alice(),
                 TimelineEventType::RoomPowerLevels,
                 Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 20 } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
             ),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "PA", "IJR", "IMB", "IMC"]),
+            state_set(&["CREATE", "IMA", "PB", "IJR", "IMB", "IMC"]),
         ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
 
-        let edges = vec![vec!["END", "MB", "MA", "PA", "START"], vec!["END", "PA", "PB"]]
-            .into_iter()
-            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-            .collect::<Vec<_>>();
+        let fetches = AtomicUsize::new(0);
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| {
+            fetches.fetch_add(1, SeqCst);
+            ready(ev_map.get(&id).cloned())
+        };
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
 
-        let expected_state_ids =
-            vec!["PA", "MA", "MB"].into_iter().map(event_id).collect::<Vec<_>>();
+        let expected =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        let uncached_fetches = fetches.swap(0, SeqCst);
 
-        do_check(events, edges, expected_state_ids).await;
+        let cache = Arc::new(PowerLevelCache::new());
+        let options =
+            ResolveOptions { power_level_cache: Some(cache.clone()), ..Default::default() };
+
+        // The first resolution fills the cache.
+        let state = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(state, expected);
+        assert_eq!(fetches.swap(0, SeqCst), uncached_fetches);
+        assert!(!cache.is_empty());
+
+        // The second resolution uses the cache and fetches fewer events.
+        let state = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(state, expected);
+        assert!(fetches.load(SeqCst) < uncached_fetches);
+    }
+
+    #[test]
+    fn power_level_cache_capacity() {
+        let cache = PowerLevelCache::with_capacity(2);
+        cache.insert_power_level(&event_id("A"), int!(100));
+        cache.insert_is_power_event(&event_id("A"), true);
+        cache.insert_power_level(&event_id("B"), int!(50));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.power_level(&event_id("A")), Some(int!(100)));
+        assert_eq!(cache.is_power_event(&event_id("A")), Some(true));
+
+        // The oldest event is evicted when the cache is full.
+        cache.insert_power_level(&event_id("C"), int!(0));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.power_level(&event_id("A")), None);
+        assert_eq!(cache.power_level(&event_id("B")), Some(int!(50)));
+        assert_eq!(cache.power_level(&event_id("C")), Some(int!(0)));
+
+        cache.retain(|id| id != event_id("B"));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.power_level(&event_id("B")), None);
+
+        cache.insert_power_level(&event_id("D"), int!(0));
+        cache.insert_power_level(&event_id("E"), int!(0));
+        assert_eq!(cache.power_level(&event_id("C")), None);
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
     }
 
     #[tokio::test]
-    async fn topic_basic() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+    async fn state_resolution_builder() {
+        use std::time::{Duration, Instant};
 
-        let events = &[
-            to_init_pdu_event(
-                "T1",
-                alice(),
-                TimelineEventType::RoomTopic,
-                Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
-            ),
-            to_init_pdu_event(
-                "PA1",
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("PA"),
+            to_pdu_event(
+                "PA",
                 alice(),
                 TimelineEventType::RoomPowerLevels,
                 Some(""),
                 to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
             ),
-            to_init_pdu_event(
-                "T2",
-                alice(),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "PA", "IJR", "IMB", "IMC"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]),
+        ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        let expected =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+
+        let resolution = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_power_cache(Arc::new(PowerLevelCache::new()))
+            .with_deadline(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(resolution.resolve(&state_sets, &auth_chain_sets).await.unwrap(), expected);
+
+        // The graph of the control events contains `PA` and `IPOWER`.
+        let resolution = resolution.with_max_graph_nodes(1);
+        let err = resolution.resolve(&state_sets, &auth_chain_sets).await.unwrap_err();
+        assert!(matches!(err, Error::GraphTooLarge), "{err:?}");
+
+        // The resolution is cancelled when the deadline has passed.
+        let resolution = resolution.with_max_graph_nodes(2).with_deadline(Instant::now());
+        let err = resolution.resolve(&state_sets, &auth_chain_sets).await.unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn trusted_events() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        // Bob doesn't have the power level required to change the topic or the name.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
                 TimelineEventType::RoomTopic,
                 Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
-            ),
-            to_init_pdu_event(
-                "PA2",
-                alice(),
-                TimelineEventType::RoomPowerLevels,
-                Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["IMC"],
             ),
-            to_init_pdu_event(
-                "PB",
+        );
+        events.insert(
+            event_id("NB"),
+            to_pdu_event(
+                "NB",
                 bob(),
-                TimelineEventType::RoomPowerLevels,
+                TimelineEventType::RoomName,
                 Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                to_raw_json_value(&json!({ "name": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["TB"],
             ),
-            to_init_pdu_event(
-                "T3",
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TB", "NB"]),
+        ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+        let topic_key = StateEventType::RoomTopic.with_state_key("");
+        let name_key = StateEventType::RoomName.with_state_key("");
+
+        let mut options = ResolveOptions::new();
+        options.report_rejected = true;
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved.state.get(&topic_key), None);
+        assert_eq!(resolved.state.get(&name_key), None);
+
+        options.trusted.insert(event_id("TB"));
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        // The trusted event is accepted, but the other events are still checked.
+        assert_eq!(resolved.state.get(&topic_key), Some(&event_id("TB")));
+        assert_eq!(resolved.state.get(&name_key), None);
+        assert_eq!(resolved.rejected, [event_id("NB")]);
+    }
+
+    #[tokio::test]
+    async fn auth_check_unconflicted() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        // Bob doesn't have the power level required to change the topic.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
                 bob(),
                 TimelineEventType::RoomTopic,
                 Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER"],
+                &["IMC"],
             ),
+        );
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        // The topic is unconflicted, so it is trusted by the state resolution algorithm.
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "TB"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "TB"]),
         ];
+        let auth_chain_sets: Vec<_> = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
 
-        let edges =
-            vec![vec!["END", "PA2", "T2", "PA1", "T1", "START"], vec!["END", "T3", "PB", "PA1"]]
-                .into_iter()
-                .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-                .collect::<Vec<_>>();
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+        let topic_key = StateEventType::RoomTopic.with_state_key("");
 
-        let expected_state_ids = vec!["PA2", "T2"].into_iter().map(event_id).collect::<Vec<_>>();
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved.state.get(&topic_key), Some(&event_id("TB")));
+        assert!(resolved.unconflicted_auth_failures.is_empty());
+
+        let mut options = ResolveOptions::new();
+        options.auth_check_unconflicted = true;
+        let resolved = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve_extra(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        // The event is reported, but not dropped.
+        assert_eq!(resolved.state.get(&topic_key), Some(&event_id("TB")));
+        assert_eq!(resolved.unconflicted_auth_failures, [event_id("TB")]);
+        assert!(resolved.rejected.is_empty());
+    }
 
-        do_check(events, edges, expected_state_ids).await;
+    #[tokio::test]
+    async fn max_conflicted_events() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect::<Vec<_>>();
+        let full_conflicted_set_len =
+            crate::get_auth_chain_diff(&auth_chain_sets).collect::<HashSet<_>>().len();
+        assert!(full_conflicted_set_len > 2);
+
+        let exists_calls = AtomicUsize::new(0);
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| {
+            exists_calls.fetch_add(1, Ordering::SeqCst);
+            ready(ev_map.get(&id).is_some())
+        };
+
+        let mut options = ResolveOptions::new();
+        options.max_conflicted_events = Some(1);
+        let res = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .resolve(&state_sets, &auth_chain_sets)
+            .await;
+
+        assert!(matches!(res, Err(Error::TooManyConflictedEvents)), "{res:?}");
+        // The collection stopped as soon as the limit was exceeded.
+        assert_eq!(exists_calls.load(Ordering::SeqCst), 2);
     }
 
     #[tokio::test]
-    async fn topic_reset() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+    async fn resolve_partial() {
+        use futures_util::future::ready;
 
-        let events = &[
-            to_init_pdu_event(
-                "T1",
-                alice(),
-                TimelineEventType::RoomTopic,
-                Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
-            ),
-            to_init_pdu_event(
-                "PA",
-                alice(),
-                TimelineEventType::RoomPowerLevels,
-                Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
+        ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect::<Vec<_>>();
+        let (clean, _) = separate(state_sets.iter());
+
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        let resolve_partial = |options: ResolveOptions, should_continue: fn() -> bool| {
+            let state_sets = &state_sets;
+            let auth_chain_sets = &auth_chain_sets;
+            let fetcher = &fetcher;
+            let exists = &exists;
+
+            async move {
+                StateResolution::new(RoomVersionId::V6, fetcher, exists)
+                    .with_options(options.clone())
+                    .with_should_continue(&should_continue)
+                    .resolve_partial(state_sets, auth_chain_sets)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let complete =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        let res = resolve_partial(ResolveOptions::new(), || true).await;
+        assert!(matches!(&res, ResolveOutcome::Complete(state) if *state == complete), "{res:?}");
+
+        let res = resolve_partial(ResolveOptions::new(), || false).await;
+        assert!(
+            matches!(
+                &res,
+                ResolveOutcome::Partial { resolved_so_far, reason: PartialReason::Cancelled }
+                    if *resolved_so_far == clean
             ),
-            to_init_pdu_event(
-                "T2",
-                bob(),
-                TimelineEventType::RoomTopic,
-                Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
+            "{res:?}"
+        );
+
+        let mut options = ResolveOptions::new();
+        options.max_conflicted_events = Some(1);
+        let res = resolve_partial(options, || true).await;
+        assert!(
+            matches!(
+                &res,
+                ResolveOutcome::Partial {
+                    resolved_so_far,
+                    reason: PartialReason::TooManyConflictedEvents,
+                } if *resolved_so_far == clean
             ),
-            to_init_pdu_event(
-                "MB",
+            "{res:?}"
+        );
+
+        // The unconflicted state is checked even when there is no conflicted state, so the
+        // unknown topic is not found.
+        let mut unconflicted_state_sets = [state_sets[0].clone(), state_sets[0].clone()];
+        for state_set in &mut unconflicted_state_sets {
+            state_set.insert(StateEventType::RoomTopic.with_state_key(""), event_id("UNKNOWN"));
+        }
+        let mut options = ResolveOptions::new();
+        let res = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .with_should_continue(|| true)
+            .resolve_partial(&unconflicted_state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert!(matches!(res, ResolveOutcome::Complete(_)), "{res:?}");
+
+        options.auth_check_unconflicted = true;
+        let err = StateResolution::new(RoomVersionId::V6, &fetcher, &exists)
+            .with_options(options.clone())
+            .with_should_continue(|| true)
+            .resolve_partial(&unconflicted_state_sets, &auth_chain_sets)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn world_readable() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        for (id, history_visibility) in [("HVW", "world_readable"), ("HVS", "shared")] {
+            let event = to_pdu_event(
+                id,
                 alice(),
-                TimelineEventType::RoomMember,
-                Some(bob().to_string().as_str()),
-                member_content_ban(),
-            ),
+                TimelineEventType::RoomHistoryVisibility,
+                Some(""),
+                to_raw_json_value(&json!({ "history_visibility": history_visibility })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            );
+            events.insert(event.event_id.clone(), event);
+        }
+
+        let fetcher = |id: <PduEvent as Event>::Id| ready(events.get(&id).cloned());
+        let state_with = |id: &str| {
+            let mut state = StateMap::new();
+            state.insert(StateEventType::RoomCreate.with_state_key(""), event_id("CREATE"));
+            state.insert(StateEventType::RoomHistoryVisibility.with_state_key(""), event_id(id));
+            state
+        };
+
+        assert!(crate::is_world_readable(&state_with("HVW"), &fetcher).await);
+        assert!(!crate::is_world_readable(&state_with("HVS"), &fetcher).await);
+    }
+
+    #[tokio::test]
+    async fn encryption_downgrade() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        let encryption = to_pdu_event(
+            "ENC",
+            alice(),
+            TimelineEventType::RoomEncryption,
+            Some(""),
+            to_raw_json_value(&json!({ "algorithm": "m.megolm.v1.aes-sha2" })).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        );
+        events.insert(encryption.event_id.clone(), encryption);
+
+        let fetcher = |id: <PduEvent as Event>::Id| ready(events.get(&id).cloned());
+        let mut unencrypted = StateMap::new();
+        unencrypted.insert(StateEventType::RoomCreate.with_state_key(""), event_id("CREATE"));
+        let mut encrypted = unencrypted.clone();
+        encrypted.insert(StateEventType::RoomEncryption.with_state_key(""), event_id("ENC"));
+
+        assert!(crate::detect_encryption_downgrade(&encrypted, &unencrypted, &fetcher).await);
+        assert!(!crate::detect_encryption_downgrade(&encrypted, &encrypted, &fetcher).await);
+        assert!(!crate::detect_encryption_downgrade(&unencrypted, &encrypted, &fetcher).await);
+        assert!(!crate::detect_encryption_downgrade(&unencrypted, &unencrypted, &fetcher).await);
+    }
+
+    #[tokio::test]
+    async fn filter_pdus_by_acl() {
+        use futures_util::future::ready;
+        use ruma_common::user_id;
+
+        let mut events = INITIAL_EVENTS();
+        let acl = to_pdu_event(
+            "ACL",
+            alice(),
+            TimelineEventType::RoomServerAcl,
+            Some(""),
+            to_raw_json_value(&json!({ "allow": ["*"], "deny": ["evil.example"] })).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        );
+        events.insert(acl.event_id.clone(), acl);
+
+        let message = |id: &str, sender| {
+            to_pdu_event(
+                id,
+                sender,
+                TimelineEventType::RoomMessage,
+                None,
+                to_raw_json_value(&json!({ "msgtype": "m.text", "body": id })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            )
+        };
+        let pdus = vec![
+            message("MA", alice()),
+            message("MX", user_id!("@mallory:evil.example")),
+            message("MB", bob()),
         ];
 
-        let edges = vec![vec!["END", "MB", "T2", "PA", "T1", "START"], vec!["END", "T1"]]
-            .into_iter()
-            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-            .collect::<Vec<_>>();
+        let fetcher = |id: <PduEvent as Event>::Id| ready(events.get(&id).cloned());
+        let mut state = StateMap::new();
+        state.insert(StateEventType::RoomCreate.with_state_key(""), event_id("CREATE"));
 
-        let expected_state_ids =
-            vec!["T1", "MB", "PA"].into_iter().map(event_id).collect::<Vec<_>>();
+        // Without ACL, all the PDUs are accepted.
+        let (accepted, rejected) = crate::filter_pdus_by_acl(pdus.clone(), &state, &fetcher).await;
+        assert_eq!(accepted.len(), 3);
+        assert!(rejected.is_empty());
 
-        do_check(events, edges, expected_state_ids).await;
+        state.insert(StateEventType::RoomServerAcl.with_state_key(""), event_id("ACL"));
+        let (accepted, rejected) = crate::filter_pdus_by_acl(pdus, &state, &fetcher).await;
+        let ids = |pdus: Vec<Arc<PduEvent>>| {
+            pdus.into_iter().map(|pdu| pdu.event_id.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(accepted), [event_id("MA"), event_id("MB")]);
+        assert_eq!(ids(rejected), [event_id("MX")]);
     }
 
     #[tokio::test]
-    async fn join_rule_evasion() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+    async fn build_state_response() {
+        use futures_util::future::ready;
 
-        let events = &[
-            to_init_pdu_event(
-                "JR",
-                alice(),
-                TimelineEventType::RoomJoinRules,
-                Some(""),
-                to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Private)).unwrap(),
-            ),
-            to_init_pdu_event(
-                "ME",
-                ella(),
-                TimelineEventType::RoomMember,
-                Some(ella().to_string().as_str()),
-                member_content_join(),
-            ),
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = store.0.get(&event_id(id)).unwrap();
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "MB", "PA"]),
+            state_set(&["CREATE", "IJR", "IMA", "IMB", "IMC", "IME", "PA"]),
         ];
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
 
-        let edges = vec![vec!["END", "JR", "START"], vec!["END", "ME", "START"]]
+        let ev_map = &store.0;
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+
+        let resolved =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+
+        let (pdus, auth_chain) = crate::build_state_response(&resolved, &fetcher).await.unwrap();
+
+        let mut state_ids = resolved.values().cloned().collect::<Vec<_>>();
+        state_ids.sort();
+        assert_eq!(pdus.iter().map(|ev| ev.event_id.clone()).collect::<Vec<_>>(), state_ids);
+
+        let auth_event_ids = pdus.iter().flat_map(|ev| ev.auth_events().cloned()).collect();
+        let expected = store.auth_event_ids(room_id(), auth_event_ids).unwrap();
+        let auth_chain_ids =
+            auth_chain.iter().map(|ev| ev.event_id.clone()).collect::<HashSet<_>>();
+        assert_eq!(auth_chain.len(), auth_chain_ids.len());
+        assert_eq!(auth_chain_ids, expected);
+
+        let mut incomplete = resolved.clone();
+        incomplete.insert(StateEventType::RoomTopic.with_state_key(""), event_id("MISSING"));
+        let res = crate::build_state_response(&incomplete, &fetcher).await;
+        assert!(matches!(res, Err(Error::NotFound(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn join_rule_with_auth_chain() {
+        let join_rule = JOIN_RULE();
+
+        let edges = vec![vec!["END", "JR", "START"], vec!["END", "IMZ", "START"]]
             .into_iter()
             .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
             .collect::<Vec<_>>();
 
-        let expected_state_ids = vec![event_id("JR")];
+        let expected_state_ids = vec!["JR"].into_iter().map(event_id).collect::<Vec<_>>();
 
-        do_check(events, edges, expected_state_ids).await;
+        do_check(&join_rule.values().cloned().collect::<Vec<_>>(), edges, expected_state_ids).await;
     }
 
     #[tokio::test]
-    async fn offtopic_power_level() {
+    async fn strict_sender_membership() {
+        use futures_util::future::ready;
+
         let _ =
             tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
 
-        let events = &[
-            to_init_pdu_event(
-                "PA",
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("IPOWER"),
+            to_pdu_event(
+                "IPOWER",
                 alice(),
                 TimelineEventType::RoomPowerLevels,
                 Some(""),
                 to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                &["CREATE", "IMA"],
+                &["IMA"],
             ),
-            to_init_pdu_event(
-                "PB",
+        );
+        // Bob's topic is authorized by his join, but he is not joined in the resolved state.
+        events.insert(
+            event_id("T"),
+            to_pdu_event(
+                "T",
                 bob(),
-                TimelineEventType::RoomPowerLevels,
-                Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50, charlie(): 50 } }))
-                    .unwrap(),
-            ),
-            to_init_pdu_event(
-                "PC",
-                charlie(),
-                TimelineEventType::RoomPowerLevels,
+                TimelineEventType::RoomTopic,
                 Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50, charlie(): 0 } }))
-                    .unwrap(),
+                to_raw_json_value(&json!({ "topic": "replayed" })).unwrap(),
+                &["CREATE", "IPOWER", "IMB"],
+                &["IMB"],
             ),
-        ];
+        );
 
-        let edges = vec![vec!["END", "PC", "PB", "PA", "START"], vec!["END", "PA"]]
+        let state = ["CREATE", "IMA", "IPOWER", "IJR"]
             .into_iter()
-            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-            .collect::<Vec<_>>();
+            .map(|id| {
+                let ev = &events[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+            })
+            .collect::<StateMap<_>>();
+        let fetcher = |id| ready(events.get(&id).cloned());
+        let topic_key = StateEventType::RoomTopic.with_state_key("");
 
-        let expected_state_ids = vec!["PC"].into_iter().map(event_id).collect::<Vec<_>>();
+        let resolved = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("T")],
+            state.clone(),
+            &fetcher,
+            &ResolveOptions::default(),
+            &|| true,
+            &(),
+            &mut Vec::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved.get(&topic_key), Some(&event_id("T")));
 
-        do_check(events, edges, expected_state_ids).await;
+        let mut options = ResolveOptions::new();
+        options.strict_sender_membership = true;
+        options.report_rejected = true;
+        let mut rejected = Vec::new();
+        let resolved = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("T")],
+            state,
+            &fetcher,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved.get(&topic_key), None);
+        assert_eq!(rejected, [(event_id("T"), RejectionReason::SenderNotJoined)]);
     }
 
     #[tokio::test]
-    async fn topic_setting() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+    async fn enforce_server_acl() {
+        use futures_util::future::ready;
 
-        let events = &[
-            to_init_pdu_event(
-                "T1",
-                alice(),
-                TimelineEventType::RoomTopic,
-                Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
-            ),
-            to_init_pdu_event(
-                "PA1",
+        let mut events = INITIAL_EVENTS();
+        // The ACL denies the server of all the users.
+        events.insert(
+            event_id("ACL"),
+            to_pdu_event(
+                "ACL",
                 alice(),
-                TimelineEventType::RoomPowerLevels,
+                TimelineEventType::RoomServerAcl,
                 Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                to_raw_json_value(&json!({ "allow": ["*"], "deny": ["foo"] })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
             ),
-            to_init_pdu_event(
-                "T2",
+        );
+        events.insert(
+            event_id("T"),
+            to_pdu_event(
+                "T",
                 alice(),
                 TimelineEventType::RoomTopic,
                 Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
+                to_raw_json_value(&json!({ "topic": "denied" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["ACL"],
             ),
-            to_init_pdu_event(
-                "PA2",
+        );
+
+        let state = ["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "ACL"]
+            .into_iter()
+            .map(|id| {
+                let ev = &events[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+            })
+            .collect::<StateMap<_>>();
+        let fetcher = |id| ready(events.get(&id).cloned());
+        let topic_key = StateEventType::RoomTopic.with_state_key("");
+
+        let resolved = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("T")],
+            state.clone(),
+            &fetcher,
+            &ResolveOptions::default(),
+            &|| true,
+            &(),
+            &mut Vec::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved.get(&topic_key), Some(&event_id("T")));
+
+        let mut options = ResolveOptions::new();
+        options.enforce_server_acl = true;
+        options.report_rejected = true;
+        let mut rejected = Vec::new();
+        let resolved = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("T")],
+            state,
+            &fetcher,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved.get(&topic_key), None);
+        assert_eq!(rejected, [(event_id("T"), RejectionReason::ServerDenied)]);
+    }
+
+    #[test]
+    fn with_state_key_custom_event_type() {
+        let (event_type, state_key) = TimelineEventType::from("com.example.foo").with_state_key("");
+        assert_eq!(event_type, StateEventType::from("com.example.foo"));
+        assert_eq!(event_type.to_string(), "com.example.foo");
+        assert_eq!(state_key, "");
+
+        let (event_type, state_key) =
+            TimelineEventType::from("com.example.Foo_bar").with_state_key("@alice:example.org");
+        assert_eq!(event_type.to_string(), "com.example.Foo_bar");
+        assert_eq!(state_key, "@alice:example.org");
+
+        let (event_type, _) = TimelineEventType::RoomMember.with_state_key("");
+        assert_eq!(event_type, StateEventType::RoomMember);
+    }
+
+    #[tokio::test]
+    async fn duplicate_auth_events() {
+        use futures_util::future::ready;
+
+        let mut events = INITIAL_EVENTS();
+        events.insert(
+            event_id("IPOWER2"),
+            to_pdu_event(
+                "IPOWER2",
                 alice(),
                 TimelineEventType::RoomPowerLevels,
                 Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 0 } })).unwrap(),
-            ),
-            to_init_pdu_event(
-                "PB",
-                bob(),
-                TimelineEventType::RoomPowerLevels,
-                Some(""),
-                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
-            ),
-            to_init_pdu_event(
-                "T3",
-                bob(),
-                TimelineEventType::RoomTopic,
-                Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
+                to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 100 } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IPOWER"],
             ),
-            to_init_pdu_event(
-                "MZ1",
-                zara(),
+        );
+        // The same auth event is listed twice.
+        events.insert(
+            event_id("TA"),
+            to_pdu_event(
+                "TA",
+                alice(),
                 TimelineEventType::RoomTopic,
                 Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
+                to_raw_json_value(&json!({ "topic": "duplicate" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER", "IPOWER"],
+                &["IMC"],
             ),
-            to_init_pdu_event(
-                "T4",
-                alice(),
+        );
+        // Two different power levels events are listed.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
                 TimelineEventType::RoomTopic,
                 Some(""),
-                to_raw_json_value(&json!({})).unwrap(),
+                to_raw_json_value(&json!({ "topic": "conflict" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER", "IPOWER2"],
+                &["IMC"],
             ),
-        ];
+        );
 
-        let edges = vec![
-            vec!["END", "T4", "MZ1", "PA2", "T2", "PA1", "T1", "START"],
-            vec!["END", "MZ1", "T3", "PB", "PA1"],
-        ]
-        .into_iter()
-        .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-        .collect::<Vec<_>>();
+        let state = ["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]
+            .into_iter()
+            .map(|id| {
+                let ev = &events[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+            })
+            .collect::<StateMap<_>>();
+        let fetcher = |id| ready(events.get(&id).cloned());
+        let topic_key = StateEventType::RoomTopic.with_state_key("");
 
-        let expected_state_ids = vec!["T4", "PA2"].into_iter().map(event_id).collect::<Vec<_>>();
+        let mut options = ResolveOptions::new();
+        options.report_rejected = true;
 
-        do_check(events, edges, expected_state_ids).await;
+        let mut rejected = Vec::new();
+        let resolved = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("TA")],
+            state.clone(),
+            &fetcher,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved.get(&topic_key), Some(&event_id("TA")));
+        assert!(rejected.is_empty());
+
+        let resolved = crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("TB")],
+            state,
+            &fetcher,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved.get(&topic_key), None);
+        assert_eq!(rejected, [(event_id("TB"), RejectionReason::DuplicateAuthEvents)]);
     }
 
     #[tokio::test]
-    async fn test_event_map_none() {
+    async fn missing_auth_event_rejection_reason() {
         use futures_util::future::ready;
 
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut events = INITIAL_EVENTS();
+        // Bob doesn't have the power level required to change the topic, regardless of the
+        // missing auth event.
+        events.insert(
+            event_id("TA"),
+            to_pdu_event(
+                "TA",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IMB", "IPOWER", "MISSING"],
+                &["IMC"],
+            ),
+        );
+        // The membership of Bob is not found, it might be the missing auth event.
+        events.insert(
+            event_id("TB"),
+            to_pdu_event(
+                "TB",
+                bob(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "bob" })).unwrap(),
+                &["CREATE", "IPOWER", "MISSING"],
+                &["IMC"],
+            ),
+        );
 
-        let mut store = TestStore::<PduEvent>(hashmap! {});
+        let state_map = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| {
+                    let ev = &events[&event_id(id)];
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let fetcher = |id| ready(events.get(&id).cloned());
 
-        // build up the DAG
-        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+        let mut options = ResolveOptions::new();
+        options.report_rejected = true;
 
-        let ev_map = store.0.clone();
-        let fetcher = |id| ready(ev_map.get(&id).cloned());
+        let mut rejected = Vec::new();
+        crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("TA")],
+            state_map(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]),
+            &fetcher,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rejected, [(event_id("TA"), RejectionReason::InsufficientPowerLevel)]);
 
-        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&*id).is_some());
+        rejected.clear();
+        crate::iterative_auth_check(
+            &RoomVersion::V6,
+            &[event_id("TB")],
+            state_map(&["CREATE", "IMA", "IPOWER", "IJR", "IMC"]),
+            &fetcher,
+            &options,
+            &|| true,
+            &(),
+            &mut rejected,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rejected,
+            [(event_id("TB"), RejectionReason::MissingAuthEvent(event_id("MISSING")))]
+        );
+    }
 
-        let state_sets = [state_at_bob, state_at_charlie];
-        let auth_chain = state_sets
+    #[test]
+    fn separate_is_deterministic() {
+        let keys = [
+            StateEventType::RoomTopic.with_state_key(""),
+            StateEventType::RoomName.with_state_key(""),
+            StateEventType::RoomMember.with_state_key(alice().as_str()),
+            StateEventType::RoomMember.with_state_key(bob().as_str()),
+        ];
+
+        let first: StateMap<_> = keys
             .iter()
-            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .enumerate()
+            .map(|(i, key)| (key.clone(), event_id(&format!("A{i}"))))
+            .collect();
+        let second: StateMap<_> = keys
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, key)| (key.clone(), event_id(&format!("B{i}"))))
             .collect();
+        let state_sets = [first, second];
 
-        let resolved =
-            match crate::resolve(&RoomVersionId::V2, &state_sets, &auth_chain, &fetcher, &exists)
-                .await
-            {
-                Ok(state) => state,
-                Err(e) => panic!("{e}"),
-            };
+        let (unconflicted, conflicted) = separate(state_sets.iter());
+        assert!(unconflicted.is_empty());
 
-        assert_eq!(expected, resolved);
+        for _ in 0..10 {
+            assert_eq!(separate(state_sets.iter()), (unconflicted.clone(), conflicted.clone()));
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(conflicted[key], [event_id(&format!("A{i}")), event_id(&format!("B{i}"))]);
+        }
     }
 
     #[tokio::test]
-    async fn test_lexicographical_sort() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+    async fn auth_chain_for_diamond() {
+        use std::sync::Mutex;
 
-        let graph = hashmap! {
-            event_id("l") => hashset![event_id("o")],
-            event_id("m") => hashset![event_id("n"), event_id("o")],
-            event_id("n") => hashset![event_id("o")],
-            event_id("o") => hashset![], // "o" has zero outgoing edges but 4 incoming edges
-            event_id("p") => hashset![event_id("o")],
+        use futures_util::future::ready;
+
+        let topic = |id: &str, auth_events: &[&str]| {
+            to_pdu_event(
+                id,
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": id })).unwrap(),
+                auth_events,
+                &[],
+            )
         };
 
-        let res = crate::lexicographical_topological_sort(&graph, &|_id| async {
-            Ok((int!(0), MilliSecondsSinceUnixEpoch(uint!(0))))
-        })
-        .await
-        .unwrap();
+        // D is authorized by B and C, which are both authorized by A and CREATE.
+        let events: HashMap<_, _> = [
+            topic("CREATE", &[]),
+            topic("A", &["CREATE"]),
+            topic("B", &["CREATE", "A"]),
+            topic("C", &["CREATE", "A"]),
+            topic("D", &["B", "C"]),
+            // X and Y are in a cycle.
+            topic("X", &["Y"]),
+            topic("Y", &["X"]),
+        ]
+        .into_iter()
+        .map(|ev| (ev.event_id.clone(), ev))
+        .collect();
+
+        let fetched = Mutex::new(Vec::new());
+        let fetcher = |id: OwnedEventId| {
+            fetched.lock().unwrap().push(id.clone());
+            ready(events.get(&id).cloned())
+        };
+        let ids = |ids: &[&str]| ids.iter().map(|id| event_id(id)).collect::<HashSet<_>>();
+
+        let chain = crate::auth_chain_for(event_id("D"), &fetcher, None).await;
+        assert_eq!(chain, ids(&["B", "C", "A", "CREATE"]));
 
+        // Each event is fetched once, even if it is in the auth events of several events.
+        let mut fetched_ids = std::mem::take(&mut *fetched.lock().unwrap());
+        fetched_ids.sort();
+        assert_eq!(fetched_ids, ["A", "B", "C", "CREATE", "D"].map(event_id));
+
+        assert_eq!(crate::auth_chain_for(event_id("D"), &fetcher, Some(1)).await, ids(&["B", "C"]));
         assert_eq!(
-            vec!["o", "l", "n", "m", "p"],
-            res.iter()
-                .map(ToString::to_string)
-                .map(|s| s.replace('$', "").replace(":foo", ""))
-                .collect::<Vec<_>>()
+            crate::auth_chain_for(event_id("D"), &fetcher, Some(2)).await,
+            ids(&["B", "C", "A", "CREATE"])
         );
+
+        // The walk stops in a cycle.
+        assert_eq!(crate::auth_chain_for(event_id("X"), &fetcher, None).await, ids(&["Y"]));
     }
+    #[test]
+    fn auth_chain_diff_incremental() {
+        let auth_chain = |ids: &[&str]| ids.iter().map(|id| event_id(id)).collect::<HashSet<_>>();
+        let auth_chain_sets = vec![
+            auth_chain(&["CREATE", "IMA", "IPOWER", "IJR"]),
+            auth_chain(&["CREATE", "IMA", "IPOWER", "IMB"]),
+            auth_chain(&["CREATE", "IMA", "IJR", "IMC"]),
+        ];
 
-    #[tokio::test]
-    async fn ban_with_auth_chains() {
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
-        let ban = BAN_STATE_SET();
+        let mut auth_chain_diff = AuthChainDiff::new();
+        for auth_chain in &auth_chain_sets {
+            auth_chain_diff.add_fork(auth_chain);
+        }
+        assert_eq!(auth_chain_diff.num_forks(), 3);
+        assert_eq!(
+            auth_chain_diff.iter().cloned().collect::<HashSet<_>>(),
+            crate::get_auth_chain_diff(&auth_chain_sets).collect::<HashSet<_>>()
+        );
 
-        let edges = vec![vec!["END", "MB", "PA", "START"], vec!["END", "IME", "MB"]]
-            .into_iter()
-            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-            .collect::<Vec<_>>();
+        auth_chain_diff.remove_fork(&auth_chain_sets[2]);
+        assert_eq!(auth_chain_diff.num_forks(), 2);
+        assert_eq!(
+            auth_chain_diff.into_diff().collect::<HashSet<_>>(),
+            crate::get_auth_chain_diff(&auth_chain_sets[..2].to_vec()).collect::<HashSet<_>>()
+        );
+    }
 
-        let expected_state_ids = vec!["PA", "MB"].into_iter().map(event_id).collect::<Vec<_>>();
+    #[test]
+    fn auth_chain_diff_without_forks() {
+        let auth_chain = |ids: &[&str]| ids.iter().map(|id| event_id(id)).collect::<HashSet<_>>();
 
-        do_check(&ban.values().cloned().collect::<Vec<_>>(), edges, expected_state_ids).await;
+        assert_eq!(crate::get_auth_chain_diff::<OwnedEventId>(&vec![]).count(), 0);
+        assert_eq!(
+            crate::get_auth_chain_diff(&vec![auth_chain(&["CREATE", "IMA", "IPOWER"])]).count(),
+            0
+        );
+        assert_eq!(
+            crate::get_auth_chain_diff(&vec![
+                auth_chain(&["CREATE", "IMA", "IPOWER"]),
+                auth_chain(&["CREATE", "IMA", "IJR"]),
+            ])
+            .collect::<HashSet<_>>(),
+            HashSet::from([event_id("IPOWER"), event_id("IJR")])
+        );
     }
 
-    #[tokio::test]
-    async fn ban_with_auth_chains2() {
-        use futures_util::future::ready;
+    #[test]
+    fn separate_with_counts() {
+        let create = StateEventType::RoomCreate.with_state_key("");
+        let topic = StateEventType::RoomTopic.with_state_key("");
+        let name = StateEventType::RoomName.with_state_key("");
 
-        let _ =
-            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
-        let init = INITIAL_EVENTS();
-        let ban = BAN_STATE_SET();
+        let state_sets = [
+            StateMap::from([(create.clone(), event_id("CREATE")), (topic.clone(), event_id("T"))]),
+            StateMap::from([(create.clone(), event_id("CREATE")), (topic.clone(), event_id("T"))]),
+            StateMap::from([(create.clone(), event_id("CREATE")), (name.clone(), event_id("N"))]),
+        ];
 
-        let mut inner = init.clone();
-        inner.extend(ban);
-        let store = TestStore(inner.clone());
+        let (unconflicted, conflicted, present_in) = crate::separate_with_counts(state_sets.iter());
 
-        let state_set_a = [
-            inner.get(&event_id("CREATE")).unwrap(),
-            inner.get(&event_id("IJR")).unwrap(),
-            inner.get(&event_id("IMA")).unwrap(),
-            inner.get(&event_id("IMB")).unwrap(),
-            inner.get(&event_id("IMC")).unwrap(),
-            inner.get(&event_id("MB")).unwrap(),
-            inner.get(&event_id("PA")).unwrap(),
-        ]
-        .iter()
-        .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone()))
-        .collect::<StateMap<_>>();
+        assert_eq!(unconflicted, StateMap::from([(create.clone(), event_id("CREATE"))]));
+        assert_eq!(
+            conflicted,
+            StateMap::from([
+                (topic.clone(), vec![event_id("T"), event_id("T")]),
+                (name.clone(), vec![event_id("N")]),
+            ])
+        );
+        assert_eq!(present_in, StateMap::from([(create, 3)]));
+    }
 
-        let state_set_b = [
-            inner.get(&event_id("CREATE")).unwrap(),
-            inner.get(&event_id("IJR")).unwrap(),
-            inner.get(&event_id("IMA")).unwrap(),
-            inner.get(&event_id("IMB")).unwrap(),
-            inner.get(&event_id("IMC")).unwrap(),
-            inner.get(&event_id("IME")).unwrap(),
-            inner.get(&event_id("PA")).unwrap(),
-        ]
-        .iter()
-        .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone()))
-        .collect::<StateMap<_>>();
+    #[test]
+    fn state_map_hash_ignores_insertion_order() {
+        let entries = [
+            (StateEventType::RoomCreate.with_state_key(""), event_id("CREATE")),
+            (StateEventType::RoomMember.with_state_key(alice().as_str()), event_id("IMA")),
+            (StateEventType::RoomPowerLevels.with_state_key(""), event_id("IPOWER")),
+            (StateEventType::RoomJoinRules.with_state_key(""), event_id("IJR")),
+        ];
 
-        let ev_map = &store.0;
-        let state_sets = [state_set_a, state_set_b];
-        let auth_chain = state_sets
-            .iter()
-            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
-            .collect();
+        let mut forward = StateMap::new();
+        for (key, id) in entries.iter().cloned() {
+            forward.insert(key, id);
+        }
+        let mut backward = StateMap::new();
+        for (key, id) in entries.iter().rev().cloned() {
+            backward.insert(key, id);
+        }
+        assert_eq!(state_map_hash(&forward), state_map_hash(&backward));
 
-        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
-        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
-        let resolved =
-            match crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain, &fetcher, &exists)
-                .await
-            {
-                Ok(state) => state,
-                Err(e) => panic!("{e}"),
-            };
+        let mut different = forward.clone();
+        different.insert(StateEventType::RoomJoinRules.with_state_key(""), event_id("JR"));
+        assert_ne!(state_map_hash(&forward), state_map_hash(&different));
 
-        debug!(
-            resolved = ?resolved
-                .iter()
-                .map(|((ty, key), id)| format!("(({ty}{key:?}), {id})"))
-                .collect::<Vec<_>>(),
-                "resolved state",
-        );
+        let mut fewer = forward.clone();
+        fewer.remove(&StateEventType::RoomJoinRules.with_state_key(""));
+        assert_ne!(state_map_hash(&forward), state_map_hash(&fewer));
+    }
 
-        let expected =
-            ["$CREATE:foo", "$IJR:foo", "$PA:foo", "$IMA:foo", "$IMB:foo", "$IMC:foo", "$MB:foo"];
+    #[test]
+    fn merge_state_maps_without_overlap() {
+        let topic = StateEventType::RoomTopic.with_state_key("");
+        let name = StateEventType::RoomName.with_state_key("");
 
-        for id in expected.iter().map(|i| event_id(i)) {
-            // make sure our resolved events are equal to the expected list
-            assert!(resolved.values().any(|eid| eid == &id) || init.contains_key(&id), "{id}");
-        }
-        assert_eq!(expected.len(), resolved.len());
+        let mut state = hashmap! { topic.clone() => event_id("T") };
+        state.merge_with(hashmap! { name.clone() => event_id("N") }, |_, _, _| {
+            panic!("no key is in both maps")
+        });
+
+        assert_eq!(state, hashmap! { topic => event_id("T"), name => event_id("N") });
     }
 
-    #[tokio::test]
-    async fn join_rule_with_auth_chain() {
-        let join_rule = JOIN_RULE();
+    #[test]
+    fn merge_state_maps_with_overlap() {
+        let topic = StateEventType::RoomTopic.with_state_key("");
+        let name = StateEventType::RoomName.with_state_key("");
+        let current = hashmap! { topic.clone() => event_id("T1"), name.clone() => event_id("N1") };
+        let new = hashmap! { topic.clone() => event_id("T2"), name.clone() => event_id("N2") };
+
+        // Keep the current value for the topic, and the new one for the name.
+        let mut state = current.clone();
+        let mut conflicts = Vec::new();
+        state.merge_with(new.clone(), |key, a, b| {
+            conflicts.push(key.clone());
+            if key.0 == StateEventType::RoomTopic {
+                a
+            } else {
+                b
+            }
+        });
 
-        let edges = vec![vec!["END", "JR", "START"], vec!["END", "IMZ", "START"]]
-            .into_iter()
-            .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
-            .collect::<Vec<_>>();
+        conflicts.sort();
+        assert_eq!(conflicts, vec![name.clone(), topic.clone()]);
+        assert_eq!(
+            state,
+            hashmap! { topic.clone() => event_id("T1"), name.clone() => event_id("N2") }
+        );
 
-        let expected_state_ids = vec!["JR"].into_iter().map(event_id).collect::<Vec<_>>();
+        // Always keep the current values.
+        let mut state = current.clone();
+        state.merge_with(new.clone(), |_, a, _| a);
+        assert_eq!(state, current);
 
-        do_check(&join_rule.values().cloned().collect::<Vec<_>>(), edges, expected_state_ids).await;
+        // Always use the new values.
+        let mut state = current;
+        state.merge_with(new.clone(), |_, _, b| b);
+        assert_eq!(state, new);
     }
 
     #[allow(non_snake_case)]
@@ -123,6 +123,17 @@ pub(crate) fn deserialize_power_levels(
     }
 }
 
+/// The fields of the content of an `m.room.power_levels` event that are used during authorization.
+///
+/// Missing fields get the defaults from the spec:
+///
+/// * `users_default` and `events_default` default to `0`,
+/// * `state_default` defaults to `50`,
+/// * `users` and `events` default to an empty map.
+///
+/// When there is no `m.room.power_levels` event at all, the creator of the room has a power level
+/// of `100`, every other user has a power level of `0`, and the levels required to send events
+/// are the same as with an event with an empty content.
 #[derive(Deserialize)]
 pub(crate) struct PowerLevelsContentFields {
     #[serde(default, deserialize_with = "btreemap_deserialize_v1_powerlevel_values")]
@@ -130,6 +141,30 @@ pub(crate) struct PowerLevelsContentFields {
 
     #[serde(default, deserialize_with = "deserialize_v1_powerlevel")]
     pub(crate) users_default: Int,
+
+    #[serde(default, deserialize_with = "btreemap_deserialize_v1_powerlevel_values")]
+    pub(crate) events: BTreeMap<TimelineEventType, Int>,
+
+    #[serde(default, deserialize_with = "deserialize_v1_powerlevel")]
+    pub(crate) events_default: Int,
+
+    #[serde(default = "default_power_level", deserialize_with = "deserialize_v1_powerlevel")]
+    pub(crate) state_default: Int,
+}
+
+impl PowerLevelsContentFields {
+    /// The power level required to send an event with the given type and state key.
+    pub(crate) fn send_level(
+        &self,
+        event_type: &TimelineEventType,
+        state_key: Option<&str>,
+    ) -> Int {
+        self.events.get(event_type).copied().unwrap_or(if state_key.is_some() {
+            self.state_default
+        } else {
+            self.events_default
+        })
+    }
 }
 
 #[derive(Deserialize)]
@@ -139,12 +174,27 @@ struct IntPowerLevelsContentFields {
 
     #[serde(default)]
     users_default: Int,
+
+    #[serde(default)]
+    events: BTreeMap<TimelineEventType, Int>,
+
+    #[serde(default)]
+    events_default: Int,
+
+    #[serde(default = "default_power_level")]
+    state_default: Int,
 }
 
 impl From<IntPowerLevelsContentFields> for PowerLevelsContentFields {
     fn from(pl: IntPowerLevelsContentFields) -> Self {
-        let IntPowerLevelsContentFields { users, users_default } = pl;
-        Self { users, users_default }
+        let IntPowerLevelsContentFields {
+            users,
+            users_default,
+            events,
+            events_default,
+            state_default,
+        } = pl;
+        Self { users, users_default, events, events_default, state_default }
     }
 }
 
@@ -218,3 +268,64 @@ pub(crate) fn deserialize_power_levels_content_redact(
         from_json_str(content)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use js_int::int;
+    use ruma_common::UserId;
+    use ruma_events::TimelineEventType;
+    use serde_json::json;
+
+    use super::deserialize_power_levels_content_fields;
+    use crate::RoomVersion;
+
+    #[test]
+    fn send_level_defaults() {
+        // All the defaults.
+        let content =
+            deserialize_power_levels_content_fields(&json!({}).to_string(), &RoomVersion::V6)
+                .unwrap();
+        assert_eq!(content.send_level(&TimelineEventType::RoomName, Some("")), int!(50));
+        assert_eq!(content.send_level(&TimelineEventType::RoomMessage, None), int!(0));
+
+        // Overriding some events doesn't change the defaults.
+        let content = deserialize_power_levels_content_fields(
+            &json!({ "events": { "m.room.name": 100 } }).to_string(),
+            &RoomVersion::V6,
+        )
+        .unwrap();
+        assert_eq!(content.send_level(&TimelineEventType::RoomName, Some("")), int!(100));
+        assert_eq!(content.send_level(&TimelineEventType::RoomTopic, Some("")), int!(50));
+        assert_eq!(content.send_level(&TimelineEventType::RoomMessage, None), int!(0));
+
+        // Explicit defaults.
+        let content = deserialize_power_levels_content_fields(
+            &json!({ "events_default": 10, "state_default": 0 }).to_string(),
+            &RoomVersion::V6,
+        )
+        .unwrap();
+        assert_eq!(content.send_level(&TimelineEventType::RoomTopic, Some("")), int!(0));
+        assert_eq!(content.send_level(&TimelineEventType::RoomMessage, None), int!(10));
+    }
+
+    #[test]
+    fn send_level_string_values() {
+        let content = json!({ "events_default": "10", "state_default": "20" }).to_string();
+
+        let fields = deserialize_power_levels_content_fields(&content, &RoomVersion::V6).unwrap();
+        assert_eq!(fields.send_level(&TimelineEventType::RoomTopic, Some("")), int!(20));
+        assert_eq!(fields.send_level(&TimelineEventType::RoomMessage, None), int!(10));
+
+        assert!(deserialize_power_levels_content_fields(&content, &RoomVersion::V10).is_err());
+    }
+
+    #[test]
+    fn users_string_values() {
+        let content = json!({ "users": { "@a:b": "100" } }).to_string();
+
+        let fields = deserialize_power_levels_content_fields(&content, &RoomVersion::V9).unwrap();
+        assert_eq!(fields.users.get(<&UserId>::try_from("@a:b").unwrap()), Some(&int!(100)));
+
+        assert!(deserialize_power_levels_content_fields(&content, &RoomVersion::V10).is_err());
+    }
+}
@@ -0,0 +1,1290 @@
+//! The configurable entry points of the state resolution.
+
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures_util::{future, Future, FutureExt};
+use ruma_common::{EventId, OwnedEventId, OwnedUserId, RoomVersionId};
+use ruma_events::StateEventType;
+use tracing::{debug, trace};
+
+use crate::{
+    auth_chain, auth_check_unconflicted, get_auth_chain_diff, resolve_with_auth_chain_diff,
+    room_creator, room_version::StateResolutionVersion, separate, v1, AuthError, Error, Event,
+    PowerLevelCache, Result, RoomVersion, StateMap,
+};
+
+/// Options to customize the behavior of a [`StateResolution`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ResolveOptions {
+    /// Whether the sender of each conflicted event must be joined in the state being resolved.
+    ///
+    /// By default, an event is authorized against its own `auth_events`, with the state being
+    /// resolved taking precedence. With this option, an event whose sender is not joined in the
+    /// state being resolved is rejected, even if its `auth_events` say otherwise. This protects
+    /// against events being replayed after their sender left the room.
+    ///
+    /// This does not apply to `m.room.create` events and to the `m.room.member` events of a user
+    /// changing their own membership.
+    pub strict_sender_membership: bool,
+
+    /// The maximum number of events in the full conflicted set.
+    ///
+    /// The full conflicted set contains the conflicted events and the difference of the auth
+    /// chains of the state sets. If it grows larger than this limit, the resolution fails with
+    /// [`Error::TooManyConflictedEvents`]. This bounds the memory and the work used to resolve
+    /// state with malicious or broken forks.
+    ///
+    /// In room version 1, auth chains are not used, so this only limits the number of conflicted
+    /// events.
+    ///
+    /// Defaults to `None`, which means that there is no limit.
+    pub max_conflicted_events: Option<usize>,
+
+    /// Whether the conflicted events that are rejected during the resolution are reported.
+    ///
+    /// Rejected events are never part of the resolved state. With this option, their IDs are
+    /// logged and returned in [`ResolvedState::rejected`] by
+    /// [`StateResolution::resolve_extra()`], in the order in which they were checked, so servers
+    /// can mark them as rejected.
+    pub report_rejected: bool,
+
+    /// Whether the unconflicted state is also checked against the authorization rules.
+    ///
+    /// The state resolution algorithm trusts the unconflicted state, so it is always part of the
+    /// resolved state. With this option, each unconflicted event is additionally authorized
+    /// against its own `auth_events`, and the IDs of the events that fail are logged and returned
+    /// in [`ResolvedState::unconflicted_auth_failures`] by [`StateResolution::resolve_extra()`].
+    /// These events are not removed from the resolved state.
+    pub auth_check_unconflicted: bool,
+
+    /// The IDs of events that are accepted without checking them against the authorization rules.
+    ///
+    /// This allows servers to skip the authorization of events that they already validated, like
+    /// the events created locally. The state resolution algorithm relies on the authorization of
+    /// the conflicted events, so only events that are known to be allowed in the state being
+    /// resolved should be listed here. Otherwise, an event that should be rejected, like one sent
+    /// by a user that was banned in a concurrent fork, ends up in the resolved state.
+    pub trusted: HashSet<OwnedEventId>,
+
+    /// A cache for the power levels of the senders of the events and whether they are power
+    /// events.
+    ///
+    /// Defaults to `None`, which means that they are computed again during each resolution.
+    ///
+    /// This is not supported in room version 1, which doesn't use the power levels of the senders,
+    /// and the resolution fails with [`Error::UnsupportedOption`] if it is set.
+    pub power_level_cache: Option<Arc<PowerLevelCache>>,
+
+    /// The maximum number of events in the graph of the control events.
+    ///
+    /// The graph contains the conflicted control events and their auth events that are part of
+    /// the full conflicted set. If it grows larger than this limit while it is built, the
+    /// resolution fails with [`Error::GraphTooLarge`]. This bounds the memory used to sort the
+    /// control events when an event references an enormous auth chain.
+    ///
+    /// In room version 1, no graph is built, so this limit is never reached.
+    ///
+    /// Defaults to [`ResolveOptions::DEFAULT_MAX_GRAPH_NODES`].
+    pub max_graph_nodes: usize,
+
+    /// Whether conflicted events sent from servers denied by the `m.room.server_acl` event are
+    /// rejected.
+    ///
+    /// The ACL is the one of the state being resolved when the event is checked. If there is no
+    /// `m.room.server_acl` event, or if it can't be fetched or deserialized, all the servers are
+    /// allowed.
+    pub enforce_server_acl: bool,
+}
+
+impl ResolveOptions {
+    /// The default value of [`ResolveOptions::max_graph_nodes`].
+    pub const DEFAULT_MAX_GRAPH_NODES: usize = 100_000;
+
+    /// Creates a new `ResolveOptions` with the default behavior.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self {
+            strict_sender_membership: false,
+            max_conflicted_events: None,
+            report_rejected: false,
+            auth_check_unconflicted: false,
+            trusted: HashSet::new(),
+            power_level_cache: None,
+            max_graph_nodes: Self::DEFAULT_MAX_GRAPH_NODES,
+            enforce_server_acl: false,
+        }
+    }
+}
+
+/// A configurable resolution of sets of state events.
+///
+/// This holds the version of the room and the closures to fetch events, along with the options of
+/// the resolution. It is created with [`StateResolution::new()`], configured with the `with_*`
+/// methods, and the state is resolved with one of the `resolve*` methods, which can be called
+/// several times:
+///
+/// * [`StateResolution::resolve()`] returns the resolved state.
+/// * [`StateResolution::resolve_report()`] also returns the conflicted and rejected events.
+/// * [`StateResolution::resolve_extra()`] also returns the creator of the room.
+/// * [`StateResolution::resolve_partial()`] returns the state resolved so far if the resolution
+///   stops before the end.
+/// * [`StateResolution::resolve_conflicted()`] resolves state that was already separated.
+/// * [`StateResolution::resolve_compute_chains()`] computes the auth chains of the state sets.
+/// * [`StateResolution::resolve_fallible()`] uses a closure to fetch events that can fail.
+/// * [`StateResolution::resolve_sync()`] uses synchronous closures.
+///
+/// # Example
+///
+/// ```
+/// # use std::{collections::HashSet, time::{Duration, Instant}};
+/// # use ruma_common::{OwnedEventId, RoomVersionId};
+/// # use ruma_state_res::{Event, StateMap, StateResolution};
+/// # async fn example<E: Event<Id = OwnedEventId> + Send>(
+/// #     state_sets: Vec<StateMap<OwnedEventId>>,
+/// #     auth_chain_sets: Vec<HashSet<OwnedEventId>>,
+/// #     fetch_event: impl Fn(OwnedEventId) -> std::future::Ready<Option<E>> + Sync,
+/// #     event_exists: impl Fn(OwnedEventId) -> std::future::Ready<bool>,
+/// # ) -> ruma_state_res::Result<()>
+/// # where
+/// #     for<'a> &'a E: Send,
+/// # {
+/// let state = StateResolution::new(RoomVersionId::V10, fetch_event, event_exists)
+///     .with_deadline(Instant::now() + Duration::from_secs(10))
+///     .resolve(&state_sets, &auth_chain_sets)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct StateResolution<Fetch, Exists, Observer = (), Batch = Fetch, Continue = fn() -> bool> {
+    room_version: RoomVersionId,
+    event_fetch: Fetch,
+    event_exists: Exists,
+    options: ResolveOptions,
+    deadline: Option<Instant>,
+    observer: Observer,
+    batch_fetch: Option<Batch>,
+    should_continue: Option<Continue>,
+}
+
+impl<Fetch, Exists> StateResolution<Fetch, Exists> {
+    /// Creates a new `StateResolution` for a room with the given version.
+    ///
+    /// `event_fetch` is used to fetch events, and `event_exists` to check whether an event exists
+    /// without fetching it. The other options use their default value.
+    pub fn new(room_version: RoomVersionId, event_fetch: Fetch, event_exists: Exists) -> Self {
+        Self {
+            room_version,
+            event_fetch,
+            event_exists,
+            options: ResolveOptions::default(),
+            deadline: None,
+            observer: (),
+            batch_fetch: None,
+            should_continue: None,
+        }
+    }
+}
+
+impl<Fetch, Exists, Observer, Batch, Continue>
+    StateResolution<Fetch, Exists, Observer, Batch, Continue>
+{
+    /// Use the given options for the resolution.
+    ///
+    /// This replaces the options set previously, including the ones set with
+    /// [`StateResolution::with_power_cache()`] and [`StateResolution::with_max_graph_nodes()`].
+    pub fn with_options(mut self, options: ResolveOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Use the given cache for the power levels of the senders of the events.
+    ///
+    /// See [`ResolveOptions::power_level_cache`] for more details.
+    pub fn with_power_cache(mut self, cache: Arc<PowerLevelCache>) -> Self {
+        self.options.power_level_cache = Some(cache);
+        self
+    }
+
+    /// Stop the resolution if it is not done before the given deadline.
+    ///
+    /// The deadline is checked like the predicate of [`StateResolution::with_should_continue()`].
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Limit the number of events in the graph of the control events.
+    ///
+    /// See [`ResolveOptions::max_graph_nodes`] for more details.
+    pub fn with_max_graph_nodes(mut self, max_graph_nodes: usize) -> Self {
+        self.options.max_graph_nodes = max_graph_nodes;
+        self
+    }
+
+    /// Report the progress of the resolution to the given observer.
+    ///
+    /// The methods of `observer` are called with the size of each step of the resolution. The
+    /// original resolution algorithm of room version 1 doesn't sort the control events in a graph,
+    /// so it never calls [`StateResObserver::on_graph_nodes`] nor
+    /// [`StateResObserver::on_power_events`].
+    pub fn with_observer<O>(self, observer: O) -> StateResolution<Fetch, Exists, O, Batch, Continue>
+    where
+        O: StateResObserver + Sync,
+    {
+        let Self {
+            room_version,
+            event_fetch,
+            event_exists,
+            options,
+            deadline,
+            observer: _,
+            batch_fetch,
+            should_continue,
+        } = self;
+
+        StateResolution {
+            room_version,
+            event_fetch,
+            event_exists,
+            options,
+            deadline,
+            observer,
+            batch_fetch,
+            should_continue,
+        }
+    }
+
+    /// Fetch several events at once when possible, with the given `batch_fetch`.
+    ///
+    /// When building the graph of the conflicted control events, all the auth events of an event
+    /// are fetched in a single call to [`BatchFetch::fetch_many`], which saves round trips with a
+    /// database for example. The other events are still fetched one at a time with the
+    /// `event_fetch` closure.
+    pub fn with_batch_fetch<B>(
+        self,
+        batch_fetch: B,
+    ) -> StateResolution<Fetch, Exists, Observer, B, Continue> {
+        let Self {
+            room_version,
+            event_fetch,
+            event_exists,
+            options,
+            deadline,
+            observer,
+            batch_fetch: _,
+            should_continue,
+        } = self;
+
+        StateResolution {
+            room_version,
+            event_fetch,
+            event_exists,
+            options,
+            deadline,
+            observer,
+            batch_fetch: Some(batch_fetch),
+            should_continue,
+        }
+    }
+
+    /// Stop the resolution as soon as the given predicate returns `false`.
+    ///
+    /// `should_continue` is called between the steps of the resolution, and regularly while
+    /// sorting and authorizing the conflicted events, so the resolution of a pathological event
+    /// graph can be stopped. A resolution that is stopped fails with [`Error::Cancelled`], or
+    /// returns a [`ResolveOutcome::Partial`] with [`StateResolution::resolve_partial()`].
+    pub fn with_should_continue<C>(
+        self,
+        should_continue: C,
+    ) -> StateResolution<Fetch, Exists, Observer, Batch, C>
+    where
+        C: Fn() -> bool + Sync,
+    {
+        let Self {
+            room_version,
+            event_fetch,
+            event_exists,
+            options,
+            deadline,
+            observer,
+            batch_fetch,
+            should_continue: _,
+        } = self;
+
+        StateResolution {
+            room_version,
+            event_fetch,
+            event_exists,
+            options,
+            deadline,
+            observer,
+            batch_fetch,
+            should_continue: Some(should_continue),
+        }
+    }
+
+    /// Resolve the given sets of state events.
+    ///
+    /// ## Arguments
+    ///
+    /// * `state_sets` - The incoming state to resolve. Each `StateMap` represents a possible fork
+    ///   in the state of a room.
+    ///
+    /// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
+    ///   `state_sets`.
+    ///
+    /// ## Invariants
+    ///
+    /// The caller must ensure that all the events are from the same room.
+    pub async fn resolve<'a, E, SetIter, FetchFut, ExistsFut>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+        auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    ) -> Result<StateMap<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        self.resolve_partial(state_sets, auth_chain_sets).await?.into_result()
+    }
+
+    /// Resolve the given sets of state events, returning the state resolved so far if the
+    /// resolution stops before the end.
+    ///
+    /// This is the same as [`StateResolution::resolve()`], except that instead of failing when the
+    /// full conflicted set is larger than [`ResolveOptions::max_conflicted_events`], or when the
+    /// resolution is stopped by the deadline or the predicate of
+    /// [`StateResolution::with_should_continue()`], this returns a [`ResolveOutcome::Partial`]
+    /// with the state that was resolved before.
+    pub async fn resolve_partial<'a, E, SetIter, FetchFut, ExistsFut>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+        auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    ) -> Result<ResolveOutcome<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        let (clean, conflicting) = separate(state_sets.into_iter());
+
+        self.resolve_reporting(
+            clean,
+            conflicting,
+            AuthChainSets::Given(auth_chain_sets),
+            &self.options,
+            &mut Report::default(),
+        )
+        .await
+    }
+
+    /// Resolve the given sets of state events, and report the conflicted events and the ones that
+    /// were rejected.
+    ///
+    /// This is the same as [`StateResolution::resolve()`], except that the rejected events are
+    /// always reported, regardless of [`ResolveOptions::report_rejected`], so servers can mark them
+    /// as rejected and avoid resolving them again.
+    pub async fn resolve_report<'a, E, SetIter, FetchFut, ExistsFut>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+        auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    ) -> Result<ResolveReport<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        let (clean, conflicting) = separate(state_sets.into_iter());
+        let options = ResolveOptions { report_rejected: true, ..self.options.clone() };
+        let mut report = Report::default();
+
+        let state = self
+            .resolve_reporting(
+                clean,
+                conflicting,
+                AuthChainSets::Given(auth_chain_sets),
+                &options,
+                &mut report,
+            )
+            .await?
+            .into_result()?;
+
+        Ok(ResolveReport { state, rejected: report.rejected, conflicted: report.conflicted })
+    }
+
+    /// Resolve the given sets of state events, and get the creator of the room from the resolved
+    /// state.
+    ///
+    /// This is the same as [`StateResolution::resolve()`], with the `m.room.create` event of the
+    /// resolved state and the creator of the room computed with [`room_creator`].
+    ///
+    /// # Errors
+    ///
+    /// In addition to the errors of [`StateResolution::resolve()`], this returns an error if the
+    /// `m.room.create` event of the resolved state can't be fetched, or if it doesn't contain a
+    /// valid creator.
+    pub async fn resolve_extra<'a, E, SetIter, FetchFut, ExistsFut>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+        auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    ) -> Result<ResolvedState<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        let (clean, conflicting) = separate(state_sets.into_iter());
+        let mut report = Report::default();
+
+        let state = self
+            .resolve_reporting(
+                clean,
+                conflicting,
+                AuthChainSets::Given(auth_chain_sets),
+                &self.options,
+                &mut report,
+            )
+            .await?
+            .into_result()?;
+
+        let create_event_id = state.get(&(StateEventType::RoomCreate, "".to_owned())).cloned();
+        let creator = match &create_event_id {
+            Some(id) => {
+                let create_event = (self.event_fetch)(id.clone())
+                    .await
+                    .ok_or_else(|| Error::NotFound(format!("Failed to find {}", id.borrow())))?;
+                Some(room_creator(&RoomVersion::new(&self.room_version)?, &create_event)?)
+            }
+            None => None,
+        };
+
+        let Report { rejected, unconflicted_auth_failures, .. } = report;
+        let rejected = rejected.into_iter().map(|(event_id, _)| event_id).collect();
+        Ok(ResolvedState { state, create_event_id, creator, rejected, unconflicted_auth_failures })
+    }
+
+    /// Resolve state that was already separated into unconflicted and conflicted state.
+    ///
+    /// This is the same as [`StateResolution::resolve()`], except that it skips the [`separate`]
+    /// step, for servers that already know the state that is not conflicted, for example because
+    /// they forked from a known state.
+    ///
+    /// ## Arguments
+    ///
+    /// * `clean` - The unconflicted state, which is the same in all the state sets.
+    ///
+    /// * `conflicting` - The conflicted state, with the events of all the state sets for each key.
+    ///
+    /// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the state
+    ///   sets.
+    ///
+    /// ## Invariants
+    ///
+    /// The keys of `clean` and `conflicting` must be disjoint, like the ones returned by
+    /// [`separate`]. Otherwise, the unconflicted event of a key overrides its resolved conflicted
+    /// event.
+    pub async fn resolve_conflicted<E, FetchFut, ExistsFut>(
+        &self,
+        clean: StateMap<E::Id>,
+        conflicting: StateMap<Vec<E::Id>>,
+        auth_chain_sets: &Vec<HashSet<E::Id>>,
+    ) -> Result<StateMap<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        self.resolve_reporting(
+            clean,
+            conflicting,
+            AuthChainSets::Given(auth_chain_sets),
+            &self.options,
+            &mut Report::default(),
+        )
+        .await?
+        .into_result()
+    }
+
+    /// Resolve the given sets of state events, computing their auth chains when needed.
+    ///
+    /// Unlike [`StateResolution::resolve()`], this doesn't take the auth chains of the
+    /// `state_sets` but walks the `auth_events` of their events with `event_fetch` instead. This
+    /// is only done if the `state_sets` are conflicting, and trades the memory of precomputed auth
+    /// chains for more calls to `event_fetch`.
+    pub async fn resolve_compute_chains<'a, E, SetIter, FetchFut, ExistsFut>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+    ) -> Result<StateMap<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync + 'a,
+        for<'b> &'b E: Send,
+    {
+        let state_sets = state_sets.into_iter();
+        let (clean, conflicting) = separate(state_sets.clone());
+
+        self.resolve_reporting(
+            clean,
+            conflicting,
+            AuthChainSets::Compute(state_sets.collect()),
+            &self.options,
+            &mut Report::default(),
+        )
+        .await?
+        .into_result()
+    }
+
+    /// The predicate that stops the resolution, combining the deadline and the predicate of
+    /// [`StateResolution::with_should_continue()`].
+    fn should_continue(&self) -> impl Fn() -> bool + Sync + '_
+    where
+        Continue: Fn() -> bool + Sync,
+    {
+        let deadline = self.deadline;
+        let should_continue = self.should_continue.as_ref();
+
+        move || {
+            deadline.map_or(true, |deadline| Instant::now() < deadline)
+                && should_continue.map_or(true, |should_continue| should_continue())
+        }
+    }
+
+    /// Resolve the `conflicting` state with the given options, and add the events reported
+    /// according to the options to `report`.
+    async fn resolve_reporting<E, FetchFut, ExistsFut>(
+        &self,
+        clean: StateMap<E::Id>,
+        conflicting: StateMap<Vec<E::Id>>,
+        auth_chain_sets: AuthChainSets<'_, E::Id>,
+        options: &ResolveOptions,
+        report: &mut Report<E::Id>,
+    ) -> Result<ResolveOutcome<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Batch: BatchFetch<E> + Sync,
+        Continue: Fn() -> bool + Sync,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        let should_continue = self.should_continue();
+
+        match &self.batch_fetch {
+            Some(batch_fetch) => {
+                resolve_conflicted_reporting(
+                    &self.room_version,
+                    clean,
+                    conflicting,
+                    auth_chain_sets,
+                    &self.event_fetch,
+                    batch_fetch,
+                    &self.event_exists,
+                    options,
+                    &self.observer,
+                    &should_continue,
+                    report,
+                )
+                .await
+            }
+            None => {
+                resolve_conflicted_reporting(
+                    &self.room_version,
+                    clean,
+                    conflicting,
+                    auth_chain_sets,
+                    &self.event_fetch,
+                    &self.event_fetch,
+                    &self.event_exists,
+                    options,
+                    &self.observer,
+                    &should_continue,
+                    report,
+                )
+                .await
+            }
+        }
+    }
+}
+
+impl<Fetch, Exists, Observer, Continue> StateResolution<Fetch, Exists, Observer, Fetch, Continue> {
+    /// Resolve the given sets of state events, with a closure to fetch events that can fail.
+    ///
+    /// This is the same as [`StateResolution::resolve()`], except that `event_fetch` returns an
+    /// error when the store fails, instead of `None`, which means that the event doesn't exist.
+    /// The resolution is stopped at the first fetch error, and the error is returned as
+    /// [`Error::Fetch`], so a storage failure can't result in a wrong resolved state.
+    ///
+    /// This can't be combined with [`StateResolution::with_batch_fetch()`].
+    pub async fn resolve_fallible<'a, E, SetIter, FetchFut, FetchError, ExistsFut>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+        auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    ) -> Result<StateMap<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = std::result::Result<Option<E>, FetchError>> + Send,
+        FetchError: std::error::Error + Send + 'static,
+        Exists: Fn(E::Id) -> ExistsFut,
+        ExistsFut: Future<Output = bool> + Send,
+        Observer: StateResObserver + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        // Only the first error is kept, the resolution stops as soon as possible after it.
+        let fetch_error = Mutex::new(None);
+
+        let fetch_error_ref = &fetch_error;
+        let fallible_fetch = &self.event_fetch;
+        let event_fetch = |event_id: E::Id| {
+            let event = fallible_fetch(event_id);
+            async move {
+                event.await.unwrap_or_else(|error| {
+                    fetch_error_ref.lock().unwrap().get_or_insert(error);
+                    None
+                })
+            }
+        };
+        let should_continue = self.should_continue();
+        let should_continue = || should_continue() && fetch_error_ref.lock().unwrap().is_none();
+        let (clean, conflicting) = separate(state_sets.into_iter());
+
+        let outcome = resolve_conflicted_reporting(
+            &self.room_version,
+            clean,
+            conflicting,
+            AuthChainSets::Given(auth_chain_sets),
+            &event_fetch,
+            &event_fetch,
+            &self.event_exists,
+            &self.options,
+            &self.observer,
+            &should_continue,
+            &mut Report::default(),
+        )
+        .await;
+
+        if let Some(error) = fetch_error.into_inner().unwrap() {
+            return Err(Error::Fetch(Box::new(error)));
+        }
+
+        outcome?.into_result()
+    }
+
+    /// Resolve the given sets of state events, with synchronous closures to fetch events.
+    ///
+    /// This is the same as [`StateResolution::resolve()`], for callers that have all the events in
+    /// memory and don't need to wrap every lookup in a future. The result is identical to the one
+    /// of [`StateResolution::resolve()`] with the same inputs.
+    ///
+    /// This can't be combined with [`StateResolution::with_batch_fetch()`].
+    pub fn resolve_sync<'a, E, SetIter>(
+        &self,
+        state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+        auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    ) -> Result<StateMap<E::Id>>
+    where
+        Fetch: Fn(E::Id) -> Option<E> + Sync,
+        Exists: Fn(E::Id) -> bool,
+        Observer: StateResObserver + Sync,
+        Continue: Fn() -> bool + Sync,
+        SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+        E: Event + Send,
+        E::Id: Borrow<EventId> + Send + Sync,
+        for<'b> &'b E: Send,
+    {
+        let (sync_fetch, sync_exists) = (&self.event_fetch, &self.event_exists);
+        let event_fetch = |id| future::ready(sync_fetch(id));
+        let event_exists = |id| future::ready(sync_exists(id));
+        let should_continue = self.should_continue();
+        let (clean, conflicting) = separate(state_sets.into_iter());
+
+        // The only futures awaited during the resolution are the ones of the closures, which are
+        // always ready, so the resolution completes on the first poll.
+        resolve_conflicted_reporting(
+            &self.room_version,
+            clean,
+            conflicting,
+            AuthChainSets::Given(auth_chain_sets),
+            &event_fetch,
+            &event_fetch,
+            &event_exists,
+            &self.options,
+            &self.observer,
+            &should_continue,
+            &mut Report::default(),
+        )
+        .now_or_never()
+        .expect("state resolution with synchronous closures completes on the first poll")?
+        .into_result()
+    }
+}
+
+/// Resolve sets of state events as they come in, with synchronous closures to fetch events.
+///
+/// This is a shortcut for [`StateResolution::resolve_sync()`] with the default options.
+///
+/// See [`StateResolution::resolve()`] for a description of the arguments.
+pub fn resolve_sync<'a, E, SetIter, Fetch, Exists>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+    auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    event_fetch: &Fetch,
+    event_exists: &Exists,
+) -> Result<StateMap<E::Id>>
+where
+    Fetch: Fn(E::Id) -> Option<E> + Sync,
+    Exists: Fn(E::Id) -> bool,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    StateResolution::new(room_version.clone(), event_fetch, event_exists)
+        .resolve_sync(state_sets, auth_chain_sets)
+}
+
+/// Resolve sets of state events as they come in, and report the conflicted events and the ones
+/// that were rejected.
+///
+/// This is a shortcut for [`StateResolution::resolve_report()`] with the given options.
+///
+/// See [`StateResolution::resolve()`] for a description of the other arguments.
+pub async fn resolve_with_report<'a, E, SetIter, Fetch, FetchFut, Exists, ExistsFut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+    auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    event_fetch: &Fetch,
+    event_exists: &Exists,
+    options: &ResolveOptions,
+) -> Result<ResolveReport<E::Id>>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    StateResolution::new(room_version.clone(), event_fetch, event_exists)
+        .with_options(options.clone())
+        .resolve_report(state_sets, auth_chain_sets)
+        .await
+}
+
+/// Resolve state that was already separated into unconflicted and conflicted state.
+///
+/// This is a shortcut for [`StateResolution::resolve_conflicted()`] with the given options.
+///
+/// See [`StateResolution::resolve_conflicted()`] for a description of the other arguments.
+pub async fn resolve_conflicted<E, Fetch, FetchFut, Exists, ExistsFut>(
+    room_version: &RoomVersionId,
+    clean: StateMap<E::Id>,
+    conflicting: StateMap<Vec<E::Id>>,
+    auth_chain_sets: &Vec<HashSet<E::Id>>,
+    event_fetch: &Fetch,
+    event_exists: &Exists,
+    options: &ResolveOptions,
+) -> Result<StateMap<E::Id>>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    StateResolution::new(room_version.clone(), event_fetch, event_exists)
+        .with_options(options.clone())
+        .resolve_conflicted(clean, conflicting, auth_chain_sets)
+        .await
+}
+
+/// Resolve sets of state events as they come in, computing their auth chains when needed.
+///
+/// This is a shortcut for [`StateResolution::resolve_compute_chains()`] with the given options.
+///
+/// See [`StateResolution::resolve()`] for a description of the other arguments.
+pub async fn resolve_compute_chains<'a, E, SetIter, Fetch, FetchFut, Exists, ExistsFut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+    event_fetch: &Fetch,
+    event_exists: &Exists,
+    options: &ResolveOptions,
+) -> Result<StateMap<E::Id>>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync + 'a,
+    for<'b> &'b E: Send,
+{
+    StateResolution::new(room_version.clone(), event_fetch, event_exists)
+        .with_options(options.clone())
+        .resolve_compute_chains(state_sets)
+        .await
+}
+
+/// The resolved state of a room, with the conflicted events and the ones that were rejected.
+///
+/// This is returned by [`StateResolution::resolve_report()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ResolveReport<Id> {
+    /// The resolved state.
+    pub state: StateMap<Id>,
+
+    /// The conflicted events that were rejected, with the reason of the rejection, in the order in
+    /// which they were checked.
+    pub rejected: Vec<(Id, RejectionReason)>,
+
+    /// The conflicted events of the state sets.
+    ///
+    /// These are the events of the state keys that don't have the same event in all the state
+    /// sets, without the difference of their auth chains.
+    pub conflicted: HashSet<Id>,
+}
+
+/// The result of [`StateResolution::resolve_partial()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ResolveOutcome<Id> {
+    /// The state was fully resolved.
+    Complete(StateMap<Id>),
+
+    /// The resolution stopped before the end.
+    Partial {
+        /// The state resolved before the resolution stopped.
+        ///
+        /// This is the unconflicted state, with the resolved control events if they were resolved
+        /// before the resolution stopped. In room version 1, this contains the conflicted state of
+        /// the steps of the original algorithm that were completed.
+        resolved_so_far: StateMap<Id>,
+
+        /// The reason why the resolution stopped.
+        reason: PartialReason,
+    },
+}
+
+impl<Id> ResolveOutcome<Id> {
+    /// Convert a partial outcome into the corresponding error.
+    pub(crate) fn into_result(self) -> Result<StateMap<Id>> {
+        match self {
+            Self::Complete(state) => Ok(state),
+            Self::Partial { reason: PartialReason::Cancelled, .. } => Err(Error::Cancelled),
+            Self::Partial { reason: PartialReason::TooManyConflictedEvents, .. } => {
+                Err(Error::TooManyConflictedEvents)
+            }
+        }
+    }
+}
+
+/// The reason why a conflicted event was rejected during the resolution.
+///
+/// These are coarse categories of the failed authorization rules, reported in
+/// [`ResolveReport::rejected`] so servers can store or surface why an event was dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RejectionReason {
+    /// An auth event of the event could not be found, and the event failed an authorization rule
+    /// that needed state that was not found, which might be the missing auth event.
+    MissingAuthEvent(OwnedEventId),
+
+    /// The event has several auth events with the same type and state key.
+    DuplicateAuthEvents,
+
+    /// There is no `m.room.create` event in the state, or it is not in the auth events of the
+    /// event.
+    MissingCreateEvent,
+
+    /// The event is malformed or breaks the rules of its type, like an `m.room.create` event with
+    /// previous events.
+    InvalidEvent,
+
+    /// The room is not federated and the sender is on another server than the creator of the room.
+    RoomNotFederated,
+
+    /// The sender of the event is not joined to the room.
+    SenderNotJoined,
+
+    /// The server of the sender of the event is denied by the `m.room.server_acl` event, with
+    /// [`ResolveOptions::enforce_server_acl`].
+    ServerDenied,
+
+    /// The `m.room.member` event is not an allowed membership change.
+    InvalidMembershipChange,
+
+    /// The sender doesn't have the power level required to send the event.
+    InsufficientPowerLevel,
+
+    /// The `m.room.power_levels` event is not an allowed change of the power levels.
+    InvalidPowerLevelsChange,
+}
+
+impl From<AuthError> for RejectionReason {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::TooLarge(_)
+            | AuthError::CreateEventHasPrevEvents
+            | AuthError::RoomIdWithoutServerName
+            | AuthError::CreateEventServerNameMismatch
+            | AuthError::UnknownRoomVersion
+            | AuthError::MissingCreator
+            | AuthError::AliasesStateKeyMismatch
+            | AuthError::MemberEventWithoutStateKey
+            | AuthError::InvalidMembership => Self::InvalidEvent,
+            AuthError::MissingCreateEvent | AuthError::CreateEventNotInAuthEvents => {
+                Self::MissingCreateEvent
+            }
+            AuthError::RoomNotFederated => Self::RoomNotFederated,
+            AuthError::MembershipChangeNotAllowed | AuthError::JoinNotAllowed => {
+                Self::InvalidMembershipChange
+            }
+            AuthError::SenderNotInRoom | AuthError::SenderNotJoined => Self::SenderNotJoined,
+            AuthError::CannotSendInvites
+            | AuthError::CannotSendEvent
+            | AuthError::RedactionNotAllowed => Self::InsufficientPowerLevel,
+            AuthError::PowerLevelsChangeNotAllowed | AuthError::InvalidPowerLevels => {
+                Self::InvalidPowerLevelsChange
+            }
+        }
+    }
+}
+
+/// The reason why the resolution stopped in a [`ResolveOutcome::Partial`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PartialReason {
+    /// The resolution was stopped by the deadline or the predicate of
+    /// [`StateResolution::with_should_continue()`].
+    Cancelled,
+
+    /// The full conflicted set is larger than [`ResolveOptions::max_conflicted_events`].
+    TooManyConflictedEvents,
+}
+
+/// The resolved state of a room, with the room creator it implies.
+///
+/// This is returned by [`StateResolution::resolve_extra()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ResolvedState<Id> {
+    /// The resolved state.
+    pub state: StateMap<Id>,
+
+    /// The ID of the `m.room.create` event in the resolved state, if any.
+    pub create_event_id: Option<Id>,
+
+    /// The creator of the room according to the `m.room.create` event in the resolved state, if
+    /// any.
+    pub creator: Option<OwnedUserId>,
+
+    /// The conflicted events that were rejected, in the order in which they were checked.
+    ///
+    /// This is only filled if [`ResolveOptions::report_rejected`] is set.
+    pub rejected: Vec<Id>,
+
+    /// The unconflicted events that failed the authorization rules, sorted by ID.
+    ///
+    /// This is only filled if [`ResolveOptions::auth_check_unconflicted`] is set. These events are
+    /// still part of the resolved state.
+    pub unconflicted_auth_failures: Vec<Id>,
+}
+
+/// A way to fetch several events at once.
+///
+/// This is implemented for the closures that fetch a single event, by calling them once for each
+/// event.
+pub trait BatchFetch<E: Event> {
+    /// Fetch the events with the given IDs.
+    ///
+    /// The returned map should only contain events whose ID was requested, any other event is
+    /// ignored. The events that can't be found are absent from the returned map.
+    fn fetch_many(&self, ids: Vec<E::Id>) -> impl Future<Output = HashMap<E::Id, E>> + Send;
+}
+
+impl<E, F, Fut> BatchFetch<E> for F
+where
+    F: Fn(E::Id) -> Fut + Sync,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+{
+    async fn fetch_many(&self, ids: Vec<E::Id>) -> HashMap<E::Id, E> {
+        let mut events = HashMap::with_capacity(ids.len());
+        for id in ids {
+            if let Some(event) = self(id.clone()).await {
+                events.insert(id, event);
+            }
+        }
+        events
+    }
+}
+
+/// Callbacks to observe the progress of a resolution with [`StateResolution::with_observer()`].
+///
+/// All the methods do nothing by default. The implementation for `()` doesn't observe anything
+/// and compiles away. It is also implemented for references, so an observer can be inspected
+/// after the resolution.
+pub trait StateResObserver {
+    /// Called with the number of events in the full conflicted set, which is the auth chain
+    /// difference of the state sets and the conflicted events.
+    fn on_conflicted(&self, count: usize) {
+        let _ = count;
+    }
+
+    /// Called with the number of nodes in the graph of the conflicted control events and their
+    /// auth chains, before it is sorted.
+    fn on_graph_nodes(&self, count: usize) {
+        let _ = count;
+    }
+
+    /// Called with the number of conflicted control events, after they are sorted.
+    fn on_power_events(&self, count: usize) {
+        let _ = count;
+    }
+
+    /// Called after an event is checked against the authorization rules, with whether it passed
+    /// the checks.
+    fn on_auth_check(&self, event_id: &EventId, passed: bool) {
+        let _ = (event_id, passed);
+    }
+}
+
+impl StateResObserver for () {}
+
+impl<T> StateResObserver for &T
+where
+    T: StateResObserver + ?Sized,
+{
+    fn on_conflicted(&self, count: usize) {
+        (**self).on_conflicted(count);
+    }
+
+    fn on_graph_nodes(&self, count: usize) {
+        (**self).on_graph_nodes(count);
+    }
+
+    fn on_power_events(&self, count: usize) {
+        (**self).on_power_events(count);
+    }
+
+    fn on_auth_check(&self, event_id: &EventId, passed: bool) {
+        (**self).on_auth_check(event_id, passed);
+    }
+}
+
+/// The events reported during a resolution, depending on the [`ResolveOptions`].
+struct Report<Id> {
+    /// The conflicted events.
+    conflicted: HashSet<Id>,
+
+    /// The conflicted events that were rejected, with the reason of the rejection, if
+    /// [`ResolveOptions::report_rejected`] is set.
+    rejected: Vec<(Id, RejectionReason)>,
+
+    /// The unconflicted events that failed the authorization rules, if
+    /// [`ResolveOptions::auth_check_unconflicted`] is set.
+    unconflicted_auth_failures: Vec<Id>,
+}
+
+impl<Id> Default for Report<Id> {
+    fn default() -> Self {
+        Self {
+            conflicted: HashSet::new(),
+            rejected: Vec::new(),
+            unconflicted_auth_failures: Vec::new(),
+        }
+    }
+}
+
+/// The auth chains of the state sets of a resolution.
+enum AuthChainSets<'a, Id> {
+    /// The auth chains given by the caller.
+    Given(&'a Vec<HashSet<Id>>),
+
+    /// The state sets, whose auth chains are computed if they are conflicting.
+    Compute(Vec<&'a StateMap<Id>>),
+}
+
+/// Resolve the `conflicting` state, with the given options, and add the events reported according
+/// to the options to `report`.
+///
+/// The keys of `clean` and `conflicting` must be disjoint.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_conflicted_reporting<
+    E,
+    Fetch,
+    FetchFut,
+    Batch,
+    Exists,
+    ExistsFut,
+    Observer,
+    Continue,
+>(
+    room_version: &RoomVersionId,
+    clean: StateMap<E::Id>,
+    conflicting: StateMap<Vec<E::Id>>,
+    auth_chain_sets: AuthChainSets<'_, E::Id>,
+    event_fetch: &Fetch,
+    batch_fetch: &Batch,
+    event_exists: &Exists,
+    options: &ResolveOptions,
+    observer: &Observer,
+    should_continue: &Continue,
+    report: &mut Report<E::Id>,
+) -> Result<ResolveOutcome<E::Id>>
+where
+    Continue: Fn() -> bool + Sync,
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Batch: BatchFetch<E> + Sync,
+    Observer: StateResObserver + Sync,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    debug!("State resolution starting");
+
+    debug!(count = clean.len(), "non-conflicting events");
+    trace!(map = ?clean, "non-conflicting events");
+
+    if options.auth_check_unconflicted {
+        auth_check_unconflicted(
+            &RoomVersion::new(room_version)?,
+            &clean,
+            event_fetch,
+            &options.trusted,
+            &mut report.unconflicted_auth_failures,
+        )
+        .await?;
+    }
+
+    if conflicting.is_empty() {
+        debug!("no conflicting state found");
+        return Ok(ResolveOutcome::Complete(clean));
+    }
+
+    debug!(count = conflicting.len(), "conflicting events");
+    trace!(map = ?conflicting, "conflicting events");
+
+    report.conflicted.extend(conflicting.values().flatten().cloned());
+
+    let rules = RoomVersion::new(room_version)?;
+    if matches!(rules.state_res, StateResolutionVersion::V1) {
+        debug!("resolving the conflicted state with the original algorithm");
+        return v1::resolve_conflicted(
+            &rules,
+            clean,
+            conflicting,
+            event_fetch,
+            options,
+            should_continue,
+            observer,
+            &mut report.rejected,
+        )
+        .await;
+    }
+
+    let computed_auth_chain_sets;
+    let auth_chain_sets = match auth_chain_sets {
+        AuthChainSets::Given(auth_chain_sets) => auth_chain_sets,
+        AuthChainSets::Compute(state_sets) => {
+            let mut auth_chain_sets = Vec::with_capacity(state_sets.len());
+            for state_set in state_sets {
+                auth_chain_sets.push(auth_chain(state_set.values().cloned(), event_fetch).await);
+            }
+            computed_auth_chain_sets = auth_chain_sets;
+            &computed_auth_chain_sets
+        }
+    };
+    let auth_chain_diff = get_auth_chain_diff(auth_chain_sets);
+
+    resolve_with_auth_chain_diff(
+        room_version,
+        clean,
+        conflicting,
+        auth_chain_diff,
+        event_fetch,
+        batch_fetch,
+        event_exists,
+        options,
+        should_continue,
+        observer,
+        &mut report.rejected,
+    )
+    .await
+}
@@ -1,6 +1,9 @@
-use ruma_common::RoomVersionId;
+use ruma_common::{canonical_json::to_canonical_string, RoomVersionId};
+use ruma_events::TimelineEventType;
+use serde::Deserialize;
+use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
 
-use crate::{Error, Result};
+use crate::{event_auth::MAX_PDU_SIZE, Error, Event, PduTooLarge, Result};
 
 #[derive(Debug)]
 #[allow(clippy::exhaustive_enums)]
@@ -79,6 +82,20 @@ pub struct RoomVersion {
     ///
     /// See: [MSC2175](https://github.com/matrix-org/matrix-spec-proposals/pull/2175) for more information.
     pub use_room_create_sender: bool,
+    /// Use the updated redaction algorithm of room version 11, which preserves more fields.
+    ///
+    /// See: [MSC2176](https://github.com/matrix-org/matrix-spec-proposals/pull/2176) for more information.
+    pub updated_redaction_rules: bool,
+    /// The creators of the room have an infinite power level, and are not listed in the
+    /// `m.room.power_levels` event.
+    ///
+    /// See: [MSC4289](https://github.com/matrix-org/matrix-spec-proposals/pull/4289) for more information.
+    pub privileged_room_creators: bool,
+    /// The room ID is derived from the reference hash of the `m.room.create` event, which is not
+    /// listed in the `auth_events` of other events.
+    ///
+    /// See: [MSC4291](https://github.com/matrix-org/matrix-spec-proposals/pull/4291) for more information.
+    pub room_ids_as_hashes: bool,
 }
 
 impl RoomVersion {
@@ -96,6 +113,9 @@ impl RoomVersion {
         knock_restricted_join_rule: false,
         integer_power_levels: false,
         use_room_create_sender: false,
+        updated_redaction_rules: false,
+        privileged_room_creators: false,
+        room_ids_as_hashes: false,
     };
 
     pub const V2: Self = Self { state_res: StateResolutionVersion::V2, ..Self::V1 };
@@ -123,7 +143,20 @@ impl RoomVersion {
     pub const V10: Self =
         Self { knock_restricted_join_rule: true, integer_power_levels: true, ..Self::V9 };
 
-    pub const V11: Self = Self { use_room_create_sender: true, ..Self::V10 };
+    pub const V11: Self =
+        Self { use_room_create_sender: true, updated_redaction_rules: true, ..Self::V10 };
+
+    /// The experimental rules of the next room version, with the `org.matrix.hydra.11` ID.
+    ///
+    /// These rules are provisional and can change at any time. Only the flags of the room version
+    /// are available for now, the auth rules of MSC4289 and MSC4291 are not implemented yet.
+    #[cfg(feature = "unstable-hydra")]
+    pub const V12: Self = Self {
+        disposition: RoomDisposition::Unstable,
+        privileged_room_creators: true,
+        room_ids_as_hashes: true,
+        ..Self::V11
+    };
 
     pub fn new(version: &RoomVersionId) -> Result<Self> {
         Ok(match version {
@@ -138,7 +171,241 @@ impl RoomVersion {
             RoomVersionId::V9 => Self::V9,
             RoomVersionId::V10 => Self::V10,
             RoomVersionId::V11 => Self::V11,
+            #[cfg(feature = "unstable-hydra")]
+            ver if ver.as_str() == "org.matrix.hydra.11" => Self::V12,
             ver => return Err(Error::Unsupported(format!("found version `{ver}`"))),
         })
     }
+
+    /// The behavioral switches of this room version, in a single struct.
+    pub const fn rules(&self) -> RoomVersionRules {
+        RoomVersionRules {
+            enforce_key_validity: self.enforce_key_validity,
+            special_case_aliases_auth: self.special_case_aliases_auth,
+            strict_canonicaljson: self.strict_canonicaljson,
+            limit_notifications_power_levels: self.limit_notifications_power_levels,
+            extra_redaction_checks: self.extra_redaction_checks,
+            integer_power_levels: self.integer_power_levels,
+            knocking: self.allow_knocking,
+            restricted_join_rules: self.restricted_join_rules,
+            knock_restricted_join_rule: self.knock_restricted_join_rule,
+            implicit_room_creator: self.use_room_create_sender,
+            updated_redaction_rules: self.updated_redaction_rules,
+            privileged_room_creators: self.privileged_room_creators,
+            room_ids_as_hashes: self.room_ids_as_hashes,
+        }
+    }
+
+    /// The maximum size of a PDU in this room version, in bytes.
+    ///
+    /// This is the size of the PDU in its canonical JSON form, including its signatures. It is the
+    /// same for all the room versions for now.
+    pub const fn max_pdu_size(&self) -> usize {
+        MAX_PDU_SIZE
+    }
+}
+
+/// The behavioral switches of a room version, returned by [`RoomVersion::rules()`].
+///
+/// See the fields of [`RoomVersion`] for the details of each rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RoomVersionRules {
+    /// Whether the validity period of the signing keys is enforced.
+    pub enforce_key_validity: bool,
+
+    /// Whether `m.room.aliases` events have special authorization rules.
+    pub special_case_aliases_auth: bool,
+
+    /// Whether canonical JSON is strictly enforced.
+    pub strict_canonicaljson: bool,
+
+    /// Whether the `notifications` key is checked in `m.room.power_levels` events.
+    pub limit_notifications_power_levels: bool,
+
+    /// Whether redaction events have extra authorization rules.
+    pub extra_redaction_checks: bool,
+
+    /// Whether power levels must be integers.
+    pub integer_power_levels: bool,
+
+    /// Whether the `knock` join rule is allowed.
+    pub knocking: bool,
+
+    /// Whether the `restricted` join rule is allowed.
+    pub restricted_join_rules: bool,
+
+    /// Whether the `knock_restricted` join rule is allowed.
+    pub knock_restricted_join_rule: bool,
+
+    /// Whether the creator of the room is the sender of the `m.room.create` event.
+    pub implicit_room_creator: bool,
+
+    /// Whether the updated redaction algorithm of room version 11 is used.
+    pub updated_redaction_rules: bool,
+
+    /// Whether the creators of the room have an infinite power level.
+    pub privileged_room_creators: bool,
+
+    /// Whether the room ID is derived from the reference hash of the `m.room.create` event.
+    pub room_ids_as_hashes: bool,
+}
+
+/// Check that the given PDU is not larger than the maximum size allowed by the room version.
+///
+/// The size of the PDU is the length of its [canonical JSON] representation, so insignificant
+/// whitespace in the JSON that was received doesn't count. If the PDU can't be converted to
+/// canonical JSON, the length of the JSON as it was received is used. It should be called with
+/// the PDU as it was received over federation, before any processing. PDUs that are too large
+/// must be rejected.
+///
+/// [canonical JSON]: https://spec.matrix.org/latest/appendices/#canonical-json
+pub fn validate_pdu_size(
+    pdu: &RawJsonValue,
+    room_version: &RoomVersion,
+) -> std::result::Result<(), PduTooLarge> {
+    let size = to_canonical_string(pdu).map_or(pdu.get().len(), |canonical| canonical.len());
+    let max_size = room_version.max_pdu_size();
+
+    if size > max_size {
+        return Err(PduTooLarge { size, max_size });
+    }
+
+    Ok(())
+}
+
+/// Get the room version of a room from its `m.room.create` event.
+///
+/// The version is read from the `room_version` field of the content, without deserializing the
+/// rest of the content. According to the spec, a create event without a `room_version` is for a
+/// room at version 1.
+///
+/// Returns `None` if the event is not an `m.room.create` event or if its content is invalid.
+pub fn room_version_from_create(create_event: &impl Event) -> Option<RoomVersionId> {
+    #[derive(Deserialize)]
+    struct RoomCreateContentRoomVersion {
+        room_version: Option<RoomVersionId>,
+    }
+
+    if *create_event.event_type() != TimelineEventType::RoomCreate {
+        return None;
+    }
+
+    let content: RoomCreateContentRoomVersion = from_json_str(create_event.content().get()).ok()?;
+    Some(content.room_version.unwrap_or(RoomVersionId::V1))
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_common::RoomVersionId;
+    use ruma_events::TimelineEventType;
+    use serde_json::{
+        json,
+        value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
+    };
+
+    use super::{room_version_from_create, validate_pdu_size, RoomVersion};
+    use crate::test_utils::{alice, to_init_pdu_event};
+
+    #[test]
+    fn room_version_from_create_content() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "creator": alice(), "room_version": "6" })).unwrap(),
+        );
+        assert_eq!(room_version_from_create(&create), Some(RoomVersionId::V6));
+    }
+
+    #[test]
+    fn room_version_from_create_defaults_to_v1() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "creator": alice() })).unwrap(),
+        );
+        assert_eq!(room_version_from_create(&create), Some(RoomVersionId::V1));
+    }
+
+    #[test]
+    fn room_version_from_create_unknown_version() {
+        let create = to_init_pdu_event(
+            "CREATE",
+            alice(),
+            TimelineEventType::RoomCreate,
+            Some(""),
+            to_raw_json_value(&json!({ "creator": alice(), "room_version": "org.example.v42" }))
+                .unwrap(),
+        );
+        let version = room_version_from_create(&create).unwrap();
+        assert_eq!(version.as_str(), "org.example.v42");
+    }
+
+    #[test]
+    fn pdu_size_limit() {
+        let pdu_of_size = |size: usize| {
+            // `{"a":""}` is 8 bytes long.
+            RawJsonValue::from_string(format!(r#"{{"a":"{}"}}"#, "a".repeat(size - 8))).unwrap()
+        };
+
+        let pdu = pdu_of_size(65_536);
+        assert_eq!(pdu.get().len(), 65_536);
+        validate_pdu_size(&pdu, &RoomVersion::V11).unwrap();
+
+        let err = validate_pdu_size(&pdu_of_size(65_537), &RoomVersion::V11).unwrap_err();
+        assert_eq!(err.size, 65_537);
+        assert_eq!(err.max_size, 65_536);
+
+        // Insignificant whitespace doesn't count in the size of the PDU.
+        let pdu =
+            RawJsonValue::from_string(format!("{{\n  \"a\": \"{}\"\n}}", "a".repeat(65_536 - 8)))
+                .unwrap();
+        assert!(pdu.get().len() > 65_536);
+        validate_pdu_size(&pdu, &RoomVersion::V11).unwrap();
+    }
+
+    #[test]
+    fn room_version_rules() {
+        let rules = RoomVersion::V6.rules();
+        assert!(rules.enforce_key_validity);
+        assert!(!rules.special_case_aliases_auth);
+        assert!(rules.strict_canonicaljson);
+        assert!(!rules.knocking);
+        assert!(!rules.integer_power_levels);
+        assert!(!rules.implicit_room_creator);
+        assert!(!rules.updated_redaction_rules);
+
+        let rules = RoomVersion::V11.rules();
+        assert!(rules.knocking);
+        assert!(rules.restricted_join_rules);
+        assert!(rules.knock_restricted_join_rule);
+        assert!(rules.integer_power_levels);
+        assert!(rules.implicit_room_creator);
+        assert!(rules.updated_redaction_rules);
+        assert!(!rules.privileged_room_creators);
+    }
+
+    #[test]
+    #[cfg(feature = "unstable-hydra")]
+    fn room_version_12() {
+        use super::RoomDisposition;
+
+        let version: RoomVersionId = "org.matrix.hydra.11".try_into().unwrap();
+        let room_version = RoomVersion::new(&version).unwrap();
+
+        assert!(matches!(room_version.disposition, RoomDisposition::Unstable));
+        assert!(room_version.privileged_room_creators);
+        assert!(room_version.room_ids_as_hashes);
+        assert!(room_version.use_room_create_sender);
+        assert!(room_version.integer_power_levels);
+        assert!(room_version.knock_restricted_join_rule);
+
+        let room_version = RoomVersion::new(&RoomVersionId::V11).unwrap();
+        assert!(!room_version.privileged_room_creators);
+        assert!(!room_version.room_ids_as_hashes);
+    }
 }
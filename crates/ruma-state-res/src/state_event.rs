@@ -5,10 +5,13 @@ use std::{
     sync::Arc,
 };
 
-use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId};
-use ruma_events::TimelineEventType;
+use js_int::UInt;
+use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName, UserId};
+use ruma_events::{StateEventType, TimelineEventType};
 use serde_json::value::RawValue as RawJsonValue;
 
+use crate::auth_types_for_event;
+
 /// Abstraction of a PDU so users can have their own PDU types.
 pub trait Event {
     type Id: Clone + Debug + Display + Eq + Ord + Hash + Send + Borrow<EventId>;
@@ -22,9 +25,23 @@ pub trait Event {
     /// The `UserId` of this event.
     fn sender(&self) -> &UserId;
 
+    /// The server name of the sender of this event.
+    fn sender_server(&self) -> &ServerName {
+        self.sender().server_name()
+    }
+
     /// The time of creation on the originating server.
     fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch;
 
+    /// The depth of this event in the room's event graph.
+    ///
+    /// This is only used by the state resolution algorithm of room version 1. The default
+    /// implementation returns `0`, so conflicted events are only ordered by the hash of their ID in
+    /// that room version. Implement this to resolve the state of version 1 rooms correctly.
+    fn depth(&self) -> UInt {
+        UInt::MIN
+    }
+
     /// The event type.
     fn event_type(&self) -> &TimelineEventType;
 
@@ -34,6 +51,13 @@ pub trait Event {
     /// The state key for this event.
     fn state_key(&self) -> Option<&str>;
 
+    /// Whether this event is a state event.
+    ///
+    /// This is the case if it has a state key.
+    fn is_state_event(&self) -> bool {
+        self.state_key().is_some()
+    }
+
     /// The events before this event.
     // Requires GATs to avoid boxing (and TAIT for making it convenient).
     fn prev_events(&self) -> impl DoubleEndedIterator<Item = &Self::Id> + Send + '_;
@@ -44,6 +68,13 @@ pub trait Event {
 
     /// If this event is a redaction event this is the event it redacts.
     fn redacts(&self) -> Option<&Self::Id>;
+
+    /// The state events that are needed to authorize this event.
+    ///
+    /// See [`auth_types_for_event`] for details.
+    fn required_auth_types(&self) -> serde_json::Result<Vec<(StateEventType, String)>> {
+        auth_types_for_event(self.event_type(), self.sender(), self.state_key(), self.content())
+    }
 }
 
 impl<T: Event> Event for &T {
@@ -65,6 +96,10 @@ impl<T: Event> Event for &T {
         (*self).origin_server_ts()
     }
 
+    fn depth(&self) -> UInt {
+        (*self).depth()
+    }
+
     fn event_type(&self) -> &TimelineEventType {
         (*self).event_type()
     }
@@ -109,6 +144,10 @@ impl<T: Event> Event for Arc<T> {
         (**self).origin_server_ts()
     }
 
+    fn depth(&self) -> UInt {
+        (**self).depth()
+    }
+
     fn event_type(&self) -> &TimelineEventType {
         (**self).event_type()
     }
@@ -133,3 +172,75 @@ impl<T: Event> Event for Arc<T> {
         (**self).redacts()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ruma_events::{StateEventType, TimelineEventType};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::Event;
+    use crate::test_utils::{alice, member_content_join, to_init_pdu_event};
+
+    #[test]
+    fn sender_server() {
+        let event = to_init_pdu_event(
+            "MSG",
+            alice(),
+            TimelineEventType::RoomMessage,
+            None,
+            to_raw_json_value(&json!({})).unwrap(),
+        );
+
+        assert_eq!(event.sender_server(), "foo");
+    }
+
+    #[test]
+    fn is_state_event() {
+        let topic = to_init_pdu_event(
+            "T",
+            alice(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": "Hello" })).unwrap(),
+        );
+        let member = to_init_pdu_event(
+            "IMA",
+            alice(),
+            TimelineEventType::RoomMember,
+            Some(alice().as_str()),
+            member_content_join(),
+        );
+        let message = to_init_pdu_event(
+            "MSG",
+            alice(),
+            TimelineEventType::RoomMessage,
+            None,
+            to_raw_json_value(&json!({ "msgtype": "m.text", "body": "Hello" })).unwrap(),
+        );
+
+        assert!(topic.is_state_event());
+        assert!(member.is_state_event());
+        assert!(!message.is_state_event());
+    }
+
+    #[test]
+    fn required_auth_types_member_join() {
+        let event = to_init_pdu_event(
+            "IMA",
+            alice(),
+            TimelineEventType::RoomMember,
+            Some(alice().as_str()),
+            member_content_join(),
+        );
+
+        assert_eq!(
+            event.required_auth_types().unwrap(),
+            [
+                (StateEventType::RoomPowerLevels, "".to_owned()),
+                (StateEventType::RoomMember, alice().to_string()),
+                (StateEventType::RoomCreate, "".to_owned()),
+                (StateEventType::RoomJoinRules, "".to_owned()),
+            ]
+        );
+    }
+}
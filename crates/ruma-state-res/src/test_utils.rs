@@ -1,3 +1,11 @@
+//! Helpers to test state resolution.
+//!
+//! These are the helpers used by the tests of this crate. They are exposed with the `test-utils`
+//! feature so that other crates can check their own expectations against
+//! [`StateResolution::resolve()`], and are not meant to be used outside of tests.
+//!
+//! [`StateResolution::resolve()`]: crate::StateResolution::resolve
+
 use std::{
     borrow::Borrow,
     collections::{BTreeMap, HashMap, HashSet},
@@ -27,12 +35,22 @@ use serde_json::{
 };
 use tracing::info;
 
-pub(crate) use self::event::PduEvent;
+pub use self::event::PduEvent;
 use crate::{auth_types_for_event, Error, Event, EventTypeExt, Result, StateMap};
 
 static SERVER_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
 
-pub(crate) async fn do_check(
+/// Resolve the state of a room built from [`INITIAL_EVENTS`] and the given `events`, and check it
+/// against the expected state.
+///
+/// `edges` are lists of event IDs that are chained to each other in the room's DAG, from the
+/// newest to the oldest. The state resolved at the `$END:foo` event must contain all the
+/// `expected_state_ids`, in addition to the unconflicted initial state.
+///
+/// # Panics
+///
+/// Panics if the resolution fails or if the resolved state doesn't match the expected state.
+pub async fn do_check(
     events: &[Arc<PduEvent>],
     edges: Vec<Vec<OwnedEventId>>,
     expected_state_ids: Vec<OwnedEventId>,
@@ -122,14 +140,26 @@ pub(crate) async fn do_check(
             let event_map = &event_map;
             let fetch = |id: <PduEvent as Event>::Id| ready(event_map.get(&id).cloned());
             let exists = |id: <PduEvent as Event>::Id| ready(event_map.get(&id).is_some());
-            let resolved =
-                crate::resolve(&RoomVersionId::V6, state_sets, &auth_chain_sets, &fetch, &exists)
-                    .await;
+            let resolved = crate::StateResolution::new(RoomVersionId::V6, &fetch, &exists)
+                .resolve(state_sets.iter().copied(), &auth_chain_sets)
+                .await;
 
-            match resolved {
+            let resolved = match resolved {
                 Ok(state) => state,
                 Err(e) => panic!("resolution for {node} failed: {e}"),
-            }
+            };
+
+            // The synchronous entry point must give the same result.
+            let resolved_sync = crate::StateResolution::new(
+                RoomVersionId::V6,
+                &|id: <PduEvent as Event>::Id| event_map.get(&id).cloned(),
+                &|id: <PduEvent as Event>::Id| event_map.contains_key(&id),
+            )
+            .resolve_sync(state_sets.iter().copied(), &auth_chain_sets)
+            .unwrap_or_else(|e| panic!("synchronous resolution for {node} failed: {e}"));
+            assert_eq!(resolved, resolved_sync, "synchronous resolution for {node} differs");
+
+            resolved
         };
 
         let mut state_after = state_before.clone();
@@ -209,11 +239,13 @@ pub(crate) async fn do_check(
     assert_eq!(expected_state, end_state);
 }
 
+/// An in-memory store of events, indexed by their ID.
 #[allow(clippy::exhaustive_structs)]
-pub(crate) struct TestStore<E: Event>(pub(crate) HashMap<OwnedEventId, Arc<E>>);
+pub struct TestStore<E: Event>(pub HashMap<OwnedEventId, Arc<E>>);
 
 impl<E: Event> TestStore<E> {
-    pub(crate) fn get_event(&self, _: &RoomId, event_id: &EventId) -> Result<Arc<E>> {
+    /// Get the event with the given ID.
+    pub fn get_event(&self, _: &RoomId, event_id: &EventId) -> Result<Arc<E>> {
         self.0
             .get(event_id)
             .cloned()
@@ -221,7 +253,7 @@ impl<E: Event> TestStore<E> {
     }
 
     /// Returns a Vec of the related auth events to the given `event`.
-    pub(crate) fn auth_event_ids(
+    pub fn auth_event_ids(
         &self,
         room_id: &RoomId,
         event_ids: Vec<E::Id>,
@@ -249,7 +281,7 @@ impl<E: Event> TestStore<E> {
 // A StateStore implementation for testing
 #[allow(clippy::type_complexity)]
 impl TestStore<PduEvent> {
-    pub(crate) fn set_up(
+    pub fn set_up(
         &mut self,
     ) -> (StateMap<OwnedEventId>, StateMap<OwnedEventId>, StateMap<OwnedEventId>) {
         let create_event = to_pdu_event::<&EventId>(
@@ -335,7 +367,9 @@ impl TestStore<PduEvent> {
     }
 }
 
-pub(crate) fn event_id(id: &str) -> OwnedEventId {
+/// Creates an event ID from the given ID, adding the `$` sigil and the `:foo` server name if
+/// needed.
+pub fn event_id(id: &str) -> OwnedEventId {
     if id.contains('$') {
         return id.try_into().unwrap();
     }
@@ -343,39 +377,48 @@ pub(crate) fn event_id(id: &str) -> OwnedEventId {
     format!("${id}:foo").try_into().unwrap()
 }
 
-pub(crate) fn alice() -> &'static UserId {
+/// The `@alice:foo` user ID.
+pub fn alice() -> &'static UserId {
     user_id!("@alice:foo")
 }
 
-pub(crate) fn bob() -> &'static UserId {
+/// The `@bob:foo` user ID.
+pub fn bob() -> &'static UserId {
     user_id!("@bob:foo")
 }
 
-pub(crate) fn charlie() -> &'static UserId {
+/// The `@charlie:foo` user ID.
+pub fn charlie() -> &'static UserId {
     user_id!("@charlie:foo")
 }
 
-pub(crate) fn ella() -> &'static UserId {
+/// The `@ella:foo` user ID.
+pub fn ella() -> &'static UserId {
     user_id!("@ella:foo")
 }
 
-pub(crate) fn zara() -> &'static UserId {
+/// The `@zara:foo` user ID.
+pub fn zara() -> &'static UserId {
     user_id!("@zara:foo")
 }
 
-pub(crate) fn room_id() -> &'static RoomId {
+/// The `!test:foo` room ID used by all test events.
+pub fn room_id() -> &'static RoomId {
     room_id!("!test:foo")
 }
 
-pub(crate) fn member_content_ban() -> Box<RawJsonValue> {
+/// The content of an `m.room.member` event with a `ban` membership.
+pub fn member_content_ban() -> Box<RawJsonValue> {
     to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Ban)).unwrap()
 }
 
-pub(crate) fn member_content_join() -> Box<RawJsonValue> {
+/// The content of an `m.room.member` event with a `join` membership.
+pub fn member_content_join() -> Box<RawJsonValue> {
     to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Join)).unwrap()
 }
 
-pub(crate) fn to_init_pdu_event(
+/// Creates a PDU without `auth_events` nor `prev_events`.
+pub fn to_init_pdu_event(
     id: &str,
     sender: &UserId,
     ev_type: TimelineEventType,
@@ -406,7 +449,10 @@ pub(crate) fn to_init_pdu_event(
     })
 }
 
-pub(crate) fn to_pdu_event<S>(
+/// Creates a PDU with the given `auth_events` and `prev_events`.
+///
+/// Event IDs are created with [`event_id()`].
+pub fn to_pdu_event<S>(
     id: &str,
     sender: &UserId,
     ev_type: TimelineEventType,
@@ -444,9 +490,9 @@ where
     })
 }
 
-// all graphs start with these input events
+/// The events all graphs start with.
 #[allow(non_snake_case)]
-pub(crate) fn INITIAL_EVENTS() -> HashMap<OwnedEventId, Arc<PduEvent>> {
+pub fn INITIAL_EVENTS() -> HashMap<OwnedEventId, Arc<PduEvent>> {
     vec![
         to_pdu_event::<&EventId>(
             "CREATE",
@@ -526,9 +572,9 @@ pub(crate) fn INITIAL_EVENTS() -> HashMap<OwnedEventId, Arc<PduEvent>> {
     .collect()
 }
 
-// all graphs start with these input events
+/// Only the `m.room.create` event of [`INITIAL_EVENTS`].
 #[allow(non_snake_case)]
-pub(crate) fn INITIAL_EVENTS_CREATE_ROOM() -> HashMap<OwnedEventId, Arc<PduEvent>> {
+pub fn INITIAL_EVENTS_CREATE_ROOM() -> HashMap<OwnedEventId, Arc<PduEvent>> {
     vec![to_pdu_event::<&EventId>(
         "CREATE",
         alice(),
@@ -543,15 +589,18 @@ pub(crate) fn INITIAL_EVENTS_CREATE_ROOM() -> HashMap<OwnedEventId, Arc<PduEvent
     .collect()
 }
 
+/// The edges between the [`INITIAL_EVENTS`], from the newest to the oldest.
 #[allow(non_snake_case)]
-pub(crate) fn INITIAL_EDGES() -> Vec<OwnedEventId> {
+pub fn INITIAL_EDGES() -> Vec<OwnedEventId> {
     vec!["START", "IMC", "IMB", "IJR", "IPOWER", "IMA", "CREATE"]
         .into_iter()
         .map(event_id)
         .collect::<Vec<_>>()
 }
 
-pub(crate) mod event {
+/// An implementation of [`Event`] for tests.
+pub mod event {
+    use js_int::UInt;
     use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, UserId};
     use ruma_events::{pdu::Pdu, TimelineEventType};
     use serde::{Deserialize, Serialize};
@@ -611,6 +660,15 @@ pub(crate) mod event {
             }
         }
 
+        fn depth(&self) -> UInt {
+            match &self.rest {
+                Pdu::RoomV1Pdu(ev) => ev.depth,
+                Pdu::RoomV3Pdu(ev) => ev.depth,
+                #[allow(unreachable_patterns)]
+                _ => unreachable!("new PDU version"),
+            }
+        }
+
         fn state_key(&self) -> Option<&str> {
             match &self.rest {
                 Pdu::RoomV1Pdu(ev) => ev.state_key.as_deref(),
@@ -648,11 +706,12 @@ pub(crate) mod event {
         }
     }
 
+    /// A PDU with its event ID.
     #[derive(Clone, Debug, Deserialize, Serialize)]
     #[allow(clippy::exhaustive_structs)]
-    pub(crate) struct PduEvent {
-        pub(crate) event_id: OwnedEventId,
+    pub struct PduEvent {
+        pub event_id: OwnedEventId,
         #[serde(flatten)]
-        pub(crate) rest: Pdu,
+        pub rest: Pdu,
     }
 }
@@ -0,0 +1,609 @@
+//! The original state resolution algorithm, used by room version 1.
+
+use std::{borrow::Borrow, cmp::Reverse, collections::HashSet};
+
+use futures_util::{future, Future};
+use ruma_common::{EventId, RoomVersionId};
+use ruma_events::StateEventType;
+use sha1::{Digest, Sha1};
+use tracing::{debug, warn};
+
+use crate::{
+    auth_check_detailed, is_sender_joined, separate, server_acl, third_party_invite_token,
+    AuthDecision, Error, Event, EventTypeExt, PartialReason, RejectionReason, ResolveOptions,
+    ResolveOutcome, Result, RoomVersion, StateMap, StateResObserver, CANCELLATION_CHECK_INTERVAL,
+};
+
+/// The types of the events that are resolved before the others, in that order.
+const AUTH_EVENT_TYPES: [StateEventType; 3] =
+    [StateEventType::RoomPowerLevels, StateEventType::RoomJoinRules, StateEventType::RoomMember];
+
+/// Resolve sets of state events with the original state resolution algorithm.
+///
+/// This algorithm is used by room version 1, and doesn't need the auth chains of the state sets.
+/// [`StateResolution`](crate::StateResolution) switches to it automatically for the room versions
+/// that require it.
+///
+/// The conflicted `m.room.power_levels` events are resolved first, then the `m.room.join_rules`
+/// events, then the `m.room.member` events. For each of these, the events are checked from the
+/// smallest depth and the last one that is allowed by the previous one is used. The other
+/// conflicted events are resolved to the event with the greatest depth that is allowed by the
+/// resolved state. Ties between events with the same depth are broken with the SHA-1 hash of their
+/// event IDs.
+///
+/// ## Arguments
+///
+/// * `room_version` - The version of the room, which defines the authorization rules.
+///
+/// * `state_sets` - The incoming state to resolve. Each `StateMap` represents a possible fork in
+///   the state of a room.
+///
+/// * `event_fetch` - Any event not found in the `event_map` will defer to this closure to find the
+///   event.
+pub async fn resolve_v1<'a, E, SetIter, Fetch, FetchFut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    event_fetch: &Fetch,
+) -> Result<StateMap<E::Id>>
+where
+    Fetch: Fn(E::Id) -> FetchFut,
+    FetchFut: Future<Output = Option<E>> + Send,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + 'a,
+    for<'b> &'b E: Send,
+{
+    let (clean, conflicting) = separate(state_sets.into_iter());
+
+    if conflicting.is_empty() {
+        debug!("no conflicting state found");
+        return Ok(clean);
+    }
+
+    let room_version = RoomVersion::new(room_version)?;
+    resolve_conflicted(
+        &room_version,
+        clean,
+        conflicting,
+        event_fetch,
+        &ResolveOptions::default(),
+        &|| true,
+        &(),
+        &mut Vec::new(),
+    )
+    .await?
+    .into_result()
+}
+
+/// Resolve the `conflicted` state on top of the `unconflicted` state.
+///
+/// The conflicted power levels are resolved first, then the join rules, then the memberships, so
+/// each step can use the state resolved by the previous ones to authorize the events. The other
+/// events are resolved last.
+///
+/// The options are applied like in the current algorithm, except for
+/// [`ResolveOptions::max_conflicted_events`] which only limits the number of conflicted events,
+/// since auth chains are not used. [`ResolveOptions::max_graph_nodes`] is always respected because
+/// no graph of events is built, and [`Error::UnsupportedOption`] is returned if
+/// [`ResolveOptions::power_level_cache`] is set, because the power levels of the senders are not
+/// computed.
+///
+/// `should_continue` is called every [`CANCELLATION_CHECK_INTERVAL`] conflicted keys, and the
+/// state resolved by the previous steps is returned in a [`ResolveOutcome::Partial`] if it returns
+/// `false`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn resolve_conflicted<E, F, Fut, Continue, Observer>(
+    room_version: &RoomVersion,
+    unconflicted: StateMap<E::Id>,
+    conflicted: StateMap<Vec<E::Id>>,
+    fetch_event: &F,
+    options: &ResolveOptions,
+    should_continue: &Continue,
+    observer: &Observer,
+    rejected: &mut Vec<(E::Id, RejectionReason)>,
+) -> Result<ResolveOutcome<E::Id>>
+where
+    F: Fn(E::Id) -> Fut,
+    Continue: Fn() -> bool,
+    Observer: StateResObserver,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send,
+    for<'b> &'b E: Send,
+{
+    if options.power_level_cache.is_some() {
+        return Err(Error::UnsupportedOption("power_level_cache"));
+    }
+
+    let conflicted_count = conflicted.values().flatten().collect::<HashSet<_>>().len();
+    if options.max_conflicted_events.is_some_and(|max| conflicted_count > max) {
+        warn!(count = conflicted_count, "too many conflicted events");
+        return Ok(ResolveOutcome::Partial {
+            resolved_so_far: unconflicted,
+            reason: PartialReason::TooManyConflictedEvents,
+        });
+    }
+    observer.on_conflicted(conflicted_count);
+
+    let mut resolver = Resolver { room_version, fetch_event, options, observer, rejected };
+    let mut resolved_state = unconflicted;
+
+    // Sort the keys so the result doesn't depend on the iteration order of the map.
+    let mut conflicted = conflicted.into_iter().collect::<Vec<_>>();
+    conflicted.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let auth_steps = AUTH_EVENT_TYPES.iter().map(|auth_type| {
+        conflicted.iter().filter(move |((ty, _), _)| ty == auth_type).collect::<Vec<_>>()
+    });
+    let normal_step =
+        conflicted.iter().filter(|((ty, _), _)| !AUTH_EVENT_TYPES.contains(ty)).collect();
+    let steps = auth_steps.map(|step| (step, true)).chain([(normal_step, false)]);
+
+    let mut resolved_count = 0;
+    for (step, is_auth_step) in steps {
+        let mut resolved_step = Vec::with_capacity(step.len());
+
+        for (key, event_ids) in step {
+            if resolved_count % CANCELLATION_CHECK_INTERVAL == 0 && !should_continue() {
+                debug!(count = resolved_count, "state resolution cancelled");
+                return Ok(ResolveOutcome::Partial {
+                    resolved_so_far: resolved_state,
+                    reason: PartialReason::Cancelled,
+                });
+            }
+            resolved_count += 1;
+
+            let events = ordered_events(event_ids, fetch_event).await?;
+            let event_id = if is_auth_step {
+                resolver.resolve_auth_events(events, &resolved_state).await?
+            } else {
+                resolver.resolve_normal_events(events, &resolved_state).await?
+            };
+            resolved_step.push((key.clone(), event_id));
+        }
+
+        resolved_state.extend(resolved_step);
+    }
+
+    Ok(ResolveOutcome::Complete(resolved_state))
+}
+
+/// Fetch the given events and sort them by decreasing depth.
+///
+/// Ties are broken with the SHA-1 hash of the event IDs.
+async fn ordered_events<E, F, Fut>(event_ids: &[E::Id], fetch_event: &F) -> Result<Vec<E>>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event,
+{
+    let mut events = Vec::with_capacity(event_ids.len());
+
+    for event_id in event_ids {
+        let event = fetch_event(event_id.clone())
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Failed to find {event_id}")))?;
+        events.push(event);
+    }
+
+    events.sort_by_cached_key(|event| {
+        let hash: [u8; 20] = Sha1::digest(event.event_id().borrow().as_bytes()).into();
+        (Reverse(event.depth()), hash)
+    });
+    // The same event can be in several state sets.
+    events.dedup_by(|a, b| a.event_id() == b.event_id());
+
+    Ok(events)
+}
+
+/// The parameters of a resolution that are used to check the conflicted events.
+struct Resolver<'a, E: Event, F, Observer> {
+    room_version: &'a RoomVersion,
+    fetch_event: &'a F,
+    options: &'a ResolveOptions,
+    observer: &'a Observer,
+    rejected: &'a mut Vec<(E::Id, RejectionReason)>,
+}
+
+impl<E, F, Fut, Observer> Resolver<'_, E, F, Observer>
+where
+    F: Fn(E::Id) -> Fut,
+    Observer: StateResObserver,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    for<'b> &'b E: Send,
+{
+    /// Resolve conflicted power levels, join rules or membership events, sorted by decreasing
+    /// depth.
+    ///
+    /// Starting from the event with the smallest depth, each event is checked against the state
+    /// with the previous event. The last event that passes the checks is used.
+    async fn resolve_auth_events(
+        &mut self,
+        events: Vec<E>,
+        resolved_state: &StateMap<E::Id>,
+    ) -> Result<E::Id> {
+        let mut auth_state = self.auth_state(&events, resolved_state).await?;
+
+        let mut events = events.into_iter().rev();
+        let mut prev_event = events.next().expect("conflicted key has at least one event");
+
+        for event in events {
+            let prev_event_id = prev_event.event_id().clone();
+            let state_key = prev_event
+                .state_key()
+                .ok_or_else(|| Error::InvalidPdu("State event had no state key".to_owned()))?;
+            auth_state.insert(prev_event.event_type().with_state_key(state_key), prev_event);
+
+            if let Some(reason) = self.check(&event, &auth_state, resolved_state).await? {
+                warn!("event {} failed the authentication check", event.event_id());
+                self.reject(&event, reason);
+                return Ok(prev_event_id);
+            }
+
+            prev_event = event;
+        }
+
+        Ok(prev_event.event_id().clone())
+    }
+
+    /// Resolve other conflicted events, sorted by decreasing depth.
+    ///
+    /// The first event that passes the checks against the resolved state is used.
+    async fn resolve_normal_events(
+        &mut self,
+        events: Vec<E>,
+        resolved_state: &StateMap<E::Id>,
+    ) -> Result<E::Id> {
+        let auth_state = self.auth_state(&events, resolved_state).await?;
+
+        let (last, others) = events.split_last().expect("conflicted key has at least one event");
+        for event in others {
+            match self.check(event, &auth_state, resolved_state).await? {
+                None => return Ok(event.event_id().clone()),
+                Some(reason) => {
+                    warn!("event {} failed the authentication check", event.event_id());
+                    self.reject(event, reason);
+                }
+            }
+        }
+
+        // If none of the other events are allowed, use the one with the smallest depth.
+        if self.check(last, &auth_state, resolved_state).await?.is_some() {
+            debug!("no conflicted event passed the authentication check");
+        }
+        Ok(last.event_id().clone())
+    }
+
+    /// Fetch the events of the resolved state that are needed to authorize the given events.
+    async fn auth_state(
+        &self,
+        events: &[E],
+        resolved_state: &StateMap<E::Id>,
+    ) -> Result<StateMap<E>> {
+        let mut auth_state = StateMap::new();
+
+        for event in events {
+            for key in event.required_auth_types()? {
+                if auth_state.contains_key(&key) {
+                    continue;
+                }
+
+                if let Some(event_id) = resolved_state.get(&key) {
+                    if let Some(auth_event) = (self.fetch_event)(event_id.clone()).await {
+                        auth_state.insert(key, auth_event);
+                    }
+                }
+            }
+        }
+
+        Ok(auth_state)
+    }
+
+    /// Check whether the given event is allowed by the given state and the options.
+    ///
+    /// Returns the reason of the rejection if the event is not allowed.
+    async fn check(
+        &self,
+        event: &E,
+        auth_state: &StateMap<E>,
+        resolved_state: &StateMap<E::Id>,
+    ) -> Result<Option<RejectionReason>> {
+        let event_id = event.event_id().borrow();
+
+        if self.options.trusted.contains(event_id) {
+            debug!("event {event_id} is trusted, skipping the authentication check");
+            return Ok(None);
+        }
+
+        if self.options.strict_sender_membership
+            && !is_sender_joined(event, resolved_state, self.fetch_event).await
+        {
+            warn!("sender of event {event_id} is not joined in the resolved state");
+            self.observer.on_auth_check(event_id, false);
+            return Ok(Some(RejectionReason::SenderNotJoined));
+        }
+
+        if self.options.enforce_server_acl
+            && server_acl(resolved_state, self.fetch_event)
+                .await
+                .is_some_and(|acl| !acl.is_allowed(event.sender_server()))
+        {
+            warn!("server of the sender of event {event_id} is denied by the server ACL");
+            self.observer.on_auth_check(event_id, false);
+            return Ok(Some(RejectionReason::ServerDenied));
+        }
+
+        let current_third_party = third_party_invite_token(event).and_then(|token| {
+            auth_state.get(&StateEventType::RoomThirdPartyInvite.with_state_key(token))
+        });
+
+        let fetch_state =
+            |ty: &StateEventType, key: &str| future::ready(auth_state.get(&ty.with_state_key(key)));
+
+        let decision =
+            auth_check_detailed(self.room_version, event, current_third_party, fetch_state).await?;
+        self.observer.on_auth_check(event_id, decision.is_allowed());
+
+        match decision {
+            AuthDecision::Allowed => Ok(None),
+            AuthDecision::Denied(error) => Ok(Some(error.into())),
+        }
+    }
+
+    /// Report the given event as rejected, if [`ResolveOptions::report_rejected`] is set.
+    fn reject(&mut self, event: &E, reason: RejectionReason) {
+        if self.options.report_rejected {
+            self.rejected.push((event.event_id().clone(), reason));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Instant};
+
+    use futures_util::future::ready;
+    use js_int::UInt;
+    use ruma_common::{OwnedEventId, RoomVersionId};
+    use ruma_events::{pdu::Pdu, TimelineEventType};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use crate::{
+        resolve_v1, separate,
+        test_utils::{alice, bob, event_id, to_pdu_event, PduEvent, INITIAL_EVENTS},
+        Error, Event, EventTypeExt, PartialReason, PowerLevelCache, RejectionReason,
+        ResolveOptions, ResolveOutcome, StateMap, StateResolution,
+    };
+
+    fn with_depth(event: Arc<PduEvent>, depth: u32) -> Arc<PduEvent> {
+        let mut event = Arc::try_unwrap(event).unwrap();
+        if let Pdu::RoomV3Pdu(pdu) = &mut event.rest {
+            pdu.depth = UInt::from(depth);
+        }
+        Arc::new(event)
+    }
+
+    /// The initial events, with the given events at the given depths.
+    fn events(extra: Vec<(Arc<PduEvent>, u32)>) -> HashMap<OwnedEventId, Arc<PduEvent>> {
+        let mut events = INITIAL_EVENTS();
+        events.extend(
+            extra
+                .into_iter()
+                .map(|(event, depth)| (event.event_id().clone(), with_depth(event, depth))),
+        );
+        events
+    }
+
+    fn state_set(
+        events: &HashMap<OwnedEventId, Arc<PduEvent>>,
+        ids: &[&str],
+    ) -> StateMap<OwnedEventId> {
+        ["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]
+            .iter()
+            .chain(ids)
+            .map(|id| {
+                let event = &events[&event_id(id)];
+                (
+                    event.event_type().with_state_key(event.state_key().unwrap()),
+                    event.event_id().clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn power_levels(
+        id: &str,
+        sender: &ruma_common::UserId,
+        content: serde_json::Value,
+    ) -> Arc<PduEvent> {
+        to_pdu_event(
+            id,
+            sender,
+            TimelineEventType::RoomPowerLevels,
+            Some(""),
+            to_raw_json_value(&content).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["START"],
+        )
+    }
+
+    fn topic(id: &str, sender: &ruma_common::UserId, power_levels: &str) -> Arc<PduEvent> {
+        to_pdu_event(
+            id,
+            sender,
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": id })).unwrap(),
+            &["CREATE", "IMA", power_levels],
+            &["START"],
+        )
+    }
+
+    #[tokio::test]
+    async fn deepest_allowed_power_levels() {
+        let events = events(vec![
+            (power_levels("PA", alice(), json!({ "users": { alice(): 100, bob(): 50 } })), 3),
+            (power_levels("PB", alice(), json!({ "users": { alice(): 100 } })), 5),
+        ]);
+        let state_sets = [state_set(&events, &["PA"]), state_set(&events, &["PB"])];
+
+        let resolved =
+            resolve_v1(&RoomVersionId::V1, &state_sets, &|id| ready(events.get(&id).cloned()))
+                .await
+                .unwrap();
+
+        let key = TimelineEventType::RoomPowerLevels.with_state_key("");
+        assert_eq!(resolved[&key], event_id("PB"));
+    }
+
+    #[tokio::test]
+    async fn power_levels_not_allowed_by_previous() {
+        // PB is deeper, but bob cannot change the power levels set by PA.
+        let events = events(vec![
+            (power_levels("PA", alice(), json!({ "users": { alice(): 100 } })), 3),
+            (power_levels("PB", bob(), json!({ "users": { alice(): 100, bob(): 100 } })), 5),
+        ]);
+        let state_sets = [state_set(&events, &["PA"]), state_set(&events, &["PB"])];
+
+        let resolved =
+            resolve_v1(&RoomVersionId::V1, &state_sets, &|id| ready(events.get(&id).cloned()))
+                .await
+                .unwrap();
+
+        let key = TimelineEventType::RoomPowerLevels.with_state_key("");
+        assert_eq!(resolved[&key], event_id("PA"));
+    }
+
+    #[tokio::test]
+    async fn topic_resolved_against_power_levels() {
+        // The topic set by bob is the deepest, but bob is only allowed to send it with the power
+        // levels of PB, which lost against PA.
+        let events = events(vec![
+            (power_levels("PA", alice(), json!({ "users": { alice(): 100 } })), 3),
+            (power_levels("PB", alice(), json!({ "users": { alice(): 100, bob(): 50 } })), 2),
+            (topic("TA", alice(), "PA"), 4),
+            (topic("TB", bob(), "PB"), 6),
+        ]);
+        let state_sets = [state_set(&events, &["PA", "TA"]), state_set(&events, &["PB", "TB"])];
+        let fetch = |id| ready(events.get(&id).cloned());
+
+        let resolved = resolve_v1(&RoomVersionId::V1, &state_sets, &fetch).await.unwrap();
+
+        let power_levels_key = TimelineEventType::RoomPowerLevels.with_state_key("");
+        let topic_key = TimelineEventType::RoomTopic.with_state_key("");
+        assert_eq!(resolved[&power_levels_key], event_id("PA"));
+        assert_eq!(resolved[&topic_key], event_id("TA"));
+
+        // `StateResolution` uses the same algorithm for room version 1.
+        let auth_chain_sets = vec![Default::default(); 2];
+        let exists = |id| ready(events.contains_key(&id));
+        let resolved_v1_room = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .resolve(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(resolved_v1_room, resolved);
+    }
+
+    #[tokio::test]
+    async fn cancelled() {
+        let events = events(vec![
+            (power_levels("PA", alice(), json!({ "users": { alice(): 100 } })), 3),
+            (power_levels("PB", alice(), json!({ "users": { alice(): 100, bob(): 50 } })), 2),
+            (topic("TA", alice(), "PA"), 4),
+            (topic("TB", bob(), "PB"), 6),
+        ]);
+        let state_sets = [state_set(&events, &["PA", "TA"]), state_set(&events, &["PB", "TB"])];
+        let auth_chain_sets = vec![Default::default(); 2];
+        let fetch = |id| ready(events.get(&id).cloned());
+        let exists = |id| ready(events.contains_key(&id));
+        let (clean, _) = separate(state_sets.iter());
+
+        let outcome = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .with_should_continue(|| false)
+            .resolve_partial(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert!(
+            matches!(
+                &outcome,
+                ResolveOutcome::Partial { resolved_so_far, reason: PartialReason::Cancelled }
+                    if *resolved_so_far == clean
+            ),
+            "{outcome:?}"
+        );
+
+        let err = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .with_deadline(Instant::now())
+            .resolve(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{err:?}");
+
+        let options = ResolveOptions { max_conflicted_events: Some(3), ..Default::default() };
+        let outcome = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .with_options(options)
+            .with_should_continue(|| true)
+            .resolve_partial(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert!(
+            matches!(
+                &outcome,
+                ResolveOutcome::Partial {
+                    resolved_so_far,
+                    reason: PartialReason::TooManyConflictedEvents,
+                } if *resolved_so_far == clean
+            ),
+            "{outcome:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn report_rejected() {
+        // PB is deeper, but bob cannot change the power levels set by PA.
+        let events = events(vec![
+            (power_levels("PA", alice(), json!({ "users": { alice(): 100 } })), 3),
+            (power_levels("PB", bob(), json!({ "users": { alice(): 100, bob(): 100 } })), 5),
+        ]);
+        let state_sets = [state_set(&events, &["PA"]), state_set(&events, &["PB"])];
+        let auth_chain_sets = vec![Default::default(); 2];
+        let fetch = |id| ready(events.get(&id).cloned());
+        let exists = |id| ready(events.contains_key(&id));
+
+        let report = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .resolve_report(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+
+        let key = TimelineEventType::RoomPowerLevels.with_state_key("");
+        assert_eq!(report.state[&key], event_id("PA"));
+        assert_eq!(report.rejected, [(event_id("PB"), RejectionReason::InsufficientPowerLevel)]);
+
+        // Trusted events are not checked.
+        let options = ResolveOptions {
+            trusted: [event_id("PB")].into(),
+            report_rejected: true,
+            ..Default::default()
+        };
+        let report = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .with_options(options.clone())
+            .resolve_report(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap();
+        assert_eq!(report.state[&key], event_id("PB"));
+        assert!(report.rejected.is_empty());
+
+        // The power level cache doesn't apply to room version 1.
+        let options = ResolveOptions {
+            power_level_cache: Some(Arc::new(PowerLevelCache::new())),
+            ..Default::default()
+        };
+        let err = StateResolution::new(RoomVersionId::V1, &fetch, &exists)
+            .with_options(options.clone())
+            .resolve_report(&state_sets, &auth_chain_sets)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnsupportedOption("power_level_cache")), "{err:?}");
+    }
+}
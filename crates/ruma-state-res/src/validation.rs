@@ -0,0 +1,144 @@
+use std::future::Future;
+
+use ruma_common::{
+    CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedServerName, RoomVersionId,
+};
+use ruma_signatures::{PublicKeyMap, PublicKeySet, Verified};
+use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
+
+use crate::{room_version::EventFormatVersion, validate_pdu_size, PduValidationError, RoomVersion};
+
+/// A PDU that passed [`validate_pdu`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ValidatedPdu {
+    /// The ID of the event.
+    ///
+    /// For room versions 1 and 2 it is the `event_id` field of the PDU, for later room versions it
+    /// is computed from the reference hash of the PDU.
+    pub event_id: OwnedEventId,
+
+    /// The PDU as a canonical JSON object.
+    pub object: CanonicalJsonObject,
+}
+
+/// Validate a PDU received over federation against the rules of the given room version.
+///
+/// This checks, in order:
+///
+/// * that the PDU is not larger than the maximum size allowed by the room version, with
+///   [`validate_pdu_size`],
+/// * that the PDU is a JSON object that can be represented as canonical JSON,
+/// * that all the fields required by the room version are present,
+/// * that the PDU is signed by all the servers that must sign it, with the keys returned by
+///   `fetch_server_keys`,
+/// * that the content hash of the PDU matches its content.
+///
+/// It should be called with the PDU as it was received, before it is authorized or used in state
+/// resolution.
+///
+/// # Parameters
+///
+/// * `raw`: The JSON of the PDU.
+/// * `room_version`: The version of the room of the PDU.
+/// * `fetch_server_keys`: Function to fetch the public signing keys of a server. Returning `None`
+///   means that the keys of the server are unknown, so its signatures can't be verified.
+pub async fn validate_pdu<F, Fut>(
+    raw: &RawJsonValue,
+    room_version: &RoomVersionId,
+    fetch_server_keys: F,
+) -> Result<ValidatedPdu, PduValidationError>
+where
+    F: Fn(OwnedServerName) -> Fut,
+    Fut: Future<Output = Option<PublicKeySet>>,
+{
+    let rules = RoomVersion::new(room_version)
+        .map_err(|_| PduValidationError::UnsupportedRoomVersion(room_version.to_string()))?;
+
+    validate_pdu_size(raw, &rules)?;
+
+    let object: CanonicalJsonObject =
+        from_json_str(raw.get()).map_err(PduValidationError::NotCanonicalJson)?;
+
+    check_required_fields(&object, &rules)?;
+
+    let servers = ruma_signatures::servers_to_check_signatures(&object, room_version)
+        .map_err(PduValidationError::InvalidSignatures)?;
+
+    let mut public_key_map = PublicKeyMap::new();
+    for server in servers {
+        if let Some(keys) = fetch_server_keys(server.clone()).await {
+            public_key_map.insert(server.into(), keys);
+        }
+    }
+
+    match ruma_signatures::verify_event(&public_key_map, &object, room_version) {
+        Ok(Verified::All) => {}
+        Ok(Verified::Signatures) => return Err(PduValidationError::ContentHashMismatch),
+        Err(error) => return Err(PduValidationError::InvalidSignatures(error)),
+    }
+
+    let event_id = match rules.event_format {
+        EventFormatVersion::V1 => match object.get("event_id") {
+            Some(CanonicalJsonValue::String(event_id)) => EventId::parse(event_id)
+                .map_err(|_| PduValidationError::MissingField("event_id"))?,
+            _ => return Err(PduValidationError::MissingField("event_id")),
+        },
+        _ => {
+            let hash = ruma_signatures::reference_hash(&object, room_version)
+                .map_err(PduValidationError::InvalidSignatures)?;
+            EventId::parse(format!("${hash}")).expect("reference hash should be a valid event ID")
+        }
+    };
+
+    Ok(ValidatedPdu { event_id, object })
+}
+
+/// Check that the fields required by the room version are present in the PDU, with the right type.
+fn check_required_fields(
+    object: &CanonicalJsonObject,
+    rules: &RoomVersion,
+) -> Result<(), PduValidationError> {
+    use CanonicalJsonValue::{Array, Integer, Object, String};
+
+    let is_create = matches!(object.get("type"), Some(String(t)) if t == "m.room.create");
+
+    for field in [
+        "type",
+        "sender",
+        "content",
+        "origin_server_ts",
+        "depth",
+        "prev_events",
+        "auth_events",
+        "hashes",
+        "signatures",
+    ] {
+        let has_type = matches!(
+            (field, object.get(field)),
+            ("content" | "hashes" | "signatures", Some(Object(_)))
+                | ("origin_server_ts" | "depth", Some(Integer(_)))
+                | ("prev_events" | "auth_events", Some(Array(_)))
+                | ("type" | "sender", Some(String(_)))
+        );
+
+        if !has_type {
+            return Err(PduValidationError::MissingField(field));
+        }
+    }
+
+    // When the room ID is the reference hash of the create event, the create event doesn't have a
+    // `room_id`.
+    let room_id_required = !(rules.room_ids_as_hashes && is_create);
+    if room_id_required && !matches!(object.get("room_id"), Some(String(_))) {
+        return Err(PduValidationError::MissingField("room_id"));
+    }
+
+    if matches!(rules.event_format, EventFormatVersion::V1)
+        && !matches!(object.get("event_id"), Some(String(_)))
+    {
+        return Err(PduValidationError::MissingField("event_id"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+#![cfg(feature = "test-utils")]
+
+use ruma_events::TimelineEventType;
+use ruma_state_res::test_utils::{alice, bob, do_check, event_id, to_init_pdu_event};
+use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+#[tokio::test]
+async fn do_check_topic() {
+    let events = &[
+        to_init_pdu_event(
+            "T1",
+            alice(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({})).unwrap(),
+        ),
+        to_init_pdu_event(
+            "T2",
+            bob(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({})).unwrap(),
+        ),
+    ];
+
+    let edges = vec![vec!["END", "T1", "START"], vec!["END", "T2", "START"]]
+        .into_iter()
+        .map(|list| list.into_iter().map(event_id).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    // Bob doesn't have the power level to change the topic.
+    let expected_state_ids = vec![event_id("T1")];
+
+    do_check(events, edges, expected_state_ids).await;
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use futures_util::future::ready;
+use ruma_common::{
+    serde::{base64::Standard, Base64},
+    CanonicalJsonObject, CanonicalJsonValue, OwnedServerName, RoomVersionId,
+};
+use ruma_signatures::{hash_and_sign_event, Ed25519KeyPair, PublicKeySet};
+use ruma_state_res::{validate_pdu, PduValidationError, ValidatedPdu};
+use serde_json::{from_value as from_json_value, json, value::to_raw_value as to_raw_json_value};
+
+fn key_pair() -> Ed25519KeyPair {
+    let document = Ed25519KeyPair::generate().unwrap();
+    Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap()
+}
+
+fn public_key_set(key_pair: &Ed25519KeyPair) -> PublicKeySet {
+    BTreeMap::from([(
+        "ed25519:1".to_owned(),
+        Base64::<Standard>::new(key_pair.public_key().to_vec()),
+    )])
+}
+
+fn unsigned_pdu() -> CanonicalJsonObject {
+    from_json_value(json!({
+        "auth_events": [],
+        "content": { "body": "hello" },
+        "depth": 3,
+        "origin_server_ts": 1_000_000,
+        "prev_events": [],
+        "room_id": "!room:domain",
+        "sender": "@alice:domain",
+        "type": "m.room.message",
+    }))
+    .unwrap()
+}
+
+fn signed_pdu(key_pair: &Ed25519KeyPair) -> CanonicalJsonObject {
+    let mut object = unsigned_pdu();
+    hash_and_sign_event("domain", key_pair, &mut object, &RoomVersionId::V6).unwrap();
+    object
+}
+
+async fn validate(
+    object: &CanonicalJsonObject,
+    keys: &PublicKeySet,
+) -> Result<ValidatedPdu, PduValidationError> {
+    let raw = to_raw_json_value(object).unwrap();
+    validate_pdu(&raw, &RoomVersionId::V6, |server: OwnedServerName| {
+        ready((server == "domain").then(|| keys.clone()))
+    })
+    .await
+}
+
+#[tokio::test]
+async fn valid_pdu() {
+    let key_pair = key_pair();
+    let keys = public_key_set(&key_pair);
+    let object = signed_pdu(&key_pair);
+
+    let pdu = validate(&object, &keys).await.unwrap();
+    let reference_hash = ruma_signatures::reference_hash(&object, &RoomVersionId::V6).unwrap();
+    assert_eq!(pdu.event_id.as_str(), format!("${reference_hash}"));
+    assert_eq!(pdu.object, object);
+}
+
+#[tokio::test]
+async fn too_large_pdu() {
+    let key_pair = key_pair();
+    let keys = public_key_set(&key_pair);
+    let mut object = signed_pdu(&key_pair);
+
+    // The size is checked before the signatures and hashes, so the PDU doesn't need to be signed
+    // again.
+    object.insert(
+        "content".to_owned(),
+        from_json_value(json!({ "body": "a".repeat(70_000) })).unwrap(),
+    );
+
+    let err = validate(&object, &keys).await.unwrap_err();
+    assert!(matches!(err, PduValidationError::TooLarge(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn non_canonical_pdu() {
+    let key_pair = key_pair();
+    let keys = public_key_set(&key_pair);
+    let raw = to_raw_json_value(&json!({
+        "content": { "body": "hello", "amount": 1.5 },
+        "type": "m.room.message",
+    }))
+    .unwrap();
+
+    let err =
+        validate_pdu(&raw, &RoomVersionId::V6, |_| ready(Some(keys.clone()))).await.unwrap_err();
+    assert!(matches!(err, PduValidationError::NotCanonicalJson(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn pdu_missing_field() {
+    let key_pair = key_pair();
+    let keys = public_key_set(&key_pair);
+    let mut object = unsigned_pdu();
+    object.remove("depth");
+    hash_and_sign_event("domain", &key_pair, &mut object, &RoomVersionId::V6).unwrap();
+
+    let err = validate(&object, &keys).await.unwrap_err();
+    assert!(matches!(err, PduValidationError::MissingField("depth")), "{err:?}");
+}
+
+#[tokio::test]
+async fn pdu_invalid_signature() {
+    let key_pair = key_pair();
+    let object = signed_pdu(&key_pair);
+
+    // The keys of the server are unknown.
+    let err = validate(&object, &PublicKeySet::new()).await.unwrap_err();
+    assert!(matches!(err, PduValidationError::InvalidSignatures(_)), "{err:?}");
+
+    // The PDU is signed by another key.
+    let err = validate(&object, &public_key_set(&self::key_pair())).await.unwrap_err();
+    assert!(matches!(err, PduValidationError::InvalidSignatures(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn pdu_content_hash_mismatch() {
+    let key_pair = key_pair();
+    let keys = public_key_set(&key_pair);
+    let mut object = signed_pdu(&key_pair);
+
+    // The content is not covered by the signatures, only by the content hash.
+    object.insert(
+        "content".to_owned(),
+        CanonicalJsonValue::Object(from_json_value(json!({ "body": "bye" })).unwrap()),
+    );
+
+    let err = validate(&object, &keys).await.unwrap_err();
+    assert!(matches!(err, PduValidationError::ContentHashMismatch), "{err:?}");
+}